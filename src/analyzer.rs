@@ -0,0 +1,274 @@
+//! Pluggable content analyzers that run over every file stored in the image
+
+use anyhow::Result;
+use std::io::{self, Write};
+
+use crate::archive::LayerSet;
+use crate::events::{self, EventSink, LayerEntry, LayerEvent, ReadOptions};
+
+/// Metadata describing a file handed to analyzers
+#[derive(Debug, Clone, Copy)]
+pub struct EntryInfo<'a> {
+    /// Path inside the layer, without a leading "./"
+    pub path: &'a str,
+    /// Label of the layer storing this file
+    pub layer: &'a str,
+    /// Size in bytes as recorded in the tar header
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u32,
+}
+
+/// Something an analyzer wants to report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    /// Name of the analyzer that produced this finding
+    pub analyzer: &'static str,
+    /// Path the finding refers to
+    pub path: String,
+    /// Label of the layer the file came from
+    pub layer: String,
+    /// Short category, e.g. "elf" or "duplicate"
+    pub kind: String,
+    /// Human-readable description
+    pub detail: String,
+    /// Line in the file the finding points at, for text matches
+    pub line: Option<usize>,
+}
+
+/// A content analyzer
+///
+/// Analyzers see every regular file of every layer (not just the merged view),
+/// in layer order, and may keep state across files to report in `finish`.
+pub trait Analyzer {
+    /// Short name used in output and `--only` filters
+    fn name(&self) -> &'static str;
+
+    /// Whether this analyzer needs the content of the given file
+    fn wants(&self, entry: &EntryInfo) -> bool;
+
+    /// Most bytes of a wanted file that `analyze` needs to see
+    fn content_limit(&self, _entry: &EntryInfo) -> u64 {
+        u64::MAX
+    }
+
+    /// Called with every piece of a wanted file, in order, before `analyze`;
+    /// lets analyzers digest whole files past their `content_limit`
+    fn read_chunk(&mut self, _entry: &EntryInfo, _chunk: &[u8]) {}
+
+    /// Called with every layer event, before `analyze` for the same entry;
+    /// lets analyzers track what later layers delete or replace
+    fn observe(&mut self, _event: &LayerEvent) {}
+
+    /// Inspect a file's content, cut to `content_limit`, and return any
+    /// per-file findings
+    fn analyze(&mut self, entry: &EntryInfo, content: &[u8]) -> Vec<Finding>;
+
+    /// Report findings that need the whole image (called once after all layers)
+    fn finish(&mut self) -> Vec<Finding> {
+        Vec::new()
+    }
+}
+
+/// Stream every regular file of every layer through the given analyzers
+///
+/// File content is read once per file and only if some analyzer wants it,
+/// and held in memory only up to the analyzers' `content_limit`.
+pub fn scan(layer_set: &LayerSet, analyzers: &mut [&mut dyn Analyzer]) -> Result<Vec<Finding>> {
+    let mut sink = AnalyzerSink { analyzers, layer: String::new(), findings: Vec::new() };
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+
+    for analyzer in sink.analyzers.iter_mut() {
+        sink.findings.extend(analyzer.finish());
+    }
+    Ok(sink.findings)
+}
+
+/// Feeds regular files from layer events to analyzers
+struct AnalyzerSink<'a, 'b> {
+    analyzers: &'a mut [&'b mut dyn Analyzer],
+    /// Label of the layer being read
+    layer: String,
+    findings: Vec<Finding>,
+}
+
+impl AnalyzerSink<'_, '_> {
+    fn info<'e>(&'e self, entry: &'e LayerEntry) -> EntryInfo<'e> {
+        EntryInfo { path: &entry.path, layer: &self.layer, size: entry.size, mode: entry.mode }
+    }
+}
+
+impl EventSink for AnalyzerSink<'_, '_> {
+    fn wants_content(&self, entry: &LayerEntry) -> bool {
+        let info = self.info(entry);
+        self.analyzers.iter().any(|a| a.wants(&info))
+    }
+
+    fn content_limit(&self, entry: &LayerEntry) -> u64 {
+        let info = self.info(entry);
+        self.analyzers.iter()
+            .filter(|a| a.wants(&info))
+            .map(|a| a.content_limit(&info))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn content_chunk(&mut self, entry: &LayerEntry, chunk: &[u8]) {
+        let info = EntryInfo { path: &entry.path, layer: &self.layer, size: entry.size, mode: entry.mode };
+        for analyzer in self.analyzers.iter_mut().filter(|a| a.wants(&info)) {
+            analyzer.read_chunk(&info, chunk);
+        }
+    }
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        for analyzer in self.analyzers.iter_mut() {
+            analyzer.observe(event);
+        }
+        match event {
+            LayerEvent::LayerStarted { layer, .. } => self.layer = layer.label(),
+            LayerEvent::EntryAdded { entry, content: Some(content) } => {
+                let info = EntryInfo { path: &entry.path, layer: &self.layer, size: entry.size, mode: entry.mode };
+                for analyzer in self.analyzers.iter_mut().filter(|a| a.wants(&info)) {
+                    let limit = usize::try_from(analyzer.content_limit(&info)).unwrap_or(usize::MAX);
+                    self.findings.extend(analyzer.analyze(&info, &content[..content.len().min(limit)]));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The set of analyzers to run
+#[derive(Default)]
+pub struct Registry {
+    analyzers: Vec<Box<dyn Analyzer>>,
+}
+
+impl Registry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry with every built-in analyzer
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(crate::elf::ElfAnalyzer));
+        registry.register(Box::new(crate::dupes::DupeAnalyzer::new()));
+        registry.register(Box::new(crate::secrets::SecretsAnalyzer::new(&Default::default())
+            .expect("default secret detectors are valid")));
+        registry.register(Box::new(crate::keys::KeySink::default()));
+        registry.register(Box::new(crate::packages::PackageSink::default()));
+        registry
+    }
+
+    /// Add an analyzer
+    pub fn register(&mut self, analyzer: Box<dyn Analyzer>) {
+        self.analyzers.push(analyzer);
+    }
+
+    /// Names of the registered analyzers, in registration order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.analyzers.iter().map(|a| a.name()).collect()
+    }
+
+    /// Keep only analyzers whose name is in `names`
+    pub fn retain(&mut self, names: &[String]) {
+        self.analyzers.retain(|a| names.iter().any(|n| n == a.name()));
+    }
+
+    /// Run all registered analyzers over the image
+    pub fn run(&mut self, layer_set: &LayerSet) -> Result<Vec<Finding>> {
+        let mut refs: Vec<&mut dyn Analyzer> = self.analyzers.iter_mut()
+            .map(|a| a.as_mut() as &mut dyn Analyzer)
+            .collect();
+        scan(layer_set, &mut refs)
+    }
+}
+
+/// Print findings grouped by analyzer
+pub fn render_findings(findings: &[Finding]) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_findings(&mut out, findings)?;
+    out.flush()
+}
+
+fn write_findings<W: Write>(out: &mut W, findings: &[Finding]) -> io::Result<()> {
+    if findings.is_empty() {
+        return writeln!(out, "No findings");
+    }
+
+    let mut current = "";
+    for finding in findings {
+        if finding.analyzer != current {
+            if !current.is_empty() {
+                writeln!(out)?;
+            }
+            writeln!(out, "{}:", finding.analyzer)?;
+            current = finding.analyzer;
+        }
+        writeln!(out, "  [{}] {}: {}", finding.layer, finding.path, finding.detail)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct CountAnalyzer;
+
+    impl Analyzer for CountAnalyzer {
+        fn name(&self) -> &'static str {
+            "count"
+        }
+
+        fn wants(&self, entry: &EntryInfo) -> bool {
+            entry.path.ends_with(".txt")
+        }
+
+        fn analyze(&mut self, entry: &EntryInfo, content: &[u8]) -> Vec<Finding> {
+            vec![Finding {
+                analyzer: self.name(),
+                path: entry.path.to_string(),
+                layer: entry.layer.to_string(),
+                kind: "size".to_string(),
+                detail: format!("{} bytes", content.len()),
+                line: None,
+            }]
+        }
+    }
+
+    #[test]
+    fn test_registry_names_and_retain() {
+        let mut registry = Registry::new();
+        registry.register(Box::new(CountAnalyzer));
+        registry.register(Box::new(crate::elf::ElfAnalyzer));
+        assert_eq!(registry.names(), vec!["count", "elf"]);
+
+        registry.retain(&["elf".to_string()]);
+        assert_eq!(registry.names(), vec!["elf"]);
+    }
+
+    #[test]
+    fn test_write_findings_groups_by_analyzer() {
+        let finding = |analyzer, path: &str| Finding {
+            analyzer,
+            path: path.to_string(),
+            layer: "abc1234".to_string(),
+            kind: "k".to_string(),
+            detail: "d".to_string(),
+            line: None,
+        };
+        let mut out = Vec::new();
+        write_findings(&mut out, &[finding("a", "x"), finding("a", "y"), finding("b", "z")]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "a:\n  [abc1234] x: d\n  [abc1234] y: d\n\nb:\n  [abc1234] z: d\n");
+    }
+}
@@ -1,13 +1,36 @@
+//! Docker and OCI image archives: locating the image's layers and merging
+//! them into a [`Node`] tree
+//!
+//! [`open_archive`] finds the layers and config of an archive without
+//! reading the layers; [`process_archive`] also merges them, and
+//! [`merge_layers`] merges layers that were located some other way.
+
 use anyhow::{Context, Result};
 use flate2::read::GzDecoder;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Seek};
-use std::path::Path;
-use tar::{Archive, Entry};
-
-use crate::manifest;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tar::Archive;
+use tempfile::TempDir;
+
+#[cfg(feature = "cache")]
+use crate::cache;
+use crate::config::{self, ImageConfig};
+use crate::diagnostics::{self, WarningKind};
+use crate::error::{self, ContreeError};
+use crate::manifest::ManifestEntry;
+use crate::oci::Platform;
+use crate::packages::{self, PackageSink};
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::paranoid::{ParanoidSink, SuspiciousEntry};
+use crate::profile::LayerProfile;
+use crate::progress::TermProgress;
+use crate::source::{self, ImageChooser, ImageSelector, LayerSource};
 use crate::tree::Node;
+use crate::utils;
 use crate::whiteout;
 
 /// Extract abbreviated hash from layer name
@@ -17,6 +40,7 @@ fn extract_layer_hash(layer_name: &str, length: usize) -> Option<String> {
     // Extract the hash portion (directory name or filename without extension)
     let path = layer_name.trim_end_matches("/layer.tar")
                          .trim_end_matches(".tar.gz")
+                         .trim_end_matches(".tar.zst")
                          .trim_end_matches(".tar");
 
     // Get the last component (the hash)
@@ -29,244 +53,875 @@ fn extract_layer_hash(layer_name: &str, length: usize) -> Option<String> {
     }
 }
 
-/// Process a Docker archive and build the merged filesystem tree
-pub fn process_archive(archive_path: &Path, show_layers: bool) -> Result<Node> {
-    let mut file = File::open(archive_path)
-        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+/// Where the bytes of a blob from the outer archive can be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blob {
+    /// Stored in an uncompressed outer archive, read in place by offset
+    InArchive { archive: PathBuf, offset: u64, size: u64 },
+    /// A file of its own (spooled out of a compressed outer archive)
+    File(PathBuf),
+    /// A small document read while scanning a compressed outer archive
+    Memory(Arc<[u8]>),
+    /// Stored in an uncompressed outer archive held in memory, read in place
+    InBuffer { buffer: Arc<[u8]>, offset: u64, size: u64 },
+}
 
-    // Check if the outer archive is gzipped - check extension first, then magic bytes
-    let is_gzipped = archive_path
-        .to_string_lossy()
-        .ends_with(".gz") || archive_path
-        .to_string_lossy()
-        .ends_with(".tgz") || {
-            // Check magic bytes: gzip files start with 0x1f 0x8b
-            let mut magic = [0u8; 2];
-            if file.read_exact(&mut magic).is_ok() {
-                file.seek(std::io::SeekFrom::Start(0)).ok();
-                magic == [0x1f, 0x8b]
-            } else {
-                file.seek(std::io::SeekFrom::Start(0)).ok();
-                false
+impl Blob {
+    /// Open the blob's raw bytes
+    pub fn open(&self) -> error::Result<Box<dyn Read>> {
+        match self {
+            Blob::InArchive { archive, offset, size } => {
+                let mut file = File::open(archive).map_err(error::io_error(archive))?;
+                file.seek(SeekFrom::Start(*offset)).map_err(error::io_error(archive))?;
+                Ok(Box::new(file.take(*size)))
             }
-        };
+            Blob::File(path) => Ok(Box::new(File::open(path).map_err(error::io_error(path))?)),
+            Blob::Memory(bytes) => Ok(Box::new(Cursor::new(Arc::clone(bytes)))),
+            Blob::InBuffer { buffer, offset, size } => {
+                let mut cursor = Cursor::new(Arc::clone(buffer));
+                cursor.set_position(*offset);
+                Ok(Box::new(cursor.take(*size)))
+            }
+        }
+    }
 
-    let mut archive = if is_gzipped {
-        Archive::new(Box::new(GzDecoder::new(file)) as Box<dyn Read>)
-    } else {
-        Archive::new(Box::new(file) as Box<dyn Read>)
-    };
+    /// Size of the raw blob in bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            Blob::InArchive { size, .. } | Blob::InBuffer { size, .. } => *size,
+            Blob::File(path) => std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            Blob::Memory(bytes) => bytes.len() as u64,
+        }
+    }
 
-    // First pass: extract manifest and layer files to temporary directory
-    let temp_dir = tempfile::tempdir().context("Failed to create temp directory")?;
-    let mut layer_paths: HashMap<String, std::path::PathBuf> = HashMap::new();
-    let mut manifest_bytes: Option<Vec<u8>> = None;
+    pub(crate) fn read_all(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.open()?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
 
-    for entry in archive.entries().context("Failed to read archive entries")? {
-        let mut entry = match entry {
-            Ok(e) => e,
-            Err(err) => {
-                eprintln!("Warning: Skipping corrupted archive entry: {}", err);
-                continue;
-            }
-        };
-        let path = match entry.path() {
-            Ok(p) => p,
-            Err(err) => {
-                eprintln!("Warning: Skipping entry with invalid path: {}", err);
-                continue;
+/// A layer blob in the outer archive
+pub struct Layer {
+    /// Path of the layer inside the outer archive (as listed in the manifest)
+    pub name: String,
+    /// Where the layer's bytes are
+    pub blob: Blob,
+    /// Uncompressed layer digest from the config's rootfs.diff_ids
+    pub diff_id: Option<String>,
+    /// How the layer blob is compressed
+    pub compression: Compression,
+}
+
+/// Compression of a layer blob, detected from its magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Identify the compression from the first bytes of a blob
+    pub fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Sniff the compression of a stream from its first bytes
+    pub(crate) fn sniff<R: Read>(reader: &mut R) -> Self {
+        let mut magic = [0u8; 4];
+        let mut filled = 0;
+        while filled < magic.len() {
+            match reader.read(&mut magic[filled..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => filled += n,
             }
-        };
-        let path_str = path.to_string_lossy();
-
-        // Check if this is manifest.json
-        if path_str == "manifest.json" {
-            let mut buf = Vec::new();
-            entry.read_to_end(&mut buf).context("Failed to read manifest.json")?;
-            manifest_bytes = Some(buf);
-            continue;
         }
+        Self::from_magic(&magic[..filled])
+    }
 
-        // Check if this is a layer tar file
-        if path_str.ends_with("/layer.tar")
-            || path_str.ends_with(".tar")
-            || path_str.ends_with(".tar.gz")
-            || path_str.ends_with(".tgz")
-            || path_str.ends_with("/layer.tar.gz") {
-            // Save layer to temp file, preserving the extension
-            let layer_name = path_str.to_string();
-            let extension = if path_str.ends_with(".tar.gz") || path_str.ends_with("/layer.tar.gz") {
-                ".tar.gz"
-            } else if path_str.ends_with(".tgz") {
-                ".tgz"
-            } else {
-                ".tar"
-            };
-            let temp_path = temp_dir.path().join(format!("layer-{}{}", layer_paths.len(), extension));
+    /// Sniff the compression of a file without disturbing its read position
+    pub(crate) fn detect<R: Read + Seek>(file: &mut R) -> Self {
+        let compression = Self::sniff(file);
+        file.seek(SeekFrom::Start(0)).ok();
+        compression
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
 
-            let mut temp_file = File::create(&temp_path)
-                .context("Failed to create temp file")?;
-            std::io::copy(&mut entry, &mut temp_file)
-                .context("Failed to copy layer to temp file")?;
+    /// File extension for a layer tarball with this compression
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => ".tar",
+            Compression::Gzip => ".tar.gz",
+            Compression::Zstd => ".tar.zst",
+        }
+    }
+}
 
-            layer_paths.insert(layer_name, temp_path);
+impl Layer {
+    /// Abbreviated hash used to label this layer in output
+    /// Prefers the config's diff_id, which is stable across compression and
+    /// archive layouts; falls back to the blob name inside the archive.
+    pub fn label(&self) -> String {
+        if let Some(diff_id) = &self.diff_id {
+            let hex = diff_id.split_once(':').map_or(diff_id.as_str(), |(_, hex)| hex);
+            if !hex.is_empty() {
+                return hex.chars().take(7).collect();
+            }
         }
+        extract_layer_hash(&self.name, 7).unwrap_or_else(|| self.name.clone())
     }
+}
 
-    let manifest_bytes = manifest_bytes.context("manifest.json not found in archive")?;
-    let layers = manifest::parse_manifest(&manifest_bytes)?;
+/// The layers of a Docker archive in manifest order
+pub struct LayerSet {
+    /// Layers ordered bottom-most first
+    pub layers: Vec<Layer>,
+    /// Parsed image configuration, if the archive has one
+    pub config: Option<ImageConfig>,
+    /// Name and raw bytes of the config blob, kept for re-export
+    pub config_blob: Option<(String, Vec<u8>)>,
+    /// Image tags from the manifest
+    pub repo_tags: Vec<String>,
+    /// Layers the manifest lists that were left out because the source
+    /// doesn't have them (see [`ImageSelector::allow_missing_layers`])
+    pub missing_layers: Vec<MissingLayer>,
+    // Keeps spooled blobs alive for as long as the set is in use
+    _temp_dir: Option<TempDir>,
+}
 
-    // Second pass: apply layers in manifest order
-    let mut root = Node::new_dir(0o755, 0, 0);
+/// A layer the manifest lists that isn't in the archive, e.g. a foreign
+/// layer stored elsewhere, or one stripped from the archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingLayer {
+    /// Position among the manifest's layers, bottom-most first
+    pub position: usize,
+    /// Path of the layer inside the outer archive, as listed in the manifest
+    pub name: String,
+}
 
-    for layer_name in layers.iter() {
-        let temp_path = layer_paths.get(layer_name)
-            .with_context(|| format!("Layer {} not found in archive", layer_name))?;
+/// Open a Docker archive, or an unpacked image directory, and locate its
+/// layers in manifest order. Blobs of an uncompressed archive are read in
+/// place; a compressed archive can't be read by offset, so its blobs are
+/// spooled to a temporary directory.
+pub fn open_archive(archive_path: &Path) -> error::Result<LayerSet> {
+    source::for_path(archive_path).open_layers(&ImageSelector::default(), &mut TermProgress::disabled())
+}
 
-        let layer_hash = if show_layers {
-            // Extract hash from layer name (e.g., "abc123def.../layer.tar" -> "abc123d")
-            extract_layer_hash(layer_name, 7)
-        } else {
-            None
+impl LayerSet {
+    /// The image ID, read off the name of the config blob (`<hex>.json`, or
+    /// `blobs/sha256/<hex>`) rather than hashing it again
+    pub fn digest(&self) -> Option<String> {
+        let (name, _) = self.config_blob.as_ref()?;
+        let hex = name.rsplit('/').next()?.trim_end_matches(".json");
+        let is_digest = hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+        is_digest.then(|| format!("sha256:{}", hex))
+    }
+
+    /// Pick the config and layers of the image `entry` out of the blobs of
+    /// its source, keeping `temp_dir` alive while the set is in use. Layers
+    /// that aren't among the blobs, and a config that disagrees with the
+    /// manifest, fail it or are warned about as `select` says.
+    pub(crate) fn assemble(
+        blobs: HashMap<String, Blob>,
+        entry: ManifestEntry,
+        temp_dir: Option<TempDir>,
+        select: &ImageSelector,
+    ) -> error::Result<LayerSet> {
+        let config_blob = match entry.config.as_ref().and_then(|name| Some((name, blobs.get(name)?))) {
+            Some((name, blob)) => {
+                let bytes = blob.read_all().context("Failed to read image config")?;
+                Some((name.clone(), bytes))
+            }
+            None => None,
+        };
+        let config = match &config_blob {
+            Some((_, bytes)) => match config::parse_config(bytes) {
+                Ok(cfg) => Some(cfg),
+                Err(err) => {
+                    diagnostics::warn(WarningKind::ImageConfig, format!("Ignoring unreadable image config: {:#}", err));
+                    None
+                }
+            },
+            None => None,
         };
 
-        apply_layer(&mut root, temp_path, layer_hash.as_deref())?;
+        let mismatches = config.as_ref()
+            .map(|cfg| config::layer_count_mismatches(cfg, entry.layers.len()))
+            .unwrap_or_default();
+        if select.strict && !mismatches.is_empty() {
+            return Err(ContreeError::LayerMismatch { mismatches });
+        }
+        for mismatch in &mismatches {
+            diagnostics::warn(WarningKind::LayerMismatch, format!("Manifest and image config disagree: {}", mismatch));
+        }
+
+        // diff_ids that don't line up with the layers would label them wrongly
+        let diff_ids = config.as_ref()
+            .and_then(|cfg| cfg.rootfs.as_ref())
+            .map(|rootfs| rootfs.diff_ids.clone())
+            .filter(|diff_ids| diff_ids.len() == entry.layers.len())
+            .unwrap_or_default();
+
+        let mut layers = Vec::with_capacity(entry.layers.len());
+        let mut missing_layers = Vec::new();
+        for (i, name) in entry.layers.into_iter().enumerate() {
+            // The same blob may be listed twice, so leave it for later entries
+            let Some(blob) = blobs.get(&name).cloned() else {
+                if !select.allow_missing_layers {
+                    return Err(ContreeError::LayerMissing { name });
+                }
+                diagnostics::warn(WarningKind::MissingLayer,
+                    format!("Layer {} not found in archive; leaving out what it changes", name));
+                missing_layers.push(MissingLayer { position: i, name });
+                continue;
+            };
+            let compression = Compression::sniff(&mut blob.open()?);
+            layers.push(Layer { name, blob, diff_id: diff_ids.get(i).cloned(), compression });
+        }
+
+        Ok(LayerSet {
+            layers,
+            config,
+            config_blob,
+            repo_tags: entry.repo_tags.unwrap_or_default(),
+            missing_layers,
+            _temp_dir: temp_dir,
+        })
     }
+}
 
-    Ok(root)
+/// Options controlling how layers are merged into a tree
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// Record which layer added each entry
+    pub record_layers: bool,
+    /// Keep earlier versions of files that later layers overwrote
+    pub track_shadowed: bool,
+    /// Keep a summary of entries hidden by opaque whiteouts
+    pub track_opaque: bool,
+    /// Report progress in the terminal title
+    pub term_progress: bool,
+    /// Collect per-layer timing and size statistics
+    pub profile_layers: bool,
+    /// Apply entry paths literally instead of following symlinked parent
+    /// directories (e.g. `data/file` when `data -> /var/lib/data`)
+    pub literal_paths: bool,
+    /// Which whiteout markers the layers use (both by default)
+    pub whiteouts: whiteout::Dialect,
+    /// Fingerprint regular file contents (needed to tell content changes from metadata changes)
+    pub hash_contents: bool,
+    /// Image to use from a multi-platform OCI index instead of manifest.json
+    pub platform: Option<Platform>,
+    /// Image to use from a manifest.json listing several, by repo tag or
+    /// position (0 = first)
+    pub image: Option<String>,
+    /// Asks which image to use when manifest.json lists several and `image`
+    /// isn't set (the first one is used without it)
+    pub choose_image: Option<ImageChooser>,
+    /// Add character and block devices to the tree (skipped by default)
+    pub keep_devices: bool,
+    /// Record on each file the package that installed it
+    pub resolve_owners: bool,
+    /// Collect entries that look crafted to escape or mislead
+    pub paranoid: bool,
+    /// Leave out layers the archive doesn't have instead of failing; the
+    /// image records them in [`Image::missing_layers`]
+    pub allow_missing_layers: bool,
+    /// Fail when the manifest and the image config disagree on the layers,
+    /// or when a layer entry is corrupted, can't be applied, or has a type
+    /// the tree can't hold, instead of warning or skipping it
+    pub strict: bool,
+    /// Reuse a merged tree stored by an earlier run, and store this one
+    #[cfg(feature = "cache")]
+    pub cache: bool,
 }
 
-/// Apply a single layer tar to the filesystem tree
-fn apply_layer(root: &mut Node, layer_path: &Path, layer_hash: Option<&str>) -> Result<()> {
-    let mut file = File::open(layer_path)
-        .with_context(|| format!("Failed to open layer: {}", layer_path.display()))?;
-
-    // Check if layer is gzipped - first check extension, then magic bytes
-    let is_gzipped = layer_path.to_string_lossy().ends_with(".gz")
-        || layer_path.to_string_lossy().ends_with(".tgz")
-        || {
-            // Check magic bytes: gzip files start with 0x1f 0x8b
-            let mut magic = [0u8; 2];
-            if file.read_exact(&mut magic).is_ok() {
-                file.seek(std::io::SeekFrom::Start(0)).ok();
-                magic == [0x1f, 0x8b]
-            } else {
-                file.seek(std::io::SeekFrom::Start(0)).ok();
-                false
+impl ProcessOptions {
+    /// The image of a source these options pick
+    pub fn selector(&self) -> ImageSelector {
+        ImageSelector {
+            platform: self.platform.clone(),
+            image: self.image.clone(),
+            choose: self.choose_image.clone(),
+            allow_missing_layers: self.allow_missing_layers,
+            strict: self.strict,
+        }
+    }
+
+    /// Whether nodes need to know which layer they came from
+    fn needs_layer_hashes(&self) -> bool {
+        // Shadowed and masked entries are only useful if we know which layer owned them
+        self.record_layers || self.track_shadowed || self.track_opaque
+    }
+}
+
+/// A merged image: the filesystem tree plus the identity of its layers
+pub struct Image {
+    /// Root of the merged filesystem
+    pub root: Node,
+    /// Labels of the applied layers, bottom-most first
+    pub layer_labels: Vec<String>,
+    /// Parsed image configuration, if the archive has one
+    pub config: Option<ImageConfig>,
+    /// Image tags from the manifest
+    pub repo_tags: Vec<String>,
+    /// Image ID (`sha256:` digest of the config), when the archive names it
+    pub digest: Option<String>,
+    /// Sizes of the layer blobs as stored, bottom-most first
+    pub layer_sizes: Vec<u64>,
+    /// Per-layer statistics (only when profiling)
+    pub layer_profiles: Vec<LayerProfile>,
+    /// Suspicious entries (only when paranoid)
+    pub suspicious: Vec<SuspiciousEntry>,
+    /// Layers of the manifest that weren't applied because the archive
+    /// doesn't have them, so the tree is partial
+    pub missing_layers: Vec<MissingLayer>,
+}
+
+impl Image {
+    /// An image that is only its merged tree, as read back from JSON, with
+    /// the labels of its layers. Without them, its layers are the ones its
+    /// entries name, in the order they first appear.
+    pub fn from_tree(root: Node, mut layer_labels: Vec<String>) -> Self {
+        if layer_labels.is_empty() {
+            for layer in root.walk().filter_map(|(_, meta)| meta.layer_hash.as_deref()) {
+                if !layer_labels.iter().any(|known| known == layer) {
+                    layer_labels.push(layer.to_string());
+                }
             }
-        };
+        }
+        Image {
+            root,
+            layer_labels,
+            config: None,
+            repo_tags: Vec::new(),
+            digest: None,
+            layer_sizes: Vec::new(),
+            layer_profiles: Vec::new(),
+            suspicious: Vec::new(),
+            missing_layers: Vec::new(),
+        }
+    }
+}
 
-    if is_gzipped {
-        let decoder = GzDecoder::new(file);
-        let mut archive = Archive::new(decoder);
-        archive.set_ignore_zeros(true);
-        archive.set_unpack_xattrs(false);
-        process_layer_entries(root, &mut archive, layer_hash)?;
-    } else {
-        let mut archive = Archive::new(file);
-        archive.set_ignore_zeros(true);
-        archive.set_unpack_xattrs(false);
-        process_layer_entries(root, &mut archive, layer_hash)?;
-    }
-
-    Ok(())
-}
-
-/// Process entries from a layer archive
-fn process_layer_entries<R: Read>(
-    root: &mut Node,
-    archive: &mut Archive<R>,
-    layer_hash: Option<&str>,
-) -> Result<()> {
-    for entry in archive.entries().context("Failed to read layer entries")? {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(err) => {
-                // Skip corrupted entries but continue processing
-                eprintln!("Warning: Skipping corrupted entry: {}", err);
-                continue;
+/// Process a Docker archive, or an unpacked image directory, and build the
+/// merged filesystem tree
+pub fn process_archive(archive_path: &Path, options: &ProcessOptions) -> error::Result<Image> {
+    process_source(source::for_path(archive_path).as_ref(), options)
+}
+
+/// Build the merged filesystem tree of the image in `source`
+pub fn process_source(source: &dyn LayerSource, options: &ProcessOptions) -> error::Result<Image> {
+    let mut progress = TermProgress::new(options.term_progress);
+    let layer_set = source.open_layers(&options.selector(), &mut progress)?;
+    build_image(&layer_set, options, &mut progress)
+}
+
+/// Build the merged filesystem tree of an image whose layers are already
+/// open, so the layers stay at hand for reading file contents (see
+/// [`content`](crate::content)); `options` can't pick another image here
+pub fn process_layer_set(layer_set: &LayerSet, options: &ProcessOptions) -> error::Result<Image> {
+    build_image(layer_set, options, &mut TermProgress::new(options.term_progress))
+}
+
+fn build_image(layer_set: &LayerSet, options: &ProcessOptions, progress: &mut TermProgress) -> error::Result<Image> {
+    let mut layer_profiles = Vec::new();
+    let mut suspicious = Vec::new();
+    let profiles = options.profile_layers.then_some(&mut layer_profiles);
+
+    #[cfg(feature = "cache")]
+    let cache_key = {
+        let config = layer_set.config_blob.as_ref().map(|(_, bytes)| bytes.as_slice());
+        cache::key(&layer_set.layers, config, options)
+    };
+    #[cfg(feature = "cache")]
+    let cached = cache_key.as_deref().and_then(cache::load);
+    #[cfg(not(feature = "cache"))]
+    let cached = None;
+
+    let root = match cached {
+        Some(root) => root,
+        None => {
+            let root = merge_layers_with_progress(&layer_set.layers, options, progress, profiles, &mut suspicious)?;
+            #[cfg(feature = "cache")]
+            if let Some(key) = &cache_key {
+                if let Err(err) = cache::store(key, &root) {
+                    diagnostics::warn(WarningKind::Cache, format!("Failed to cache merged tree: {:#}", err));
+                }
             }
-        };
+            root
+        }
+    };
 
-        if let Err(err) = apply_entry(root, entry, layer_hash) {
-            eprintln!("Warning: Failed to apply entry: {}", err);
-            continue;
+    Ok(Image {
+        root,
+        layer_labels: layer_set.layers.iter().map(|l| l.label()).collect(),
+        digest: layer_set.digest(),
+        layer_sizes: layer_set.layers.iter().map(|l| l.blob.size()).collect(),
+        repo_tags: layer_set.repo_tags.clone(),
+        config: layer_set.config.clone(),
+        layer_profiles,
+        suspicious,
+        missing_layers: layer_set.missing_layers.clone(),
+    })
+}
+
+/// Merge the given layers, bottom-most first, into a filesystem tree
+pub fn merge_layers(layers: &[Layer], options: &ProcessOptions) -> error::Result<Node> {
+    merge_layers_with_progress(layers, options, &mut TermProgress::disabled(), None, &mut Vec::new())
+}
+
+fn merge_layers_with_progress(
+    layers: &[Layer],
+    options: &ProcessOptions,
+    progress: &mut TermProgress,
+    mut profiles: Option<&mut Vec<LayerProfile>>,
+    suspicious: &mut Vec<SuspiciousEntry>,
+) -> error::Result<Node> {
+    let mut builder = TreeBuilder::new(options);
+    let mut progress = ProgressSink { progress, count: layers.len() };
+    let mut packages = options.resolve_owners.then(PackageSink::default);
+    let mut paranoid = options.paranoid.then(ParanoidSink::default);
+    let read_options = ReadOptions {
+        hash_contents: options.hash_contents,
+        strict: options.strict,
+        whiteouts: options.whiteouts,
+    };
+
+    // Apply layers in manifest order
+    for (i, layer) in layers.iter().enumerate() {
+        let mut profile = profiles.is_some().then(LayerProfile::default);
+        let mut sinks: Vec<&mut dyn EventSink> = vec![&mut progress, &mut builder];
+        if let Some(packages) = packages.as_mut() {
+            sinks.push(packages);
+        }
+        if let Some(paranoid) = paranoid.as_mut() {
+            sinks.push(paranoid);
         }
+        events::read_layer(layer, i, layers.len(), read_options, &mut sinks, profile.as_mut())
+            .map_err(|err| corrupt_layer(layer, err))?;
+
+        if let (Some(profiles), Some(mut profile)) = (profiles.as_deref_mut(), profile) {
+            profile.nodes = builder.root.count_descendants();
+            profile.peak_rss_kib = crate::profile::peak_rss_kib();
+            profiles.push(profile);
+        }
+    }
+
+    if let Some(paranoid) = paranoid {
+        suspicious.extend(paranoid.into_entries());
+    }
+    let mut root = builder.root;
+    root.number_layers(&builder.layers);
+    if let Some(packages) = packages {
+        packages::annotate_owners(&mut root, &packages.into_packages());
     }
+    Ok(root)
+}
 
-    Ok(())
+/// Reports merge progress as layers start and finish
+struct ProgressSink<'a> {
+    progress: &'a mut TermProgress,
+    count: usize,
 }
 
-/// Apply a single tar entry to the tree
-fn apply_entry<R: Read>(
-    root: &mut Node,
-    entry: Entry<R>,
-    layer_hash: Option<&str>,
-) -> Result<()> {
-    let header = entry.header();
-    let path = entry.path().context("Failed to read entry path")?;
-    let path_str = path.to_string_lossy();
+impl EventSink for ProgressSink<'_> {
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { index, count, .. } => {
+                self.count = *count;
+                self.progress.update("merging layers", *index as u64, *count as u64);
+            }
+            LayerEvent::LayerFinished { index } => {
+                self.progress.update("merging layers", *index as u64 + 1, self.count as u64);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
-    // Normalize path (strip leading ./ segments)
-    let normalized_path = path_str.trim_start_matches("./").trim_end_matches('/');
+/// Applies layer events to the merged filesystem tree
+pub(crate) struct TreeBuilder<'a> {
+    root: Node,
+    options: &'a ProcessOptions,
+    /// Label of the layer being applied, when nodes record their layer
+    layer_hash: Option<Arc<str>>,
+    /// Labels of the layers applied so far, when nodes record their layer
+    layers: Vec<Arc<str>>,
+}
 
-    if normalized_path.is_empty() {
-        return Ok(());
+impl<'a> TreeBuilder<'a> {
+    fn new(options: &'a ProcessOptions) -> Self {
+        Self::on(Node::new_dir(0o755, 0, 0), options)
     }
 
-    // Extract metadata from tar header
-    let mode = header.mode().context("Failed to read mode")?;
-    let uid = header.uid().context("Failed to read uid")?;
-    let gid = header.gid().context("Failed to read gid")?;
-    let entry_type = header.entry_type();
+    /// A builder applying further layers on top of the merged tree `root`
+    pub(crate) fn on(root: Node, options: &'a ProcessOptions) -> Self {
+        TreeBuilder { root, options, layer_hash: None, layers: Vec::new() }
+    }
 
-    // Handle whiteouts
-    if whiteout::is_whiteout(normalized_path) {
-        if whiteout::is_opaque(normalized_path) {
-            let dir_path = whiteout::opaque_dir(normalized_path);
-            root.mark_opaque(dir_path);
+    /// Follow symlinked parent directories the way a runtime does when extracting
+    pub(crate) fn resolve<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        if self.options.literal_paths {
+            Cow::Borrowed(path)
         } else {
-            let target = whiteout::whiteout_target(normalized_path);
-            root.remove(&target);
+            self.root.resolve_parents(path)
         }
-        return Ok(());
     }
 
-    // Apply regular entries
-    match entry_type {
-        tar::EntryType::Directory => {
-            root.ensure_path(normalized_path, mode, uid, gid, layer_hash);
-        }
-        tar::EntryType::Regular => {
-            root.put_file(normalized_path, mode, uid, gid, false, None, layer_hash);
+    fn add_entry(&mut self, entry: &LayerEntry) -> Result<()> {
+        let path = self.resolve(&entry.path).into_owned();
+        let layer_hash = self.layer_hash.as_ref();
+        let (mode, uid, gid) = (entry.mode, entry.uid, entry.gid);
+
+        let replaced = match &entry.kind {
+            EntryKind::Dir => {
+                self.root.ensure_path(&path, mode, uid, gid, layer_hash);
+                None
+            }
+            EntryKind::File => self.root.put_file(&path, mode, uid, gid, false, None, layer_hash),
+            EntryKind::Symlink { target } => {
+                self.root.put_file(&path, mode, uid, gid, true, target.clone(), layer_hash)
+            }
+            EntryKind::Hardlink { target } => {
+                let replaced = self.root.put_file(&path, mode, uid, gid, false, None, layer_hash);
+                if let Some(target) = target {
+                    // The file linked to, if a symlinked parent put it elsewhere
+                    let clean = utils::clean_path(target).path;
+                    let target = match self.resolve(&clean) {
+                        Cow::Owned(resolved) => resolved,
+                        Cow::Borrowed(_) => target.clone(),
+                    };
+                    if let Err(e) = self.root.set_hardlink_target(&path, target) {
+                        if self.options.strict {
+                            return Err(e.context("Failed to set hard link target"));
+                        }
+                        // Log warning but don't fail - the file still exists
+                        diagnostics::warn(WarningKind::HardLink, format!("Failed to set hard link target: {}", e));
+                    }
+                }
+                replaced
+            }
+            EntryKind::Device(_) if !self.options.keep_devices => return Ok(()),
+            EntryKind::Device(_) => self.root.put_file(&path, mode, uid, gid, false, None, layer_hash),
+        };
+
+        if let Some(node) = self.root.get_mut(&path) {
+            let meta = &mut node.metadata;
+            meta.mtime = entry.mtime;
+            if entry.kind == EntryKind::File {
+                meta.size = entry.size;
+                meta.content_hash = entry.content_hash;
+            }
+            if let EntryKind::Device(device) = entry.kind {
+                meta.device = Some(device);
+            }
+            if !entry.xattrs.is_empty() {
+                meta.xattrs = entry.xattrs.clone();
+            }
         }
-        tar::EntryType::Symlink => {
-            let link_target = header.link_name()
-                .context("Failed to read symlink target")?
-                .map(|p| p.to_string_lossy().to_string());
-            root.put_file(normalized_path, mode, uid, gid, true, link_target, layer_hash);
+
+        if self.options.track_shadowed {
+            if let Some(previous) = replaced.filter(|node| node.metadata.is_file) {
+                self.root.push_shadowed(&path, previous.metadata);
+            }
         }
-        tar::EntryType::Link => {
-            // Hard link support
-            let link_target = header.link_name()
-                .context("Failed to read hard link target")?
-                .map(|p| p.to_string_lossy().to_string());
-
-            // Create the file node first
-            root.put_file(normalized_path, mode, uid, gid, false, None, layer_hash);
-
-            // Then set the hard link target
-            if let Some(target) = link_target {
-                if let Err(e) = root.set_hardlink_target(normalized_path, target) {
-                    // Log warning but don't fail - the file still exists
-                    eprintln!("Warning: Failed to set hard link target: {}", e);
-                }
+        Ok(())
+    }
+}
+
+impl EventSink for TreeBuilder<'_> {
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { layer, .. } => {
+                self.layer_hash = self.options.needs_layer_hashes().then(|| layer.label().into());
+                self.layers.extend(self.layer_hash.clone());
+            }
+            LayerEvent::EntryAdded { entry, .. } => self.add_entry(entry)?,
+            LayerEvent::EntryRemoved { path } => {
+                let path = self.resolve(path).into_owned();
+                self.root.remove(&path);
+            }
+            LayerEvent::OpaqueApplied { dir } => {
+                // Resolve the directory itself too, not just its parents
+                let marker = format!("{}/{}", dir, whiteout::OPAQUE_WHITEOUT);
+                let marker = self.resolve(&marker).into_owned();
+                let dir = whiteout::opaque_dir(&marker);
+                self.root.mark_opaque(dir, self.layer_hash.as_ref(), self.options.track_opaque);
             }
+            LayerEvent::Suspicious { .. } | LayerEvent::LayerFinished { .. } => {}
         }
-        _ => {
-            // Skip other entry types (char devices, block devices, fifos, etc.)
+        Ok(())
+    }
+}
+
+/// Wrap a reader in the decoder for `compression`
+pub(crate) fn decompress<R: Read + 'static>(compression: Compression, reader: R) -> Result<Box<dyn Read>> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader).context("Failed to start zstd decoder")?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => anyhow::bail!("Reading zstd-compressed data needs the zstd feature"),
+        Compression::None => Box::new(reader),
+    })
+}
+
+/// Open a layer as a stream of uncompressed tar bytes
+pub fn open_blob(layer: &Layer) -> error::Result<Box<dyn Read>> {
+    decompress(layer.compression, layer.blob.open()?).map_err(|err| corrupt_layer(layer, err))
+}
+
+/// Open a layer as a tar archive, decompressing if needed
+pub fn open_layer(layer: &Layer) -> error::Result<Archive<Box<dyn Read>>> {
+    Ok(layer_archive(open_blob(layer)?))
+}
+
+/// Reading `layer` failed; unless the error already has a kind, the layer is corrupt
+fn corrupt_layer(layer: &Layer, err: anyhow::Error) -> ContreeError {
+    ContreeError::or_else(err, |source| ContreeError::CorruptLayer { layer: layer.name.clone(), source })
+}
+
+pub fn layer_archive(reader: Box<dyn Read>) -> Archive<Box<dyn Read>> {
+    let mut archive = Archive::new(reader);
+    archive.set_ignore_zeros(true);
+    archive.set_unpack_xattrs(false);
+    archive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &str, diff_id: Option<&str>) -> Layer {
+        Layer {
+            name: name.to_string(),
+            blob: Blob::File(PathBuf::new()),
+            diff_id: diff_id.map(|d| d.to_string()),
+            compression: Compression::None,
+        }
+    }
+
+    #[test]
+    fn test_compression_from_magic() {
+        assert_eq!(Compression::from_magic(&[0x1f, 0x8b, 0x08, 0x00]), Compression::Gzip);
+        assert_eq!(Compression::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]), Compression::Zstd);
+        assert_eq!(Compression::from_magic(b"etc/"), Compression::None);
+        assert_eq!(Compression::from_magic(&[]), Compression::None);
+    }
+
+    #[test]
+    fn test_extract_layer_hash() {
+        assert_eq!(extract_layer_hash("abc123def456/layer.tar", 7).as_deref(), Some("abc123d"));
+        assert_eq!(extract_layer_hash("blobs/sha256/0123456789", 7).as_deref(), Some("0123456"));
+        assert_eq!(extract_layer_hash("fedcba9876.tar.gz", 7).as_deref(), Some("fedcba9"));
+    }
+
+    /// A tar holding the given files
+    fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            builder.append_data(&mut header, name, *data).unwrap();
         }
+        builder.into_inner().unwrap()
+    }
+
+    /// A Docker archive with one layer holding `etc/hostname`
+    fn docker_archive() -> Vec<u8> {
+        let layer = tar_of(&[("etc/hostname", b"box\n")]);
+        let manifest = br#"[{"Config":"","RepoTags":[],"Layers":["abc123/layer.tar"]}]"#;
+        tar_of(&[("abc123/layer.tar", &layer), ("manifest.json", manifest)])
+    }
+
+    fn write_gzipped(path: &Path, data: &[u8]) {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        std::fs::write(path, encoder.finish().unwrap()).unwrap();
+    }
+
+    fn layer_paths(layer: &Layer) -> Vec<String> {
+        open_layer(layer).unwrap().entries().unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect()
     }
 
-    Ok(())
+    #[test]
+    fn test_open_archive_reads_uncompressed_blobs_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, docker_archive()).unwrap();
+
+        let layer_set = open_archive(&path).unwrap();
+        assert!(layer_set._temp_dir.is_none());
+        let layer = &layer_set.layers[0];
+        assert!(matches!(&layer.blob, Blob::InArchive { archive, .. } if *archive == path));
+        assert_eq!(layer_paths(layer), vec!["etc/hostname"]);
+    }
+
+    #[test]
+    fn test_open_archive_spools_compressed_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar.gz");
+        write_gzipped(&path, &docker_archive());
+
+        let layer_set = open_archive(&path).unwrap();
+        let layer = &layer_set.layers[0];
+        assert!(matches!(layer.blob, Blob::File(_)));
+        assert_eq!(layer_paths(layer), vec!["etc/hostname"]);
+    }
+
+    #[test]
+    fn test_open_archive_spools_only_selected_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.tar.gz");
+        let app = tar_of(&[("app/main", b"bin")]);
+        let db = tar_of(&[("var/lib/db", b"data")]);
+        let config = br#"{"rootfs":{"type":"layers","diff_ids":["sha256:1234567890"]}}"#;
+        let manifest = br#"[
+            {"Config":"app.json","RepoTags":["app:1"],"Layers":["app/layer.tar"]},
+            {"Config":"db.json","RepoTags":["db:1"],"Layers":["db/layer.tar"]}
+        ]"#;
+        write_gzipped(&path, &tar_of(&[
+            ("app/layer.tar", &app),
+            ("db/layer.tar", &db),
+            ("app.json", config),
+            ("manifest.json", manifest),
+        ]));
+
+        let layer_set = open_archive(&path).unwrap();
+        assert_eq!(layer_set.repo_tags, vec!["app:1"]);
+        assert_eq!(layer_set.layers[0].label(), "1234567");
+        assert_eq!(layer_paths(&layer_set.layers[0]), vec!["app/main"]);
+        // The config came from the first pass; only the one layer was spooled
+        assert!(matches!(layer_set.config_blob, Some((ref name, _)) if name == "app.json"));
+        let spool = layer_set._temp_dir.as_ref().unwrap().path();
+        assert_eq!(std::fs::read_dir(spool).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_allow_missing_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        let layer = tar_of(&[("etc/hostname", b"box\n")]);
+        let config = br#"{"rootfs":{"type":"layers","diff_ids":["sha256:aaaaaaaaaa","sha256:bbbbbbbbbb"]}}"#;
+        let manifest = br#"[{"Config":"c.json","RepoTags":[],"Layers":["foreign/layer.tar","abc123/layer.tar"]}]"#;
+        std::fs::write(&path, tar_of(&[("abc123/layer.tar", &layer), ("c.json", config), ("manifest.json", manifest)]))
+            .unwrap();
+
+        let err = process_archive(&path, &ProcessOptions::default()).err().unwrap();
+        assert!(matches!(err, ContreeError::LayerMissing { name } if name == "foreign/layer.tar"));
+
+        let options = ProcessOptions { allow_missing_layers: true, ..Default::default() };
+        let image = process_archive(&path, &options).unwrap();
+        assert_eq!(image.missing_layers, [MissingLayer { position: 0, name: "foreign/layer.tar".to_string() }]);
+        // The layers that are there keep their own diff_ids
+        assert_eq!(image.layer_labels, ["bbbbbbb"]);
+        assert!(image.root.get("etc/hostname").is_some());
+    }
+
+    #[test]
+    fn test_layer_count_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        let layer = tar_of(&[("etc/hostname", b"box\n")]);
+        // A diff_id too many, as when a layer was cut out of the manifest by hand
+        let config = br#"{"rootfs":{"type":"layers","diff_ids":["sha256:aaaaaaaaaa","sha256:bbbbbbbbbb"]}}"#;
+        let manifest = br#"[{"Config":"c.json","RepoTags":[],"Layers":["abc123/layer.tar"]}]"#;
+        std::fs::write(&path, tar_of(&[("abc123/layer.tar", &layer), ("c.json", config), ("manifest.json", manifest)]))
+            .unwrap();
+
+        let image = process_archive(&path, &ProcessOptions::default()).unwrap();
+        // Labeled by blob name rather than by a diff_id that may be another layer's
+        assert_eq!(image.layer_labels, ["abc123"]);
+
+        let options = ProcessOptions { strict: true, ..Default::default() };
+        let err = process_archive(&path, &options).err().unwrap();
+        assert_eq!(err.to_string(), "Manifest and image config disagree: the manifest lists 1 layers but rootfs.diff_ids has 2");
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    fn memory_layer(entries: &[(&str, tar::EntryType, &str)]) -> Layer {
+        let mut builder = tar::Builder::new(Vec::new());
+        for &(path, entry_type, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            if link.is_empty() {
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            } else {
+                builder.append_link(&mut header, path, link).unwrap();
+            }
+        }
+        let blob = Blob::Memory(builder.into_inner().unwrap().into());
+        Layer { name: "layer.tar".to_string(), blob, diff_id: None, compression: Compression::None }
+    }
+
+    #[test]
+    fn test_merge_follows_symlinked_parents() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+        let layers = [
+            memory_layer(&[("usr/bin", Directory, ""), ("usr/bin/busybox", Regular, ""), ("bin", Symlink, "usr/bin")]),
+            memory_layer(&[("bin/tool", Regular, ""), ("sbin/sh", Link, "bin/busybox")]),
+        ];
+
+        // Written where the runtime would put them, leaving the symlink alone
+        let root = merge_layers(&layers, &ProcessOptions::default()).unwrap();
+        assert!(root.get("bin").unwrap().metadata.is_symlink);
+        assert!(root.get("bin/tool").is_none());
+        assert!(root.get("usr/bin/tool").unwrap().metadata.is_file);
+        assert_eq!(root.get("sbin/sh").unwrap().metadata.hardlink_target.as_deref(), Some("usr/bin/busybox"));
+
+        // The layout as the layers spell it
+        let options = ProcessOptions { literal_paths: true, ..Default::default() };
+        let root = merge_layers(&layers, &options).unwrap();
+        assert!(root.get("bin/tool").is_some());
+        assert!(root.get("usr/bin/tool").is_none());
+        assert_eq!(root.get("sbin/sh").unwrap().metadata.hardlink_target.as_deref(), Some("bin/busybox"));
+    }
+
+    #[test]
+    fn test_strict_fails_on_skipped_entries() {
+        use tar::EntryType::{Fifo, Regular};
+        let layers = [memory_layer(&[("etc/hostname", Regular, ""), ("run/initctl", Fifo, "")])];
+
+        // Skipped without a word by default
+        let root = merge_layers(&layers, &ProcessOptions::default()).unwrap();
+        assert!(root.get("etc/hostname").is_some());
+        assert!(root.get("run/initctl").is_none());
+
+        let options = ProcessOptions { strict: true, ..Default::default() };
+        let err = merge_layers(&layers, &options).err().unwrap();
+        assert_eq!(err.exit_code(), 5);
+        assert_eq!(format!("{:#}", anyhow::Error::from(err)),
+            "Failed to read layer layer.tar: Failed to apply entry run/initctl: Unsupported entry type Fifo");
+    }
+
+    #[test]
+    fn test_label_prefers_diff_id() {
+        assert_eq!(layer("blobs/sha256/0123456789", Some("sha256:abcdef0123")).label(), "abcdef0");
+        assert_eq!(layer("blobs/sha256/0123456789", None).label(), "0123456");
+    }
 }
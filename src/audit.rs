@@ -0,0 +1,336 @@
+//! Security audit of the merged filesystem: risky permissions, capabilities,
+//! stray device nodes, and hard links that extract broken
+
+use anyhow::Result;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, ProcessOptions};
+use crate::capability;
+use crate::sarif;
+use crate::tree::{DeviceKind, Node, NodeMetadata};
+use crate::utils;
+
+/// Kinds of audit findings, in report order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Setuid,
+    Capabilities,
+    WorldWritable,
+    RootWritable,
+    Device,
+    HardLink,
+}
+
+impl Category {
+    const ALL: [Category; 6] = [
+        Category::Setuid,
+        Category::Capabilities,
+        Category::WorldWritable,
+        Category::RootWritable,
+        Category::Device,
+        Category::HardLink,
+    ];
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Category::Setuid => "setuid/setgid binaries",
+            Category::Capabilities => "files with capabilities",
+            Category::WorldWritable => "world-writable paths",
+            Category::RootWritable => "root-owned paths writable by a non-root group",
+            Category::Device => "device nodes outside /dev",
+            Category::HardLink => "hard links to removed or replaced files",
+        }
+    }
+
+    /// Rule id in SARIF output
+    pub fn id(&self) -> &'static str {
+        match self {
+            Category::Setuid => "setuid",
+            Category::Capabilities => "capabilities",
+            Category::WorldWritable => "world-writable",
+            Category::RootWritable => "root-writable",
+            Category::Device => "device",
+            Category::HardLink => "hard-link",
+        }
+    }
+
+    fn level(&self) -> sarif::Level {
+        match self {
+            Category::Setuid | Category::Capabilities | Category::WorldWritable | Category::HardLink => {
+                sarif::Level::Warning
+            }
+            Category::RootWritable | Category::Device => sarif::Level::Note,
+        }
+    }
+}
+
+/// One risky entry in the merged tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditFinding {
+    pub category: Category,
+    /// Absolute path in the image
+    pub path: String,
+    /// Layer that added the entry
+    pub layer: Option<String>,
+    pub detail: String,
+}
+
+/// Audit the merged tree of an archive and print a categorized report
+/// (`format` "text") or a SARIF log ("sarif")
+pub fn run(archive_path: &Path, format: &str) -> Result<()> {
+    let options = ProcessOptions { record_layers: true, keep_devices: true, ..Default::default() };
+    let image = archive::process_archive(archive_path, &options)?;
+    let findings = audit_tree(&image.root, &image.layer_labels);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if format == "sarif" {
+        write_sarif(&mut out, &findings)?;
+    } else {
+        write_report(&mut out, &findings)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Collect findings for every entry, sorted by category then path.
+/// `layer_labels` are the labels the tree's entries were recorded with,
+/// bottom-most first, for telling which of two entries came later
+pub fn audit_tree(root: &Node, layer_labels: &[String]) -> Vec<AuditFinding> {
+    let mut findings = Vec::new();
+    for (path, meta) in root.walk() {
+        let path = format!("/{}", path.display());
+        audit_entry(&path, meta, &mut findings);
+        audit_hard_link(&path, meta, root, layer_labels, &mut findings);
+    }
+    findings.sort_by(|a, b| a.category.cmp(&b.category).then_with(|| a.path.cmp(&b.path)));
+    findings
+}
+
+fn audit_entry(path: &str, meta: &NodeMetadata, findings: &mut Vec<AuditFinding>) {
+    // Symlink permissions are meaningless
+    if meta.is_symlink {
+        return;
+    }
+
+    let mut push = |category, detail: String| {
+        findings.push(AuditFinding {
+            category,
+            path: path.to_string(),
+            layer: meta.layer_hash.as_deref().map(String::from),
+            detail,
+        });
+    };
+    let mode = format!("mode {:04o}, owner {}:{}", meta.mode & 0o7777, meta.uid, meta.gid);
+    let is_regular = meta.is_file && meta.device.is_none();
+
+    if is_regular && meta.mode & 0o6000 != 0 {
+        let bits = match (meta.mode & 0o4000 != 0, meta.mode & 0o2000 != 0) {
+            (true, true) => "setuid+setgid",
+            (true, false) => "setuid",
+            _ => "setgid",
+        };
+        push(Category::Setuid, format!("{}, {}", bits, mode));
+    }
+
+    if let Some(value) = meta.xattrs.get(capability::XATTR) {
+        push(Category::Capabilities, capability::describe(value));
+    }
+
+    // Sticky world-writable directories such as /tmp and devices such as
+    // /dev/null are the norm
+    let exempt = meta.device.is_some() || (!meta.is_file && meta.mode & 0o1000 != 0);
+    if !exempt {
+        if meta.mode & 0o002 != 0 {
+            push(Category::WorldWritable, mode.clone());
+        } else if meta.uid == 0 && meta.gid != 0 && meta.mode & 0o020 != 0 {
+            push(Category::RootWritable, format!("group-writable, {}", mode));
+        }
+    }
+
+    if let Some(device) = meta.device.filter(|_| path != "/dev" && !path.starts_with("/dev/")) {
+        let kind = match device.kind {
+            DeviceKind::Char => "character",
+            DeviceKind::Block => "block",
+        };
+        push(Category::Device, format!("{} device {}:{}", kind, device.major, device.minor));
+    }
+}
+
+/// A hard link whose target a later layer deleted or replaced: the file it
+/// shared contents with is gone, so extracting the image breaks the link or
+/// leaves it holding what the target used to be
+fn audit_hard_link(
+    path: &str,
+    meta: &NodeMetadata,
+    root: &Node,
+    layer_labels: &[String],
+    findings: &mut Vec<AuditFinding>,
+) {
+    let Some(target) = &meta.hardlink_target else {
+        return;
+    };
+    let clean = utils::clean_path(target).path;
+    let shown = format!("/{}", utils::display_path(&clean));
+    let position = |layer: Option<&str>| layer.and_then(|label| layer_labels.iter().position(|l| l == label));
+
+    let detail = match root.get(&root.resolve_parents(&clean)).map(|node| &node.metadata) {
+        None => format!("target {} is not in the image; a later layer removed it, or no layer had it", shown),
+        Some(target) if target.is_symlink => format!("target {} is now a symlink", shown),
+        Some(target) if !target.is_file => format!("target {} is now a directory", shown),
+        Some(target) => match (position(meta.layer_hash.as_deref()), position(target.layer_hash.as_deref())) {
+            (Some(link), Some(replaced)) if replaced > link => format!(
+                "target {} was replaced by layer {} after the link was made",
+                shown,
+                target.layer_hash.as_deref().unwrap_or("-")
+            ),
+            _ => return,
+        },
+    };
+    findings.push(AuditFinding {
+        category: Category::HardLink,
+        path: path.to_string(),
+        layer: meta.layer_hash.as_deref().map(String::from),
+        detail,
+    });
+}
+
+pub fn write_report<W: Write>(out: &mut W, findings: &[AuditFinding]) -> io::Result<()> {
+    if findings.is_empty() {
+        return writeln!(out, "No findings");
+    }
+
+    let mut current = None;
+    for finding in findings {
+        if current != Some(finding.category) {
+            if current.is_some() {
+                writeln!(out)?;
+            }
+            let count = findings.iter().filter(|f| f.category == finding.category).count();
+            writeln!(out, "{} ({}):", finding.category.title(), count)?;
+            current = Some(finding.category);
+        }
+        let layer = finding.layer.as_deref().unwrap_or("-");
+        writeln!(out, "  [{}] {}: {}", layer, finding.path, finding.detail)?;
+    }
+    Ok(())
+}
+
+pub fn write_sarif<W: Write>(out: &mut W, findings: &[AuditFinding]) -> Result<()> {
+    let rules: Vec<sarif::Rule> = Category::ALL.iter()
+        .map(|c| sarif::Rule { id: c.id().to_string(), description: c.title().to_string() })
+        .collect();
+    let results: Vec<sarif::Finding> = findings.iter()
+        .map(|f| sarif::Finding {
+            rule_id: f.category.id().to_string(),
+            level: f.category.level(),
+            message: format!("{}: {}", f.category.title(), f.detail),
+            path: f.path.clone(),
+            line: None,
+            layer: f.layer.clone(),
+        })
+        .collect();
+    sarif::write_log(out, &rules, &results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::Device;
+
+    fn tree() -> Node {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("usr/bin/su", 0o4755, 0, 0, false, None, Some(&"aaa".into()));
+        root.put_file("usr/bin/ping", 0o755, 0, 0, false, None, Some(&"aaa".into()));
+        root.get_mut("usr/bin/ping").unwrap().metadata.xattrs
+            .insert("security.capability".to_string(), vec![1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        root.ensure_path("tmp", 0o1777, 0, 0, None);
+        root.ensure_path("srv", 0o755, 0, 0, None);
+        root.ensure_path("srv/uploads", 0o777, 0, 0, None);
+        root.put_file("etc/app.conf", 0o664, 0, 50, false, None, None);
+        root.put_file("bin/sh", 0o777, 0, 0, true, Some("busybox".to_string()), None);
+        for path in ["dev/null", "opt/null"] {
+            root.put_file(path, 0o666, 0, 0, false, None, None);
+            root.get_mut(path).unwrap().metadata.device =
+                Some(Device { kind: DeviceKind::Char, major: 1, minor: 3 });
+        }
+        // Hard links made by "aaa": one still shares its target, one lost it
+        // to a whiteout, one to a file "bbb" wrote over it
+        let links = [("usr/bin/ping6", "usr/bin/ping"), ("usr/bin/old", "/usr/lib/old"), ("bin/ls", "./bin/busybox")];
+        for (link, target) in links {
+            root.put_file(link, 0o755, 0, 0, false, None, Some(&"aaa".into()));
+            root.set_hardlink_target(link, target.to_string()).unwrap();
+        }
+        root.put_file("bin/busybox", 0o755, 0, 0, false, None, Some(&"bbb".into()));
+        root
+    }
+
+    fn labels() -> Vec<String> {
+        vec!["aaa".to_string(), "bbb".to_string()]
+    }
+
+    #[test]
+    fn test_audit_tree() {
+        let summary: Vec<(Category, String)> = audit_tree(&tree(), &labels()).into_iter()
+            .map(|f| (f.category, f.path))
+            .collect();
+        assert_eq!(summary, vec![
+            (Category::Setuid, "/usr/bin/su".to_string()),
+            (Category::Capabilities, "/usr/bin/ping".to_string()),
+            (Category::WorldWritable, "/srv/uploads".to_string()),
+            (Category::RootWritable, "/etc/app.conf".to_string()),
+            (Category::Device, "/opt/null".to_string()),
+            (Category::HardLink, "/bin/ls".to_string()),
+            (Category::HardLink, "/usr/bin/old".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_hard_link_detail() {
+        let details: Vec<String> = audit_tree(&tree(), &labels()).into_iter()
+            .filter(|f| f.category == Category::HardLink)
+            .map(|f| f.detail)
+            .collect();
+        assert_eq!(details, vec![
+            "target /bin/busybox was replaced by layer bbb after the link was made",
+            "target /usr/lib/old is not in the image; a later layer removed it, or no layer had it",
+        ]);
+
+        // Without the layer order, a replaced target can't be told apart
+        assert_eq!(audit_tree(&tree(), &[]).iter().filter(|f| f.category == Category::HardLink).count(), 1);
+    }
+
+    #[test]
+    fn test_capability_detail() {
+        let findings = audit_tree(&tree(), &labels());
+        let caps = findings.iter().find(|f| f.category == Category::Capabilities).unwrap();
+        assert_eq!(caps.detail, "cap_net_raw+ep");
+    }
+
+    #[test]
+    fn test_write_report() {
+        let findings = vec![AuditFinding {
+            category: Category::Setuid,
+            path: "/usr/bin/su".to_string(),
+            layer: Some("aaa".to_string()),
+            detail: "setuid, mode 4755, owner 0:0".to_string(),
+        }];
+        let mut out = Vec::new();
+        write_report(&mut out, &findings).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "setuid/setgid binaries (1):\n  [aaa] /usr/bin/su: setuid, mode 4755, owner 0:0\n");
+    }
+
+    #[test]
+    fn test_write_sarif() {
+        let mut out = Vec::new();
+        write_sarif(&mut out, &audit_tree(&tree(), &labels())).unwrap();
+        let log: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 6);
+        assert_eq!(run["results"][0]["ruleId"], "setuid");
+        assert_eq!(run["results"][0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"], "usr/bin/su");
+        assert_eq!(run["results"][4]["level"], "note");
+    }
+}
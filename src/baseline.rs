@@ -0,0 +1,307 @@
+//! Named baselines: compact snapshots of a merged tree kept in the user's cache
+//!
+//! A baseline records every path with its type, permissions, ownership, size,
+//! and link target, plus the image config, so a later archive can be diffed
+//! against it without keeping the original tarball around.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::archive::{self, ProcessOptions};
+use crate::colordepth::ColorDepth;
+use crate::config::{self, ConfigChange, ImageConfig};
+use crate::diagnostics;
+use crate::diff::{self, FileChange};
+use crate::theme::Theme;
+use crate::tree::{Node, NodeMetadata};
+use crate::utils;
+
+/// Bumped whenever the on-disk layout changes incompatibly
+const FORMAT_VERSION: u32 = 1;
+
+/// A saved snapshot of an image
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Baseline {
+    pub version: u32,
+    pub name: String,
+    /// File name of the archive the baseline was taken from
+    pub source: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    #[serde(default)]
+    pub config: Option<ImageConfig>,
+    /// Every path in the merged tree, parents before children
+    pub entries: Vec<BaselineEntry>,
+}
+
+/// One path of the merged tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaselineEntry {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,
+    /// Symlink target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Hard link target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Machine-readable `baseline diff` output
+#[derive(Serialize)]
+struct BaselineReport<'a> {
+    baseline: &'a str,
+    config: &'a [ConfigChange],
+    files: &'a [FileChange],
+}
+
+impl Baseline {
+    /// Snapshot a merged tree
+    pub fn capture(name: &str, source: &str, root: &Node, config: Option<ImageConfig>) -> Self {
+        let entries = root.walk().map(|(path, meta)| BaselineEntry::new(path.display().to_string(), meta)).collect();
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Baseline {
+            version: FORMAT_VERSION,
+            name: name.to_string(),
+            source: source.to_string(),
+            created,
+            config,
+            entries,
+        }
+    }
+
+    /// Rebuild the merged tree described by this baseline
+    pub fn to_tree(&self) -> Node {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        for entry in &self.entries {
+            match entry.kind {
+                EntryKind::Dir => root.ensure_path(&entry.path, entry.mode, entry.uid, entry.gid, None),
+                EntryKind::File | EntryKind::Symlink => {
+                    let is_symlink = entry.kind == EntryKind::Symlink;
+                    root.put_file(&entry.path, entry.mode, entry.uid, entry.gid,
+                        is_symlink, entry.target.clone(), None);
+                }
+            }
+
+            // Directories may already exist from an earlier child; apply their metadata here
+            if let Some(node) = root.get_mut(&entry.path) {
+                node.metadata.mode = entry.mode;
+                node.metadata.uid = entry.uid;
+                node.metadata.gid = entry.gid;
+                node.metadata.size = entry.size;
+                node.metadata.hardlink_target = entry.link.clone();
+            }
+        }
+        root
+    }
+
+    /// Write the baseline to the cache, replacing any earlier one with the same name
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = baseline_path(&self.name)?;
+        let dir = path.parent().context("Baseline path has no parent directory")?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let staged = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Cannot write to {}", dir.display()))?;
+        let mut encoder = GzEncoder::new(staged, Compression::default());
+        serde_json::to_writer(&mut encoder, self).context("Failed to serialize baseline")?;
+        let staged = encoder.finish().context("Failed to write baseline")?;
+
+        staged.persist(&path)
+            .with_context(|| format!("Failed to save {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Read a named baseline from the cache
+    pub fn load(name: &str) -> Result<Self> {
+        let path = baseline_path(name)?;
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let saved = list_names().unwrap_or_default();
+                bail!("No baseline named '{}' (saved: {})", name,
+                    if saved.is_empty() { "none".to_string() } else { saved.join(", ") });
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to open {}", path.display())),
+        };
+
+        let baseline: Baseline = serde_json::from_reader(GzDecoder::new(file))
+            .with_context(|| format!("Failed to read baseline {}", path.display()))?;
+        if baseline.version != FORMAT_VERSION {
+            bail!("Baseline '{}' uses format version {} (expected {}); save it again",
+                name, baseline.version, FORMAT_VERSION);
+        }
+        Ok(baseline)
+    }
+}
+
+impl BaselineEntry {
+    fn new(path: String, meta: &NodeMetadata) -> Self {
+        let kind = if meta.is_symlink {
+            EntryKind::Symlink
+        } else if meta.is_file {
+            EntryKind::File
+        } else {
+            EntryKind::Dir
+        };
+        BaselineEntry {
+            path,
+            kind,
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            size: meta.size,
+            target: meta.symlink_target.clone(),
+            link: meta.hardlink_target.clone(),
+        }
+    }
+}
+
+/// Directory holding saved baselines
+fn baselines_dir() -> Result<PathBuf> {
+    Ok(utils::cache_dir()?.join("baselines"))
+}
+
+fn baseline_path(name: &str) -> Result<PathBuf> {
+    validate_name(name)?;
+    Ok(baselines_dir()?.join(format!("{}.json.gz", name)))
+}
+
+/// Names become file names, so keep them to a safe character set
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        bail!("Invalid baseline name '{}' (use letters, digits, '.', '-', '_')", name);
+    }
+    Ok(())
+}
+
+/// Names of all saved baselines, sorted
+fn list_names() -> Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(baselines_dir()?)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry.file_name().to_str()
+                .and_then(|n| n.strip_suffix(".json.gz"))
+                .map(|n| n.to_string())
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// `baseline save`: snapshot an archive under `name`
+pub fn save(archive_path: &Path, name: &str) -> Result<()> {
+    validate_name(name)?;
+    let image = archive::process_archive(archive_path, &ProcessOptions::default())?;
+    let source = archive_path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let baseline = Baseline::capture(name, &source, &image.root, image.config);
+    let path = baseline.save()?;
+    diagnostics::note(format!("Saved baseline '{}' ({} entries) to {}", name, baseline.entries.len(), path.display()));
+    Ok(())
+}
+
+/// `baseline diff`: compare an archive against a saved baseline
+pub fn run_diff(name: &str, archive_path: &Path, format: &str, color: Option<ColorDepth>) -> Result<()> {
+    let baseline = Baseline::load(name)?;
+    let image = archive::process_archive(archive_path, &ProcessOptions::default())?;
+
+    let empty = ImageConfig::default();
+    let config_changes = config::diff_configs(
+        baseline.config.as_ref().unwrap_or(&empty),
+        image.config.as_ref().unwrap_or(&empty),
+    );
+    let tree = diff::diff_trees(&baseline.to_tree(), &image.root, &diff::DiffOptions::default());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if format == "json" {
+        let files = tree.flatten();
+        let report = BaselineReport { baseline: name, config: &config_changes, files: &files };
+        serde_json::to_writer_pretty(&mut out, &report)?;
+        writeln!(out)?;
+    } else {
+        writeln!(out, "Baseline '{}' (from {})", baseline.name, baseline.source)?;
+        config::write_changes(&mut out, &config_changes)?;
+        let theme = Theme::default().with_depth(color.unwrap_or(ColorDepth::TrueColor));
+        diff::write_tree_diff(&mut out, &tree, color.is_some(), &theme)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Node {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.ensure_path("etc", 0o750, 0, 0, None);
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        root.get_mut("etc/passwd").unwrap().metadata.size = 512;
+        root.put_file("bin/sh", 0o777, 0, 0, true, Some("busybox".to_string()), None);
+        root.put_file("bin/busybox", 0o755, 0, 0, false, None, None);
+        root.put_file("bin/ash", 0o755, 0, 0, false, None, None);
+        root.set_hardlink_target("bin/ash", "bin/busybox".to_string()).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_round_trip_preserves_tree() {
+        let tree = sample();
+        let baseline = Baseline::capture("r1", "img.tar", &tree, None);
+        assert_eq!(baseline.entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+            vec!["bin", "bin/ash", "bin/busybox", "bin/sh", "etc", "etc/passwd"]);
+
+        let json = serde_json::to_string(&baseline).unwrap();
+        let restored: Baseline = serde_json::from_str(&json).unwrap();
+        assert!(diff::diff_trees(&tree, &restored.to_tree(), &diff::DiffOptions::default()).children.is_empty());
+        assert_eq!(restored.to_tree().get("etc").unwrap().metadata.mode, 0o750);
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("release-1.4").is_ok());
+        assert!(validate_name("nightly_2024").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("../escape").is_err());
+        assert!(validate_name(".hidden").is_err());
+    }
+}
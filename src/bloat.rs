@@ -0,0 +1,219 @@
+//! Build leftovers that made it into the final image
+//!
+//! Package-manager caches, VCS metadata, core dumps and object files are
+//! rarely needed at runtime, yet they ship with every pull. Each is found in
+//! the merged tree by its path, reported with its size and the layers that
+//! put it there, so the step that should clean up after itself is obvious.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, ProcessOptions};
+use crate::tree::{Node, Visitor};
+use crate::utils;
+
+/// Directories that are leftovers as a whole, by their path from the root
+const LEFTOVER_DIRS: &[(&str, &str)] = &[
+    ("var/lib/apt/lists", "apt lists"),
+    ("var/cache/apt", "apt cache"),
+    ("var/cache/apk", "apk cache"),
+    ("var/cache/yum", "yum cache"),
+    ("var/cache/dnf", "dnf cache"),
+];
+
+/// Directories that are leftovers wherever they are, by how their path ends
+const LEFTOVER_DIR_NAMES: &[(&str, &str)] = &[
+    (".cache/pip", "pip cache"),
+    (".npm", "npm cache"),
+    (".cache/yarn", "yarn cache"),
+    (".cache/go-build", "go build cache"),
+    (".git", "git repository"),
+];
+
+/// Something in the image that a build should have cleaned up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub kind: &'static str,
+    /// A directory, or a pattern for files of one directory (`app/build/*.o`),
+    /// without a leading "/"
+    pub path: String,
+    /// Bytes of content in the files
+    pub size: u64,
+    pub files: usize,
+    /// Labels of the layers that added the files, in layer order
+    pub layers: Vec<String>,
+}
+
+/// The kind of leftover a directory is, if it is one as a whole
+fn leftover_dir(path: &str) -> Option<&'static str> {
+    if let Some(&(_, kind)) = LEFTOVER_DIRS.iter().find(|(dir, _)| *dir == path) {
+        return Some(kind);
+    }
+    // Other package managers' caches are just as stale
+    if path.strip_prefix("var/cache/").is_some_and(|rest| !rest.contains('/')) {
+        return Some("package cache");
+    }
+    LEFTOVER_DIR_NAMES.iter()
+        .find(|(name, _)| path == *name || path.ends_with(&format!("/{}", name)))
+        .map(|&(_, kind)| kind)
+}
+
+/// The kind of leftover a file is, and the pattern it's reported under
+fn leftover_file(name: &str) -> Option<(&'static str, &'static str)> {
+    if name == "core" || name.strip_prefix("core.").is_some_and(|pid| pid.bytes().all(|b| b.is_ascii_digit())) {
+        Some(("core dump", "core*"))
+    } else if name.ends_with(".o") {
+        Some(("object files", "*.o"))
+    } else {
+        None
+    }
+}
+
+/// Collects leftovers while walking the merged tree
+struct BloatVisitor<'a> {
+    /// Layer labels, bottom-most first
+    layer_order: &'a [String],
+    leftovers: Vec<Leftover>,
+    /// Files matched one by one, grouped by kind and directory, each group
+    /// with the path of its first file
+    grouped: BTreeMap<(&'static str, String), (Leftover, String)>,
+}
+
+/// `layers` ordered as the image stacks them (`layer_order`), without duplicates
+fn ordered(layers: impl IntoIterator<Item = String>, layer_order: &[String]) -> Vec<String> {
+    let mut layers: Vec<String> = layers.into_iter().collect();
+    layers.sort_by_key(|layer| layer_order.iter().position(|l| l == layer));
+    layers.dedup();
+    layers
+}
+
+impl Visitor for BloatVisitor<'_> {
+    fn enter(&mut self, path: &Path, node: &Node) -> bool {
+        let path = path.display().to_string();
+        if !node.metadata.is_file {
+            let Some(kind) = leftover_dir(&path) else {
+                return true;
+            };
+            let files: Vec<_> = node.walk().filter(|(_, meta)| meta.is_file && !meta.is_symlink).collect();
+            let layers = files.iter().filter_map(|(_, meta)| meta.layer_hash.as_deref().map(str::to_string));
+            let leftover = Leftover {
+                kind,
+                size: files.iter().map(|(_, meta)| meta.size).sum(),
+                files: files.len(),
+                layers: ordered(layers, self.layer_order),
+                path,
+            };
+            if leftover.size > 0 {
+                self.leftovers.push(leftover);
+            }
+            return false;
+        }
+
+        let (dir, name) = utils::split_path(&path);
+        let Some((kind, pattern)) = leftover_file(name).filter(|_| !node.metadata.is_symlink) else {
+            return true;
+        };
+        let (group, _) = self.grouped.entry((kind, dir.to_string())).or_insert_with(|| {
+            let pattern = if dir.is_empty() { pattern.to_string() } else { format!("{}/{}", dir, pattern) };
+            (Leftover { kind, path: pattern, size: 0, files: 0, layers: Vec::new() }, path.clone())
+        });
+        group.size += node.metadata.size;
+        group.files += 1;
+        let layer = node.metadata.layer_hash.as_deref().map(str::to_string);
+        group.layers = ordered(group.layers.drain(..).chain(layer), self.layer_order);
+        true
+    }
+}
+
+/// Leftovers in the merged tree under `root`, largest first
+pub fn find_leftovers(root: &Node, layer_labels: &[String]) -> Vec<Leftover> {
+    let mut visitor = BloatVisitor { layer_order: layer_labels, leftovers: Vec::new(), grouped: BTreeMap::new() };
+    root.visit(&mut visitor);
+    let mut leftovers = visitor.leftovers;
+    // A pattern that matched a single file names it
+    let groups = visitor.grouped.into_values()
+        .map(|(group, first)| if group.files == 1 { Leftover { path: first, ..group } } else { group });
+    leftovers.extend(groups.filter(|group| group.size > 0));
+    leftovers.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    leftovers
+}
+
+/// List the build leftovers of an archive's image, largest first
+pub fn run(archive_path: &Path) -> Result<()> {
+    let options = ProcessOptions { record_layers: true, ..Default::default() };
+    let image = archive::process_archive(archive_path, &options)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_report(&mut out, &find_leftovers(&image.root, &image.layer_labels))?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn write_report<W: Write>(out: &mut W, leftovers: &[Leftover]) -> io::Result<()> {
+    if leftovers.is_empty() {
+        return writeln!(out, "No caches or build leftovers found");
+    }
+
+    writeln!(out, "{:>10}  {:>6}  {:<14}  {:<12}  PATH", "SIZE", "FILES", "KIND", "LAYER")?;
+    for leftover in leftovers {
+        let layer = match leftover.layers.as_slice() {
+            [] => "-".to_string(),
+            [only] => only.clone(),
+            [first, rest @ ..] => format!("{} (+{})", first, rest.len()),
+        };
+        writeln!(out, "{:>10}  {:>6}  {:<14}  {:<12}  /{}", utils::human_size(leftover.size), leftover.files,
+            leftover.kind, layer, utils::display_path(&leftover.path))?;
+    }
+
+    let total: u64 = leftovers.iter().map(|l| l.size).sum();
+    let noun = if leftovers.len() == 1 { "place" } else { "places" };
+    writeln!(out, "\n{} in {} {} could be cleaned up", utils::human_size(total), leftovers.len(), noun)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_find_leftovers() {
+        let base: Arc<str> = Arc::from("aaaaaaa");
+        let build: Arc<str> = Arc::from("bbbbbbb");
+        let mut root = Node::new_dir(0o755, 0, 0);
+        for (path, size, layer) in [
+            ("var/lib/apt/lists/deb.debian.org_dists_bookworm_InRelease", 150_000, &base),
+            ("var/lib/apt/lists/deb.debian.org_dists_bookworm_main_Packages.lz4", 9_000_000, &build),
+            ("var/lib/apt/lists/lock", 0, &base),
+            ("var/cache/fontconfig/cache-1", 4_000, &base),
+            ("var/cache/ldconfig/aux-cache", 0, &base),
+            ("root/.cache/pip/http/0/1/blob", 2_000_000, &build),
+            ("app/.git/objects/pack/pack-1.pack", 500_000, &build),
+            ("app/build/main.o", 30_000, &build),
+            ("app/build/util.o", 10_000, &build),
+            ("app/core.1234", 1_000_000, &build),
+            ("app/core/__init__.py", 100, &build),
+            ("usr/lib/python3/core.py", 100, &base),
+        ] {
+            root.put_file(path, 0o644, 0, 0, false, None, Some(layer));
+            root.get_mut(path).unwrap().metadata.size = size;
+        }
+        let labels = vec![base.to_string(), build.to_string()];
+
+        let mut out = Vec::new();
+        write_report(&mut out, &find_leftovers(&root, &labels)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "      SIZE   FILES  KIND            LAYER         PATH\n",
+            "   8.7 MiB       3  apt lists       aaaaaaa (+1)  /var/lib/apt/lists\n",
+            "   1.9 MiB       1  pip cache       bbbbbbb       /root/.cache/pip\n",
+            " 976.6 KiB       1  core dump       bbbbbbb       /app/core.1234\n",
+            " 488.3 KiB       1  git repository  bbbbbbb       /app/.git\n",
+            "  39.1 KiB       2  object files    bbbbbbb       /app/build/*.o\n",
+            "   3.9 KiB       1  package cache   aaaaaaa       /var/cache/fontconfig\n",
+            "\n",
+            "12.1 MiB in 6 places could be cleaned up\n",
+        ));
+    }
+}
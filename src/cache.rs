@@ -0,0 +1,165 @@
+//! On-disk cache of merged trees for `--cache`
+//!
+//! Merging reads every layer tar, which dominates the run time on large
+//! images. With `--cache` the finished tree is kept in the user's cache,
+//! keyed by a digest of the image (its layers, their diff_ids, and its
+//! config) and of the options that shape the tree, so later runs with other
+//! display flags, or diffs against the same image, skip reading the layers.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::archive::{Layer, ProcessOptions};
+use crate::diagnostics::{self, WarningKind};
+use crate::tree::{self, Node};
+use crate::utils;
+
+/// Bumped whenever the encoding, or the way layers merge into a tree,
+/// changes; changes to the tree itself bump `tree::SCHEMA_VERSION`, which is
+/// part of the key too
+const FORMAT_VERSION: u32 = 7;
+
+/// Cache key for the tree `options` would build from `layers` and the raw
+/// image config, or None when the cache is off or the run needs to read the
+/// layers anyway
+pub fn key(layers: &[Layer], config: Option<&[u8]>, options: &ProcessOptions) -> Option<String> {
+    // Destructured in full so a new option has to be placed on one side
+    let ProcessOptions {
+        record_layers,
+        track_shadowed,
+        track_opaque,
+        term_progress: _,
+        profile_layers,
+        literal_paths,
+        whiteouts,
+        hash_contents,
+        platform: _,
+        image: _,
+        choose_image: _,
+        keep_devices,
+        resolve_owners,
+        paranoid,
+        allow_missing_layers: _,
+        strict,
+        cache,
+    } = options;
+    // A cached tree may have been built skipping entries strict mode fails on
+    if !cache || *profile_layers || *paranoid || *strict {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("contree {} tree v{} schema {}\n",
+        env!("CARGO_PKG_VERSION"), FORMAT_VERSION, tree::SCHEMA_VERSION));
+    // The platform, image and missing layers are already reflected in which
+    // layers and config were picked
+    for layer in layers {
+        let diff_id = layer.diff_id.as_deref().unwrap_or("-");
+        hasher.update(format!("layer {} {} {}\n", layer.name, layer.blob.size(), diff_id));
+    }
+    if let Some(config) = config {
+        hasher.update(format!("config {}\n", config.len()));
+        hasher.update(config);
+    }
+    let flags = [record_layers, track_shadowed, track_opaque, literal_paths, hash_contents, keep_devices, resolve_owners];
+    hasher.update(flags.map(|&flag| if flag { b'1' } else { b'0' }));
+    hasher.update(format!("\nwhiteouts {:?}\n", whiteouts));
+
+    Some(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The tree stored under `key`, if there is a readable one
+pub fn load(key: &str) -> Option<Node> {
+    let path = entry_path(key).ok()?;
+    let file = File::open(&path).ok()?;
+    match rmp_serde::from_read(BufReader::new(file)) {
+        Ok(root) => Some(root),
+        Err(err) => {
+            diagnostics::warn(WarningKind::Cache, format!("Ignoring unreadable cached tree {}: {}", path.display(), err));
+            None
+        }
+    }
+}
+
+/// Store `root` under `key`; the entry appears all at once, so concurrent
+/// runs never read a partial tree
+pub fn store(key: &str, root: &Node) -> Result<()> {
+    let path = entry_path(key)?;
+    let dir = path.parent().context("Cache entry has no directory")?;
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let mut temp = tempfile::NamedTempFile::new_in(dir).context("Failed to create temp file")?;
+    let mut writer = BufWriter::new(temp.as_file_mut());
+    rmp_serde::encode::write(&mut writer, root).context("Failed to encode tree")?;
+    writer.flush()?;
+    drop(writer);
+    temp.persist(&path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+fn entry_path(key: &str) -> Result<PathBuf> {
+    Ok(utils::cache_dir()?.join("trees").join(format!("{}.msgpack", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Blob, Compression};
+    use std::sync::Arc;
+
+    fn key_for(diff_id: &str, options: &ProcessOptions) -> Option<String> {
+        let layer = Layer {
+            name: "aaaaaaa/layer.tar".to_string(),
+            blob: Blob::InArchive { archive: PathBuf::new(), offset: 0, size: 1024 },
+            diff_id: Some(diff_id.to_string()),
+            compression: Compression::None,
+        };
+        key(&[layer], Some(b"{}"), options)
+    }
+
+    #[test]
+    fn test_key_tracks_image_and_tree_options() {
+        let options = ProcessOptions { cache: true, ..Default::default() };
+        let key = key_for("sha256:1111", &options).unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(key_for("sha256:1111", &options), Some(key.clone()));
+        assert_ne!(key_for("sha256:2222", &options), Some(key.clone()));
+
+        // Display-only options share the tree, tree-shaping ones don't
+        let progress = ProcessOptions { term_progress: true, ..options.clone() };
+        assert_eq!(key_for("sha256:1111", &progress), Some(key.clone()));
+        let shadowed = ProcessOptions { track_shadowed: true, ..options.clone() };
+        assert_ne!(key_for("sha256:1111", &shadowed), Some(key));
+
+        // Runs that have to read the layers don't use the cache
+        let paranoid = ProcessOptions { paranoid: true, ..options };
+        assert!(key_for("sha256:1111", &paranoid).is_none());
+        assert!(key_for("sha256:1111", &ProcessOptions::default()).is_none());
+    }
+
+    #[test]
+    fn test_tree_round_trip() {
+        let layer: Arc<str> = "aaaaaaa".into();
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("a/index.js", 0o644, 0, 0, false, None, Some(&layer));
+        root.put_file("b/index.js", 0o755, 1000, 1000, true, Some("../a/index.js".to_string()), Some(&layer));
+        root.mark_opaque("a", Some(&layer), true);
+
+        let bytes = rmp_serde::to_vec(&root).unwrap();
+        let decoded: Node = rmp_serde::from_slice(&bytes).unwrap();
+        let link = decoded.get("b/index.js").unwrap();
+        assert!(link.metadata.is_symlink);
+        assert_eq!(link.metadata.symlink_target.as_deref(), Some("../a/index.js"));
+        assert_eq!((link.metadata.uid, link.metadata.mode), (1000, 0o755));
+        let dir = decoded.get("a").unwrap();
+        assert_eq!(dir.metadata.masked.len(), 1);
+        // Labels are shared again after loading
+        assert!(Arc::ptr_eq(
+            link.metadata.layer_hash.as_ref().unwrap(),
+            dir.metadata.opaque_layer.as_ref().unwrap(),
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! Decoding of `security.capability` xattrs (VFS capability data) into the
+//! text form used by `getcap` and `setcap`, e.g. `cap_net_bind_service+ep`
+
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// The xattr holding file capabilities
+pub const XATTR: &str = "security.capability";
+
+const REVISION_MASK: u32 = 0xff00_0000;
+const FLAG_EFFECTIVE: u32 = 0x0000_0001;
+const REVISION_1: u32 = 0x0100_0000;
+const REVISION_2: u32 = 0x0200_0000;
+const REVISION_3: u32 = 0x0300_0000;
+
+/// Capability names by bit number, as in linux/capability.h
+const NAMES: [&str; 41] = [
+    "cap_chown", "cap_dac_override", "cap_dac_read_search", "cap_fowner", "cap_fsetid",
+    "cap_kill", "cap_setgid", "cap_setuid", "cap_setpcap", "cap_linux_immutable",
+    "cap_net_bind_service", "cap_net_broadcast", "cap_net_admin", "cap_net_raw", "cap_ipc_lock",
+    "cap_ipc_owner", "cap_sys_module", "cap_sys_rawio", "cap_sys_chroot", "cap_sys_ptrace",
+    "cap_sys_pacct", "cap_sys_admin", "cap_sys_boot", "cap_sys_nice", "cap_sys_resource",
+    "cap_sys_time", "cap_sys_tty_config", "cap_mknod", "cap_lease", "cap_audit_write",
+    "cap_audit_control", "cap_setfcap", "cap_mac_override", "cap_mac_admin", "cap_syslog",
+    "cap_wake_alarm", "cap_block_suspend", "cap_audit_read", "cap_perfmon", "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// Capability sets stored on a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileCaps {
+    pub permitted: u64,
+    pub inheritable: u64,
+    /// Permitted and inheritable capabilities become effective on exec
+    pub effective: bool,
+    /// Root uid of the user namespace the capabilities apply in (revision 3)
+    pub rootid: Option<u32>,
+}
+
+impl FileCaps {
+    /// Parse a raw `security.capability` value
+    pub fn decode(value: &[u8]) -> Result<FileCaps> {
+        let word = |i: usize| -> Option<u32> {
+            value.get(i * 4..i * 4 + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let Some(magic) = word(0) else {
+            bail!("capability data too short ({} bytes)", value.len());
+        };
+        let (words, expected_len) = match magic & REVISION_MASK {
+            REVISION_1 => (1, 12),
+            REVISION_2 => (2, 20),
+            REVISION_3 => (2, 24),
+            revision => bail!("unknown capability revision {:#x}", revision >> 24),
+        };
+        if value.len() != expected_len {
+            bail!("capability data is {} bytes, expected {}", value.len(), expected_len);
+        }
+
+        let mut caps = FileCaps {
+            permitted: 0,
+            inheritable: 0,
+            effective: magic & FLAG_EFFECTIVE != 0,
+            rootid: (magic & REVISION_MASK == REVISION_3).then(|| word(5).unwrap_or(0)),
+        };
+        for i in 0..words {
+            caps.permitted |= (word(1 + i * 2).unwrap_or(0) as u64) << (32 * i);
+            caps.inheritable |= (word(2 + i * 2).unwrap_or(0) as u64) << (32 * i);
+        }
+        Ok(caps)
+    }
+}
+
+fn name(bit: u32) -> String {
+    NAMES.get(bit as usize).map_or_else(|| format!("cap_{}", bit), |n| n.to_string())
+}
+
+impl fmt::Display for FileCaps {
+    /// Capabilities grouped by the flags they carry, like `cap_to_text`:
+    /// `cap_net_admin,cap_net_raw+ep cap_sys_nice+i`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for bit in 0..64 {
+            let mask = 1u64 << bit;
+            let (p, i) = (self.permitted & mask != 0, self.inheritable & mask != 0);
+            if !p && !i {
+                continue;
+            }
+            let flags = format!("{}{}{}",
+                if self.effective { "e" } else { "" },
+                if i { "i" } else { "" },
+                if p { "p" } else { "" });
+            match groups.iter_mut().find(|(f, _)| *f == flags) {
+                Some((_, names)) => names.push(name(bit)),
+                None => groups.push((flags, vec![name(bit)])),
+            }
+        }
+
+        if groups.is_empty() {
+            write!(f, "=")?;
+        }
+        for (n, (flags, names)) in groups.iter().enumerate() {
+            if n > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}+{}", names.join(","), flags)?;
+        }
+        if let Some(rootid) = self.rootid.filter(|&id| id != 0) {
+            write!(f, " [rootid={}]", rootid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Human-readable form of a raw xattr value, or why it couldn't be decoded
+pub fn describe(value: &[u8]) -> String {
+    match FileCaps::decode(value) {
+        Ok(caps) => caps.to_string(),
+        Err(e) => format!("undecodable ({})", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(effective: bool, permitted: u64, inheritable: u64) -> Vec<u8> {
+        let magic = REVISION_2 | if effective { FLAG_EFFECTIVE } else { 0 };
+        [magic, permitted as u32, inheritable as u32, (permitted >> 32) as u32, (inheritable >> 32) as u32]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_v2() {
+        let caps = FileCaps::decode(&v2(true, 1 << 10, 0)).unwrap();
+        assert_eq!(caps.to_string(), "cap_net_bind_service+ep");
+
+        let caps = FileCaps::decode(&v2(true, (1 << 12) | (1 << 13), 0)).unwrap();
+        assert_eq!(caps.to_string(), "cap_net_admin,cap_net_raw+ep");
+
+        let caps = FileCaps::decode(&v2(false, 1 << 39, 1 << 23)).unwrap();
+        assert_eq!(caps.to_string(), "cap_sys_nice+i cap_bpf+p");
+    }
+
+    #[test]
+    fn test_decode_v3_rootid() {
+        let mut value = v2(true, 1 << 13, 0);
+        value[3] = 0x03;
+        value.extend(1000u32.to_le_bytes());
+        let caps = FileCaps::decode(&value).unwrap();
+        assert_eq!(caps.rootid, Some(1000));
+        assert_eq!(caps.to_string(), "cap_net_raw+ep [rootid=1000]");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(FileCaps::decode(&[1]).is_err());
+        assert!(FileCaps::decode(&v2(true, 1, 0)[..12]).is_err());
+        assert_eq!(describe(&[0, 0, 0, 9, 0, 0, 0, 0]), "undecodable (unknown capability revision 0x9)");
+    }
+}
@@ -0,0 +1,91 @@
+//! Paths that differ only by case, for `--check-case-collisions`
+//!
+//! Linux filesystems keep `Makefile` and `makefile` apart. The default
+//! filesystems of macOS and Windows don't, so extracting such an image there,
+//! or bind-mounting its files through Docker Desktop, quietly leaves one of
+//! them behind.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::tree::Node;
+
+/// Entries of one directory whose names differ only by case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseCollision {
+    /// Directory holding the entries, without a leading "/" ("" for the root)
+    pub dir: String,
+    /// Names of the colliding entries, in name order
+    pub names: Vec<String>,
+}
+
+impl CaseCollision {
+    /// Full paths of the colliding entries
+    pub fn paths(&self) -> impl Iterator<Item = String> + '_ {
+        self.names.iter().map(|name| format!("/{}{}{}", self.dir, if self.dir.is_empty() { "" } else { "/" }, name))
+    }
+}
+
+/// Every directory of the merged tree holding names that differ only by
+/// case, parents before children
+pub fn find(root: &Node) -> Vec<CaseCollision> {
+    fn check_dir(dir: &str, node: &Node, collisions: &mut Vec<CaseCollision>) {
+        let mut folded: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for name in node.children.keys() {
+            folded.entry(name.to_lowercase()).or_default().push(name.to_string());
+        }
+        for names in folded.into_values().filter(|names| names.len() > 1) {
+            collisions.push(CaseCollision { dir: dir.to_string(), names });
+        }
+        for (name, child) in &node.children {
+            if !child.children.is_empty() {
+                let path = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+                check_dir(&path, child, collisions);
+            }
+        }
+    }
+
+    let mut collisions = Vec::new();
+    check_dir("", root, &mut collisions);
+    collisions
+}
+
+/// Print the colliding paths of each collision on one line, then a count
+pub fn write_report<W: Write>(out: &mut W, collisions: &[CaseCollision]) -> io::Result<()> {
+    for collision in collisions {
+        writeln!(out, "  {}", collision.paths().collect::<Vec<_>>().join(", "))?;
+    }
+    writeln!(out, "{} case collision{}", collisions.len(), if collisions.len() == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_names_differing_only_by_case() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("Makefile", 0o644, 0, 0, false, None, None);
+        root.put_file("makefile", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/share/perl5/Foo.pm", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/share/perl5/foo.pm", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/share/perl5/FOO.PM", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/share/perl5/bar.pm", 0o644, 0, 0, false, None, None);
+        // Same name in different directories is fine
+        root.put_file("etc/foo.pm", 0o644, 0, 0, false, None, None);
+
+        let collisions = find(&root);
+        assert_eq!(collisions, vec![
+            CaseCollision { dir: String::new(), names: vec!["Makefile".to_string(), "makefile".to_string()] },
+            CaseCollision {
+                dir: "usr/share/perl5".to_string(),
+                names: vec!["FOO.PM".to_string(), "Foo.pm".to_string(), "foo.pm".to_string()],
+            },
+        ]);
+
+        let mut out = Vec::new();
+        write_report(&mut out, &collisions).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "  /Makefile, /makefile\n  \
+            /usr/share/perl5/FOO.PM, /usr/share/perl5/Foo.pm, /usr/share/perl5/foo.pm\n2 case collisions\n");
+    }
+}
@@ -0,0 +1,141 @@
+//! Sanity checks over an image archive
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, Compression, Layer, LayerSet};
+
+/// Outcome of a single check
+#[derive(Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub check: &'static str,
+    /// None when the check passed
+    pub problem: Option<String>,
+    /// One-line summary of what was inspected
+    pub summary: String,
+}
+
+/// Run every check against a layer set
+pub fn run_checks(layer_set: &LayerSet) -> Vec<CheckResult> {
+    #[cfg_attr(not(feature = "analyzers"), allow(unused_mut))]
+    let mut results = vec![check_compression(&layer_set.layers)];
+    #[cfg(feature = "analyzers")]
+    results.push(check_entrypoint(layer_set));
+    results
+}
+
+/// Flag images whose layers mix gzip and zstd, which older runtimes reject
+pub fn check_compression(layers: &[Layer]) -> CheckResult {
+    let mut by_kind: BTreeMap<Compression, Vec<usize>> = BTreeMap::new();
+    for (i, layer) in layers.iter().enumerate() {
+        by_kind.entry(layer.compression).or_default().push(i);
+    }
+
+    let groups: Vec<String> = by_kind.iter()
+        .map(|(kind, indices)| {
+            let list: Vec<String> = indices.iter().map(|i| i.to_string()).collect();
+            format!("{}: {}", kind.name(), list.join(", "))
+        })
+        .collect();
+
+    let mixed = by_kind.contains_key(&Compression::Gzip) && by_kind.contains_key(&Compression::Zstd);
+    let problem = mixed.then(|| format!(
+        "layers mix gzip and zstd compression ({}); some older runtimes cannot load this image. \
+         Normalize with `contree export --recompress gzip`",
+        groups.join("; ")));
+
+    CheckResult {
+        check: "compression",
+        problem,
+        summary: format!("{} layers ({})", layers.len(), groups.join("; ")),
+    }
+}
+
+/// Flag images whose Entrypoint/Cmd can't start: a program that is missing
+/// or not executable, or a missing interpreter or shared library
+#[cfg(feature = "analyzers")]
+pub fn check_entrypoint(layer_set: &LayerSet) -> CheckResult {
+    match crate::entrypoint::validate(layer_set) {
+        Ok(report) => CheckResult {
+            check: "entrypoint",
+            problem: (!report.problems.is_empty()).then(|| report.problems.join("; ")),
+            summary: report.summary,
+        },
+        Err(err) => CheckResult {
+            check: "entrypoint",
+            problem: Some(format!("could not be checked: {:#}", err)),
+            summary: String::new(),
+        },
+    }
+}
+
+/// Print check results; fails if any check found a problem
+pub fn run(archive_path: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let results = run_checks(&layer_set);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_results(&mut out, &results)?;
+    out.flush()?;
+
+    let failed = results.iter().filter(|r| r.problem.is_some()).count();
+    if failed > 0 {
+        bail!("{} of {} checks reported problems", failed, results.len());
+    }
+    Ok(())
+}
+
+pub fn write_results<W: Write>(out: &mut W, results: &[CheckResult]) -> io::Result<()> {
+    for result in results {
+        match &result.problem {
+            None => writeln!(out, "ok    {}: {}", result.check, result.summary)?,
+            Some(problem) => writeln!(out, "warn  {}: {}", result.check, problem)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Blob;
+    use std::path::PathBuf;
+
+    fn layers(kinds: &[Compression]) -> Vec<Layer> {
+        kinds.iter().enumerate()
+            .map(|(i, &compression)| Layer {
+                name: format!("{}/layer.tar", i),
+                blob: Blob::File(PathBuf::new()),
+                diff_id: None,
+                compression,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check_compression_flags_gzip_zstd_mix() {
+        let result = check_compression(&layers(&[Compression::Gzip, Compression::Zstd, Compression::Gzip]));
+        let problem = result.problem.unwrap();
+        assert!(problem.contains("gzip: 0, 2; zstd: 1"), "{}", problem);
+    }
+
+    #[test]
+    fn test_check_compression_allows_uniform_or_uncompressed() {
+        assert!(check_compression(&layers(&[Compression::Gzip, Compression::Gzip])).problem.is_none());
+        assert!(check_compression(&layers(&[Compression::None, Compression::Zstd])).problem.is_none());
+    }
+
+    #[test]
+    fn test_write_results() {
+        let results = vec![
+            CheckResult { check: "a", problem: None, summary: "3 layers".to_string() },
+            CheckResult { check: "b", problem: Some("broken".to_string()), summary: String::new() },
+        ];
+        let mut out = Vec::new();
+        write_results(&mut out, &results).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "ok    a: 3 layers\nwarn  b: broken\n");
+    }
+}
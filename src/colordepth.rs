@@ -0,0 +1,236 @@
+//! How many colors the terminal shows, and fitting theme colors to it
+//!
+//! Themes are written in 24-bit RGB, which terminals without true color show
+//! as the wrong colors or not at all. `--color-depth auto` looks at
+//! COLORTERM and the terminfo entry for TERM, and themes are then converted
+//! to the nearest of the 256 xterm colors or the 16 basic ANSI colors.
+
+use anyhow::{bail, Result};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Colors a terminal can show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    /// 24-bit RGB (`38;2;r;g;b`)
+    TrueColor,
+    /// The xterm 256-color palette (`38;5;n`)
+    Ansi256,
+    /// The 16 basic colors (30–37 and 90–97)
+    Ansi16,
+}
+
+impl ColorDepth {
+    /// A `--color-depth` value other than `auto`: truecolor, 256 or 16
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "truecolor" | "24bit" => Ok(ColorDepth::TrueColor),
+            "256" => Ok(ColorDepth::Ansi256),
+            "16" => Ok(ColorDepth::Ansi16),
+            _ => bail!("Unknown color depth '{}' (expected truecolor, 256 or 16)", name),
+        }
+    }
+
+    /// The depth of the terminal described by the environment
+    pub fn detect() -> Self {
+        Self::from_env(|name| std::env::var_os(name), |term| terminfo_colors(term, |name| std::env::var_os(name)))
+    }
+
+    /// COLORTERM=truecolor (or 24bit) means true color. Otherwise the
+    /// `colors` capability of TERM's terminfo entry decides, or TERM's name
+    /// when there is no entry. Without a TERM (Windows consoles, or output
+    /// forced into a pipe) colors are left as they are.
+    fn from_env(var: impl Fn(&str) -> Option<OsString>, colors: impl Fn(&str) -> Option<u32>) -> Self {
+        if var("COLORTERM").is_some_and(|v| v == "truecolor" || v == "24bit") {
+            return ColorDepth::TrueColor;
+        }
+        let Some(term) = var("TERM").filter(|t| !t.is_empty()) else {
+            return ColorDepth::TrueColor;
+        };
+        let term = term.to_string_lossy();
+        match colors(&term) {
+            Some(n) if n >= 1 << 24 => ColorDepth::TrueColor,
+            Some(n) if n >= 256 => ColorDepth::Ansi256,
+            Some(_) => ColorDepth::Ansi16,
+            None if term.ends_with("-direct") => ColorDepth::TrueColor,
+            None if term.contains("256color") => ColorDepth::Ansi256,
+            None => ColorDepth::Ansi16,
+        }
+    }
+
+    /// The SGR foreground code for an RGB color at this depth, e.g. `38;5;73`
+    pub fn foreground(self, r: u8, g: u8, b: u8) -> String {
+        match self {
+            ColorDepth::TrueColor => format!("38;2;{};{};{}", r, g, b),
+            ColorDepth::Ansi256 => format!("38;5;{}", nearest_256(r, g, b)),
+            ColorDepth::Ansi16 => nearest_16(r, g, b).to_string(),
+        }
+    }
+
+    /// The SGR background code for an RGB color at this depth, e.g. `48;5;73`
+    pub fn background(self, r: u8, g: u8, b: u8) -> String {
+        match self {
+            ColorDepth::TrueColor => format!("48;2;{};{};{}", r, g, b),
+            ColorDepth::Ansi256 => format!("48;5;{}", nearest_256(r, g, b)),
+            ColorDepth::Ansi16 => (nearest_16(r, g, b) + 10).to_string(),
+        }
+    }
+}
+
+/// Channel levels of the 6×6×6 color cube at indices 16–231
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The closest xterm color: the nearest cube color or gray (232–255),
+/// whichever is nearer
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| {
+        (0..6).min_by_key(|&i| (i32::from(CUBE_LEVELS[i]) - i32::from(c)).abs()).expect("six levels")
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    let average = (u32::from(r) + u32::from(g) + u32::from(b)) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray = 8 + 10 * gray_index;
+
+    let distance = |(cr, cg, cb): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(cr, r) + d(cg, g) + d(cb, b)
+    };
+    if distance((gray, gray, gray)) < distance(cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8
+    }
+}
+
+/// The closest basic color. The 16 colors look different in every terminal
+/// theme, so this matches hue and brightness rather than exact RGB: nearly
+/// gray colors become black, gray or white, and others the primaries and
+/// secondaries their strong channels make, bright when the color is light.
+fn nearest_16(r: u8, g: u8, b: u8) -> u8 {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    if max - min < 40 {
+        return match max {
+            0..=63 => 30,
+            64..=159 => 90,
+            160..=223 => 37,
+            _ => 97,
+        };
+    }
+    // Channels in the upper half between the weakest and strongest count
+    let on = |c: u8| u8::from(u16::from(c - min) * 2 >= u16::from(max - min));
+    let code = 30 + (on(b) << 2 | on(g) << 1 | on(r));
+    if max >= 192 {
+        code + 60
+    } else {
+        code
+    }
+}
+
+/// The `colors` capability of a terminfo entry, if one can be found
+fn terminfo_colors(term: &str, var: impl Fn(&str) -> Option<OsString>) -> Option<u32> {
+    // Where ncurses looks, in its order
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    dirs.extend(var("TERMINFO").map(PathBuf::from));
+    dirs.extend(var("HOME").map(|home| PathBuf::from(home).join(".terminfo")));
+    if let Some(list) = var("TERMINFO_DIRS") {
+        dirs.extend(std::env::split_paths(&list).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs.extend(["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"].map(PathBuf::from));
+
+    let first = term.chars().next()?;
+    dirs.iter()
+        .flat_map(|dir| {
+            // Linux names the subdirectory by the first letter, macOS by its hex code
+            [dir.join(first.to_string()).join(term), dir.join(format!("{:x}", u32::from(first))).join(term)]
+        })
+        .find_map(|path| std::fs::read(path).ok())
+        .and_then(|entry| parse_colors(&entry))
+}
+
+/// Index of `colors` among the numeric capabilities
+const COLORS_CAPABILITY: usize = 13;
+
+/// Read `colors` from a compiled terminfo entry (term(5)): a header of six
+/// little-endian shorts, the names, the booleans, padding to an even
+/// offset, then the numbers (16-bit, or 32-bit in the extended format)
+fn parse_colors(entry: &[u8]) -> Option<u32> {
+    let short = |i: usize| entry.get(i * 2..i * 2 + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let width = match short(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+    let (names, booleans, numbers) = (short(1)?, short(2)?, short(3)?);
+    if COLORS_CAPABILITY >= numbers {
+        return None;
+    }
+    let start = (12 + names + booleans).next_multiple_of(2) + COLORS_CAPABILITY * width;
+    let bytes = entry.get(start..start + width)?;
+    // Negative values mean absent or cancelled
+    let value = if width == 2 {
+        i32::from(i16::from_le_bytes([bytes[0], bytes[1]]))
+    } else {
+        i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    u32::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_color_depth() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| OsString::from(v))
+        };
+        let terminfo = |term: &str| match term {
+            "xterm" => Some(8),
+            "xterm-256color" => Some(256),
+            "xterm-direct" => Some(1 << 24),
+            _ => None,
+        };
+        let detect = |vars| ColorDepth::from_env(env(vars), terminfo);
+        assert_eq!(detect(&[]), ColorDepth::TrueColor);
+        assert_eq!(detect(&[("COLORTERM", "truecolor"), ("TERM", "xterm")]), ColorDepth::TrueColor);
+        assert_eq!(detect(&[("TERM", "xterm")]), ColorDepth::Ansi16);
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), ColorDepth::Ansi256);
+        assert_eq!(detect(&[("TERM", "xterm-direct")]), ColorDepth::TrueColor);
+        assert_eq!(detect(&[("TERM", "tmux-256color")]), ColorDepth::Ansi256);
+        assert_eq!(detect(&[("TERM", "linux")]), ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn test_nearest_colors() {
+        assert_eq!(nearest_256(255, 0, 0), 196);
+        assert_eq!(nearest_256(125, 174, 163), 109);
+        assert_eq!(nearest_256(128, 128, 128), 244);
+        assert_eq!(nearest_16(234, 105, 98), 91);
+        assert_eq!(nearest_16(125, 174, 163), 36);
+        assert_eq!(nearest_16(146, 131, 116), 90);
+        assert_eq!(nearest_16(0x26, 0x8b, 0xd2), 96);
+    }
+
+    #[test]
+    fn test_parse_terminfo_colors() {
+        let entry = |magic: u16, width: usize, colors: i32| {
+            let mut entry = Vec::new();
+            for short in [magic, 6, 1, 14, 0, 0] {
+                entry.extend(short.to_le_bytes());
+            }
+            entry.extend(b"term\0\0\x01\0");
+            for i in 0..14 {
+                let value = if i == COLORS_CAPABILITY { colors } else { -1 };
+                entry.extend(&value.to_le_bytes()[..width]);
+            }
+            entry
+        };
+        assert_eq!(parse_colors(&entry(0o432, 2, 256)), Some(256));
+        assert_eq!(parse_colors(&entry(0o1036, 4, 1 << 24)), Some(1 << 24));
+        assert_eq!(parse_colors(&entry(0o432, 2, -1)), None);
+        assert_eq!(parse_colors(b"not terminfo"), None);
+    }
+}
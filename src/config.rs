@@ -0,0 +1,344 @@
+//! Docker image configuration (the JSON blob referenced by the manifest's `Config`)
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Image configuration blob
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub architecture: Option<String>,
+
+    #[serde(default)]
+    pub os: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+
+    /// Runtime defaults for containers started from this image
+    #[serde(default)]
+    pub config: ContainerConfig,
+
+    #[serde(default)]
+    pub rootfs: Option<RootFs>,
+
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+/// The `config` section of an image configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub working_dir: Option<String>,
+
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+
+    #[serde(default)]
+    pub user: Option<String>,
+
+    #[serde(default)]
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+
+    #[serde(default)]
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+
+    /// Hostname of the build container (set by the legacy builder)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+}
+
+/// Layer digests of the uncompressed root filesystem
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(default)]
+    pub diff_ids: Vec<String>,
+}
+
+/// One build step recorded in the image history
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(default)]
+    pub created: Option<String>,
+
+    #[serde(default)]
+    pub created_by: Option<String>,
+
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub comment: Option<String>,
+
+    /// true if this step produced no filesystem layer
+    #[serde(default)]
+    pub empty_layer: bool,
+}
+
+/// Parse an image configuration blob
+pub fn parse_config(config_bytes: &[u8]) -> Result<ImageConfig> {
+    serde_json::from_slice(config_bytes).context("Failed to parse image config")
+}
+
+/// How the config disagrees with a manifest listing `layers` layers: a
+/// `rootfs.diff_ids` or history of another length, as hand-edited or
+/// corrupted archives have. Empty when they agree, or the config has neither.
+pub fn layer_count_mismatches(config: &ImageConfig, layers: usize) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if let Some(rootfs) = &config.rootfs {
+        if rootfs.diff_ids.len() != layers {
+            mismatches.push(format!("the manifest lists {} layers but rootfs.diff_ids has {}",
+                layers, rootfs.diff_ids.len()));
+        }
+    }
+    let steps = config.history.iter().filter(|entry| !entry.empty_layer).count();
+    if !config.history.is_empty() && steps != layers {
+        mismatches.push(format!("the manifest lists {} layers but the history has {} steps that add one",
+            layers, steps));
+    }
+    mismatches
+}
+
+/// How a config field changed between two images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    pub fn marker(&self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Changed => '~',
+        }
+    }
+}
+
+/// A single difference between two image configurations
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigChange {
+    pub kind: ChangeKind,
+    /// Field name, qualified by key for map-like fields (e.g. "Env PATH")
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compare two image configurations field by field
+pub fn diff_configs(old: &ImageConfig, new: &ImageConfig) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    diff_value(&mut changes, "Platform", platform(old), platform(new));
+
+    let (o, n) = (&old.config, &new.config);
+    diff_value(&mut changes, "User", non_empty(&o.user), non_empty(&n.user));
+    diff_value(&mut changes, "WorkingDir", non_empty(&o.working_dir), non_empty(&n.working_dir));
+    diff_value(&mut changes, "Entrypoint", o.entrypoint.as_ref().map(|v| format_args(v)),
+        n.entrypoint.as_ref().map(|v| format_args(v)));
+    diff_value(&mut changes, "Cmd", o.cmd.as_ref().map(|v| format_args(v)),
+        n.cmd.as_ref().map(|v| format_args(v)));
+
+    diff_maps(&mut changes, "Env", &env_map(&o.env), &env_map(&n.env));
+    diff_maps(&mut changes, "Label", &o.labels.clone().unwrap_or_default(),
+        &n.labels.clone().unwrap_or_default());
+    diff_maps(&mut changes, "ExposedPort", &key_set(&o.exposed_ports), &key_set(&n.exposed_ports));
+    diff_maps(&mut changes, "Volume", &key_set(&o.volumes), &key_set(&n.volumes));
+
+    diff_history(&mut changes, &old.history, &new.history);
+
+    changes
+}
+
+/// `os/architecture[/variant]`, when the config names both an OS and an architecture
+fn platform(config: &ImageConfig) -> Option<String> {
+    let os = non_empty(&config.os)?;
+    let architecture = non_empty(&config.architecture)?;
+    Some(match non_empty(&config.variant) {
+        Some(variant) => format!("{}/{}/{}", os, architecture, variant),
+        None => format!("{}/{}", os, architecture),
+    })
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.as_ref().filter(|v| !v.is_empty()).cloned()
+}
+
+/// Render an argv-style list the way `docker inspect` does
+fn format_args(args: &[String]) -> String {
+    serde_json::to_string(args).unwrap_or_default()
+}
+
+fn env_map(env: &Option<Vec<String>>) -> BTreeMap<String, String> {
+    env.iter()
+        .flatten()
+        .map(|var| match var.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => (var.clone(), String::new()),
+        })
+        .collect()
+}
+
+fn key_set(map: &Option<BTreeMap<String, serde_json::Value>>) -> BTreeMap<String, String> {
+    map.iter().flatten().map(|(k, _)| (k.clone(), String::new())).collect()
+}
+
+fn diff_value(changes: &mut Vec<ConfigChange>, field: &str, old: Option<String>, new: Option<String>) {
+    let kind = match (&old, &new) {
+        (None, None) => return,
+        (Some(a), Some(b)) if a == b => return,
+        (None, Some(_)) => ChangeKind::Added,
+        (Some(_), None) => ChangeKind::Removed,
+        (Some(_), Some(_)) => ChangeKind::Changed,
+    };
+    changes.push(ConfigChange { kind, field: field.to_string(), old, new });
+}
+
+fn diff_maps(
+    changes: &mut Vec<ConfigChange>,
+    field: &str,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) {
+    let keys: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        diff_value(changes, &format!("{} {}", field, key), old.get(key).cloned(), new.get(key).cloned());
+    }
+}
+
+/// Report history steps that differ after the common prefix of both images
+fn diff_history(changes: &mut Vec<ConfigChange>, old: &[HistoryEntry], new: &[HistoryEntry]) {
+    let step = |h: &HistoryEntry| h.created_by.clone().unwrap_or_default();
+    let common = old.iter().zip(new).take_while(|(a, b)| step(a) == step(b)).count();
+
+    for entry in &old[common..] {
+        changes.push(ConfigChange {
+            kind: ChangeKind::Removed,
+            field: "History".to_string(),
+            old: Some(step(entry)),
+            new: None,
+        });
+    }
+    for entry in &new[common..] {
+        changes.push(ConfigChange {
+            kind: ChangeKind::Added,
+            field: "History".to_string(),
+            old: None,
+            new: Some(step(entry)),
+        });
+    }
+}
+
+/// Write a concise config-change section
+pub fn write_changes<W: Write>(out: &mut W, changes: &[ConfigChange]) -> io::Result<()> {
+    writeln!(out, "Config changes:")?;
+    if changes.is_empty() {
+        return writeln!(out, "  (none)");
+    }
+
+    for change in changes {
+        let value = match (&change.old, &change.new) {
+            (Some(old), Some(new)) => format!("{} -> {}", old, new),
+            (Some(v), None) | (None, Some(v)) => v.clone(),
+            (None, None) => String::new(),
+        };
+        if value.is_empty() {
+            writeln!(out, "  {} {}", change.kind.marker(), change.field)?;
+        } else {
+            writeln!(out, "  {} {}: {}", change.kind.marker(), change.field, value)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> ImageConfig {
+        parse_config(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let cfg = config(r#"{
+            "architecture": "amd64",
+            "config": {"Env": ["PATH=/bin"], "Cmd": ["/bin/sh"], "ExposedPorts": {"80/tcp": {}}},
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:aaa"]},
+            "history": [{"created_by": "ENV A=1", "empty_layer": true}]
+        }"#);
+        assert_eq!(cfg.architecture.as_deref(), Some("amd64"));
+        assert_eq!(cfg.config.cmd, Some(vec!["/bin/sh".to_string()]));
+        assert_eq!(cfg.rootfs.unwrap().diff_ids, vec!["sha256:aaa"]);
+        assert!(cfg.history[0].empty_layer);
+    }
+
+    #[test]
+    fn test_layer_count_mismatches() {
+        let cfg = config(r#"{
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:aaa", "sha256:bbb"]},
+            "history": [{"created_by": "ADD rootfs"}, {"created_by": "ENV A=1", "empty_layer": true}, {"created_by": "RUN make"}]
+        }"#);
+        assert!(layer_count_mismatches(&cfg, 2).is_empty());
+        assert_eq!(layer_count_mismatches(&cfg, 3), [
+            "the manifest lists 3 layers but rootfs.diff_ids has 2",
+            "the manifest lists 3 layers but the history has 2 steps that add one",
+        ]);
+        // Nothing to compare against
+        assert!(layer_count_mismatches(&config("{}"), 3).is_empty());
+    }
+
+    #[test]
+    fn test_diff_configs() {
+        let old = config(r#"{"config": {"Env": ["PATH=/bin", "OLD=1"], "User": "root",
+            "Labels": {"a": "1"}}, "history": [{"created_by": "ADD x"}]}"#);
+        let new = config(r#"{"config": {"Env": ["PATH=/usr/bin"], "User": "app",
+            "Labels": {"a": "1"}, "ExposedPorts": {"443/tcp": {}}},
+            "history": [{"created_by": "ADD x"}, {"created_by": "RUN y"}]}"#);
+
+        let changes = diff_configs(&old, &new);
+        let summary: Vec<String> = changes.iter()
+            .map(|c| format!("{} {}", c.kind.marker(), c.field))
+            .collect();
+        assert_eq!(summary, vec![
+            "~ User",
+            "- Env OLD",
+            "~ Env PATH",
+            "+ ExposedPort 443/tcp",
+            "+ History",
+        ]);
+        assert_eq!(changes[2].new.as_deref(), Some("/usr/bin"));
+        assert_eq!(changes[4].new.as_deref(), Some("RUN y"));
+    }
+
+    #[test]
+    fn test_diff_configs_platform() {
+        let amd = config(r#"{"os": "linux", "architecture": "amd64"}"#);
+        let arm = config(r#"{"os": "linux", "architecture": "arm64", "variant": "v8"}"#);
+        let changes = diff_configs(&amd, &arm);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "Platform");
+        assert_eq!(changes[0].old.as_deref(), Some("linux/amd64"));
+        assert_eq!(changes[0].new.as_deref(), Some("linux/arm64/v8"));
+    }
+
+    #[test]
+    fn test_identical_configs() {
+        let cfg = config(r#"{"config": {"Cmd": ["sh"]}}"#);
+        assert!(diff_configs(&cfg, &cfg).is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+//! Reading files of the merged image
+//!
+//! The merged tree only keeps metadata, so contents come from the layers
+//! again: a file's bytes are in the topmost layer that wrote its path, and a
+//! hard link's are in its target as of the link's layer. Symlinked parent
+//! directories are followed the way the tree builder follows them, so a path
+//! from the tree finds the entry that produced it.
+
+use anyhow::{bail, Result};
+
+use crate::archive::{Layer, LayerSet};
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::tree::Node;
+use crate::utils;
+use crate::whiteout;
+
+/// The start of a file's contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileContent {
+    /// As many bytes as were asked for, or the whole file
+    pub bytes: Vec<u8>,
+    /// Size of the whole file
+    pub size: u64,
+}
+
+impl FileContent {
+    /// Whether `bytes` stop short of the end of the file
+    pub fn truncated(&self) -> bool {
+        (self.bytes.len() as u64) < self.size
+    }
+
+    /// Whether the contents look binary: a NUL byte, or bytes that aren't
+    /// UTF-8 (a character cut off at the end of a truncated read aside)
+    pub fn is_binary(&self) -> bool {
+        if self.bytes.contains(&0) {
+            return true;
+        }
+        match std::str::from_utf8(&self.bytes) {
+            Ok(_) => false,
+            Err(err) => err.error_len().is_some() || !self.truncated(),
+        }
+    }
+}
+
+/// Hard links followed before giving up; a crafted layer can chain them
+const MAX_LINKS: usize = 8;
+
+/// Read up to `limit` bytes of the regular file at `path` in the merged image
+pub fn read_file(layer_set: &LayerSet, path: &str, limit: usize) -> Result<FileContent> {
+    read_file_in(&layer_set.layers, path, limit)
+}
+
+/// [`read_file`] in the image merged from `layers` alone, e.g. the image as
+/// of an earlier layer
+pub fn read_file_in(layers: &[Layer], path: &str, limit: usize) -> Result<FileContent> {
+    read_from(layers, &utils::clean_path(path).path, limit, 0)
+}
+
+fn read_from(layers: &[Layer], path: &str, limit: usize, links: usize) -> Result<FileContent> {
+    let mut sink = ContentSink::new(path, limit);
+    let count = layers.len();
+    for (i, layer) in layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+    match sink.found {
+        // Directories made only by the entries inside them have none of their own
+        None if sink.tree.get(path).is_some() => bail!("/{} is a directory", path),
+        None => bail!("/{} is not in the image", path),
+        Some(Found::File(content)) => Ok(content),
+        Some(Found::HardLink { target, layer }) if links < MAX_LINKS => {
+            read_from(&layers[..=layer], &target, limit, links + 1)
+        }
+        Some(Found::HardLink { target, .. }) => bail!("/{} is a hard link to /{} in a chain too long to follow", path, target),
+        Some(Found::Other(what)) => bail!("/{} is {}", path, what),
+    }
+}
+
+/// What was last written at the wanted path
+enum Found {
+    File(FileContent),
+    /// A hard link to `target`, written by layer `layer`
+    HardLink { target: String, layer: usize },
+    /// Something without contents, described for the error
+    Other(String),
+}
+
+/// Follows one path through the layers and keeps what ends up there
+struct ContentSink<'a> {
+    wanted: &'a str,
+    limit: usize,
+    /// The merged tree so far, for following symlinked parents
+    tree: Node,
+    layer: usize,
+    found: Option<Found>,
+}
+
+impl<'a> ContentSink<'a> {
+    fn new(wanted: &'a str, limit: usize) -> Self {
+        ContentSink { wanted, limit, tree: Node::new_dir(0o755, 0, 0), layer: 0, found: None }
+    }
+
+    fn add_entry(&mut self, entry: &LayerEntry, content: Option<&[u8]>) {
+        let path = self.tree.resolve_parents(&entry.path).into_owned();
+        let (mode, uid, gid) = (entry.mode, entry.uid, entry.gid);
+        match &entry.kind {
+            EntryKind::Dir => self.tree.ensure_path(&path, mode, uid, gid, None),
+            EntryKind::Symlink { target } => {
+                self.tree.put_file(&path, mode, uid, gid, true, target.clone(), None);
+            }
+            _ => {
+                self.tree.put_file(&path, mode, uid, gid, false, None, None);
+            }
+        }
+        if path != self.wanted {
+            return;
+        }
+        self.found = Some(match &entry.kind {
+            EntryKind::File => {
+                let bytes = content.unwrap_or_default();
+                Found::File(FileContent { bytes: bytes[..bytes.len().min(self.limit)].to_vec(), size: entry.size })
+            }
+            EntryKind::Hardlink { target: Some(target) } => Found::HardLink {
+                target: self.tree.resolve_parents(&utils::clean_path(target).path).into_owned(),
+                layer: self.layer,
+            },
+            EntryKind::Hardlink { target: None } => Found::Other("a hard link without a target".to_string()),
+            EntryKind::Symlink { target } => {
+                Found::Other(format!("a symlink to {}", target.as_deref().unwrap_or("nothing")))
+            }
+            EntryKind::Dir => Found::Other("a directory".to_string()),
+            EntryKind::Device(_) => Found::Other("a device node".to_string()),
+        });
+    }
+}
+
+impl EventSink for ContentSink<'_> {
+    fn wants_content(&self, entry: &LayerEntry) -> bool {
+        self.tree.resolve_parents(&entry.path) == self.wanted
+    }
+
+    fn content_limit(&self, _entry: &LayerEntry) -> u64 {
+        self.limit as u64
+    }
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { index, .. } => self.layer = *index,
+            LayerEvent::EntryAdded { entry, content } => self.add_entry(entry, *content),
+            LayerEvent::EntryRemoved { path } => {
+                let path = self.tree.resolve_parents(path).into_owned();
+                self.tree.remove(&path);
+            }
+            LayerEvent::OpaqueApplied { dir } => {
+                // Resolve the directory itself too, not just its parents
+                let marker = format!("{}/{}", dir, whiteout::OPAQUE_WHITEOUT);
+                let marker = self.tree.resolve_parents(&marker).into_owned();
+                self.tree.mark_opaque(whiteout::opaque_dir(&marker), None, false);
+            }
+            LayerEvent::Suspicious { .. } | LayerEvent::LayerFinished { .. } => {}
+        }
+        // Deleted, or gone with a directory that was replaced or emptied
+        if self.found.is_some() && self.tree.get(self.wanted).is_none() {
+            self.found = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Blob, Compression};
+
+    fn layer(entries: &[(&str, tar::EntryType, &str)]) -> Layer {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            if entry_type.is_file() {
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+            } else {
+                header.set_size(0);
+                builder.append_link(&mut header, path, data).unwrap();
+            }
+        }
+        let bytes = builder.into_inner().unwrap();
+        Layer { name: "layer.tar".to_string(), blob: Blob::Memory(bytes.into()), diff_id: None, compression: Compression::None }
+    }
+
+    #[test]
+    fn test_read_from() {
+        use tar::EntryType::{Link, Regular, Symlink};
+        let layers = [
+            layer(&[
+                ("data/config", Regular, "old"),
+                ("app", Symlink, "data"),
+                ("bin/busybox", Regular, "BUSYBOX"),
+                ("bin/sh", Link, "bin/busybox"),
+            ]),
+            // Written through the symlinked parent, then the link's target changes
+            layer(&[("app/config", Regular, "abcdef"), ("bin/busybox", Regular, "toybox")]),
+            layer(&[("bin/.wh.busybox", Regular, "")]),
+        ];
+        let read = |path, limit| read_from(&layers, path, limit, 0).map_err(|err| err.to_string());
+
+        let content = read("data/config", 4).unwrap();
+        assert_eq!((content.bytes.as_slice(), content.size, content.truncated()), (&b"abcd"[..], 6, true));
+        // A hard link keeps the contents its target had when it was made
+        assert_eq!(read("bin/sh", 64).unwrap().bytes, b"BUSYBOX");
+        assert_eq!(read("bin/busybox", 64).unwrap_err(), "/bin/busybox is not in the image");
+        assert_eq!(read("app", 64).unwrap_err(), "/app is a symlink to data");
+        assert_eq!(read("bin", 64).unwrap_err(), "/bin is a directory");
+    }
+
+    #[test]
+    fn test_is_binary() {
+        let content = |bytes: &[u8], size| FileContent { bytes: bytes.to_vec(), size };
+        assert!(!content(b"hello\n", 6).is_binary());
+        assert!(content(b"\x7fELF\x00", 5).is_binary());
+        // A character cut off by the limit isn't binary, a broken one is
+        assert!(!content(&"é".as_bytes()[..1], 2).is_binary());
+        assert!(content(b"\xff\xfe", 2).is_binary());
+    }
+}
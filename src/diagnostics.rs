@@ -0,0 +1,178 @@
+//! Warnings and progress notes on stderr, and how many of them to show
+//!
+//! Messy layers can produce a warning for every entry. The command line
+//! collects them instead ([`configure`]) and, once the run is over
+//! ([`report`]), prints the first warning of each kind with a count of the
+//! rest; `-v` shows every warning as it happens, `-q` none, and
+//! `--warnings json` writes them all as one JSON array for tools. Library
+//! users who never call [`configure`] get each warning on stderr right away.
+
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+
+/// How much a run prints to stderr besides errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// Nothing but errors
+    Quiet,
+    /// Progress notes, and a summary of the warnings at the end
+    #[default]
+    Normal,
+    /// Progress notes, and every warning as it happens
+    Verbose,
+}
+
+/// How the collected warnings are reported at the end of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WarningFormat {
+    /// Lines of text, as much as the verbosity asks for
+    #[default]
+    Text,
+    /// One JSON array of every warning, whatever the verbosity
+    Json,
+}
+
+/// What a warning is about, so summaries can group them and tools filter them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WarningKind {
+    /// A tar entry that couldn't be read and was skipped
+    CorruptEntry,
+    /// A tar entry whose path couldn't be decoded
+    InvalidPath,
+    /// A layer the manifest lists that the archive doesn't have
+    MissingLayer,
+    /// A manifest and image config that disagree on the layers
+    LayerMismatch,
+    /// An entry that couldn't be applied to the tree
+    UnappliedEntry,
+    /// A hard link whose target couldn't be found
+    HardLink,
+    /// A symlink in an unpacked image directory that points nowhere
+    DanglingSymlink,
+    /// An image config that couldn't be parsed
+    ImageConfig,
+    /// A cached tree that couldn't be read or stored
+    Cache,
+    /// A package database that couldn't be read
+    PackageDatabase,
+    /// A layer whose tar format couldn't be scanned
+    LayerFormat,
+    /// An entry `extract` left out
+    NotExtracted,
+}
+
+/// One warning, as collected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+/// How a run reports warnings and notes, set once from the command line
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reporting {
+    pub verbosity: Verbosity,
+    pub format: WarningFormat,
+}
+
+static REPORTING: OnceLock<Reporting> = OnceLock::new();
+static COLLECTED: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+/// Collect warnings for [`report`] from now on, and show as much as `reporting` says
+pub fn configure(reporting: Reporting) {
+    REPORTING.set(reporting).expect("reporting is configured once");
+}
+
+/// Warn about something that didn't stop the run
+pub fn warn(kind: WarningKind, message: impl Into<String>) {
+    let message = message.into();
+    let Some(reporting) = REPORTING.get() else {
+        eprintln!("Warning: {}", message);
+        return;
+    };
+    if reporting.verbosity == Verbosity::Verbose && reporting.format == WarningFormat::Text {
+        eprintln!("Warning: {}", message);
+    }
+    COLLECTED.lock().unwrap_or_else(|err| err.into_inner()).push(Warning { kind, message });
+}
+
+/// Tell the user what the run is doing (e.g. which archive it picked), unless quiet
+pub fn note(message: impl Display) {
+    if REPORTING.get().is_none_or(|reporting| reporting.verbosity != Verbosity::Quiet) {
+        eprintln!("{}", message);
+    }
+}
+
+/// The warnings collected so far, which are then forgotten
+pub fn take() -> Vec<Warning> {
+    std::mem::take(&mut *COLLECTED.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+/// Print the warnings collected during the run to stderr: a summary, nothing
+/// (quiet, or verbose when they were printed already), or JSON
+pub fn report() -> io::Result<()> {
+    let Some(reporting) = REPORTING.get() else {
+        return Ok(());
+    };
+    let warnings = take();
+    let stderr = io::stderr();
+    let mut out = stderr.lock();
+    match (reporting.format, reporting.verbosity) {
+        (WarningFormat::Json, _) => {
+            serde_json::to_writer(&mut out, &warnings)?;
+            writeln!(out)?;
+        }
+        (WarningFormat::Text, Verbosity::Normal) => write_summary(&mut out, &warnings)?,
+        (WarningFormat::Text, _) => {}
+    }
+    out.flush()
+}
+
+/// One line per kind of warning, in the order they first came up: the first
+/// warning of the kind, and how many more there were
+pub fn write_summary<W: Write>(out: &mut W, warnings: &[Warning]) -> io::Result<()> {
+    let mut kinds: Vec<(WarningKind, &str, usize)> = Vec::new();
+    for warning in warnings {
+        match kinds.iter_mut().find(|(kind, _, _)| *kind == warning.kind) {
+            Some((_, _, count)) => *count += 1,
+            None => kinds.push((warning.kind, &warning.message, 1)),
+        }
+    }
+    for (_, first, count) in kinds {
+        if count == 1 {
+            writeln!(out, "Warning: {}", first)?;
+        } else {
+            writeln!(out, "Warning: {} (and {} more like it; -v shows them all)", first, count - 1)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_summary() {
+        let warning = |kind, message: &str| Warning { kind, message: message.to_string() };
+        let warnings = [
+            warning(WarningKind::CorruptEntry, "Skipping corrupted entry: bad checksum"),
+            warning(WarningKind::HardLink, "Failed to set hard link target: /a"),
+            warning(WarningKind::CorruptEntry, "Skipping corrupted entry: truncated"),
+            warning(WarningKind::CorruptEntry, "Skipping corrupted entry: truncated"),
+        ];
+        let mut out = Vec::new();
+        write_summary(&mut out, &warnings).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "Warning: Skipping corrupted entry: bad checksum (and 2 more like it; -v shows them all)\n",
+            "Warning: Failed to set hard link target: /a\n",
+        ));
+        assert_eq!(
+            serde_json::to_string(&warnings[1]).unwrap(),
+            r#"{"kind":"hard-link","message":"Failed to set hard link target: /a"}"#
+        );
+    }
+}
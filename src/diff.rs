@@ -0,0 +1,503 @@
+//! Comparison of two Docker image archives
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, Image, ProcessOptions};
+use crate::colordepth::ColorDepth;
+use crate::config::{self, ConfigChange, ImageConfig};
+use crate::layerdiff::Change;
+use crate::oci::Platform;
+use crate::theme::Theme;
+use crate::tree::{Node, NodeMetadata};
+use crate::utils;
+
+const COLOR_RESET: &str = "\x1b[0m";
+
+/// Machine-readable diff output
+#[derive(Serialize)]
+struct DiffReport<'a> {
+    config: &'a [ConfigChange],
+    files: &'a [FileChange],
+}
+
+/// One metadata field that differs between the old and new entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// A path that was added, deleted, or modified
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub change: Change,
+    pub path: String,
+    /// What differs, for modified entries
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldChange>,
+}
+
+/// Options for comparing two trees
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Only report entries whose contents are identical but whose mode,
+    /// ownership, or extended attributes changed
+    pub metadata_only: bool,
+    /// Image to take from each archive's multi-platform index
+    pub old_platform: Option<Platform>,
+    pub new_platform: Option<Platform>,
+    /// Reuse and store merged trees in the on-disk cache
+    #[cfg(feature = "cache")]
+    pub cache: bool,
+}
+
+/// Combined view of two trees, keeping only changed entries and their ancestors
+#[derive(Debug, Default)]
+pub struct DiffNode {
+    /// None for unchanged directories that lead to a change
+    pub change: Option<Change>,
+    pub fields: Vec<FieldChange>,
+    pub is_dir: bool,
+    pub children: BTreeMap<String, DiffNode>,
+}
+
+/// Compare two archives and print the differences as text or JSON (nothing when `quiet`),
+/// in colors for a terminal of the given depth if any.
+/// Returns whether the images differ.
+pub fn run(
+    old_path: &Path,
+    new_path: &Path,
+    format: &str,
+    color: Option<ColorDepth>,
+    quiet: bool,
+    options: &DiffOptions,
+) -> Result<bool> {
+    // A rewritten file can keep its size, so contents are always compared
+    let process_options = |platform: &Option<Platform>| ProcessOptions {
+        hash_contents: true,
+        platform: platform.clone(),
+        #[cfg(feature = "cache")]
+        cache: options.cache,
+        ..Default::default()
+    };
+    let old = archive::process_archive(old_path, &process_options(&options.old_platform))?;
+    let new = archive::process_archive(new_path, &process_options(&options.new_platform))?;
+
+    let (changes, tree) = diff_images(&old, &new, options);
+    let differs = !changes.is_empty() || !tree.children.is_empty();
+    if quiet {
+        return Ok(differs);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if format == "json" {
+        let files = tree.flatten();
+        serde_json::to_writer_pretty(&mut out, &DiffReport { config: &changes, files: &files })?;
+        writeln!(out)?;
+    } else {
+        write_text(&mut out, &changes, &tree, color)?;
+    }
+
+    out.flush()?;
+    Ok(differs)
+}
+
+/// Exit status for the result of [`run`], as `cmp` has it: 0 when the
+/// images are the same, 1 when they differ, 2 when they couldn't be compared
+pub fn exit_code(result: &Result<bool>) -> i32 {
+    match result {
+        Ok(differs) => i32::from(*differs),
+        Err(_) => 2,
+    }
+}
+
+/// Compare the configs and merged trees of two images
+pub fn diff_images(old: &Image, new: &Image, options: &DiffOptions) -> (Vec<ConfigChange>, DiffNode) {
+    let empty = ImageConfig::default();
+    let changes = config::diff_configs(
+        old.config.as_ref().unwrap_or(&empty),
+        new.config.as_ref().unwrap_or(&empty),
+    );
+    (changes, diff_trees(&old.root, &new.root, options))
+}
+
+/// Write config changes and the combined tree as text, in colors for a
+/// terminal of the given depth if any
+pub fn write_text<W: Write>(
+    out: &mut W,
+    changes: &[ConfigChange],
+    tree: &DiffNode,
+    color: Option<ColorDepth>,
+) -> io::Result<()> {
+    config::write_changes(out, changes)?;
+    let theme = Theme::default().with_depth(color.unwrap_or(ColorDepth::TrueColor));
+    write_tree_diff(out, tree, color.is_some(), &theme)
+}
+
+/// Build the combined tree of two merged filesystems. Entries count as
+/// modified when their type, permissions, ownership, size, contents, link
+/// target, or extended attributes differ; contents are only compared when
+/// both trees were built with `hash_contents`.
+pub fn diff_trees(old: &Node, new: &Node, options: &DiffOptions) -> DiffNode {
+    DiffNode {
+        change: None,
+        fields: Vec::new(),
+        is_dir: true,
+        children: diff_children(Some(old), Some(new), options),
+    }
+}
+
+fn diff_children(old: Option<&Node>, new: Option<&Node>, options: &DiffOptions) -> BTreeMap<String, DiffNode> {
+    let old_children = old.map(|n| &n.children);
+    let new_children = new.map(|n| &n.children);
+    let names: BTreeSet<&str> = old_children.into_iter().flat_map(|c| c.keys())
+        .chain(new_children.into_iter().flat_map(|c| c.keys()))
+        .map(|name| &**name)
+        .collect();
+
+    let mut result = BTreeMap::new();
+    for name in names {
+        let before = old_children.and_then(|c| c.get(name)).map(|node| &**node);
+        let after = new_children.and_then(|c| c.get(name)).map(|node| &**node);
+
+        let (change, fields, is_dir) = match (before, after) {
+            (Some(b), Some(a)) => {
+                let fields = if !options.metadata_only {
+                    metadata_changes(&b.metadata, &a.metadata)
+                } else if same_content(&b.metadata, &a.metadata) {
+                    ownership_changes(&b.metadata, &a.metadata)
+                } else {
+                    Vec::new()
+                };
+                let change = (!fields.is_empty()).then_some(Change::Modified);
+                (change, fields, !a.metadata.is_file)
+            }
+            // Additions and removals are content changes
+            _ if options.metadata_only => continue,
+            (Some(b), None) => (Some(Change::Deleted), Vec::new(), !b.metadata.is_file),
+            (None, Some(a)) => (Some(Change::Added), Vec::new(), !a.metadata.is_file),
+            (None, None) => unreachable!("name comes from one of the trees"),
+        };
+
+        let children = diff_children(before, after, options);
+        if change.is_some() || !children.is_empty() {
+            result.insert(name.to_string(), DiffNode { change, fields, is_dir, children });
+        }
+    }
+    result
+}
+
+fn entry_type(meta: &NodeMetadata) -> &'static str {
+    if meta.is_symlink {
+        "symlink"
+    } else if meta.is_file {
+        "file"
+    } else {
+        "dir"
+    }
+}
+
+/// Whether two versions of an entry have the same type and contents
+fn same_content(a: &NodeMetadata, b: &NodeMetadata) -> bool {
+    entry_type(a) == entry_type(b)
+        && a.size == b.size
+        && a.content_hash == b.content_hash
+        && a.symlink_target == b.symlink_target
+        && a.hardlink_target == b.hardlink_target
+}
+
+fn push_field(fields: &mut Vec<FieldChange>, field: &str, old: String, new: String) {
+    if old != new {
+        fields.push(FieldChange { field: field.to_string(), old, new });
+    }
+}
+
+/// List the metadata fields that differ between two versions of an entry
+fn metadata_changes(a: &NodeMetadata, b: &NodeMetadata) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    push_field(&mut fields, "type", entry_type(a).to_string(), entry_type(b).to_string());
+    push_ownership_fields(&mut fields, a, b);
+    push_field(&mut fields, "size", a.size.to_string(), b.size.to_string());
+    if let (Some(old), Some(new)) = (a.content_hash, b.content_hash) {
+        push_field(&mut fields, "content", format!("{:016x}", old), format!("{:016x}", new));
+    }
+    push_field(&mut fields, "target", a.symlink_target.clone().unwrap_or_default(),
+        b.symlink_target.clone().unwrap_or_default());
+    push_field(&mut fields, "link", a.hardlink_target.clone().unwrap_or_default(),
+        b.hardlink_target.clone().unwrap_or_default());
+    push_xattr_fields(&mut fields, a, b);
+    fields
+}
+
+/// Permission, ownership, and extended-attribute differences only
+fn ownership_changes(a: &NodeMetadata, b: &NodeMetadata) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    push_ownership_fields(&mut fields, a, b);
+    push_xattr_fields(&mut fields, a, b);
+    fields
+}
+
+fn push_ownership_fields(fields: &mut Vec<FieldChange>, a: &NodeMetadata, b: &NodeMetadata) {
+    push_field(fields, "mode", format!("{:04o}", a.mode & 0o7777), format!("{:04o}", b.mode & 0o7777));
+    push_field(fields, "owner", format!("{}:{}", a.uid, a.gid), format!("{}:{}", b.uid, b.gid));
+}
+
+fn push_xattr_fields(fields: &mut Vec<FieldChange>, a: &NodeMetadata, b: &NodeMetadata) {
+    let mut names: Vec<&String> = a.xattrs.keys().chain(b.xattrs.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        push_field(fields, &format!("xattr {}", name),
+            format_xattr(a.xattrs.get(name)), format_xattr(b.xattrs.get(name)));
+    }
+}
+
+/// Show printable xattr values as text and anything else as hex
+fn format_xattr(value: Option<&Vec<u8>>) -> String {
+    match value {
+        None => "(unset)".to_string(),
+        Some(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) if !text.is_empty() && text.chars().all(|c| !c.is_control()) => text.to_string(),
+            _ => format!("0x{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        },
+    }
+}
+
+impl DiffNode {
+    /// Every changed path, parents before children
+    pub fn flatten(&self) -> Vec<FileChange> {
+        let mut changes = Vec::new();
+        self.collect("", &mut changes);
+        changes
+    }
+
+    fn collect(&self, prefix: &str, changes: &mut Vec<FileChange>) {
+        for (name, child) in &self.children {
+            let path = format!("{}{}", prefix, name);
+            if let Some(change) = child.change {
+                changes.push(FileChange { change, path: path.clone(), fields: child.fields.clone() });
+            }
+            child.collect(&format!("{}/", path), changes);
+        }
+    }
+}
+
+/// Write the combined tree, marking added (+), removed (−), and changed (~) entries
+pub fn write_tree_diff<W: Write>(
+    out: &mut W,
+    tree: &DiffNode,
+    use_color: bool,
+    theme: &Theme,
+) -> io::Result<()> {
+    writeln!(out, "Filesystem changes:")?;
+    if tree.children.is_empty() {
+        return writeln!(out, "  (none)");
+    }
+    write_diff_node(out, tree, "", use_color, theme)
+}
+
+fn write_diff_node<W: Write>(
+    out: &mut W,
+    node: &DiffNode,
+    prefix: &str,
+    use_color: bool,
+    theme: &Theme,
+) -> io::Result<()> {
+    for (idx, (name, child)) in node.children.iter().enumerate() {
+        let is_last = idx + 1 == node.children.len();
+        let branch = if is_last { "└── " } else { "├── " };
+
+        if use_color {
+            write!(out, "{}{}{}{}", theme.tree_chars, prefix, branch, COLOR_RESET)?;
+        } else {
+            write!(out, "{}{}", prefix, branch)?;
+        }
+
+        let (marker, color) = match child.change {
+            Some(Change::Added) => ("+ ", &theme.added),
+            Some(Change::Deleted) => ("− ", &theme.removed),
+            Some(Change::Modified) => ("~ ", &theme.changed),
+            None => ("", &theme.directory),
+        };
+        let name = utils::display_path(name);
+        let slash = if child.is_dir { "/" } else { "" };
+        let details = if child.fields.is_empty() {
+            String::new()
+        } else {
+            let parts: Vec<String> = child.fields.iter()
+                .map(|f| format!("{} {} -> {}", f.field, utils::display_path(&f.old), utils::display_path(&f.new)))
+                .collect();
+            format!(" ({})", parts.join(", "))
+        };
+
+        if use_color {
+            writeln!(out, "{}{}{}{}{}{}", color, marker, name, slash, details, COLOR_RESET)?;
+        } else {
+            writeln!(out, "{}{}{}{}", marker, name, slash, details)?;
+        }
+
+        let new_prefix = if is_last {
+            format!("{}    ", prefix)
+        } else {
+            format!("{}│   ", prefix)
+        };
+        write_diff_node(out, child, &new_prefix, use_color, theme)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(change: Change, path: &str) -> FileChange {
+        FileChange { change, path: path.to_string(), fields: Vec::new() }
+    }
+
+    fn field(field: &str, old: &str, new: &str) -> FieldChange {
+        FieldChange { field: field.to_string(), old: old.to_string(), new: new.to_string() }
+    }
+
+    fn trees() -> (Node, Node) {
+        let mut old = Node::new_dir(0o755, 0, 0);
+        old.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        old.put_file("etc/shadow", 0o600, 0, 0, false, None, None);
+        old.put_file("opt/app/run", 0o755, 0, 0, false, None, None);
+
+        let mut new = Node::new_dir(0o755, 0, 0);
+        new.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        new.put_file("etc/shadow", 0o640, 0, 42, false, None, None);
+        new.put_file("usr/bin/tool", 0o755, 0, 0, false, None, None);
+        (old, new)
+    }
+
+    #[test]
+    fn test_diff_trees() {
+        let (old, new) = trees();
+        let mut shadow = change(Change::Modified, "etc/shadow");
+        shadow.fields = vec![field("mode", "0600", "0640"), field("owner", "0:0", "0:42")];
+
+        assert_eq!(diff_trees(&old, &new, &DiffOptions::default()).flatten(), vec![
+            shadow,
+            change(Change::Deleted, "opt"),
+            change(Change::Deleted, "opt/app"),
+            change(Change::Deleted, "opt/app/run"),
+            change(Change::Added, "usr"),
+            change(Change::Added, "usr/bin"),
+            change(Change::Added, "usr/bin/tool"),
+        ]);
+    }
+
+    #[test]
+    fn test_diff_trees_detects_size_change() {
+        let mut old = Node::new_dir(0o755, 0, 0);
+        old.put_file("app.bin", 0o755, 0, 0, false, None, None);
+        let mut new = old.clone();
+        new.get_mut("app.bin").unwrap().metadata.size = 10;
+
+        let mut expected = change(Change::Modified, "app.bin");
+        expected.fields = vec![field("size", "0", "10")];
+        assert_eq!(diff_trees(&old, &new, &DiffOptions::default()).flatten(), vec![expected]);
+    }
+
+    #[test]
+    fn test_diff_trees_detects_same_size_content_change() {
+        let mut old = Node::new_dir(0o755, 0, 0);
+        old.put_file("etc/app.conf", 0o644, 0, 0, false, None, None);
+        let conf = old.get_mut("etc/app.conf").unwrap();
+        conf.metadata.size = 12;
+        conf.metadata.content_hash = Some(0xa1);
+        let mut new = old.clone();
+        new.get_mut("etc/app.conf").unwrap().metadata.content_hash = Some(0xb2);
+
+        let mut expected = change(Change::Modified, "etc/app.conf");
+        expected.fields = vec![field("content", "00000000000000a1", "00000000000000b2")];
+        assert_eq!(diff_trees(&old, &new, &DiffOptions::default()).flatten(), vec![expected]);
+        assert!(diff_trees(&old, &old, &DiffOptions::default()).children.is_empty());
+    }
+
+    #[test]
+    fn test_exit_code_counts_content_changes() {
+        let archive = |conf: &[u8]| {
+            let mut layer = tar::Builder::new(Vec::new());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(conf.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            layer.append_data(&mut header, "etc/app.conf", conf).unwrap();
+            let layer = layer.into_inner().unwrap();
+
+            let manifest = br#"[{"Config":null,"RepoTags":["app:1"],"Layers":["l1/layer.tar"]}]"#;
+            let mut builder = tar::Builder::new(Vec::new());
+            for (name, data) in [("l1/layer.tar", &layer[..]), ("manifest.json", &manifest[..])] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, name, data).unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let (c1, c2) = (dir.path().join("c1.tar"), dir.path().join("c2.tar"));
+        std::fs::write(&c1, archive(b"debug=false\n")).unwrap();
+        std::fs::write(&c2, archive(b"debug=true!\n")).unwrap();
+
+        let compare = |new: &Path| exit_code(&run(&c1, new, "text", None, true, &DiffOptions::default()));
+        assert_eq!(compare(&c1), 0);
+        assert_eq!(compare(&c2), 1);
+        assert_eq!(compare(&dir.path().join("missing.tar")), 2);
+    }
+
+    #[test]
+    fn test_write_tree_diff() {
+        let (old, new) = trees();
+        let mut out = Vec::new();
+        write_tree_diff(&mut out, &diff_trees(&old, &new, &DiffOptions::default()), false, &Theme::default()).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+Filesystem changes:
+├── etc/
+│   └── ~ shadow (mode 0600 -> 0640, owner 0:0 -> 0:42)
+├── − opt/
+│   └── − app/
+│       └── − run
+└── + usr/
+    └── + bin/
+        └── + tool
+");
+    }
+
+    #[test]
+    fn test_metadata_only_skips_content_changes() {
+        let mut old = Node::new_dir(0o755, 0, 0);
+        old.put_file("bin/su", 0o4755, 0, 0, false, None, None);
+        old.put_file("etc/motd", 0o644, 0, 0, false, None, None);
+        old.put_file("bin/ping", 0o755, 0, 0, false, None, None);
+        old.get_mut("etc/motd").unwrap().metadata.content_hash = Some(1);
+
+        let mut new = old.clone();
+        new.get_mut("bin/su").unwrap().metadata.mode = 0o755;
+        new.get_mut("bin/ping").unwrap().metadata.xattrs
+            .insert("security.capability".to_string(), vec![0x01, 0x00]);
+        let motd = new.get_mut("etc/motd").unwrap();
+        motd.metadata.content_hash = Some(2);
+        motd.metadata.uid = 1000;
+        new.put_file("etc/new", 0o644, 0, 0, false, None, None);
+
+        let mut ping = change(Change::Modified, "bin/ping");
+        ping.fields = vec![field("xattr security.capability", "(unset)", "0x0100")];
+        let mut su = change(Change::Modified, "bin/su");
+        su.fields = vec![field("mode", "4755", "0755")];
+
+        let options = DiffOptions { metadata_only: true, ..Default::default() };
+        assert_eq!(diff_trees(&old, &new, &options).flatten(), vec![ping, su]);
+    }
+}
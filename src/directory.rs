@@ -0,0 +1,104 @@
+//! Unpacked images: the directory a `docker save` archive or an OCI layout
+//! was extracted to
+//!
+//! Every file is a blob of its own and is read where it is, so nothing is
+//! copied. Blob names are paths relative to the directory, as they would be
+//! inside the archive.
+
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Blob, LayerSet};
+use crate::diagnostics::{self, WarningKind};
+use crate::error::{self, io_error};
+use crate::progress::TermProgress;
+use crate::source::{self, Contents, ImageSelector, LayerSource};
+
+/// A directory holding an unpacked image
+pub struct DirectorySource {
+    path: PathBuf,
+}
+
+impl DirectorySource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        DirectorySource { path: path.into() }
+    }
+}
+
+impl LayerSource for DirectorySource {
+    fn open_layers(&self, select: &ImageSelector, _progress: &mut TermProgress) -> error::Result<LayerSet> {
+        let mut contents = Contents::default();
+        collect_files(&self.path, "", &mut contents)?;
+        let entry = source::select_image(&self.path, &contents, select)?;
+        LayerSet::assemble(contents.blobs, entry, None, select)
+    }
+}
+
+/// Record the regular files under `dir`, named by their path below the image
+/// directory. `docker save` links repeated layers to their first copy, so
+/// symlinked files count as files; symlinked directories are not followed.
+fn collect_files(dir: &Path, prefix: &str, contents: &mut Contents) -> error::Result<()> {
+    for entry in fs::read_dir(dir).map_err(io_error(dir))? {
+        let entry = entry.map_err(io_error(dir))?;
+        let path = entry.path();
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type().map_err(io_error(&path))?;
+
+        if file_type.is_dir() {
+            collect_files(&path, &format!("{}/", name), contents)?;
+            continue;
+        }
+        let Ok(metadata) = fs::metadata(&path) else {
+            diagnostics::warn(WarningKind::DanglingSymlink, format!("Skipping dangling symlink {}", path.display()));
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        if name == "manifest.json" {
+            let bytes = fs::read(&path).context("Failed to read manifest.json")?;
+            contents.manifest = Some(bytes);
+            continue;
+        }
+        contents.sizes.insert(name.clone(), metadata.len());
+        contents.blobs.insert(name, Blob::File(path));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive;
+
+    #[test]
+    fn test_directory_source_reads_unpacked_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, "etc/hostname", &b"box\n"[..]).unwrap();
+        let layer = builder.into_inner().unwrap();
+
+        fs::create_dir(root.join("abc123")).unwrap();
+        fs::write(root.join("abc123/layer.tar"), layer).unwrap();
+        fs::create_dir(root.join("def456")).unwrap();
+        std::os::unix::fs::symlink("../abc123/layer.tar", root.join("def456/layer.tar")).unwrap();
+        fs::write(root.join("manifest.json"),
+            br#"[{"Config":"","RepoTags":["box:1"],"Layers":["abc123/layer.tar","def456/layer.tar"]}]"#).unwrap();
+
+        let layer_set = archive::open_archive(root).unwrap();
+        assert_eq!(layer_set.repo_tags, vec!["box:1"]);
+        assert_eq!(layer_set.layers.len(), 2);
+        assert_eq!(layer_set.layers[1].blob, Blob::File(root.join("def456/layer.tar")));
+
+        let image = archive::process_archive(root, &Default::default()).unwrap();
+        assert!(image.root.get("etc/hostname").is_some());
+    }
+}
@@ -0,0 +1,256 @@
+//! An approximate Dockerfile, read back out of the image history
+//!
+//! Every build step records its instruction in the config's history, in one
+//! of a few spellings: the legacy builder wraps it in `/bin/sh -c #(nop)` (or
+//! just `/bin/sh -c` for `RUN`), BuildKit writes the instruction itself and
+//! ends it with `# buildkit`. Most turn back into the line that produced them.
+//! What can't be recovered exactly (the base image, `COPY` sources known only
+//! by digest, build argument declarations, steps made outside a Dockerfile)
+//! is written as a best guess with a `# guess:` note above it.
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::config::HistoryEntry;
+
+/// Instructions the history may spell out as they were written
+const INSTRUCTIONS: &[&str] = &[
+    "ADD", "ARG", "CMD", "COPY", "ENTRYPOINT", "ENV", "EXPOSE", "HEALTHCHECK", "LABEL", "MAINTAINER",
+    "ONBUILD", "RUN", "SHELL", "STOPSIGNAL", "USER", "VOLUME", "WORKDIR",
+];
+
+/// One line of the reconstructed Dockerfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// The instruction, or empty when there is only a note
+    pub line: String,
+    /// Why the line is a guess rather than what was written
+    pub guess: Option<String>,
+}
+
+impl Instruction {
+    fn exact(line: impl Into<String>) -> Self {
+        Instruction { line: line.into(), guess: None }
+    }
+
+    fn guess(line: impl Into<String>, why: impl Into<String>) -> Self {
+        Instruction { line: line.into(), guess: Some(why.into()) }
+    }
+}
+
+/// The Dockerfile the history of an image suggests, starting from the
+/// base image's own steps
+pub fn reconstruct(history: &[HistoryEntry]) -> Vec<Instruction> {
+    let mut instructions = vec![Instruction::guess("FROM scratch",
+        "the base image's own steps come first below; replace them with its FROM line")];
+    let mut args = HashSet::new();
+    for entry in history {
+        let created_by = entry.created_by.as_deref().unwrap_or_default().trim();
+        if created_by.is_empty() {
+            instructions.push(Instruction::guess("", "a step without a recorded instruction"));
+            continue;
+        }
+        instructions.extend(from_history(created_by, &mut args));
+    }
+    instructions
+}
+
+/// The instructions behind one `created_by`, after `ARG` lines for build
+/// arguments not declared yet (tracked in `args`)
+fn from_history(created_by: &str, args: &mut HashSet<String>) -> Vec<Instruction> {
+    let step = created_by.strip_suffix("# buildkit").unwrap_or(created_by).trim();
+    let step = step.strip_prefix("RUN ").map_or(step, str::trim_start);
+    let (build_args, step) = split_build_args(step);
+
+    let mut instructions: Vec<_> = build_args.into_iter()
+        .filter(|arg| args.insert(arg.split('=').next().unwrap_or(arg).to_string()))
+        .map(|arg| Instruction::guess(format!("ARG {}", arg), "declared where first used, with the value it had"))
+        .collect();
+    let instruction = if let Some(nop) = step.strip_prefix("/bin/sh -c #(nop)") {
+        spelled_out(nop.trim())
+    } else if let Some(command) = step.strip_prefix("/bin/sh -c ") {
+        Instruction::exact(format!("RUN {}", command.trim()))
+    } else if created_by.starts_with("RUN ") || created_by.starts_with('|') {
+        Instruction::exact(format!("RUN {}", step))
+    } else if INSTRUCTIONS.contains(&step.split_whitespace().next().unwrap_or_default()) {
+        spelled_out(step)
+    } else {
+        // `docker commit` records the container's command, `docker import` a comment
+        Instruction::guess("", format!("made outside a Dockerfile: {}", step))
+    };
+    instructions.push(instruction);
+    instructions
+}
+
+/// Build arguments a `RUN` step recorded ahead of its command
+/// (`|2 A=1 B=2 /bin/sh -c make`), and the rest of the step
+fn split_build_args(step: &str) -> (Vec<&str>, &str) {
+    let Some((count, mut rest)) = step.strip_prefix('|').and_then(|s| s.split_once(' ')) else {
+        return (Vec::new(), step);
+    };
+    let Ok(count) = count.parse::<usize>() else {
+        return (Vec::new(), step);
+    };
+    let mut build_args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let Some((arg, after)) = rest.trim_start().split_once(' ') else {
+            break;
+        };
+        build_args.push(arg);
+        rest = after;
+    }
+    (build_args, rest.trim_start())
+}
+
+/// An instruction recorded as written, with the spellings only history
+/// uses turned back into Dockerfile syntax
+fn spelled_out(step: &str) -> Instruction {
+    let step = step.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (keyword, rest) = step.split_once(' ').unwrap_or((&step, ""));
+    match keyword {
+        // The legacy builder names sources by content digest: `ADD file:0123 in /`
+        "ADD" | "COPY" => match rest.split_once(" in ") {
+            Some((source, dest)) if is_digest_source(source) => Instruction::guess(
+                format!("{} {} {}", keyword, source, dest),
+                format!("the source is only known by its content digest ({})", source),
+            ),
+            _ => Instruction::exact(step.clone()),
+        },
+        // BuildKit prints ports as a Go map: `EXPOSE map[80/tcp:{} 443/tcp:{}]`
+        "EXPOSE" => match rest.strip_prefix("map[").and_then(|ports| ports.strip_suffix(']')) {
+            Some(ports) => {
+                let ports: Vec<_> = ports.split_whitespace().map(|port| port.trim_end_matches(":{}")).collect();
+                Instruction::exact(format!("EXPOSE {}", ports.join(" ")))
+            }
+            None => Instruction::exact(step.clone()),
+        },
+        "CMD" | "ENTRYPOINT" | "SHELL" | "VOLUME" => Instruction::exact(format!("{} {}", keyword, exec_form(rest))),
+        "HEALTHCHECK" if rest.starts_with("&{") => {
+            Instruction::guess(step.clone(), "the health check was recorded as a Go struct, not as written")
+        }
+        _ => Instruction::exact(step.clone()),
+    }
+}
+
+/// Whether an `ADD`/`COPY` source is a legacy content digest
+/// (`file:0123...`, `dir:...`, `multi:...`)
+fn is_digest_source(source: &str) -> bool {
+    ["file:", "dir:", "multi:"].iter().any(|prefix| source.starts_with(prefix))
+}
+
+/// A JSON array from the Go syntax history uses (`["sh" "-c"]`); anything
+/// else as it is
+fn exec_form(args: &str) -> String {
+    match args.strip_prefix("[\"").and_then(|inner| inner.strip_suffix("\"]")) {
+        Some(inner) if !inner.contains("\", \"") => format!("[\"{}\"]", inner.replace("\" \"", "\", \"")),
+        _ => args.to_string(),
+    }
+}
+
+/// Print the reconstructed Dockerfile, each guess after a note saying why
+pub fn write_dockerfile<W: Write>(out: &mut W, instructions: &[Instruction]) -> io::Result<()> {
+    writeln!(out, "# Reconstructed from the image history; lines after a \"# guess:\" note are approximate")?;
+    for instruction in instructions {
+        if let Some(why) = &instruction.guess {
+            writeln!(out, "# guess: {}", why)?;
+        }
+        if !instruction.line.is_empty() {
+            writeln!(out, "{}", instruction.line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the Dockerfile the history of an archive's image suggests
+pub fn run(archive_path: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let history = layer_set.config.as_ref().map(|config| config.history.as_slice()).unwrap_or_default();
+    if history.is_empty() {
+        anyhow::bail!("The image config has no history to reconstruct a Dockerfile from");
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_dockerfile(&mut out, &reconstruct(history))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(created_by: &[&str]) -> Vec<String> {
+        let history: Vec<_> = created_by.iter()
+            .map(|step| HistoryEntry { created_by: Some(step.to_string()), ..Default::default() })
+            .collect();
+        let mut out = Vec::new();
+        write_dockerfile(&mut out, &reconstruct(&history)).unwrap();
+        String::from_utf8(out).unwrap().lines().skip(3).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_legacy_builder_history() {
+        assert_eq!(lines(&[
+            "/bin/sh -c #(nop) ADD file:0123abcd in / ",
+            "/bin/sh -c #(nop)  CMD [\"/bin/sh\"]",
+            "/bin/sh -c apk add --no-cache curl",
+            "|2 VERSION=1.2 TARGET=x86_64 /bin/sh -c make install",
+            "/bin/sh -c #(nop)  ENV PATH=/usr/local/bin:/usr/bin",
+            "/bin/sh -c #(nop)  EXPOSE 8080/tcp",
+        ]), [
+            "# guess: the source is only known by its content digest (file:0123abcd)",
+            "ADD file:0123abcd /",
+            "CMD [\"/bin/sh\"]",
+            "RUN apk add --no-cache curl",
+            "# guess: declared where first used, with the value it had",
+            "ARG VERSION=1.2",
+            "# guess: declared where first used, with the value it had",
+            "ARG TARGET=x86_64",
+            "RUN make install",
+            "ENV PATH=/usr/local/bin:/usr/bin",
+            "EXPOSE 8080/tcp",
+        ]);
+    }
+
+    #[test]
+    fn test_buildkit_history() {
+        assert_eq!(lines(&[
+            "WORKDIR /app",
+            "COPY package.json . # buildkit",
+            "RUN /bin/sh -c npm ci # buildkit",
+            "RUN |1 NODE_ENV=production /bin/sh -c npm run build # buildkit",
+            "RUN |1 NODE_ENV=production /bin/sh -c npm prune # buildkit",
+            "EXPOSE map[3000/tcp:{} 9229/tcp:{}]",
+            "ENTRYPOINT [\"node\" \"server.js\"]",
+            "",
+            "bash",
+        ]), [
+            "WORKDIR /app",
+            "COPY package.json .",
+            "RUN npm ci",
+            "# guess: declared where first used, with the value it had",
+            "ARG NODE_ENV=production",
+            "RUN npm run build",
+            "RUN npm prune",
+            "EXPOSE 3000/tcp 9229/tcp",
+            "ENTRYPOINT [\"node\", \"server.js\"]",
+            "# guess: a step without a recorded instruction",
+            "# guess: made outside a Dockerfile: bash",
+        ]);
+    }
+
+    #[test]
+    fn test_write_dockerfile_starts_from_scratch() {
+        let mut out = Vec::new();
+        write_dockerfile(&mut out, &reconstruct(&[])).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "# Reconstructed from the image history; lines after a \"# guess:\" note are approximate\n",
+            "# guess: the base image's own steps come first below; replace them with its FROM line\n",
+            "FROM scratch\n",
+        ));
+    }
+}
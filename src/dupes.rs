@@ -0,0 +1,197 @@
+//! Duplicate file detection across paths and layers
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::analyzer::{self, Analyzer, EntryInfo, Finding};
+use crate::archive::LayerSet;
+use crate::utils;
+
+/// A single stored copy of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    /// Path of the file inside the layer
+    pub path: String,
+    /// Label of the layer that stores this copy
+    pub layer: String,
+}
+
+/// A set of files with identical content
+#[derive(Debug, Clone)]
+pub struct DupeGroup {
+    /// Hex-encoded SHA-256 of the shared content
+    pub digest: String,
+    /// Size of a single copy in bytes
+    pub size: u64,
+    /// Every place the content is stored, in layer order
+    pub occurrences: Vec<Occurrence>,
+}
+
+impl DupeGroup {
+    /// Bytes that could be saved by keeping only one copy
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.occurrences.len() as u64 - 1)
+    }
+}
+
+/// Hashes every non-empty regular file and groups identical content
+///
+/// Files overwritten by later layers still count, since their bytes are
+/// still shipped in the image. Content is hashed as it streams past, so no
+/// file is held in memory.
+#[derive(Default)]
+pub struct DupeAnalyzer {
+    by_digest: HashMap<String, DupeGroup>,
+    /// Digest of the file being read
+    hasher: Sha256,
+}
+
+impl DupeAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Groups with more than one copy, largest reclaimable space first
+    pub fn groups(&self) -> Vec<DupeGroup> {
+        let mut groups: Vec<DupeGroup> = self.by_digest
+            .values()
+            .filter(|g| g.occurrences.len() > 1)
+            .cloned()
+            .collect();
+        groups.sort_by(|a, b| b.reclaimable().cmp(&a.reclaimable()).then_with(|| a.digest.cmp(&b.digest)));
+        groups
+    }
+}
+
+impl Analyzer for DupeAnalyzer {
+    fn name(&self) -> &'static str {
+        "dupes"
+    }
+
+    fn wants(&self, entry: &EntryInfo) -> bool {
+        entry.size > 0
+    }
+
+    fn content_limit(&self, _entry: &EntryInfo) -> u64 {
+        0
+    }
+
+    fn read_chunk(&mut self, _entry: &EntryInfo, chunk: &[u8]) {
+        self.hasher.update(chunk);
+    }
+
+    fn analyze(&mut self, entry: &EntryInfo, _content: &[u8]) -> Vec<Finding> {
+        let digest = format!("{:x}", self.hasher.finalize_reset());
+        self.by_digest.entry(digest.clone())
+            .or_insert_with(|| DupeGroup { digest, size: entry.size, occurrences: Vec::new() })
+            .occurrences
+            .push(Occurrence { path: entry.path.to_string(), layer: entry.layer.to_string() });
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for group in self.groups() {
+            let first = &group.occurrences[0];
+            for occ in &group.occurrences[1..] {
+                findings.push(Finding {
+                    analyzer: self.name(),
+                    path: occ.path.clone(),
+                    layer: occ.layer.clone(),
+                    kind: "duplicate".to_string(),
+                    detail: format!("same content as {} [{}], {}",
+                        first.path, first.layer, utils::human_size(group.size)),
+                    line: None,
+                });
+            }
+        }
+        findings
+    }
+}
+
+/// Hash every regular file in every layer and group identical content
+pub fn find_duplicates(layer_set: &LayerSet) -> Result<Vec<DupeGroup>> {
+    let mut analyzer = DupeAnalyzer::new();
+    analyzer::scan(layer_set, &mut [&mut analyzer])?;
+    Ok(analyzer.groups())
+}
+
+/// Print duplicate groups followed by a reclaimable-space summary
+pub fn render_report(groups: &[DupeGroup]) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_report(&mut out, groups)?;
+    out.flush()
+}
+
+fn write_report<W: Write>(out: &mut W, groups: &[DupeGroup]) -> io::Result<()> {
+    if groups.is_empty() {
+        return writeln!(out, "No duplicate files found");
+    }
+
+    for group in groups {
+        writeln!(out, "{} copies of {} ({} reclaimable)  sha256:{}",
+            group.occurrences.len(),
+            utils::human_size(group.size),
+            utils::human_size(group.reclaimable()),
+            &group.digest[..12])?;
+        for occ in &group.occurrences {
+            writeln!(out, "  [{}] {}", occ.layer, occ.path)?;
+        }
+        writeln!(out)?;
+    }
+
+    let files: usize = groups.iter().map(|g| g.occurrences.len()).sum();
+    let total: u64 = groups.iter().map(|g| g.reclaimable()).sum();
+    writeln!(out, "{} groups, {} files, {} reclaimable", groups.len(), files, utils::human_size(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(size: u64, copies: usize) -> DupeGroup {
+        DupeGroup {
+            digest: "0123456789abcdef0123".to_string(),
+            size,
+            occurrences: (0..copies)
+                .map(|i| Occurrence { path: format!("file{}", i), layer: "abc1234".to_string() })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_reclaimable() {
+        assert_eq!(group(100, 2).reclaimable(), 100);
+        assert_eq!(group(100, 4).reclaimable(), 300);
+    }
+
+    #[test]
+    fn test_analyzer_hashes_streamed_chunks() {
+        let mut analyzer = DupeAnalyzer::new();
+        for (path, chunks) in [("a", ["he", "llo"]), ("b", ["hel", "lo"]), ("c", ["hel", "p!"])] {
+            let entry = EntryInfo { path, layer: "abc1234", size: 5, mode: 0o644 };
+            for chunk in chunks {
+                analyzer.read_chunk(&entry, chunk.as_bytes());
+            }
+            analyzer.analyze(&entry, b"");
+        }
+        let groups = analyzer.groups();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].digest, format!("{:x}", Sha256::digest(b"hello")));
+        assert_eq!(groups[0].size, 5);
+        assert_eq!(groups[0].occurrences.len(), 2);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let mut out = Vec::new();
+        write_report(&mut out, &[group(2048, 3)]).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("3 copies of 2.0 KiB (4.0 KiB reclaimable)  sha256:0123456789ab"));
+        assert!(text.contains("  [abc1234] file2"));
+        assert!(text.ends_with("1 groups, 3 files, 4.0 KiB reclaimable\n"));
+    }
+}
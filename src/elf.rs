@@ -0,0 +1,397 @@
+//! Minimal ELF header inspection
+
+use crate::analyzer::{Analyzer, EntryInfo, Finding};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+/// Bytes from the start of a file the analyzer reads: the ELF header,
+/// program headers and interpreter path sit well inside this
+const HEADER_REGION: u64 = 64 * 1024;
+
+/// What kind of object an ELF file is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfKind {
+    Relocatable,
+    Executable,
+    SharedObject,
+    Core,
+    Other,
+}
+
+/// Facts read from an ELF header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+    /// 32 or 64
+    pub bits: u8,
+    /// Human-readable machine name (e.g. "x86-64")
+    pub machine: &'static str,
+    pub kind: ElfKind,
+    /// Program interpreter requested via PT_INTERP (dynamic executables only)
+    pub interpreter: Option<String>,
+    /// Shared libraries named by DT_NEEDED, in order
+    pub needed: Vec<String>,
+    /// Directories from DT_RUNPATH (or the older DT_RPATH), `$ORIGIN` unexpanded
+    pub runpath: Vec<String>,
+}
+
+impl ElfInfo {
+    /// Short description like "64-bit x86-64 dynamic executable"
+    pub fn describe(&self) -> String {
+        let kind = match (self.kind, &self.interpreter) {
+            // PIE executables are ET_DYN with an interpreter
+            (ElfKind::Executable, Some(_)) | (ElfKind::SharedObject, Some(_)) => "dynamic executable",
+            (ElfKind::Executable, None) => "static executable",
+            (ElfKind::SharedObject, None) => "shared object",
+            (ElfKind::Relocatable, _) => "relocatable object",
+            (ElfKind::Core, _) => "core dump",
+            (ElfKind::Other, _) => "ELF file",
+        };
+        match &self.interpreter {
+            Some(interp) => format!("{}-bit {} {}, interpreter {}", self.bits, self.machine, kind, interp),
+            None => format!("{}-bit {} {}", self.bits, self.machine, kind),
+        }
+    }
+}
+
+/// Check whether content starts with the ELF magic
+pub fn is_elf(content: &[u8]) -> bool {
+    content.starts_with(ELF_MAGIC)
+}
+
+/// Parse the ELF header and program headers; returns None for non-ELF or truncated files
+pub fn parse(content: &[u8]) -> Option<ElfInfo> {
+    if !is_elf(content) || content.len() < 52 {
+        return None;
+    }
+
+    let bits = match content[4] {
+        1 => 32,
+        2 => 64,
+        _ => return None,
+    };
+    let little = match content[5] {
+        1 => true,
+        2 => false,
+        _ => return None,
+    };
+    let reader = Reader { data: content, little };
+
+    let kind = match reader.u16(16)? {
+        1 => ElfKind::Relocatable,
+        2 => ElfKind::Executable,
+        3 => ElfKind::SharedObject,
+        4 => ElfKind::Core,
+        _ => ElfKind::Other,
+    };
+    let machine = machine_name(reader.u16(18)?);
+
+    let (phoff, phentsize, phnum) = if bits == 64 {
+        (reader.u64(32)?, reader.u16(54)? as u64, reader.u16(56)? as u64)
+    } else {
+        (reader.u32(28)? as u64, reader.u16(42)? as u64, reader.u16(44)? as u64)
+    };
+
+    let segments = (0..phnum)
+        .map(|i| Segment::read(&reader, bits, offset(phoff, i.checked_mul(phentsize)?)?))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut interpreter = None;
+    if let Some(interp) = segments.iter().find(|segment| segment.kind == PT_INTERP) {
+        let bytes = content.get(offset(interp.offset, 0)?..offset(interp.offset, interp.size)?)?;
+        interpreter = Some(c_string(bytes));
+    }
+    // Linking details are best effort: a stripped or odd dynamic section leaves them empty
+    let (needed, runpath) = segments.iter()
+        .find(|segment| segment.kind == PT_DYNAMIC)
+        .and_then(|dynamic| read_dynamic(&reader, bits, dynamic, &segments))
+        .unwrap_or_default();
+
+    Some(ElfInfo { bits, machine, kind, interpreter, needed, runpath })
+}
+
+/// The parts of a program header the parser needs
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    /// Bytes of the segment stored in the file
+    size: u64,
+}
+
+impl Segment {
+    fn read(reader: &Reader, bits: u8, ph: usize) -> Option<Self> {
+        let kind = reader.u32(ph)?;
+        let field = |at: usize| ph.checked_add(at);
+        let (offset, vaddr, size) = if bits == 64 {
+            (reader.u64(field(8)?)?, reader.u64(field(16)?)?, reader.u64(field(32)?)?)
+        } else {
+            (reader.u32(field(4)?)? as u64, reader.u32(field(8)?)? as u64, reader.u32(field(16)?)? as u64)
+        };
+        Some(Segment { kind, offset, vaddr, size })
+    }
+}
+
+/// DT_NEEDED libraries and the run path, from the dynamic section. Its
+/// string table is given by address, found in the file through the loadable
+/// segment that maps it.
+fn read_dynamic(
+    reader: &Reader,
+    bits: u8,
+    dynamic: &Segment,
+    segments: &[Segment],
+) -> Option<(Vec<String>, Vec<String>)> {
+    let entry_size = if bits == 64 { 16 } else { 8 };
+    let mut needed = Vec::new();
+    let mut runpath = None;
+    let mut rpath = None;
+    let mut strtab = None;
+    for i in 0..dynamic.size / entry_size {
+        let at = offset(dynamic.offset, i * entry_size)?;
+        let (tag, value) = if bits == 64 {
+            (reader.u64(at)?, reader.u64(at.checked_add(8)?)?)
+        } else {
+            (reader.u32(at)? as u64, reader.u32(at.checked_add(4)?)? as u64)
+        };
+        match tag {
+            DT_NULL => break,
+            DT_NEEDED => needed.push(value),
+            DT_STRTAB => strtab = Some(value),
+            DT_RUNPATH => runpath = Some(value),
+            DT_RPATH => rpath = Some(value),
+            _ => {}
+        }
+    }
+
+    let address = strtab?;
+    let strtab = segments.iter()
+        .find(|s| s.kind == PT_LOAD && (s.vaddr..s.vaddr.saturating_add(s.size)).contains(&address))
+        .and_then(|s| s.offset.checked_add(address - s.vaddr))?;
+    let string = |at: u64| reader.data.get(offset(strtab, at)?..).map(c_string);
+    let needed = needed.into_iter().map(string).collect::<Option<Vec<_>>>()?;
+    // DT_RUNPATH takes precedence; the loader ignores DT_RPATH when both are set
+    let runpath = match runpath.or(rpath) {
+        Some(offset) => string(offset)?.split(':').filter(|dir| !dir.is_empty()).map(str::to_string).collect(),
+        None => Vec::new(),
+    };
+    Some((needed, runpath))
+}
+
+/// `base + delta` as an index into the file, or None if it overflows
+fn offset(base: u64, delta: u64) -> Option<usize> {
+    usize::try_from(base.checked_add(delta)?).ok()
+}
+
+/// The text up to the first NUL byte
+fn c_string(bytes: &[u8]) -> String {
+    let bytes = bytes.split(|b| *b == 0).next().unwrap_or(bytes);
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+fn machine_name(machine: u16) -> &'static str {
+    match machine {
+        0x03 => "x86",
+        0x08 => "mips",
+        0x14 => "ppc",
+        0x15 => "ppc64",
+        0x16 => "s390",
+        0x28 => "arm",
+        0x3e => "x86-64",
+        0xb7 => "aarch64",
+        0xf3 => "riscv",
+        _ => "unknown-arch",
+    }
+}
+
+/// Endian-aware fixed-width reads with bounds checking
+struct Reader<'a> {
+    data: &'a [u8],
+    little: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let b = self.bytes::<2>(offset)?;
+        Some(if self.little { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let b = self.bytes::<4>(offset)?;
+        Some(if self.little { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<8>(offset)?;
+        Some(if self.little { u64::from_le_bytes(b) } else { u64::from_be_bytes(b) })
+    }
+}
+
+/// Reports every ELF binary with its architecture and interpreter
+pub struct ElfAnalyzer;
+
+impl Analyzer for ElfAnalyzer {
+    fn name(&self) -> &'static str {
+        "elf"
+    }
+
+    fn wants(&self, entry: &EntryInfo) -> bool {
+        entry.size >= 52 && (entry.mode & 0o111 != 0 || entry.path.contains(".so"))
+    }
+
+    fn content_limit(&self, _entry: &EntryInfo) -> u64 {
+        HEADER_REGION
+    }
+
+    fn analyze(&mut self, entry: &EntryInfo, content: &[u8]) -> Vec<Finding> {
+        match parse(content) {
+            Some(info) => vec![Finding {
+                analyzer: self.name(),
+                path: entry.path.to_string(),
+                layer: entry.layer.to_string(),
+                kind: "elf".to_string(),
+                detail: info.describe(),
+                line: None,
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build a little-endian ELF64 image with an optional PT_INTERP segment
+    pub(crate) fn elf64(e_type: u16, interp: Option<&str>) -> Vec<u8> {
+        let mut data = vec![0u8; 64 + 56];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = 2;
+        data[5] = 1;
+        data[16..18].copy_from_slice(&e_type.to_le_bytes());
+        data[18..20].copy_from_slice(&0x3eu16.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+
+        if let Some(interp) = interp {
+            data[56..58].copy_from_slice(&1u16.to_le_bytes());
+            let offset = data.len() as u64;
+            data[64..68].copy_from_slice(&PT_INTERP.to_le_bytes());
+            data[72..80].copy_from_slice(&offset.to_le_bytes());
+            data[96..104].copy_from_slice(&(interp.len() as u64 + 1).to_le_bytes());
+            data.extend_from_slice(interp.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    /// Build a little-endian ELF64 executable that links against `needed`,
+    /// its string table mapped at an address other than its offset
+    pub(crate) fn elf64_linked(interp: &str, needed: &[&str], runpath: Option<&str>) -> Vec<u8> {
+        const BASE: u64 = 0x400000;
+        let mut data = vec![0u8; 64 + 3 * 56];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = 2;
+        data[5] = 1;
+        data[16..18].copy_from_slice(&3u16.to_le_bytes());
+        data[18..20].copy_from_slice(&0x3eu16.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&3u16.to_le_bytes());
+
+        // String table: the interpreter, then the names the dynamic section points at
+        let strtab = data.len() as u64;
+        let mut name_offsets = Vec::new();
+        data.push(0);
+        for name in needed.iter().chain(runpath.as_slice()).copied().chain([interp]) {
+            name_offsets.push(data.len() as u64 - strtab);
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        let interp_offset = strtab + name_offsets.pop().unwrap();
+
+        let dynamic = data.len() as u64;
+        let mut entries: Vec<(u64, u64)> = name_offsets.iter().take(needed.len()).map(|&o| (DT_NEEDED, o)).collect();
+        if runpath.is_some() {
+            entries.push((DT_RUNPATH, name_offsets[needed.len()]));
+        }
+        entries.extend([(DT_STRTAB, BASE + strtab), (DT_NULL, 0)]);
+        for (tag, value) in &entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let headers = [
+            (PT_INTERP, interp_offset, interp_offset, interp.len() as u64 + 1),
+            (PT_LOAD, 0, BASE, data.len() as u64),
+            (PT_DYNAMIC, dynamic, BASE + dynamic, entries.len() as u64 * 16),
+        ];
+        for (i, (kind, offset, vaddr, size)) in headers.into_iter().enumerate() {
+            let ph = 64 + i * 56;
+            data[ph..ph + 4].copy_from_slice(&kind.to_le_bytes());
+            data[ph + 8..ph + 16].copy_from_slice(&offset.to_le_bytes());
+            data[ph + 16..ph + 24].copy_from_slice(&vaddr.to_le_bytes());
+            data[ph + 32..ph + 40].copy_from_slice(&size.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_dynamic_executable() {
+        let info = parse(&elf64(3, Some("/lib/ld-musl-x86_64.so.1"))).unwrap();
+        assert_eq!(info.bits, 64);
+        assert_eq!(info.machine, "x86-64");
+        assert_eq!(info.interpreter.as_deref(), Some("/lib/ld-musl-x86_64.so.1"));
+        assert_eq!(info.describe(), "64-bit x86-64 dynamic executable, interpreter /lib/ld-musl-x86_64.so.1");
+    }
+
+    #[test]
+    fn test_parse_needed_libraries() {
+        let info = parse(&elf64_linked("/lib64/ld-linux-x86-64.so.2", &["libssl.so.3", "libc.so.6"],
+            Some("$ORIGIN/../lib:/opt/app/lib"))).unwrap();
+        assert_eq!(info.interpreter.as_deref(), Some("/lib64/ld-linux-x86-64.so.2"));
+        assert_eq!(info.needed, ["libssl.so.3", "libc.so.6"]);
+        assert_eq!(info.runpath, ["$ORIGIN/../lib", "/opt/app/lib"]);
+
+        let info = parse(&elf64_linked("/lib/ld-musl-x86_64.so.1", &[], None)).unwrap();
+        assert!(info.needed.is_empty() && info.runpath.is_empty());
+    }
+
+    #[test]
+    fn test_parse_static_and_shared() {
+        assert_eq!(parse(&elf64(2, None)).unwrap().describe(), "64-bit x86-64 static executable");
+        assert_eq!(parse(&elf64(3, None)).unwrap().describe(), "64-bit x86-64 shared object");
+    }
+
+    #[test]
+    fn test_parse_rejects_non_elf() {
+        assert!(parse(b"#!/bin/sh\necho hi\n").is_none());
+        assert!(parse(b"\x7fELF").is_none());
+    }
+
+    #[test]
+    fn test_parse_rejects_overflowing_offsets() {
+        // Program headers past the end of the address space
+        let mut data = elf64(2, None);
+        data[56..58].copy_from_slice(&2u16.to_le_bytes());
+        data[32..40].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert!(parse(&data).is_none());
+
+        // An interpreter whose end wraps around
+        let mut data = elf64(3, Some("/lib/ld.so"));
+        data[72..80].copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+        assert!(parse(&data).is_none());
+        data[72..80].copy_from_slice(&120u64.to_le_bytes());
+        data[96..104].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse(&data).is_none());
+    }
+}
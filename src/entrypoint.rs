@@ -0,0 +1,267 @@
+//! Whether the image's Entrypoint/Cmd can start
+//!
+//! The program is looked up the way a runtime does before `exec`: a bare
+//! name through the `PATH` of the image's Env, a relative path from its
+//! WorkingDir, symlinks followed. Then it has to be an executable file, a
+//! script's `#!` interpreter has to exist, and so do an ELF program's
+//! interpreter and the libraries it names. Libraries are looked for in its
+//! run path and the usual system directories; `/etc/ld.so.conf` isn't read.
+
+use anyhow::Result;
+
+use crate::archive::{self, LayerSet, ProcessOptions};
+use crate::config::ContainerConfig;
+use crate::content;
+use crate::elf;
+use crate::tree::Node;
+use crate::utils;
+
+/// What runtimes use when the image sets no `PATH`
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Where the dynamic loader finds libraries without being told
+const LIBRARY_DIRS: &[&str] = &["lib", "usr/lib", "lib64", "usr/lib64", "usr/local/lib"];
+
+/// Symlinks followed before giving up, as Linux's MAXSYMLINKS
+const MAX_HOPS: usize = 40;
+
+/// Bytes of the program read to look inside it
+const READ_LIMIT: usize = 64 << 20;
+
+/// What checking the start command found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// The program and where it led, e.g. `python3 -> /usr/bin/python3.12 (64-bit x86-64 ...)`
+    pub summary: String,
+    /// Why the container would fail to start; empty when nothing is wrong
+    pub problems: Vec<String>,
+}
+
+/// Check the start command of the image in `layer_set`
+pub fn validate(layer_set: &LayerSet) -> Result<Report> {
+    let image = archive::process_layer_set(layer_set, &ProcessOptions::default())?;
+    let config = layer_set.config.as_ref().map(|config| config.config.clone()).unwrap_or_default();
+    Ok(validate_in(&image.root, &config, |path| Ok(content::read_file(layer_set, path, READ_LIMIT)?.bytes)))
+}
+
+/// Check the start command `config` gives against the merged tree `root`,
+/// reading files through `read`
+pub fn validate_in(root: &Node, config: &ContainerConfig, mut read: impl FnMut(&str) -> Result<Vec<u8>>) -> Report {
+    let argv: Vec<&String> = config.entrypoint.iter().chain(&config.cmd).flatten().collect();
+    let Some(program) = argv.first() else {
+        let problems = vec!["neither Entrypoint nor Cmd is set".to_string()];
+        return Report { summary: "no command".to_string(), problems };
+    };
+    let search_path = config.env.iter().flatten()
+        .find_map(|var| var.strip_prefix("PATH="))
+        .unwrap_or(DEFAULT_PATH);
+
+    let mut problems = Vec::new();
+    let path = match locate(root, program, search_path, config.working_dir.as_deref()) {
+        Ok(path) => path,
+        Err(problem) => {
+            problems.push(problem);
+            return Report { summary: program.to_string(), problems };
+        }
+    };
+    let mut summary = if path.trim_start_matches('/') == program.trim_start_matches('/') {
+        format!("/{}", path)
+    } else {
+        format!("{} -> /{}", program, path)
+    };
+
+    if let Err(problem) = check_executable(root, &path) {
+        problems.push(problem);
+        return Report { summary, problems };
+    }
+    let content = match read(&path) {
+        Ok(content) => content,
+        Err(err) => {
+            problems.push(format!("/{} could not be read: {:#}", path, err));
+            return Report { summary, problems };
+        }
+    };
+
+    if let Some(line) = content.strip_prefix(b"#!") {
+        let line = String::from_utf8_lossy(line.split(|&b| b == b'\n').next().unwrap_or_default());
+        let mut words = line.split_whitespace();
+        let interpreter = words.next().unwrap_or_default();
+        summary.push_str(&format!(" (script for {})", interpreter));
+        if interpreter.is_empty() {
+            problems.push(format!("/{} has an empty #! line", path));
+        } else if let Err(problem) = locate(root, interpreter, search_path, None)
+            .and_then(|found| check_executable(root, &found))
+        {
+            problems.push(format!("script interpreter: {}", problem));
+        } else if interpreter.ends_with("/env") {
+            // `#!/usr/bin/env python3` finds its program through PATH too
+            if let Some(target) = words.find(|word| !word.starts_with('-')) {
+                if let Err(problem) = locate(root, target, search_path, None) {
+                    problems.push(format!("script interpreter: {}", problem));
+                }
+            }
+        }
+    } else if let Some(info) = elf::parse(&content) {
+        summary.push_str(&format!(" ({})", info.describe()));
+        if let Some(interpreter) = &info.interpreter {
+            if let Err(problem) = resolve(root, interpreter) {
+                problems.push(format!("ELF interpreter: {}", problem));
+            }
+        }
+        let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+        for library in &info.needed {
+            if find_library(root, library, &info, dir).is_none() {
+                problems.push(format!("shared library {} is in none of the library directories", library));
+            }
+        }
+    } else if content.len() >= 4 {
+        problems.push(format!("/{} is neither an ELF binary nor a #! script", path));
+    }
+    Report { summary, problems }
+}
+
+/// Where the program of a start command is, without a leading "/"
+fn locate(root: &Node, program: &str, search_path: &str, working_dir: Option<&str>) -> Result<String, String> {
+    if program.contains('/') {
+        let path = match working_dir.filter(|_| !program.starts_with('/')) {
+            Some(dir) => format!("{}/{}", dir, program),
+            None => program.to_string(),
+        };
+        return resolve(root, &path).map(|(path, _)| path);
+    }
+    let dirs: Vec<&str> = search_path.split(':').filter(|dir| !dir.is_empty()).collect();
+    dirs.iter()
+        .find_map(|dir| resolve(root, &format!("{}/{}", dir, program)).ok().filter(|(_, node)| node.metadata.is_file))
+        .map(|(path, _)| path)
+        .ok_or_else(|| format!("{} is in none of the PATH directories ({})", program, dirs.join(":")))
+}
+
+/// The entry `path` leads to once symlinks are followed, and its path
+fn resolve<'a>(root: &'a Node, path: &str) -> Result<(String, &'a Node), String> {
+    let original = utils::clean_path(path).path;
+    let mut path = original.clone();
+    for _ in 0..MAX_HOPS {
+        path = root.resolve_parents(&path).into_owned();
+        let Some(node) = root.get(&path) else {
+            return Err(if path == original {
+                format!("/{} does not exist", original)
+            } else {
+                format!("/{} is a dangling symlink (/{} does not exist)", original, path)
+            });
+        };
+        let target = node.metadata.symlink_target.as_deref().filter(|_| node.metadata.is_symlink);
+        let Some(target) = target else {
+            return Ok((path, node));
+        };
+        path = match (target.starts_with('/'), path.rsplit_once('/')) {
+            (false, Some((dir, _))) => utils::clean_path(&format!("{}/{}", dir, target)).path,
+            _ => utils::clean_path(target).path,
+        };
+    }
+    Err(format!("/{} is in a symlink loop", original))
+}
+
+/// Whether the entry at `path` (symlinks already followed) can be executed
+fn check_executable(root: &Node, path: &str) -> Result<(), String> {
+    let (path, node) = resolve(root, path)?;
+    if !node.metadata.is_file {
+        return Err(format!("/{} is a directory", path));
+    }
+    if node.metadata.mode & 0o111 == 0 {
+        return Err(format!("/{} is not executable (mode {:o})", path, node.metadata.mode & 0o7777));
+    }
+    Ok(())
+}
+
+/// Where the loader would find `library` for a program in `dir`
+fn find_library(root: &Node, library: &str, info: &elf::ElfInfo, dir: &str) -> Option<String> {
+    if library.contains('/') {
+        return resolve(root, library).ok().map(|(path, _)| path);
+    }
+    let runpath = info.runpath.iter().map(|entry| entry.replace("${ORIGIN}", dir).replace("$ORIGIN", dir));
+    let multiarch = multiarch_triple(info.machine).into_iter()
+        .flat_map(|triple| [format!("lib/{}", triple), format!("usr/lib/{}", triple)]);
+    runpath
+        .chain(LIBRARY_DIRS.iter().map(|dir| dir.to_string()))
+        .chain(multiarch)
+        .find_map(|dir| resolve(root, &format!("{}/{}", dir, library)).ok().filter(|(_, node)| node.metadata.is_file))
+        .map(|(path, _)| path)
+}
+
+/// Debian's directory name for a machine's libraries
+fn multiarch_triple(machine: &str) -> Option<&'static str> {
+    Some(match machine {
+        "x86-64" => "x86_64-linux-gnu",
+        "x86" => "i386-linux-gnu",
+        "aarch64" => "aarch64-linux-gnu",
+        "arm" => "arm-linux-gnueabihf",
+        "ppc64" => "powerpc64le-linux-gnu",
+        "s390" => "s390x-linux-gnu",
+        "riscv" => "riscv64-linux-gnu",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::elf64_linked;
+    use std::collections::HashMap;
+
+    fn image() -> (Node, HashMap<&'static str, Vec<u8>>) {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("usr/bin/app", 0o755, 0, 0, false, None, None);
+        root.put_file("usr/bin/python3.12", 0o755, 0, 0, false, None, None);
+        root.put_file("usr/bin/python3", 0o777, 0, 0, true, Some("python3.12".to_string()), None);
+        root.put_file("usr/bin/env", 0o755, 0, 0, false, None, None);
+        root.put_file("app/run.sh", 0o755, 0, 0, false, None, None);
+        root.put_file("app/notes.txt", 0o644, 0, 0, false, None, None);
+        root.put_file("lib/ld-musl-x86_64.so.1", 0o755, 0, 0, false, None, None);
+        root.put_file("usr/lib/libz.so.1", 0o755, 0, 0, false, None, None);
+        let files = HashMap::from([
+            ("usr/bin/app", elf64_linked("/lib/ld-musl-x86_64.so.1", &["libz.so.1", "libssl.so.3"], None)),
+            ("usr/bin/python3.12", elf64_linked("/lib/ld-musl-x86_64.so.1", &["libz.so.1"], None)),
+            ("app/run.sh", b"#!/usr/bin/env bash\nexec app\n".to_vec()),
+        ]);
+        (root, files)
+    }
+
+    fn check(root: &Node, files: &HashMap<&str, Vec<u8>>, config: serde_json::Value) -> Report {
+        let config: ContainerConfig = serde_json::from_value(config).unwrap();
+        validate_in(root, &config, |path| files.get(path).cloned().ok_or_else(|| anyhow::anyhow!("no content")))
+    }
+
+    #[test]
+    fn test_valid_command() {
+        let (root, files) = image();
+        let report = check(&root, &files, serde_json::json!({"Cmd": ["python3", "-m", "http.server"]}));
+        assert_eq!(report.summary, "python3 -> /usr/bin/python3.12 (64-bit x86-64 dynamic executable, \
+            interpreter /lib/ld-musl-x86_64.so.1)");
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn test_missing_library_and_interpreter() {
+        let (root, files) = image();
+        let report = check(&root, &files, serde_json::json!({"Entrypoint": ["/usr/bin/app"], "Cmd": ["--serve"]}));
+        assert_eq!(report.problems, ["shared library libssl.so.3 is in none of the library directories"]);
+
+        let report = check(&root, &files, serde_json::json!({"WorkingDir": "/app", "Cmd": ["./run.sh"]}));
+        assert_eq!(report.summary, "./run.sh -> /app/run.sh (script for /usr/bin/env)");
+        assert_eq!(report.problems, ["script interpreter: bash is in none of the PATH directories \
+            (/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin)"]);
+    }
+
+    #[test]
+    fn test_program_not_found_or_not_executable() {
+        let (root, files) = image();
+        let report = check(&root, &files, serde_json::json!({"Env": ["PATH=/usr/bin"], "Cmd": ["node"]}));
+        assert_eq!(report.problems, ["node is in none of the PATH directories (/usr/bin)"]);
+
+        let report = check(&root, &files, serde_json::json!({"Cmd": ["/app/notes.txt"]}));
+        assert_eq!(report.problems, ["/app/notes.txt is not executable (mode 644)"]);
+
+        let report = check(&root, &files, serde_json::json!({}));
+        assert_eq!(report.problems, ["neither Entrypoint nor Cmd is set"]);
+    }
+}
@@ -0,0 +1,154 @@
+//! Typed errors for opening archives and merging layers
+//!
+//! Reading an archive can fail in a few distinct ways that callers want to
+//! tell apart: the file can't be read, it isn't an image archive, a layer is
+//! missing or corrupt, the requested platform or image isn't there, or
+//! spooling ran out of disk. [`ContreeError`] names each of them; the CLI
+//! maps them to exit codes with [`ContreeError::exit_code`]. Failures without
+//! a kind of their own are carried as [`ContreeError::Other`].
+
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::utils;
+
+/// Underlying cause of a typed error
+pub type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+/// Result of the library's archive functions
+pub type Result<T, E = ContreeError> = std::result::Result<T, E>;
+
+const TEMP_SPACE_ADVICE: &str = "point --temp-dir at a larger disk, or decompress the archive first \
+    (uncompressed archives are read in place without temporary files)";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ContreeError {
+    /// The archive or a blob spooled from it couldn't be opened or read
+    #[error("Failed to read {}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// The file is not a tar archive at all
+    #[error("{} is not a Docker or OCI image archive", path.display())]
+    UnsupportedFormat { path: PathBuf },
+
+    /// The archive lacks the manifest needed to find the image
+    /// (`manifest.json`, or `index.json` when selecting a platform)
+    #[error("{name} not found in archive")]
+    ManifestMissing { name: String },
+
+    /// A manifest, index, or image config couldn't be parsed
+    #[error("Invalid {name}")]
+    InvalidManifest { name: String, source: BoxError },
+
+    /// The image index has no image for the requested platform
+    #[error("Platform {platform} not found in image index (available: {})",
+        if available.is_empty() { "none".to_string() } else { available.join(", ") })]
+    PlatformNotFound { platform: String, available: Vec<String> },
+
+    /// The archive has no image with the requested tag or position
+    #[error("Image {image} not found in archive (available: {})", available.join(", "))]
+    ImageNotFound { image: String, available: Vec<String> },
+
+    /// A layer listed by the manifest is not in the archive
+    #[error("Layer {name} not found in archive")]
+    LayerMissing { name: String },
+
+    /// The manifest and the image config list different numbers of layers
+    /// (only an error when asked to be strict)
+    #[error("Manifest and image config disagree: {}", mismatches.join("; "))]
+    LayerMismatch { mismatches: Vec<String> },
+
+    /// A layer couldn't be decompressed or read as a tar archive
+    #[error("Failed to read layer {layer}")]
+    CorruptLayer { layer: String, source: BoxError },
+
+    /// Spooling the layers would take more than `--max-temp-size`
+    #[error("Unpacking the layers needs {} of temporary space, more than --max-temp-size {}; \
+        raise the limit, or {TEMP_SPACE_ADVICE}", utils::human_size(*needed), utils::human_size(*max))]
+    TempSpaceExceeded { needed: u64, max: u64 },
+
+    /// The temp directory filled up while spooling
+    #[error("No space left in {} for temporary files; {TEMP_SPACE_ADVICE}", dir.display())]
+    NoSpace { dir: PathBuf, source: io::Error },
+
+    /// Any other failure
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl ContreeError {
+    /// Exit status the CLI reports for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ContreeError::Other(_) => 1,
+            ContreeError::Io { .. } => 3,
+            ContreeError::UnsupportedFormat { .. }
+            | ContreeError::ManifestMissing { .. }
+            | ContreeError::InvalidManifest { .. }
+            | ContreeError::LayerMismatch { .. } => 4,
+            ContreeError::LayerMissing { .. } | ContreeError::CorruptLayer { .. } => 5,
+            ContreeError::PlatformNotFound { .. } | ContreeError::ImageNotFound { .. } => 6,
+            ContreeError::TempSpaceExceeded { .. } | ContreeError::NoSpace { .. } => 7,
+        }
+    }
+
+    /// Keep a typed error as it is, or give an untyped one the kind `wrap`
+    pub(crate) fn or_else(err: anyhow::Error, wrap: impl FnOnce(BoxError) -> ContreeError) -> ContreeError {
+        match ContreeError::from(err) {
+            ContreeError::Other(err) => wrap(err.into()),
+            typed => typed,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ContreeError {
+    /// Recover a typed error that passed through anyhow, dropping any
+    /// context added on the way
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<ContreeError>() {
+            Ok(typed) => typed,
+            Err(err) => ContreeError::Other(err),
+        }
+    }
+}
+
+/// Opening or reading `path` failed
+pub(crate) fn io_error(path: &Path) -> impl FnOnce(io::Error) -> ContreeError + '_ {
+    move |source| ContreeError::Io { path: path.to_path_buf(), source }
+}
+
+/// Exit status for an error from anywhere in the CLI: the code of the first
+/// typed error in its chain, else 1
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<ContreeError>())
+        .map_or(1, ContreeError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_errors_survive_anyhow() {
+        let missing = ContreeError::LayerMissing { name: "aaa/layer.tar".to_string() };
+        let err = anyhow::Error::from(missing).context("Failed to open image.tar");
+        assert_eq!(exit_code(&err), 5);
+        assert!(matches!(ContreeError::from(err), ContreeError::LayerMissing { name } if name == "aaa/layer.tar"));
+
+        let err = anyhow::anyhow!("truncated").context("Failed to read layer entries");
+        assert_eq!(exit_code(&err), 1);
+        let corrupt = ContreeError::or_else(err, |source| ContreeError::CorruptLayer { layer: "bbb".to_string(), source });
+        assert_eq!(corrupt.exit_code(), 5);
+        assert_eq!(corrupt.source().unwrap().to_string(), "Failed to read layer entries");
+    }
+
+    #[test]
+    fn test_platform_not_found_lists_available() {
+        let err = ContreeError::PlatformNotFound { platform: "linux/s390x".to_string(), available: Vec::new() };
+        assert_eq!(err.to_string(), "Platform linux/s390x not found in image index (available: none)");
+    }
+}
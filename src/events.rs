@@ -0,0 +1,794 @@
+//! Typed events emitted while reading layer tarballs
+//!
+//! `read_layer` turns a layer into a stream of events. Building the merged
+//! tree, running analyzers, and reporting progress are all sinks of that
+//! stream, so none of them needs to know how tar entries are decoded.
+//!
+//! PAX extended headers and GNU long names (`././@LongLink`) are folded into
+//! the entries they describe: their `path`, `linkpath`, `mtime`, `uid`, `gid`
+//! and `SCHILY.xattr.*` records override the ustar fields. PAX global headers
+//! set the `mtime` of the entries after them. None of them is an entry of its
+//! own ([`is_metadata_entry`]).
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::io::Read;
+use tar::Entry;
+
+use crate::archive::{self, Layer};
+use crate::diagnostics::{self, WarningKind};
+use crate::profile::{Clock, LayerProfile, TimingReader};
+use crate::tree::{Device, DeviceKind};
+use crate::utils;
+use crate::whiteout;
+
+/// What a layer entry is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink { target: Option<String> },
+    Hardlink { target: Option<String> },
+    Device(Device),
+}
+
+/// A file, directory, link, or device stored in a layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerEntry {
+    /// Path relative to the image root, with `.` and `..` resolved
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Modification time in seconds since the epoch
+    pub mtime: u64,
+    /// Content size in bytes (regular files only)
+    pub size: u64,
+    /// Extended attributes (from PAX `SCHILY.xattr.*` records)
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Fingerprint of the contents, when requested
+    pub content_hash: Option<u64>,
+}
+
+/// Why an entry looks crafted to mislead or escape the image root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Suspicion {
+    /// The path starts with "/"
+    AbsolutePath,
+    /// The path has `..` components that stay inside the root
+    DotDot,
+    /// A `..` climbs above the root; the entry is skipped
+    EscapesRoot,
+    /// A symlink or hard link target climbs above the root
+    LinkEscapesRoot { target: String },
+    /// The path has control characters that can garble terminal output
+    ControlCharacters,
+    /// The entry is written through a symlink created earlier in the same layer
+    ThroughSymlink { link: String, target: String },
+}
+
+impl Suspicion {
+    /// One-line explanation for reports
+    pub fn describe(&self) -> String {
+        match self {
+            Suspicion::AbsolutePath => "absolute path".to_string(),
+            Suspicion::DotDot => "path contains '..'".to_string(),
+            Suspicion::EscapesRoot => "path escapes the image root (skipped)".to_string(),
+            Suspicion::LinkEscapesRoot { target } => format!("link target {} escapes the image root", target),
+            Suspicion::ControlCharacters => "path contains control characters".to_string(),
+            Suspicion::ThroughSymlink { link, target } => {
+                format!("written through {} -> {}, created in the same layer", link, target)
+            }
+        }
+    }
+}
+
+/// Something that happened while reading a layer
+pub enum LayerEvent<'a> {
+    LayerStarted { index: usize, count: usize, layer: &'a Layer },
+    /// An entry was added or replaced; `content` is set for regular files
+    /// when a sink asked for it, cut to the largest `content_limit` of the
+    /// sinks that did
+    EntryAdded {
+        entry: &'a LayerEntry,
+        content: Option<&'a [u8]>,
+    },
+    /// A whiteout removed `path`
+    EntryRemoved { path: &'a str },
+    /// An opaque whiteout hid everything lower layers put in `dir`
+    OpaqueApplied { dir: &'a str },
+    /// An entry looks malicious; `path` is as written in the layer
+    Suspicious { path: &'a str, reason: Suspicion },
+    LayerFinished { index: usize },
+}
+
+/// Consumer of layer events
+pub trait EventSink {
+    /// Whether this sink needs the content of a regular file
+    fn wants_content(&self, _entry: &LayerEntry) -> bool {
+        false
+    }
+
+    /// Most bytes of a wanted file's content this sink needs in `EntryAdded`
+    fn content_limit(&self, _entry: &LayerEntry) -> u64 {
+        u64::MAX
+    }
+
+    /// Every piece of a wanted file's content, in order and uncut, before its
+    /// `EntryAdded`: for digesting files too large to hold in memory
+    fn content_chunk(&mut self, _entry: &LayerEntry, _chunk: &[u8]) {}
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()>;
+}
+
+/// Options for decoding layer entries
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadOptions {
+    /// Fingerprint regular file contents
+    pub hash_contents: bool,
+    /// Fail on entries that would otherwise be skipped with a warning
+    /// (corrupted, unapplied, or of an unsupported type), naming the entry
+    pub strict: bool,
+    /// Which whiteout markers delete files and make directories opaque
+    pub whiteouts: whiteout::Dialect,
+}
+
+/// Read layer `index` of `count` and send its events to every sink in order.
+/// With `profile`, also record entry counts and where the time went.
+pub fn read_layer(
+    layer: &Layer,
+    index: usize,
+    count: usize,
+    options: ReadOptions,
+    sinks: &mut [&mut dyn EventSink],
+    profile: Option<&mut LayerProfile>,
+) -> Result<()> {
+    let clock = Clock::new(profile.is_some());
+    let start = clock.now();
+    let reader = TimingReader::new(archive::open_blob(layer)?, clock);
+    let (read_time, bytes) = reader.counters();
+    let mut tar = archive::layer_archive(Box::new(reader));
+
+    emit(sinks, &LayerEvent::LayerStarted { index, count, layer })?;
+
+    let mut entries = 0;
+    let mut handling = std::time::Duration::ZERO;
+    let mut content = Vec::new();
+    let mut global = Extended::default();
+    // Headers the tar crate didn't fold into the entry after them
+    let mut pending = Extended::default();
+    // Corrupted entries have no path of their own, only their position
+    for (position, entry) in tar.entries().context("Failed to read layer entries")?.enumerate() {
+        let mut entry = match entry {
+            Ok(e) => e,
+            Err(err) if options.strict => {
+                return Err(err).with_context(|| format!("Corrupted entry #{} of the layer", position + 1));
+            }
+            Err(err) => {
+                // Skip corrupted entries but continue processing
+                diagnostics::warn(WarningKind::CorruptEntry, format!("Skipping corrupted entry: {}", err));
+                continue;
+            }
+        };
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() {
+            // Paths name one entry, never all of them
+            global = Extended { path: None, link: None, ..read_extended(&mut entry).or(&global) };
+            continue;
+        }
+        // The tar crate folds local headers into the next entry itself, unless
+        // their header is too old for it to recognize
+        if is_metadata_entry(entry_type) {
+            pending = read_extended(&mut entry).or(&pending);
+            continue;
+        }
+        entries += 1;
+
+        // Content reads while handling count as decompression, not merging
+        let started = clock.now();
+        let read_before = read_time.get();
+        let name = options.strict.then(|| utils::path_from_bytes(&entry.path_bytes()).into_owned());
+        let result = read_entry(entry, options, std::mem::take(&mut pending).or(&global), sinks, &mut content);
+        handling += clock.since(started).saturating_sub(read_time.get() - read_before);
+        match (result, name) {
+            (Ok(()), _) => {}
+            (Err(err), Some(name)) => return Err(err.context(format!("Failed to apply entry {}", name))),
+            (Err(err), None) => {
+                diagnostics::warn(WarningKind::UnappliedEntry, format!("Failed to apply entry: {}", err));
+            }
+        }
+    }
+
+    emit(sinks, &LayerEvent::LayerFinished { index })?;
+
+    if let Some(profile) = profile {
+        profile.label = layer.label();
+        profile.entries = entries;
+        profile.bytes = bytes.get();
+        profile.decompress = read_time.get();
+        profile.merge = handling;
+        profile.parse = clock.since(start).saturating_sub(profile.decompress + profile.merge);
+    }
+    Ok(())
+}
+
+/// Whether a tar entry of this type only carries metadata for the entry
+/// after it (PAX headers, GNU long names) rather than being one itself
+pub fn is_metadata_entry(entry_type: tar::EntryType) -> bool {
+    entry_type.is_pax_global_extensions()
+        || entry_type.is_pax_local_extensions()
+        || entry_type.is_gnu_longname()
+        || entry_type.is_gnu_longlink()
+}
+
+fn emit(sinks: &mut [&mut dyn EventSink], event: &LayerEvent) -> Result<()> {
+    for sink in sinks.iter_mut() {
+        sink.handle(event)?;
+    }
+    Ok(())
+}
+
+/// Decode one tar entry and emit the matching event
+fn read_entry<R: Read>(
+    mut entry: Entry<R>,
+    options: ReadOptions,
+    outer: Extended,
+    sinks: &mut [&mut dyn EventSink],
+    content: &mut Vec<u8>,
+) -> Result<()> {
+    let extended = read_extended(&mut entry).or(&outer);
+    let header = entry.header().clone();
+    let raw_path = match &extended.path {
+        Some(path) => path.clone(),
+        None => utils::path_from_bytes(&entry.path_bytes()).into_owned(),
+    };
+    let clean = utils::clean_path(&raw_path);
+    let suspicious = |sinks: &mut [&mut dyn EventSink], reason| {
+        emit(sinks, &LayerEvent::Suspicious { path: &raw_path, reason })
+    };
+    if clean.escapes {
+        suspicious(sinks, Suspicion::EscapesRoot)?;
+        bail!("{} escapes the image root", raw_path);
+    }
+    if clean.absolute {
+        suspicious(sinks, Suspicion::AbsolutePath)?;
+    }
+    if clean.dot_dot {
+        suspicious(sinks, Suspicion::DotDot)?;
+    }
+    if raw_path.chars().any(char::is_control) {
+        suspicious(sinks, Suspicion::ControlCharacters)?;
+    }
+    let path = clean.path;
+    if path.is_empty() {
+        return Ok(());
+    }
+
+    if options.whiteouts.marker_files() {
+        if whiteout::is_opaque(&path) {
+            return emit(sinks, &LayerEvent::OpaqueApplied { dir: whiteout::opaque_dir(&path) });
+        }
+        if whiteout::is_whiteout(&path) {
+            return emit(sinks, &LayerEvent::EntryRemoved { path: &whiteout::whiteout_target(&path) });
+        }
+    }
+
+    // From the entry rather than its header, for PAX and GNU long link names
+    let link_target = || match &extended.link {
+        Some(link) => Some(link.clone()),
+        None => entry.link_name_bytes().map(|bytes| utils::path_from_bytes(&bytes).into_owned()),
+    };
+    let kind = match header.entry_type() {
+        tar::EntryType::Directory => EntryKind::Dir,
+        tar::EntryType::Regular => EntryKind::File,
+        tar::EntryType::Symlink => EntryKind::Symlink { target: link_target() },
+        tar::EntryType::Link => EntryKind::Hardlink { target: link_target() },
+        entry_type @ (tar::EntryType::Char | tar::EntryType::Block) => EntryKind::Device(Device {
+            kind: if entry_type == tar::EntryType::Char { DeviceKind::Char } else { DeviceKind::Block },
+            major: header.device_major().ok().flatten().unwrap_or(0),
+            minor: header.device_minor().ok().flatten().unwrap_or(0),
+        }),
+        // FIFOs and other special entries aren't tracked
+        entry_type if options.strict => bail!("Unsupported entry type {:?}", entry_type),
+        _ => return Ok(()),
+    };
+
+    match &kind {
+        EntryKind::Device(device) if options.whiteouts.overlay() && whiteout::is_device_whiteout(device) => {
+            return emit(sinks, &LayerEvent::EntryRemoved { path: &path });
+        }
+        // Hard link targets are image paths; one outside the image can't be linked
+        EntryKind::Hardlink { target: Some(target) } if utils::clean_path(target).escapes => {
+            suspicious(sinks, Suspicion::LinkEscapesRoot { target: target.clone() })?;
+            bail!("{} links outside the image root to {}", raw_path, target);
+        }
+        // Runtimes clamp symlinks at the root, but extracting the layer to disk doesn't
+        EntryKind::Symlink { target: Some(target) } if !target.starts_with('/') => {
+            let (dir, _) = utils::split_path(&path);
+            if utils::clean_path(&format!("{}/{}", dir, target)).escapes {
+                suspicious(sinks, Suspicion::LinkEscapesRoot { target: target.clone() })?;
+            }
+        }
+        _ => {}
+    }
+
+    let mut xattrs = extended.xattrs;
+    if kind == EntryKind::Dir && options.whiteouts.overlay() && whiteout::has_opaque_xattr(&xattrs) {
+        // Lower layers' contents go first, then the directory is written anew
+        emit(sinks, &LayerEvent::OpaqueApplied { dir: &path })?;
+        whiteout::strip_opaque_xattrs(&mut xattrs);
+    }
+
+    let mut layer_entry = LayerEntry {
+        path,
+        size: if kind == EntryKind::File { entry.size() } else { 0 },
+        kind,
+        mode: header.mode().context("Failed to read mode")?,
+        uid: header.uid().context("Failed to read uid")?,
+        gid: header.gid().context("Failed to read gid")?,
+        mtime: extended.mtime.unwrap_or_else(|| header.mtime().unwrap_or(0)),
+        xattrs,
+        content_hash: None,
+    };
+
+    let wanting: Vec<bool> = sinks.iter()
+        .map(|sink| layer_entry.kind == EntryKind::File && sink.wants_content(&layer_entry))
+        .collect();
+    let wanted = wanting.contains(&true);
+    if wanted {
+        let limit = sinks.iter().zip(&wanting)
+            .filter(|(_, &wants)| wants)
+            .map(|(sink, _)| sink.content_limit(&layer_entry))
+            .max()
+            .unwrap_or_default();
+        content.clear();
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = entry.read(&mut buf).with_context(|| format!("Failed to read {}", layer_entry.path))?;
+            if n == 0 {
+                break;
+            }
+            let chunk = &buf[..n];
+            for (sink, _) in sinks.iter_mut().zip(&wanting).filter(|(_, &wants)| wants) {
+                sink.content_chunk(&layer_entry, chunk);
+            }
+            let room = usize::try_from(limit.saturating_sub(content.len() as u64)).unwrap_or(usize::MAX);
+            content.extend_from_slice(&chunk[..n.min(room)]);
+            hasher.write(chunk);
+        }
+        if options.hash_contents {
+            layer_entry.content_hash = Some(hasher.finish());
+        }
+    } else if options.hash_contents && layer_entry.kind == EntryKind::File {
+        layer_entry.content_hash = Some(hash_contents(&mut entry).context("Failed to read file contents")?);
+    }
+
+    let content = wanted.then_some(content.as_slice());
+    emit(sinks, &LayerEvent::EntryAdded { entry: &layer_entry, content })
+}
+
+/// What PAX records and GNU long names say about an entry beyond its
+/// header. The tar crate applies `size`, `uid` and `gid` itself, and the
+/// paths too when it recognizes the header they came in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Extended {
+    path: Option<String>,
+    link: Option<String>,
+    /// From `SCHILY.xattr.*` records
+    xattrs: BTreeMap<String, Vec<u8>>,
+    /// Whole seconds of `mtime`, which may have a fraction; times before the
+    /// epoch count as the epoch
+    mtime: Option<u64>,
+}
+
+impl Extended {
+    /// These records, with `outer`'s (earlier or global ones) for what they leave out
+    fn or(mut self, outer: &Extended) -> Extended {
+        self.path = self.path.or_else(|| outer.path.clone());
+        self.link = self.link.or_else(|| outer.link.clone());
+        self.mtime = self.mtime.or(outer.mtime);
+        if self.xattrs.is_empty() {
+            self.xattrs = outer.xattrs.clone();
+        }
+        self
+    }
+}
+
+/// The extended records for `entry`, or the ones it carries when it is a
+/// metadata entry itself
+fn read_extended<R: Read>(entry: &mut Entry<R>) -> Extended {
+    let mut extended = Extended::default();
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_gnu_longname() || entry_type.is_gnu_longlink() {
+        let mut name = Vec::new();
+        if entry.read_to_end(&mut name).is_ok() {
+            let name = utils::path_from_bytes(name.split(|&b| b == 0).next().unwrap_or_default()).into_owned();
+            if entry_type.is_gnu_longname() {
+                extended.path = Some(name);
+            } else {
+                extended.link = Some(name);
+            }
+        }
+        return extended;
+    }
+    let Ok(Some(extensions)) = entry.pax_extensions() else {
+        return extended;
+    };
+    for ext in extensions.flatten() {
+        let Ok(key) = ext.key() else {
+            continue;
+        };
+        match key {
+            "path" => extended.path = Some(utils::path_from_bytes(ext.value_bytes()).into_owned()),
+            "linkpath" => extended.link = Some(utils::path_from_bytes(ext.value_bytes()).into_owned()),
+            "mtime" => {
+                let seconds = ext.value().ok().and_then(|value| value.split('.').next()?.parse::<i64>().ok());
+                extended.mtime = seconds.map(|seconds| seconds.max(0) as u64);
+            }
+            _ => {
+                if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                    extended.xattrs.insert(name.to_string(), ext.value_bytes().to_vec());
+                }
+            }
+        }
+    }
+    extended
+}
+
+/// Fingerprint file contents for equality checks within one run
+/// (SipHash, not a cryptographic digest)
+fn hash_contents<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records a one-line summary of every event
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl EventSink for Recorder {
+        fn wants_content(&self, entry: &LayerEntry) -> bool {
+            entry.path.ends_with(".txt")
+        }
+
+        fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+            self.events.push(match event {
+                LayerEvent::LayerStarted { index, count, .. } => format!("start {}/{}", index, count),
+                LayerEvent::EntryAdded { entry, content } => {
+                    format!("add {} {:?}", entry.path, content.map(|c| c.len()))
+                }
+                LayerEvent::EntryRemoved { path } => format!("remove {}", path),
+                LayerEvent::OpaqueApplied { dir } => format!("opaque {}", dir),
+                LayerEvent::Suspicious { path, reason } => format!("suspicious {} {:?}", path, reason),
+                LayerEvent::LayerFinished { index } => format!("finish {}", index),
+            });
+            Ok(())
+        }
+    }
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, entry_type: tar::EntryType, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn test_read_layer_events() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "./etc/", tar::EntryType::Directory, b"");
+        append(&mut builder, "./etc/notes.txt", tar::EntryType::Regular, b"hello");
+        append(&mut builder, "./etc/passwd", tar::EntryType::Regular, b"root");
+        append(&mut builder, "./etc/.wh.group", tar::EntryType::Regular, b"");
+        append(&mut builder, "./var/.wh..wh..opq", tar::EntryType::Regular, b"");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("layer.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        let layer = Layer {
+            name: "layer.tar".to_string(),
+            blob: archive::Blob::File(path),
+            diff_id: None,
+            compression: archive::Compression::None,
+        };
+
+        let mut recorder = Recorder::default();
+        read_layer(&layer, 0, 1, ReadOptions::default(), &mut [&mut recorder], None).unwrap();
+        assert_eq!(recorder.events, vec![
+            "start 0/1",
+            "add etc None",
+            "add etc/notes.txt Some(5)",
+            "add etc/passwd None",
+            "remove etc/group",
+            "opaque var",
+            "finish 0",
+        ]);
+    }
+
+    /// Append an entry without the tar crate's path checks, as a malicious builder would
+    fn append_raw(builder: &mut tar::Builder<Vec<u8>>, path: &str, entry_type: tar::EntryType, link: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        let old = header.as_old_mut();
+        old.name[..path.len()].copy_from_slice(path.as_bytes());
+        old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+    }
+
+    #[test]
+    fn test_read_layer_suspicious_entries() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_raw(&mut builder, "../../etc/cron.d/evil", tar::EntryType::Regular, "");
+        append_raw(&mut builder, "/etc/passwd", tar::EntryType::Regular, "");
+        append_raw(&mut builder, "usr/../etc/group", tar::EntryType::Regular, "");
+        append_raw(&mut builder, "app/up", tar::EntryType::Symlink, "../../..");
+        append_raw(&mut builder, "app/shadow", tar::EntryType::Link, "../etc/shadow");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("layer.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        let layer = Layer {
+            name: "layer.tar".to_string(),
+            blob: archive::Blob::File(path),
+            diff_id: None,
+            compression: archive::Compression::None,
+        };
+
+        let mut recorder = Recorder::default();
+        read_layer(&layer, 0, 1, ReadOptions::default(), &mut [&mut recorder], None).unwrap();
+        assert_eq!(recorder.events, vec![
+            "start 0/1",
+            "suspicious ../../etc/cron.d/evil EscapesRoot",
+            "suspicious /etc/passwd AbsolutePath",
+            "add etc/passwd None",
+            "suspicious usr/../etc/group DotDot",
+            "add etc/group None",
+            "suspicious app/up LinkEscapesRoot { target: \"../../..\" }",
+            "add app/up None",
+            "suspicious app/shadow LinkEscapesRoot { target: \"../etc/shadow\" }",
+            "finish 0",
+        ]);
+    }
+
+    /// The entries `layer` adds, in order
+    fn added_entries(layer: &Layer) -> Vec<LayerEntry> {
+        #[derive(Default)]
+        struct Entries(Vec<LayerEntry>);
+        impl EventSink for Entries {
+            fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+                if let LayerEvent::EntryAdded { entry, .. } = event {
+                    self.0.push((*entry).clone());
+                }
+                Ok(())
+            }
+        }
+        let mut entries = Entries::default();
+        read_layer(layer, 0, 1, ReadOptions::default(), &mut [&mut entries], None).unwrap();
+        entries.0
+    }
+
+    fn memory_layer(bytes: Vec<u8>) -> Layer {
+        Layer {
+            name: "layer.tar".to_string(),
+            blob: archive::Blob::Memory(bytes.into()),
+            diff_id: None,
+            compression: archive::Compression::None,
+        }
+    }
+
+    #[test]
+    fn test_read_layer_raw_byte_paths() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, link) in [
+            (&b"caf\xe9/menu"[..], tar::EntryType::Regular, &b""[..]),
+            (b"caf\xe9/.wh.old", tar::EntryType::Regular, b""),
+            (b"link", tar::EntryType::Symlink, b"caf\xe9/menu"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+            header.as_gnu_mut().unwrap().linkname[..link.len()].copy_from_slice(link);
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+        }
+        let layer = memory_layer(builder.into_inner().unwrap());
+
+        let mut recorder = Recorder::default();
+        read_layer(&layer, 0, 1, ReadOptions::default(), &mut [&mut recorder], None).unwrap();
+        assert_eq!(recorder.events[1..3], ["add caf\u{10ffe9}/menu None", "remove caf\u{10ffe9}/old"]);
+        let entries = added_entries(&layer);
+        assert_eq!(entries[1].kind, EntryKind::Symlink { target: Some("caf\u{10ffe9}/menu".to_string()) });
+        assert_eq!(utils::path_bytes(&entries[0].path), &b"caf\xe9/menu"[..]);
+    }
+
+    #[test]
+    fn test_read_layer_gnu_long_names() {
+        let deep = format!("usr/lib/{}/libfoo.so.1", "x".repeat(110));
+        let target = format!("/usr/lib/{}/libfoo.so.1.2.3", "x".repeat(110));
+
+        // GNU tar's own headers, which the tar crate folds in itself
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_link(&mut header, &deep, &target).unwrap();
+        let entries = added_entries(&memory_layer(builder.into_inner().unwrap()));
+        assert_eq!(entries.len(), 1);
+        assert_eq!((&entries[0].path, &entries[0].kind), (&deep, &EntryKind::Symlink { target: Some(target.clone()) }));
+
+        // Old-style headers without a magic, as older tools wrote them
+        let mut bytes = Vec::new();
+        let mut old = |entry_type: tar::EntryType, name: &str, link: &str, data: &[u8]| {
+            let mut header = tar::Header::new_old();
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o777);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_cksum();
+            bytes.extend_from_slice(header.as_bytes());
+            bytes.extend_from_slice(data);
+            bytes.resize(bytes.len().next_multiple_of(512), 0);
+        };
+        old(tar::EntryType::GNULongName, "././@LongLink", "", format!("{}\0", deep).as_bytes());
+        old(tar::EntryType::GNULongLink, "././@LongLink", "", format!("{}\0", target).as_bytes());
+        old(tar::EntryType::Symlink, &deep[..100], &target[..100], b"");
+        old(tar::EntryType::Regular, "etc/hosts", "", b"");
+        bytes.extend_from_slice(&[0; 1024]);
+        let entries = added_entries(&memory_layer(bytes));
+        let paths: Vec<_> = entries.iter().map(|entry| (entry.path.as_str(), &entry.kind)).collect();
+        assert_eq!(paths, [
+            (deep.as_str(), &EntryKind::Symlink { target: Some(target.clone()) }),
+            // The long names were for the symlink alone
+            ("etc/hosts", &EntryKind::File),
+        ]);
+    }
+
+    #[test]
+    fn test_read_layer_pax_headers() {
+        let mut builder = tar::Builder::new(Vec::new());
+        // A global header, then a long path and link target in a local one
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_size(20);
+        builder.append_data(&mut header, "pax_global_header", &b"20 mtime=1600000000\n"[..]).unwrap();
+        let deep = format!("usr/share/{}/file.txt", "d".repeat(120));
+        let target = format!("/opt/{}", "t".repeat(120));
+        builder.append_pax_extensions([
+            ("path", deep.as_bytes()),
+            ("linkpath", target.as_bytes()),
+            ("mtime", &b"1700000000.25"[..]),
+        ]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_link(&mut header, "short", "short-target").unwrap();
+        append(&mut builder, "etc/hosts", tar::EntryType::Regular, b"");
+
+        let entries = added_entries(&memory_layer(builder.into_inner().unwrap()));
+        let summary: Vec<_> = entries.iter().map(|entry| (entry.path.as_str(), &entry.kind, entry.mtime)).collect();
+        assert_eq!(summary, [
+            (deep.as_str(), &EntryKind::Symlink { target: Some(target.clone()) }, 1700000000),
+            // The global mtime stands in for the entry's own
+            ("etc/hosts", &EntryKind::File, 1600000000),
+        ]);
+    }
+
+    #[test]
+    fn test_read_layer_whiteout_dialects() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_pax_extensions([("SCHILY.xattr.trusted.overlay.opaque", &b"y"[..])]).unwrap();
+        append(&mut builder, "var/cache", tar::EntryType::Directory, b"");
+        append(&mut builder, "etc/group", tar::EntryType::Char, b"");
+        append(&mut builder, "etc/.wh.passwd", tar::EntryType::Regular, b"");
+        let layer = memory_layer(builder.into_inner().unwrap());
+
+        let events = |whiteouts| {
+            let mut recorder = Recorder::default();
+            let options = ReadOptions { whiteouts, ..Default::default() };
+            read_layer(&layer, 0, 1, options, &mut [&mut recorder], None).unwrap();
+            recorder.events[1..recorder.events.len() - 1].to_vec()
+        };
+        assert_eq!(events(whiteout::Dialect::Auto),
+            ["opaque var/cache", "add var/cache None", "remove etc/group", "remove etc/passwd"]);
+        assert_eq!(events(whiteout::Dialect::Aufs),
+            ["add var/cache None", "add etc/group None", "remove etc/passwd"]);
+        assert_eq!(events(whiteout::Dialect::Overlay),
+            ["opaque var/cache", "add var/cache None", "remove etc/group", "add etc/.wh.passwd None"]);
+
+        // The marker isn't an attribute of the directory
+        let entries = added_entries(&layer);
+        assert!(entries[0].xattrs.is_empty());
+    }
+
+    /// Wants the first `limit` bytes of every file and counts what streams past
+    struct Prefix {
+        limit: u64,
+        streamed: usize,
+        seen: Vec<(Option<u64>, usize)>,
+    }
+
+    impl EventSink for Prefix {
+        fn wants_content(&self, _entry: &LayerEntry) -> bool {
+            true
+        }
+
+        fn content_limit(&self, _entry: &LayerEntry) -> u64 {
+            self.limit
+        }
+
+        fn content_chunk(&mut self, _entry: &LayerEntry, chunk: &[u8]) {
+            self.streamed += chunk.len();
+        }
+
+        fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+            if let LayerEvent::EntryAdded { entry, content } = event {
+                self.seen.push((entry.content_hash, content.map_or(0, |c| c.len())));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_content_limit() {
+        let data = vec![7u8; 200 * 1024];
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "./big.bin", tar::EntryType::Regular, &data);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("layer.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        let layer = Layer {
+            name: "layer.tar".to_string(),
+            blob: archive::Blob::File(path),
+            diff_id: None,
+            compression: archive::Compression::None,
+        };
+
+        let options = ReadOptions { hash_contents: true, ..Default::default() };
+        let mut small = Prefix { limit: 16, streamed: 0, seen: Vec::new() };
+        let mut large = Prefix { limit: 1024, streamed: 0, seen: Vec::new() };
+        read_layer(&layer, 0, 1, options, &mut [&mut small, &mut large], None).unwrap();
+
+        // Content is cut to the largest limit, but chunks and the fingerprint cover the whole file
+        let hash = hash_contents(&mut &data[..]).unwrap();
+        assert_eq!(small.seen, [(Some(hash), 1024)]);
+        assert_eq!(small.streamed, data.len());
+        assert_eq!(large.streamed, data.len());
+    }
+}
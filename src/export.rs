@@ -0,0 +1,410 @@
+//! Re-export an image archive, optionally normalizing layer compression
+
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use crate::archive::{self, Compression, Layer, LayerSet};
+use crate::diagnostics;
+use crate::error::ContreeError;
+use crate::manifest::ManifestEntry;
+use crate::progress::TermProgress;
+use crate::source::{self, ImageSelector};
+use crate::tempspace;
+use crate::utils;
+
+/// Target compression for `--recompress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recompress {
+    pub compression: Compression,
+    /// Encoder level; None uses the format's default
+    pub level: Option<i32>,
+}
+
+impl Recompress {
+    /// Parse `gzip`, `gzip:LEVEL`, `zstd`, or `zstd:LEVEL`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (format, level) = match spec.split_once(':') {
+            Some((format, level)) => {
+                let level: i32 = level.parse()
+                    .with_context(|| format!("Invalid compression level '{}'", level))?;
+                (format, Some(level))
+            }
+            None => (spec, None),
+        };
+
+        let (compression, range) = match format {
+            "gzip" => (Compression::Gzip, 0..=9),
+            "zstd" => (Compression::Zstd, 1..=22),
+            _ => bail!("Unknown compression '{}' (expected gzip or zstd)", format),
+        };
+        if let Some(level) = level.filter(|l| !range.contains(l)) {
+            bail!("{} level {} out of range ({}-{})", format, level, range.start(), range.end());
+        }
+        Ok(Recompress { compression, level })
+    }
+}
+
+/// How one manifest layer ends up in the exported archive
+struct PlannedLayer<'a> {
+    layer: &'a Layer,
+    /// Path of the blob inside the exported archive
+    name: String,
+    /// Compression to re-encode to; None copies the blob unchanged
+    target: Option<Recompress>,
+    /// The blob was already written, for an earlier layer or image; only
+    /// the first copy is written
+    duplicate: bool,
+}
+
+/// Names given to the blobs of the exported archive, shared by all of its images
+#[derive(Default)]
+struct Names {
+    /// Name in the export of each source blob, by its name in the source
+    by_source: HashMap<String, String>,
+    written: HashSet<String>,
+}
+
+fn plan_layers<'a>(layers: &'a [Layer], recompress: Option<Recompress>, names: &mut Names) -> Vec<PlannedLayer<'a>> {
+    layers.iter()
+        .map(|layer| {
+            let target = recompress.filter(|r| r.compression != layer.compression);
+            let seen = names.by_source.len();
+            let name = names.by_source.entry(layer.name.clone())
+                .or_insert_with(|| match target {
+                    Some(r) => recompressed_name(layer, seen, r.compression),
+                    None => layer.name.clone(),
+                })
+                .clone();
+            let duplicate = !names.written.insert(name.clone());
+            PlannedLayer { layer, name, target, duplicate }
+        })
+        .collect()
+}
+
+/// The image at `position` in the manifest of `archive_path`, or None past the last one
+fn open_image(archive_path: &Path, position: usize) -> Result<Option<LayerSet>> {
+    let select = ImageSelector { image: (position > 0).then(|| position.to_string()), ..Default::default() };
+    match source::for_path(archive_path).open_layers(&select, &mut TermProgress::disabled()) {
+        Ok(layer_set) => Ok(Some(layer_set)),
+        Err(ContreeError::ImageNotFound { .. }) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write a copy of `archive_path` to `output` as a docker-save archive,
+/// with every image its manifest lists. With `recompress`, layers in any
+/// other compression are re-encoded; layers already in the target
+/// compression are copied unchanged. The archive is written next to
+/// `output` and only renamed over it once complete.
+pub fn export(archive_path: &Path, output: &Path, recompress: Option<Recompress>) -> Result<()> {
+    let dir = output.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let staged = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Cannot write to {}", dir.display()))?;
+    let mut builder = tar::Builder::new(io::BufWriter::new(staged));
+
+    let mut names = Names::default();
+    let mut manifest = Vec::new();
+    let (mut layers, mut recompressed) = (0, 0);
+    while let Some(layer_set) = open_image(archive_path, manifest.len())? {
+        let config_name = match &layer_set.config_blob {
+            Some((name, bytes)) => {
+                if names.written.insert(name.clone()) {
+                    append_bytes(&mut builder, name, bytes)?;
+                }
+                Some(name.clone())
+            }
+            None => None,
+        };
+
+        let plan = plan_layers(&layer_set.layers, recompress, &mut names);
+        for (i, planned) in plan.iter().enumerate().filter(|(_, p)| !p.duplicate) {
+            let layer = planned.layer;
+            match planned.target {
+                Some(r) => {
+                    diagnostics::note(format!("Layer {}: {} -> {}", i, layer.compression.name(), r.compression.name()));
+                    let mut encoded = tempfile::tempfile_in(tempspace::dir()).context("Failed to create temp file")?;
+                    encode_layer(layer, r, &mut encoded)?;
+                    let size = encoded.stream_position()?;
+                    encoded.rewind()?;
+                    append_blob(&mut builder, &planned.name, size, &mut encoded)?;
+                    recompressed += 1;
+                }
+                None => {
+                    append_blob(&mut builder, &planned.name, layer.blob.size(), &mut layer.blob.open()?)?;
+                }
+            }
+        }
+
+        layers += plan.len();
+        manifest.push(ManifestEntry {
+            config: config_name,
+            repo_tags: Some(layer_set.repo_tags.clone()),
+            layers: plan.iter().map(|p| p.name.clone()).collect(),
+        });
+    }
+    let manifest_bytes = serde_json::to_vec(&manifest).context("Failed to serialize manifest")?;
+    append_bytes(&mut builder, "manifest.json", &manifest_bytes)?;
+
+    let staged = builder.into_inner()
+        .context("Failed to finish archive")?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    staged.persist(output)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    diagnostics::note(format!("Wrote {} ({} images, {} layers, {} recompressed)", output.display(),
+        manifest.len(), layers, recompressed));
+    Ok(())
+}
+
+/// Print what `export` would write, and any problems writing it, without
+/// touching the output path
+pub fn dry_run(archive_path: &Path, output: &Path, recompress: Option<Recompress>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let action = if output.exists() { "overwrite" } else { "create" };
+    writeln!(out, "Would {} {}:", action, output.display())?;
+
+    let mut names = Names::default();
+    let mut position = 0;
+    while let Some(layer_set) = open_image(archive_path, position)? {
+        let tags = match layer_set.repo_tags.as_slice() {
+            [] => "untagged".to_string(),
+            tags => tags.join(", "),
+        };
+        writeln!(out, "  image {} ({})", position, tags)?;
+        if let Some((name, bytes)) = &layer_set.config_blob {
+            let how = match names.written.insert(name.clone()) {
+                true => format!("image config, {}", utils::human_size(bytes.len() as u64)),
+                false => "already written".to_string(),
+            };
+            writeln!(out, "    {}  {}", name, how)?;
+        }
+        for (i, planned) in plan_layers(&layer_set.layers, recompress, &mut names).iter().enumerate() {
+            let layer = planned.layer;
+            let how = match planned.target {
+                _ if planned.duplicate => "already written".to_string(),
+                Some(r) => format!("{} re-encoded as {}", layer.compression.name(), r.compression.name()),
+                None => {
+                    let size = layer.blob.size();
+                    format!("{} copied, {}", layer.compression.name(), utils::human_size(size))
+                }
+            };
+            writeln!(out, "    {}  layer {}, {}", planned.name, i, how)?;
+        }
+        position += 1;
+    }
+    writeln!(out, "  manifest.json")?;
+
+    for warning in output_warnings(output, archive_path) {
+        writeln!(out, "warn  {}", warning)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Problems the real export would run into, or data it would clobber, at `output`
+fn output_warnings(output: &Path, input: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+    match std::fs::metadata(output) {
+        Ok(meta) if meta.is_dir() => {
+            warnings.push(format!("{} is a directory", output.display()));
+        }
+        Ok(meta) => {
+            let same_file = output.canonicalize().ok().zip(input.canonicalize().ok())
+                .is_some_and(|(a, b)| a == b);
+            if same_file {
+                warnings.push(format!("{} is the input archive and would be replaced", output.display()));
+            } else {
+                warnings.push(format!("{} already exists and would be overwritten", output.display()));
+            }
+            if meta.permissions().readonly() {
+                warnings.push(format!("{} is read-only", output.display()));
+            }
+        }
+        Err(_) => {}
+    }
+
+    let parent = output.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match std::fs::metadata(parent) {
+        Ok(meta) if meta.permissions().readonly() => {
+            warnings.push(format!("directory {} is read-only", parent.display()));
+        }
+        Ok(_) => {}
+        Err(_) => warnings.push(format!("directory {} does not exist", parent.display())),
+    }
+    warnings
+}
+
+/// Name for a re-encoded layer, keyed by its diff_id when known
+fn recompressed_name(layer: &Layer, index: usize, compression: Compression) -> String {
+    let stem = layer.diff_id.as_deref()
+        .map(|d| d.split_once(':').map_or(d, |(_, hex)| hex))
+        .filter(|hex| !hex.is_empty())
+        .map(|hex| hex.to_string())
+        .unwrap_or_else(|| format!("layer-{}", index));
+    format!("{}/layer{}", stem, compression.extension())
+}
+
+/// Decompress a layer and encode it with the requested compression
+fn encode_layer(layer: &Layer, target: Recompress, out: &mut File) -> Result<()> {
+    let mut source = archive::open_blob(layer)?;
+    match target.compression {
+        Compression::Gzip => {
+            let level = target.level.map_or(flate2::Compression::default(), |l| flate2::Compression::new(l as u32));
+            let mut encoder = flate2::write::GzEncoder::new(out, level);
+            io::copy(&mut source, &mut encoder).context("Failed to recompress layer")?;
+            encoder.finish().context("Failed to recompress layer")?;
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let level = target.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+            let mut encoder = zstd::Encoder::new(out, level).context("Failed to start zstd encoder")?;
+            io::copy(&mut source, &mut encoder).context("Failed to recompress layer")?;
+            encoder.finish().context("Failed to recompress layer")?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => anyhow::bail!("Writing zstd-compressed layers needs the zstd feature"),
+        Compression::None => {
+            io::copy(&mut source, out).context("Failed to decompress layer")?;
+        }
+    }
+    Ok(())
+}
+
+fn header_for(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_entry_type(tar::EntryType::Regular);
+    header
+}
+
+fn append_blob<W: Write, R: Read>(builder: &mut tar::Builder<W>, name: &str, size: u64, blob: &mut R) -> Result<()> {
+    builder.append_data(&mut header_for(size), name, blob)
+        .with_context(|| format!("Failed to write {}", name))
+}
+
+fn append_bytes<W: Write>(builder: &mut tar::Builder<W>, name: &str, bytes: &[u8]) -> Result<()> {
+    builder.append_data(&mut header_for(bytes.len() as u64), name, bytes)
+        .with_context(|| format!("Failed to write {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recompress() {
+        assert_eq!(Recompress::parse("gzip").unwrap(),
+            Recompress { compression: Compression::Gzip, level: None });
+        assert_eq!(Recompress::parse("zstd:19").unwrap(),
+            Recompress { compression: Compression::Zstd, level: Some(19) });
+        assert!(Recompress::parse("zstd:23").is_err());
+        assert!(Recompress::parse("gzip:fast").is_err());
+        assert!(Recompress::parse("brotli").is_err());
+    }
+
+    #[test]
+    fn test_recompressed_name() {
+        let layer = Layer {
+            name: "blobs/sha256/aaa".to_string(),
+            blob: archive::Blob::File(Default::default()),
+            diff_id: Some("sha256:abc123".to_string()),
+            compression: Compression::Gzip,
+        };
+        assert_eq!(recompressed_name(&layer, 0, Compression::Zstd), "abc123/layer.tar.zst");
+
+        let anonymous = Layer { diff_id: None, ..layer };
+        assert_eq!(recompressed_name(&anonymous, 3, Compression::Gzip), "layer-3/layer.tar.gz");
+    }
+
+    #[test]
+    fn test_plan_layers() {
+        let layer = |name: &str, compression| Layer {
+            name: name.to_string(),
+            blob: archive::Blob::File(Default::default()),
+            diff_id: None,
+            compression,
+        };
+        let layers = vec![
+            layer("a/layer.tar.gz", Compression::Gzip),
+            layer("b/layer.tar", Compression::None),
+            layer("a/layer.tar.gz", Compression::Gzip),
+        ];
+        let gzip = Recompress { compression: Compression::Gzip, level: None };
+        let plan = plan_layers(&layers, Some(gzip), &mut Names::default());
+
+        let summary: Vec<(&str, bool, bool)> = plan.iter()
+            .map(|p| (p.name.as_str(), p.target.is_some(), p.duplicate))
+            .collect();
+        assert_eq!(summary, vec![
+            ("a/layer.tar.gz", false, false),
+            ("layer-1/layer.tar.gz", true, false),
+            ("a/layer.tar.gz", false, true),
+        ]);
+    }
+
+    #[test]
+    fn test_export_keeps_every_image() {
+        let layer = |path: &str| {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_bytes(&mut builder, path, b"data").unwrap();
+            builder.into_inner().unwrap()
+        };
+        let archive = |manifest: &[u8]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_bytes(&mut builder, "base/layer.tar", &layer("etc/os-release")).unwrap();
+            append_bytes(&mut builder, "app/layer.tar", &layer("srv/app")).unwrap();
+            append_bytes(&mut builder, "c.json", b"{}").unwrap();
+            append_bytes(&mut builder, "manifest.json", manifest).unwrap();
+            builder.into_inner().unwrap()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.tar");
+        let output = dir.path().join("out.tar");
+        std::fs::write(&input, archive(br#"[
+            {"Config":"c.json","RepoTags":["base:1"],"Layers":["base/layer.tar"]},
+            {"Config":"c.json","RepoTags":["app:1"],"Layers":["base/layer.tar","app/layer.tar"]}
+        ]"#)).unwrap();
+
+        export(&input, &output, None).unwrap();
+        let mut names: Vec<String> = tar::Archive::new(File::open(&output).unwrap()).entries().unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["app/layer.tar", "base/layer.tar", "c.json", "manifest.json"]);
+        let app = open_image(&output, 1).unwrap().unwrap();
+        assert_eq!(app.repo_tags, ["app:1"]);
+        assert_eq!(app.layers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), ["base/layer.tar", "app/layer.tar"]);
+        assert!(open_image(&output, 2).unwrap().is_none());
+
+        // A failed export leaves what was at the output path alone
+        std::fs::write(&input, archive(br#"[{"Config":"c.json","Layers":["gone/layer.tar"]}]"#)).unwrap();
+        let before = std::fs::read(&output).unwrap();
+        assert!(export(&input, &output, None).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), before);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_output_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.tar");
+        std::fs::write(&input, b"").unwrap();
+
+        assert!(output_warnings(&dir.path().join("out.tar"), &input).is_empty());
+
+        let warnings = output_warnings(&input, &input);
+        assert_eq!(warnings, vec![format!("{} is the input archive and would be replaced", input.display())]);
+
+        let missing = dir.path().join("missing").join("out.tar");
+        let warnings = output_warnings(&missing, &input);
+        assert_eq!(warnings, vec![format!("directory {} does not exist", dir.path().join("missing").display())]);
+    }
+}
@@ -0,0 +1,603 @@
+//! Copy a file or directory of the merged image out to disk
+//!
+//! Layers are applied to the output directory in order, the way a runtime
+//! unpacks them: later entries replace earlier ones, whiteouts delete, and
+//! opaque directories are emptied. Symlinked parent directories are followed
+//! inside the image, never on disk, so links in the image can't send files
+//! outside the output directory; an entry whose path still meets a link on
+//! disk (a chain too long to follow) is skipped.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, LayerSet};
+use crate::diagnostics::{self, WarningKind};
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::tree::Node;
+use crate::utils;
+use crate::whiteout;
+
+/// What an extraction wrote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractSummary {
+    /// Directories, files and links written, counting rewrites by later layers
+    pub written: usize,
+    /// Entries that could not be written, with the reason
+    pub skipped: Vec<String>,
+}
+
+/// What an extraction would leave in the output directory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractPlan {
+    /// Image paths that would be written, in path order, once every layer is applied
+    pub entries: Vec<(String, Planned)>,
+    /// Entries that would not be written, with the reason
+    pub skipped: Vec<String>,
+}
+
+/// One entry an extraction would write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Planned {
+    Dir,
+    File { size: u64 },
+    Symlink { target: String },
+    /// A hard link to another extracted path
+    Hardlink { source: String },
+}
+
+impl Planned {
+    fn describe(&self) -> String {
+        match self {
+            Planned::Dir => "directory".to_string(),
+            Planned::File { size } => format!("file, {}", utils::human_size(*size)),
+            Planned::Symlink { target } => format!("symlink to {}", target),
+            Planned::Hardlink { source } => format!("hard link to /{}", utils::display_path(source)),
+        }
+    }
+}
+
+/// Write `path` of the merged image (a file or directory; "/" for all of
+/// it) under `output`, at the same path it has in the image. Permission
+/// bits are kept, but not ownership or setuid/setgid bits.
+pub fn extract(archive_path: &Path, path: &str, output: &Path) -> Result<ExtractSummary> {
+    check_output(output)?;
+    let layer_set = archive::open_archive(archive_path)?;
+    extract_paths(&layer_set, &[path], output)
+}
+
+/// [`extract`] several paths of an image already opened, in one pass over its layers
+pub fn extract_paths(layer_set: &LayerSet, paths: &[&str], output: &Path) -> Result<ExtractSummary> {
+    check_output(output)?;
+    fs::create_dir_all(output).with_context(|| format!("Failed to create {}", output.display()))?;
+
+    let mut sink = ExtractSink::new(paths, output);
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+    sink.finish()
+}
+
+/// What [`extract_paths`] would write, without reading file contents or
+/// touching the disk
+pub fn plan_paths(layer_set: &LayerSet, paths: &[&str]) -> Result<ExtractPlan> {
+    let mut sink = ExtractSink::planning(paths);
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+    let plan = sink.plan.take().unwrap_or_default();
+    let summary = sink.finish()?;
+    Ok(ExtractPlan { entries: plan.into_iter().collect(), skipped: summary.skipped })
+}
+
+/// Print what `extract` would write under `output`, and why it would
+/// refuse to, without touching the disk
+pub fn dry_run(archive_path: &Path, path: &str, output: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let plan = plan_paths(&layer_set, &[path])?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let action = if output.exists() { "write into" } else { "create" };
+    writeln!(out, "Would {} {}:", action, output.display())?;
+    for (path, planned) in &plan.entries {
+        writeln!(out, "  /{}  {}", utils::display_path(path), planned.describe())?;
+    }
+    for skipped in &plan.skipped {
+        writeln!(out, "skip  {}", skipped)?;
+    }
+    if let Err(err) = check_output(output) {
+        writeln!(out, "warn  {}", err)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Links already in the output would be followed, so start from nothing
+fn check_output(output: &Path) -> Result<()> {
+    let occupied = fs::read_dir(output).map(|mut dir| dir.next().is_some()).unwrap_or(false);
+    if occupied {
+        bail!("{} is not empty", output.display());
+    }
+    Ok(())
+}
+
+/// Extract `path` and report what was written
+pub fn run(archive_path: &Path, path: &str, output: &Path) -> Result<()> {
+    let summary = extract(archive_path, path, output)?;
+    for skipped in &summary.skipped {
+        diagnostics::warn(WarningKind::NotExtracted, format!("Skipped {}", skipped));
+    }
+    diagnostics::note(format!("Extracted {} to {}", path, output.display()));
+    Ok(())
+}
+
+/// Mirrors layer events onto the output directory
+struct ExtractSink {
+    /// Image paths to extract, without a leading "/" ("" for everything)
+    prefixes: Vec<String>,
+    output: PathBuf,
+    /// The merged tree so far, for following symlinked parents
+    tree: Node,
+    /// Directory modes, applied last so read-only directories can be filled
+    dir_modes: Vec<(PathBuf, u32)>,
+    summary: ExtractSummary,
+    /// For a dry run, what the output directory would hold instead of
+    /// writing it
+    plan: Option<BTreeMap<String, Planned>>,
+}
+
+impl ExtractSink {
+    fn new(paths: &[&str], output: &Path) -> Self {
+        ExtractSink {
+            prefixes: paths.iter().map(|path| utils::clean_path(path).path).collect(),
+            output: output.to_path_buf(),
+            tree: Node::new_dir(0o755, 0, 0),
+            dir_modes: Vec::new(),
+            summary: ExtractSummary::default(),
+            plan: None,
+        }
+    }
+
+    /// A sink that only records what it would write
+    fn planning(paths: &[&str]) -> Self {
+        ExtractSink { plan: Some(BTreeMap::new()), ..ExtractSink::new(paths, Path::new("")) }
+    }
+
+    /// Whether `path` is an extracted path or below one
+    fn wanted(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| within(path, prefix))
+    }
+
+    /// Where image path `path` goes in the output, with the bytes it had in the layer
+    fn local(&self, path: &str) -> PathBuf {
+        self.output.join(utils::os_path(path))
+    }
+
+    /// Delete the extracted paths below `dir`, which went away with it
+    fn remove_below(&mut self, dir: &str) -> Result<()> {
+        if let Some(plan) = &mut self.plan {
+            plan.retain(|path, _| !within(path, dir));
+            return Ok(());
+        }
+        for prefix in self.prefixes.iter().filter(|prefix| within(prefix, dir)) {
+            remove_existing(&self.local(prefix))?;
+        }
+        Ok(())
+    }
+
+    fn add_entry(&mut self, entry: &LayerEntry, content: Option<&[u8]>) -> Result<()> {
+        let path = self.tree.resolve_parents(&entry.path).into_owned();
+        let (mode, uid, gid) = (entry.mode, entry.uid, entry.gid);
+        match &entry.kind {
+            EntryKind::Dir => self.tree.ensure_path(&path, mode, uid, gid, None),
+            EntryKind::Symlink { target } => {
+                self.tree.put_file(&path, mode, uid, gid, true, target.clone(), None);
+            }
+            _ => {
+                self.tree.put_file(&path, mode, uid, gid, false, None, None);
+            }
+        }
+        if !self.wanted(&path) {
+            return Ok(());
+        }
+        if self.plan.is_some() {
+            self.plan_entry(&path, entry);
+            return Ok(());
+        }
+
+        let target = self.local(&path);
+        if !create_parents(&self.output, &target)? {
+            self.summary.skipped.push(format!("/{} (through a symlink chain too long to follow)",
+                utils::display_path(&path)));
+            return Ok(());
+        }
+        match &entry.kind {
+            EntryKind::Dir => {
+                if !fs::symlink_metadata(&target).is_ok_and(|meta| meta.is_dir()) {
+                    remove_existing(&target)?;
+                    fs::create_dir(&target).with_context(|| format!("Failed to create {}", target.display()))?;
+                }
+                self.dir_modes.push((target, mode));
+            }
+            EntryKind::File => {
+                remove_existing(&target)?;
+                fs::write(&target, content.unwrap_or_default())
+                    .with_context(|| format!("Failed to write {}", target.display()))?;
+                set_mode(&target, mode)?;
+            }
+            EntryKind::Symlink { target: Some(link) } => {
+                remove_existing(&target)?;
+                symlink(link, &target).with_context(|| format!("Failed to link {}", target.display()))?;
+            }
+            EntryKind::Hardlink { target: Some(link) } => {
+                let source = self.tree.resolve_parents(&utils::clean_path(link).path).into_owned();
+                let source_path = self.local(&source);
+                if !self.wanted(&source) || !fs::symlink_metadata(&source_path).is_ok_and(|meta| meta.is_file())
+                    || !no_links_between(&self.output, &source_path)
+                {
+                    self.summary.skipped.push(format!("/{} (hard link to /{}, which was not extracted)",
+                        utils::display_path(&path), utils::display_path(&source)));
+                    return Ok(());
+                }
+                remove_existing(&target)?;
+                fs::hard_link(&source_path, &target)
+                    .with_context(|| format!("Failed to link {}", target.display()))?;
+            }
+            EntryKind::Symlink { target: None } | EntryKind::Hardlink { target: None } => {
+                self.summary.skipped.push(format!("/{} (link without a target)", utils::display_path(&path)));
+                return Ok(());
+            }
+            EntryKind::Device(_) => {
+                self.summary.skipped.push(format!("/{} (device node)", utils::display_path(&path)));
+                return Ok(());
+            }
+        }
+        self.summary.written += 1;
+        Ok(())
+    }
+
+    /// Record what writing `entry` at `path` would leave in the output
+    fn plan_entry(&mut self, path: &str, entry: &LayerEntry) {
+        let Some(plan) = &mut self.plan else {
+            return;
+        };
+        let display = utils::display_path(path);
+        let mut parents = path.match_indices('/').map(|(end, _)| &path[..end]);
+        if parents.any(|parent| matches!(plan.get(parent), Some(Planned::Symlink { .. }))) {
+            self.summary.skipped.push(format!("/{} (through a symlink chain too long to follow)", display));
+            return;
+        }
+        let planned = match &entry.kind {
+            EntryKind::Dir => Planned::Dir,
+            EntryKind::File => Planned::File { size: entry.size },
+            EntryKind::Symlink { target: Some(target) } => Planned::Symlink { target: target.clone() },
+            EntryKind::Hardlink { target: Some(link) } => {
+                let source = self.tree.resolve_parents(&utils::clean_path(link).path).into_owned();
+                if !matches!(plan.get(&source), Some(Planned::File { .. })) {
+                    self.summary.skipped.push(format!("/{} (hard link to /{}, which was not extracted)",
+                        display, utils::display_path(&source)));
+                    return;
+                }
+                Planned::Hardlink { source }
+            }
+            EntryKind::Symlink { target: None } | EntryKind::Hardlink { target: None } => {
+                self.summary.skipped.push(format!("/{} (link without a target)", display));
+                return;
+            }
+            EntryKind::Device(_) => {
+                self.summary.skipped.push(format!("/{} (device node)", display));
+                return;
+            }
+        };
+        // A directory keeps what is already below it; anything else replaces it
+        if planned != Planned::Dir {
+            plan.retain(|other, _| !within(other, path));
+        }
+        plan.insert(path.to_string(), planned);
+        self.summary.written += 1;
+    }
+
+    /// Delete `path` from the output, or the extracted paths below it
+    fn remove(&mut self, path: &str) -> Result<()> {
+        if let Some(plan) = &mut self.plan {
+            plan.retain(|other, _| !within(other, path));
+            return Ok(());
+        }
+        if self.wanted(path) {
+            remove_existing(&self.local(path))
+        } else {
+            self.remove_below(path)
+        }
+    }
+
+    /// Check the extracted paths exist, and give directories their modes
+    fn finish(self) -> Result<ExtractSummary> {
+        for prefix in self.prefixes.iter().filter(|prefix| !prefix.is_empty()) {
+            if self.tree.get(prefix).is_some() {
+                continue;
+            }
+            let resolved = self.tree.resolve_parents(prefix);
+            if resolved != *prefix && self.tree.get(&resolved).is_some() {
+                bail!("/{} is reached through a symlink; extract /{} instead", prefix, resolved);
+            }
+            bail!("/{} is not in the image", prefix);
+        }
+        // Children before parents, so a read-only parent doesn't block them
+        for (dir, mode) in self.dir_modes.iter().rev() {
+            if fs::symlink_metadata(dir).is_ok_and(|meta| meta.is_dir()) {
+                set_mode(dir, *mode)?;
+            }
+        }
+        Ok(self.summary)
+    }
+}
+
+impl EventSink for ExtractSink {
+    fn wants_content(&self, entry: &LayerEntry) -> bool {
+        self.plan.is_none() && self.wanted(&self.tree.resolve_parents(&entry.path))
+    }
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::EntryAdded { entry, content } => self.add_entry(entry, *content)?,
+            LayerEvent::EntryRemoved { path } => {
+                let path = self.tree.resolve_parents(path).into_owned();
+                self.tree.remove(&path);
+                self.remove(&path)?;
+            }
+            LayerEvent::OpaqueApplied { dir } => {
+                // Resolve the directory itself too, not just its parents
+                let marker = format!("{}/{}", dir, whiteout::OPAQUE_WHITEOUT);
+                let marker = self.tree.resolve_parents(&marker).into_owned();
+                let dir = whiteout::opaque_dir(&marker);
+                self.tree.mark_opaque(dir, None, false);
+                if let Some(plan) = &mut self.plan {
+                    plan.retain(|path, _| path == dir || !within(path, dir));
+                } else if self.wanted(dir) {
+                    let target = self.local(dir);
+                    if fs::symlink_metadata(&target).is_ok_and(|meta| meta.is_dir()) {
+                        for child in fs::read_dir(&target)? {
+                            remove_existing(&child?.path())?;
+                        }
+                    }
+                } else {
+                    self.remove_below(dir)?;
+                }
+            }
+            LayerEvent::LayerStarted { .. } | LayerEvent::Suspicious { .. } | LayerEvent::LayerFinished { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is `dir` or below it ("" is the root, above everything)
+fn within(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path == dir || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Create the directories leading to `target` below `output` one at a time,
+/// returning false instead of going through a symlink on disk
+fn create_parents(output: &Path, target: &Path) -> Result<bool> {
+    let Some(parent) = target.parent().and_then(|parent| parent.strip_prefix(output).ok()) else {
+        return Ok(false);
+    };
+    let mut dir = output.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => return Ok(false),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {}", dir.display())),
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the directories leading to `path` below `output` exist without
+/// a symlink among them
+fn no_links_between(output: &Path, path: &Path) -> bool {
+    let Some(parent) = path.parent().and_then(|parent| parent.strip_prefix(output).ok()) else {
+        return false;
+    };
+    let mut dir = output.to_path_buf();
+    parent.components().all(|component| {
+        dir.push(component);
+        fs::symlink_metadata(&dir).is_ok_and(|meta| meta.is_dir())
+    })
+}
+
+/// Delete whatever is at `path` without following a symlink there
+fn remove_existing(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    };
+    result.with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(utils::os_path(target), path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks can only be extracted on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: EntryKind) -> LayerEntry {
+        LayerEntry {
+            path: path.to_string(),
+            kind,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            xattrs: Default::default(),
+            content_hash: None,
+        }
+    }
+
+    fn apply(sink: &mut ExtractSink, events: &[LayerEvent]) {
+        for event in events {
+            sink.handle(event).unwrap();
+        }
+    }
+
+    fn add<'a>(entry: &'a LayerEntry, content: Option<&'a [u8]>) -> LayerEvent<'a> {
+        LayerEvent::EntryAdded { entry, content }
+    }
+
+    #[test]
+    fn test_extract_follows_links_inside_the_image() {
+        let out = tempfile::tempdir().unwrap();
+        let mut sink = ExtractSink::new(&["/usr"], out.path());
+        let lib = entry("lib", EntryKind::Symlink { target: Some("/usr/lib".to_string()) });
+        let usr_lib = entry("usr/lib", EntryKind::Dir);
+        let libc = entry("lib/libc.so", EntryKind::File);
+        let hosts = entry("etc/hosts", EntryKind::File);
+        let old = entry("usr/lib/old.so", EntryKind::File);
+        apply(&mut sink, &[
+            add(&lib, None),
+            add(&usr_lib, None),
+            add(&libc, Some(b"elf")),
+            add(&hosts, Some(b"localhost")),
+            add(&old, Some(b"old")),
+            LayerEvent::EntryRemoved { path: "lib/old.so" },
+        ]);
+        let summary = sink.finish().unwrap();
+
+        assert_eq!(summary.written, 3);
+        assert_eq!(fs::read(out.path().join("usr/lib/libc.so")).unwrap(), b"elf");
+        assert!(!out.path().join("usr/lib/old.so").exists());
+        // Outside the extracted path
+        assert!(fs::symlink_metadata(out.path().join("lib")).is_err());
+        assert!(!out.path().join("etc").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_never_writes_through_links_on_disk() {
+        let out = tempfile::tempdir().unwrap();
+        let victim = tempfile::tempdir().unwrap();
+        let mut sink = ExtractSink::new(&["/"], out.path());
+        // Longer than resolve_parents follows, so the last link is taken for a directory
+        let mut links: Vec<LayerEntry> = (0..40)
+            .map(|i| entry(&format!("s{}", i), EntryKind::Symlink { target: Some(format!("s{}", i + 1)) }))
+            .collect();
+        let escape = victim.path().display().to_string();
+        links.push(entry("s40", EntryKind::Symlink { target: Some(escape) }));
+        let pwned = entry("s0/pwned", EntryKind::File);
+        let mut events: Vec<LayerEvent> = links.iter().map(|link| add(link, None)).collect();
+        events.push(add(&pwned, Some(b"owned")));
+        apply(&mut sink, &events);
+        let summary = sink.finish().unwrap();
+
+        assert_eq!(fs::read_dir(victim.path()).unwrap().count(), 0);
+        assert_eq!(summary.skipped.len(), 1, "{:?}", summary.skipped);
+        assert!(summary.skipped[0].ends_with("(through a symlink chain too long to follow)"));
+    }
+
+    #[test]
+    fn test_plan_writes_nothing() {
+        let mut sink = ExtractSink::planning(&["/"]);
+        let etc = entry("etc", EntryKind::Dir);
+        let hosts = LayerEntry { size: 9, ..entry("etc/hosts", EntryKind::File) };
+        let old = entry("etc/old", EntryKind::File);
+        let link = entry("etc/hosts.bak", EntryKind::Hardlink { target: Some("etc/hosts".to_string()) });
+        let missing = entry("etc/gone", EntryKind::Hardlink { target: Some("etc/old".to_string()) });
+        let device = crate::tree::Device { kind: crate::tree::DeviceKind::Char, major: 1, minor: 3 };
+        let null = entry("dev/null", EntryKind::Device(device));
+        // Longer than resolve_parents follows
+        let links: Vec<LayerEntry> = (0..41)
+            .map(|i| entry(&format!("s{}", i), EntryKind::Symlink { target: Some(format!("s{}", i + 1)) }))
+            .collect();
+        let escape = entry("s0/pwned", EntryKind::File);
+        assert!(!sink.wants_content(&hosts));
+        let mut events = vec![
+            add(&etc, None),
+            add(&hosts, None),
+            add(&old, None),
+            add(&link, None),
+            LayerEvent::EntryRemoved { path: "etc/old" },
+            add(&missing, None),
+            add(&null, None),
+        ];
+        events.extend(links.iter().map(|link| add(link, None)));
+        events.push(add(&escape, None));
+        apply(&mut sink, &events);
+        let plan = sink.plan.take().unwrap();
+        let summary = sink.finish().unwrap();
+
+        let entries: Vec<(&str, String)> = plan.iter()
+            .filter(|(path, _)| !path.starts_with('s'))
+            .map(|(path, planned)| (path.as_str(), planned.describe()))
+            .collect();
+        assert_eq!(entries, [
+            ("etc", "directory".to_string()),
+            ("etc/hosts", "file, 9 B".to_string()),
+            ("etc/hosts.bak", "hard link to /etc/hosts".to_string()),
+        ]);
+        assert_eq!(plan.len(), 3 + 41);
+        assert_eq!(summary.skipped, [
+            "/etc/gone (hard link to /etc/old, which was not extracted)",
+            "/dev/null (device node)",
+            "/s40/pwned (through a symlink chain too long to follow)",
+        ]);
+    }
+
+    #[test]
+    fn test_extract_opaque_and_missing_paths() {
+        let out = tempfile::tempdir().unwrap();
+        let mut sink = ExtractSink::new(&["etc", "root/.profile"], out.path());
+        let etc = entry("etc", EntryKind::Dir);
+        let passwd = entry("etc/passwd", EntryKind::File);
+        let group = entry("etc/group", EntryKind::File);
+        let profile = entry("root/.profile", EntryKind::File);
+        let bashrc = entry("root/.bashrc", EntryKind::File);
+        apply(&mut sink, &[
+            add(&etc, None),
+            add(&passwd, Some(b"root")),
+            add(&profile, Some(b"PS1")),
+            add(&bashrc, Some(b"alias")),
+            LayerEvent::OpaqueApplied { dir: "etc" },
+            add(&group, Some(b"wheel")),
+        ]);
+        sink.finish().unwrap();
+        assert!(!out.path().join("etc/passwd").exists());
+        assert_eq!(fs::read(out.path().join("etc/group")).unwrap(), b"wheel");
+        assert_eq!(fs::read(out.path().join("root/.profile")).unwrap(), b"PS1");
+        assert!(!out.path().join("root/.bashrc").exists());
+
+        let sink = ExtractSink::new(&["/", "/var"], out.path());
+        let err = sink.finish().unwrap_err();
+        assert_eq!(err.to_string(), "/var is not in the image");
+    }
+}
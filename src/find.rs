@@ -0,0 +1,175 @@
+//! Files located by content digest, size, or name, for `contree find`
+//!
+//! Every regular file of every layer is considered, including ones a later
+//! layer deleted or overwrote, since their bytes still ship with the image.
+//! Contents are only read for files whose size and name already match, so
+//! narrowing a digest search with `--size` or `--name` makes it fast.
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+
+use crate::analyzer::{self, Analyzer, EntryInfo, Finding};
+use crate::archive::LayerSet;
+use crate::utils;
+
+/// What the files to find have in common; every criterion given must match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    /// Hex SHA-256 of the contents, lowercase
+    pub sha256: Option<String>,
+    /// Exact size in bytes
+    pub size: Option<u64>,
+    /// Pattern for the basename, or for the whole path when it has a `/`
+    /// (`*` and `?` are wildcards)
+    pub name: Option<String>,
+}
+
+impl Query {
+    /// A query for the given criteria, at least one of them; the digest may
+    /// carry a `sha256:` prefix
+    pub fn new(sha256: Option<&str>, size: Option<u64>, name: Option<&str>) -> Result<Self> {
+        if sha256.is_none() && size.is_none() && name.is_none() {
+            bail!("Give at least one of --sha256, --size and --name");
+        }
+        let sha256 = match sha256 {
+            Some(digest) => {
+                let hex = digest.strip_prefix("sha256:").unwrap_or(digest).to_ascii_lowercase();
+                if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    bail!("Invalid SHA-256 digest '{}' (expected 64 hex digits)", digest);
+                }
+                Some(hex)
+            }
+            None => None,
+        };
+        let name = name.map(|name| name.trim_start_matches('/').to_string());
+        Ok(Query { sha256, size, name })
+    }
+
+    /// Whether a file could match before its contents are read
+    fn matches_entry(&self, path: &str, size: u64) -> bool {
+        if self.size.is_some_and(|wanted| wanted != size) {
+            return false;
+        }
+        match &self.name {
+            Some(pattern) if pattern.contains('/') => utils::glob_match(pattern, path),
+            Some(pattern) => utils::glob_match(pattern, path.rsplit('/').next().unwrap_or(path)),
+            None => true,
+        }
+    }
+}
+
+/// A stored file that matches the query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Path inside the layer, without a leading "/"
+    pub path: String,
+    /// Label of the layer that stores the file
+    pub layer: String,
+    pub size: u64,
+    /// Hex SHA-256 of the contents
+    pub sha256: String,
+}
+
+/// Collects the files that match a query, in layer order
+pub struct FindAnalyzer {
+    query: Query,
+    matches: Vec<Match>,
+    /// Digest of the file being read
+    hasher: Sha256,
+}
+
+impl FindAnalyzer {
+    pub fn new(query: Query) -> Self {
+        FindAnalyzer { query, matches: Vec::new(), hasher: Sha256::new() }
+    }
+
+    pub fn into_matches(self) -> Vec<Match> {
+        self.matches
+    }
+}
+
+impl Analyzer for FindAnalyzer {
+    fn name(&self) -> &'static str {
+        "find"
+    }
+
+    fn wants(&self, entry: &EntryInfo) -> bool {
+        self.query.matches_entry(entry.path, entry.size)
+    }
+
+    fn content_limit(&self, _entry: &EntryInfo) -> u64 {
+        0
+    }
+
+    fn read_chunk(&mut self, _entry: &EntryInfo, chunk: &[u8]) {
+        self.hasher.update(chunk);
+    }
+
+    fn analyze(&mut self, entry: &EntryInfo, _content: &[u8]) -> Vec<Finding> {
+        let sha256 = format!("{:x}", self.hasher.finalize_reset());
+        if self.query.sha256.as_ref().is_none_or(|wanted| *wanted == sha256) {
+            self.matches.push(Match {
+                path: entry.path.to_string(),
+                layer: entry.layer.to_string(),
+                size: entry.size,
+                sha256,
+            });
+        }
+        Vec::new()
+    }
+}
+
+/// Every stored file of the image that matches `query`
+pub fn find_files(layer_set: &LayerSet, query: Query) -> Result<Vec<Match>> {
+    let mut analyzer = FindAnalyzer::new(query);
+    analyzer::scan(layer_set, &mut [&mut analyzer])?;
+    Ok(analyzer.into_matches())
+}
+
+/// One line per match with its layer, size and digest, then a count
+pub fn write_report<W: Write>(out: &mut W, matches: &[Match]) -> io::Result<()> {
+    for found in matches {
+        writeln!(out, "[{}] /{}  {}  sha256:{}",
+            found.layer, utils::display_path(&found.path), utils::human_size(found.size), &found.sha256[..12])?;
+    }
+    writeln!(out, "{} match{}", matches.len(), if matches.len() == 1 { "" } else { "es" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        assert!(Query::new(None, None, None).is_err());
+        assert!(Query::new(Some("sha256:abc"), None, None).is_err());
+        let digest = "A".repeat(64);
+        let query = Query::new(Some(&format!("sha256:{}", digest)), Some(4), Some("/etc/*")).unwrap();
+        assert_eq!(query.sha256, Some("a".repeat(64)));
+        assert!(query.matches_entry("etc/motd", 4));
+        assert!(!query.matches_entry("etc/motd", 5));
+        assert!(!query.matches_entry("usr/etc/motd", 4));
+    }
+
+    #[test]
+    fn test_find_analyzer() {
+        let hello = format!("{:x}", Sha256::digest(b"hello"));
+        let mut analyzer = FindAnalyzer::new(Query::new(Some(&hello), None, None).unwrap());
+        for (path, layer, content) in [("a.txt", "aaaaaaa", &b"hello"[..]), ("b.txt", "bbbbbbb", b"world"),
+            ("c/a.txt", "bbbbbbb", b"hello")] {
+            let info = EntryInfo { path, layer, size: content.len() as u64, mode: 0o644 };
+            assert!(analyzer.wants(&info));
+            analyzer.read_chunk(&info, content);
+            analyzer.analyze(&info, &[]);
+        }
+        let matches = analyzer.into_matches();
+        let found: Vec<_> = matches.iter().map(|m| (m.layer.as_str(), m.path.as_str())).collect();
+        assert_eq!(found, [("aaaaaaa", "a.txt"), ("bbbbbbb", "c/a.txt")]);
+
+        let mut out = Vec::new();
+        write_report(&mut out, &matches[..1]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            format!("[aaaaaaa] /a.txt  5 B  sha256:{}\n1 match\n", &hello[..12]));
+    }
+}
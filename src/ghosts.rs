@@ -0,0 +1,257 @@
+//! Files deleted by whiteouts that still ship in a lower layer
+//!
+//! A whiteout only hides a file from the merged tree; the layer that added it
+//! is unchanged, so the bytes are still downloaded and anyone with the image
+//! can read them. Credentials removed in a later `RUN rm` and package caches
+//! cleaned in a separate step are the usual culprits.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::events::{self, EntryKind, EventSink, LayerEvent, ReadOptions};
+use crate::keys::{self, KeyKind};
+use crate::utils;
+
+/// Directories whose contents are caches that should not outlive a build step
+const CACHE_DIRS: &[&str] = &[
+    "var/cache/",
+    "var/lib/apt/lists/",
+    "root/.cache/",
+    "root/.npm/",
+    "tmp/",
+];
+
+/// File names that usually hold credentials
+const CREDENTIAL_NAMES: &[&str] = &[
+    ".env", ".netrc", ".pgpass", ".npmrc", ".pypirc", ".git-credentials", "credentials",
+];
+
+/// A deleted file still stored in the layer that added it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ghost {
+    /// Path inside the layer, without a leading "/"
+    pub path: String,
+    pub size: u64,
+    /// Label of the layer that stores the file
+    pub added: String,
+    /// Label of the layer whose whiteout hid it
+    pub deleted: String,
+}
+
+impl Ghost {
+    /// Why the file deserves a look, if its path suggests anything
+    pub fn note(&self) -> Option<&'static str> {
+        let basename = self.path.rsplit('/').next().unwrap_or(&self.path);
+        let env_file = basename.starts_with(".env.") || basename.ends_with(".env");
+        if CREDENTIAL_NAMES.contains(&basename) || env_file {
+            return Some("credentials");
+        }
+        if let Some((kind, _)) = keys::classify(&self.path, None) {
+            if kind != KeyKind::Certificate {
+                return Some("key material");
+            }
+        }
+        if CACHE_DIRS.iter().any(|dir| self.path.starts_with(dir) || self.path.contains(&format!("/{}", dir))) {
+            return Some("cache");
+        }
+        None
+    }
+}
+
+/// List deleted files still stored in lower layers, largest first
+pub fn run(archive_path: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let mut sink = GhostSink::default();
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_report(&mut out, &sink.into_ghosts())?;
+    out.flush()?;
+    Ok(())
+}
+
+/// A regular file visible in the merged tree so far
+struct Present {
+    index: usize,
+    layer: String,
+    size: u64,
+}
+
+/// Tracks visible files across layers and collects the ones whiteouts hide
+#[derive(Default)]
+pub struct GhostSink {
+    layer: String,
+    index: usize,
+    present: BTreeMap<String, Present>,
+    ghosts: Vec<Ghost>,
+}
+
+impl GhostSink {
+    /// Ghosts sorted by size, largest first, then by path
+    pub fn into_ghosts(self) -> Vec<Ghost> {
+        let mut ghosts = self.ghosts;
+        ghosts.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        ghosts
+    }
+
+    /// Paths of present files at `path` or below it
+    fn matching(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        let below = self.present.range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .map(|(p, _)| p.clone());
+        self.present.contains_key(path).then(|| path.to_string()).into_iter().chain(below).collect()
+    }
+
+    /// Stop tracking files at `path` and below, recording them as ghosts when
+    /// `deleted`; `lower_only` spares files added by the current layer
+    fn hide(&mut self, path: &str, deleted: bool, lower_only: bool) {
+        for hidden in self.matching(path) {
+            if lower_only && self.present[&hidden].index == self.index {
+                continue;
+            }
+            let file = self.present.remove(&hidden).expect("matched path is present");
+            if deleted && file.size > 0 {
+                self.ghosts.push(Ghost { path: hidden, size: file.size, added: file.layer, deleted: self.layer.clone() });
+            }
+        }
+    }
+}
+
+impl EventSink for GhostSink {
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { index, layer, .. } => {
+                self.layer = layer.label();
+                self.index = *index;
+            }
+            LayerEvent::EntryAdded { entry, .. } => {
+                // Overwritten files are replaced, not deleted; a directory
+                // keeps what's below it
+                if entry.kind == EntryKind::Dir {
+                    self.present.remove(&entry.path);
+                } else {
+                    self.hide(&entry.path, false, false);
+                }
+                if entry.kind == EntryKind::File {
+                    self.present.insert(entry.path.clone(), Present {
+                        index: self.index,
+                        layer: self.layer.clone(),
+            size: entry.size,
+                    });
+                }
+            }
+            LayerEvent::EntryRemoved { path } => self.hide(path, true, false),
+            LayerEvent::OpaqueApplied { dir } => self.hide(dir, true, true),
+            LayerEvent::Suspicious { .. } | LayerEvent::LayerFinished { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn write_report<W: Write>(out: &mut W, ghosts: &[Ghost]) -> io::Result<()> {
+    if ghosts.is_empty() {
+        return writeln!(out, "No deleted files are still stored in lower layers");
+    }
+
+    writeln!(out, "{:>10}  {:<7}  {:<7}  {:<12}  PATH", "SIZE", "ADDED", "DELETED", "NOTE")?;
+    for ghost in ghosts {
+        writeln!(out, "{:>10}  {:<7}  {:<7}  {:<12}  /{}", utils::human_size(ghost.size),
+            ghost.added, ghost.deleted, ghost.note().unwrap_or("-"), ghost.path)?;
+    }
+
+    let total: u64 = ghosts.iter().map(|g| g.size).sum();
+    let noun = if ghosts.len() == 1 { "file" } else { "files" };
+    writeln!(out, "\n{} deleted {} still stored in lower layers ({})", ghosts.len(), noun, utils::human_size(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Blob, Compression, Layer};
+    use crate::events::LayerEntry;
+    use std::path::PathBuf;
+
+    fn entry(path: &str, kind: EntryKind, size: u64) -> LayerEntry {
+        LayerEntry {
+            path: path.to_string(),
+            kind,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size,
+            xattrs: Default::default(),
+            content_hash: None,
+        }
+    }
+
+    fn start(sink: &mut GhostSink, index: usize, name: &str) {
+        let layer = Layer {
+            name: format!("{}/layer.tar", name),
+            blob: Blob::File(PathBuf::new()),
+            diff_id: None,
+            compression: Compression::None,
+        };
+        sink.handle(&LayerEvent::LayerStarted { index, count: 3, layer: &layer }).unwrap();
+    }
+
+    fn add(sink: &mut GhostSink, path: &str, size: u64) {
+        sink.handle(&LayerEvent::EntryAdded { entry: &entry(path, EntryKind::File, size), content: None }).unwrap();
+    }
+
+    #[test]
+    fn test_ghost_sink() {
+        let mut sink = GhostSink::default();
+        start(&mut sink, 0, "aaaaaaa");
+        add(&mut sink, "app/.env", 100);
+        add(&mut sink, "app/main", 5000);
+        add(&mut sink, "var/cache/apt/pkgcache.bin", 30000);
+        add(&mut sink, "var/cache/apt/srcpkgcache.bin", 20000);
+        add(&mut sink, "etc/motd", 10);
+
+        start(&mut sink, 1, "bbbbbbb");
+        // Overwriting is not deleting
+        add(&mut sink, "etc/motd", 12);
+        sink.handle(&LayerEvent::EntryRemoved { path: "app/.env" }).unwrap();
+        sink.handle(&LayerEvent::EntryRemoved { path: "var/cache/apt" }).unwrap();
+
+        start(&mut sink, 2, "ccccccc");
+        add(&mut sink, "app/config", 7);
+        sink.handle(&LayerEvent::OpaqueApplied { dir: "app" }).unwrap();
+
+        let ghosts: Vec<(String, u64, String, Option<&str>)> = sink.into_ghosts().into_iter()
+            .map(|g| (g.path.clone(), g.size, g.deleted.clone(), g.note()))
+            .collect();
+        assert_eq!(ghosts, vec![
+            ("var/cache/apt/pkgcache.bin".to_string(), 30000, "bbbbbbb".to_string(), Some("cache")),
+            ("var/cache/apt/srcpkgcache.bin".to_string(), 20000, "bbbbbbb".to_string(), Some("cache")),
+            ("app/main".to_string(), 5000, "ccccccc".to_string(), None),
+            ("app/.env".to_string(), 100, "bbbbbbb".to_string(), Some("credentials")),
+        ]);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let ghosts = vec![Ghost {
+            path: "root/.ssh/id_rsa".to_string(),
+            size: 2048,
+            added: "aaaaaaa".to_string(),
+            deleted: "bbbbbbb".to_string(),
+        }];
+        let mut out = Vec::new();
+        write_report(&mut out, &ghosts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "      SIZE  ADDED    DELETED  NOTE          PATH\n",
+            "   2.0 KiB  aaaaaaa  bbbbbbb  key material  /root/.ssh/id_rsa\n",
+            "\n1 deleted file still stored in lower layers (2.0 KiB)\n",
+        ));
+    }
+}
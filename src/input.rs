@@ -0,0 +1,272 @@
+//! Finding an archive to open when none is named on the command line, and
+//! the image to read when an archive holds several
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, terminal};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::NamedTempFile;
+#[cfg(feature = "remote")]
+use tempfile::TempDir;
+
+#[cfg(feature = "remote")]
+use contree::{oci, registry};
+use contree::source::ImageSummary;
+use contree::{diagnostics, tempspace};
+
+/// Archive files in `dir` that contree can open, sorted by name
+pub fn find_candidates(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.to_string_lossy();
+            name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+        })
+        .collect();
+    candidates.sort();
+    Ok(candidates)
+}
+
+/// Pick the archive in the current directory when none was given
+///
+/// A single candidate is used without asking when `auto` is set, and after
+/// a confirmation prompt when running interactively.
+pub fn detect_archive(auto: bool) -> Result<PathBuf> {
+    let candidates = find_candidates(Path::new("."))?;
+
+    match candidates.as_slice() {
+        [only] if auto => {
+            diagnostics::note(format!("Using {}", only.display()));
+            Ok(only.clone())
+        }
+        [only] if is_interactive() => {
+            if confirm(&format!("No archive given. Use {}?", only.display()))? {
+                Ok(only.clone())
+            } else {
+                bail!("No archive given");
+            }
+        }
+        [only] => bail!("No archive given (found {}; pass it explicitly or use --auto-detect-input)",
+            only.display()),
+        [] => bail!("No archive given and no *.tar files in the current directory"),
+        many => {
+            let names: Vec<String> = many.iter().map(|p| p.display().to_string()).collect();
+            bail!("No archive given and several candidates found: {}", names.join(", "))
+        }
+    }
+}
+
+/// Whether there is someone at a terminal to ask
+pub fn is_interactive() -> bool {
+    atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stderr)
+}
+
+/// Ask a yes/no question on stderr; an empty answer means yes
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [Y/n] ", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "" | "y" | "yes"))
+}
+
+/// Save the most recently created image from the local Docker daemon
+/// Returns a description of the image and the archive it was saved to.
+pub fn save_last_image() -> Result<(String, NamedTempFile)> {
+    let listing = Command::new("docker")
+        .args(["image", "ls", "--format", "{{.ID}} {{.Repository}}:{{.Tag}}"])
+        .output()
+        .context("Failed to run docker (is it installed?)")?;
+    if !listing.status.success() {
+        bail!("Cannot list Docker images: {}", String::from_utf8_lossy(&listing.stderr).trim());
+    }
+
+    // `docker image ls` lists the newest image first
+    let stdout = String::from_utf8_lossy(&listing.stdout);
+    let (id, name) = parse_image_line(stdout.lines().next().unwrap_or_default())
+        .context("The Docker daemon has no images")?;
+
+    let archive = tempfile::Builder::new()
+        .prefix("contree-last-")
+        .suffix(".tar")
+        .tempfile_in(tempspace::dir())
+        .context("Failed to create temp file")?;
+    let status = Command::new("docker")
+        .args(["save", "-o"])
+        .arg(archive.path())
+        .arg(id)
+        .status()
+        .context("Failed to run docker save")?;
+    if !status.success() {
+        bail!("docker save {} failed", id);
+    }
+
+    let description = match name {
+        Some(name) => format!("{} ({})", name, id),
+        None => id.to_string(),
+    };
+    Ok((description, archive))
+}
+
+/// Pull the image a `docker://` reference names, for this machine's
+/// platform, into a temporary directory
+#[cfg(feature = "remote")]
+pub fn pull_image(spec: &str) -> Result<TempDir> {
+    let reference = registry::Reference::parse(spec)?;
+    let dir = tempfile::Builder::new()
+        .prefix("contree-pull-")
+        .tempdir_in(tempspace::dir())
+        .context("Failed to create temp directory")?;
+    diagnostics::note(format!("Pulling {}", reference));
+    registry::pull(&reference, &oci::Platform::native(), dir.path())?;
+    Ok(dir)
+}
+
+/// Ask on stderr which of an archive's images to read, returning its position
+///
+/// Up and down (or k and j) move the marker and digits jump to an image by
+/// its number, which is what `--image` takes; Enter picks, Esc or q cancels.
+pub fn choose_image(images: &[ImageSummary]) -> Result<usize> {
+    let rows = image_rows(images);
+    let mut out = io::stderr();
+    writeln!(out, "The archive holds {} images. Which one? (--image N skips this)", images.len())?;
+
+    let raw = RawMode::enable()?;
+    let mut selected = 0;
+    let mut typed = String::new();
+    loop {
+        for (index, row) in rows.iter().enumerate() {
+            let marker = if index == selected { '>' } else { ' ' };
+            queue!(out, terminal::Clear(terminal::ClearType::CurrentLine))?;
+            write!(out, "\r{} {}\r\n", marker, row)?;
+        }
+        out.flush()?;
+
+        let Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. }) = event::read()? else {
+            queue!(out, cursor::MoveUp(rows.len() as u16))?;
+            continue;
+        };
+        let number = |typed: &str| typed.parse().ok().filter(|&number: &usize| number < rows.len());
+        match code {
+            KeyCode::Enter => break,
+            KeyCode::Esc | KeyCode::Char('q') => bail!("No image chosen"),
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => bail!("No image chosen"),
+            KeyCode::Char(digit) if digit.is_ascii_digit() => {
+                // Digits add up to a number for as long as it names an image
+                typed.push(digit);
+                if number(&typed).is_none() {
+                    typed = digit.to_string();
+                }
+                match number(&typed) {
+                    Some(number) => selected = number,
+                    None => typed.clear(),
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                selected = selected.checked_sub(1).unwrap_or(rows.len() - 1);
+                typed.clear();
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                selected = (selected + 1) % rows.len();
+                typed.clear();
+            }
+            _ => {}
+        }
+        queue!(out, cursor::MoveUp(rows.len() as u16))?;
+    }
+    drop(raw);
+    Ok(selected)
+}
+
+/// One line per image, numbered from 0: tags, platform and layer count in columns
+fn image_rows(images: &[ImageSummary]) -> Vec<String> {
+    let tags: Vec<String> = images.iter()
+        .map(|image| if image.tags.is_empty() { "(untagged)".to_string() } else { image.tags.join(", ") })
+        .collect();
+    let platforms: Vec<String> = images.iter()
+        .map(|image| image.platform.as_ref().map(|p| p.to_string()).unwrap_or_default())
+        .collect();
+    let number_width = (images.len() - 1).to_string().len();
+    let tags_width = tags.iter().map(|t| t.chars().count()).max().unwrap_or(0);
+    let platform_width = platforms.iter().map(|p| p.len()).max().unwrap_or(0);
+    images.iter().enumerate()
+        .map(|(index, image)| {
+            let layers = if image.layers == 1 { "1 layer".to_string() } else { format!("{} layers", image.layers) };
+            format!("{:>nw$}  {:<tw$}  {:<pw$}  {}", index, tags[index], platforms[index], layers,
+                nw = number_width, tw = tags_width, pw = platform_width)
+        })
+        .collect()
+}
+
+/// Raw terminal mode, so keys arrive one by one, for as long as it is held
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Split an `ID REPOSITORY:TAG` line; untagged images have no name
+fn parse_image_line(line: &str) -> Option<(&str, Option<&str>)> {
+    let mut parts = line.split_whitespace();
+    let id = parts.next()?;
+    let name = parts.next().filter(|n| *n != "<none>:<none>");
+    Some((id, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_candidates() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.tar", "a.tar.gz", "notes.txt", "c.tgz"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("dir.tar")).unwrap();
+
+        let names: Vec<String> = find_candidates(dir.path()).unwrap().iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["a.tar.gz", "b.tar", "c.tgz"]);
+    }
+
+    #[test]
+    fn test_image_rows() {
+        let image = |tags: &[&str], platform: Option<&str>, layers| ImageSummary {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            platform: platform.map(|p| contree::oci::Platform::parse(p).unwrap()),
+            layers,
+        };
+        let rows = image_rows(&[
+            image(&["app:1.0"], Some("linux/amd64"), 3),
+            image(&[], Some("linux/arm64/v8"), 1),
+        ]);
+        assert_eq!(rows, vec![
+            "0  app:1.0     linux/amd64     3 layers",
+            "1  (untagged)  linux/arm64/v8  1 layer",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_image_line() {
+        assert_eq!(parse_image_line("3f57d9401f8d alpine:3.20"), Some(("3f57d9401f8d", Some("alpine:3.20"))));
+        assert_eq!(parse_image_line("0123abcd <none>:<none>"), Some(("0123abcd", None)));
+        assert_eq!(parse_image_line(""), None);
+    }
+}
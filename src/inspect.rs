@@ -0,0 +1,149 @@
+//! What an image is and how containers start from it
+//!
+//! `contree inspect` reads only the manifest and the image config, so it
+//! answers quickly even for large images: tags, digest, platform, layer
+//! count, and the runtime defaults (entrypoint, command, environment, ...).
+
+use anyhow::Result;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::LayerSet;
+use crate::config::ContainerConfig;
+use crate::oci::Platform;
+use crate::progress::TermProgress;
+use crate::source::{self, ImageSelector};
+use crate::utils;
+
+/// The identity and runtime defaults of an image
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Inspection {
+    pub tags: Vec<String>,
+    /// Image ID (`sha256:` digest of the config), when the archive names it
+    pub digest: Option<String>,
+    /// os/architecture[/variant]
+    pub platform: Option<String>,
+    pub layers: usize,
+    /// Total size of the layer blobs as stored
+    pub size: u64,
+    /// Runtime defaults, with Docker's field names
+    pub config: ContainerConfig,
+}
+
+impl Inspection {
+    pub fn of(layer_set: &LayerSet) -> Self {
+        let config = layer_set.config.as_ref();
+        let platform = config.and_then(|config| {
+            Some(Platform {
+                os: config.os.clone()?,
+                architecture: config.architecture.clone()?,
+                variant: config.variant.clone(),
+            })
+        });
+        Inspection {
+            tags: layer_set.repo_tags.clone(),
+            digest: layer_set.digest(),
+            platform: platform.map(|p| p.to_string()),
+            layers: layer_set.layers.len(),
+            size: layer_set.layers.iter().map(|l| l.blob.size()).sum(),
+            config: config.map(|c| c.config.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Print the inspection of an archive as text or JSON; `image` picks one of
+/// several by tag or position, else the first is inspected
+pub fn run(archive_path: &Path, format: &str, image: Option<&str>) -> Result<()> {
+    let select = ImageSelector { image: image.map(str::to_string), ..Default::default() };
+    let layer_set = source::for_path(archive_path).open_layers(&select, &mut TermProgress::disabled())?;
+    let inspection = Inspection::of(&layer_set);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if format == "json" {
+        serde_json::to_writer_pretty(&mut out, &inspection)?;
+        writeln!(out)?;
+    } else {
+        write_text(&mut out, &inspection)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// One `Label: value` line per field, with lists continued on the lines below
+pub fn write_text<W: Write>(out: &mut W, inspection: &Inspection) -> io::Result<()> {
+    let config = &inspection.config;
+    let tags = if inspection.tags.is_empty() { "(untagged)".to_string() } else { inspection.tags.join(", ") };
+    // Exec-form commands read best as the JSON arrays they are
+    let exec_form = |args: &Option<Vec<String>>| {
+        args.as_ref().map(|args| serde_json::to_string(args).unwrap_or_default())
+    };
+    let keys = |map: &Option<std::collections::BTreeMap<String, serde_json::Value>>| {
+        map.as_ref().map(|map| map.keys().cloned().collect::<Vec<_>>().join(", "))
+    };
+    let labels = config.labels.as_ref()
+        .map(|labels| labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>());
+
+    let fields = [
+        ("Image", vec![tags]),
+        ("Digest", inspection.digest.clone().into_iter().collect()),
+        ("Platform", inspection.platform.clone().into_iter().collect()),
+        ("Layers", vec![format!("{}, {}", inspection.layers, utils::human_size(inspection.size))]),
+        ("Entrypoint", exec_form(&config.entrypoint).into_iter().collect()),
+        ("Cmd", exec_form(&config.cmd).into_iter().collect()),
+        ("WorkingDir", config.working_dir.clone().into_iter().filter(|d| !d.is_empty()).collect()),
+        ("User", config.user.clone().into_iter().filter(|u| !u.is_empty()).collect()),
+        ("Env", config.env.clone().unwrap_or_default()),
+        ("Ports", keys(&config.exposed_ports).into_iter().collect()),
+        ("Volumes", keys(&config.volumes).into_iter().collect()),
+        ("Labels", labels.unwrap_or_default()),
+    ];
+    for (label, values) in fields {
+        let mut label = format!("{}:", label);
+        for value in values {
+            writeln!(out, "{:<12}{}", label, value)?;
+            label.clear();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_text() {
+        let config: ContainerConfig = serde_json::from_str(r#"{
+            "Env": ["PATH=/usr/bin", "LANG=C.UTF-8"],
+            "Entrypoint": ["/app/server"],
+            "Cmd": null,
+            "WorkingDir": "/app",
+            "ExposedPorts": {"8080/tcp": {}},
+            "Labels": {"version": "1.0"}
+        }"#).unwrap();
+        let inspection = Inspection {
+            tags: vec!["app:1.0".to_string()],
+            digest: Some("sha256:abc".to_string()),
+            platform: Some("linux/amd64".to_string()),
+            layers: 2,
+            size: 3 << 20,
+            config,
+        };
+        let mut out = Vec::new();
+        write_text(&mut out, &inspection).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "Image:      app:1.0\n",
+            "Digest:     sha256:abc\n",
+            "Platform:   linux/amd64\n",
+            "Layers:     2, 3.0 MiB\n",
+            "Entrypoint: [\"/app/server\"]\n",
+            "WorkingDir: /app\n",
+            "Env:        PATH=/usr/bin\n",
+            "            LANG=C.UTF-8\n",
+            "Ports:      8080/tcp\n",
+            "Labels:     version=1.0\n",
+        ));
+    }
+}
@@ -0,0 +1,280 @@
+//! What a single layer changed relative to the layers below it
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, ProcessOptions, TreeBuilder};
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::tree::Node;
+use crate::utils;
+use crate::whiteout;
+
+/// Kind of change a layer made to a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Change {
+    /// Path did not exist below this layer
+    #[serde(rename = "A")]
+    Added,
+    /// Path existed below and was replaced or had its metadata changed
+    #[serde(rename = "M")]
+    Modified,
+    /// Path existed below and was removed by a whiteout
+    #[serde(rename = "D")]
+    Deleted,
+}
+
+impl Change {
+    pub fn letter(&self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Modified => 'M',
+            Change::Deleted => 'D',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathChange {
+    pub change: Change,
+    pub path: String,
+}
+
+/// Result of diffing one layer against the stack below it
+#[derive(Debug, Serialize)]
+pub struct LayerDiff {
+    /// Position of the layer in the manifest (0 = bottom)
+    pub index: usize,
+    /// Layer name as listed in the manifest
+    pub layer: String,
+    /// Changes sorted by path
+    pub changes: Vec<PathChange>,
+}
+
+/// Compute the changes layer `index` makes on top of the layers below it,
+/// at the paths they end up at in the merged tree (through symlinked parent
+/// directories, unless `options` says `literal_paths`)
+pub fn layer_diff(archive_path: &Path, index: usize, options: &ProcessOptions) -> Result<LayerDiff> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let layer = layer_set.layers.get(index).with_context(|| {
+        format!("Layer index {} out of range (archive has {} layers)", index, layer_set.layers.len())
+    })?;
+
+    let below = archive::merge_layers(&layer_set.layers[..index], options)?;
+    let mut sink = DiffSink::new(&below, options);
+    let read_options = ReadOptions { hash_contents: false, strict: options.strict, whiteouts: options.whiteouts };
+    events::read_layer(layer, index, layer_set.layers.len(), read_options, &mut [&mut sink], None)?;
+
+    Ok(LayerDiff {
+        index,
+        layer: layer.name.clone(),
+        changes: sink.changes.into_iter().map(|(path, change)| PathChange { change, path }).collect(),
+    })
+}
+
+/// Classifies the events of one layer against the tree of the layers below it
+struct DiffSink<'a> {
+    below: &'a Node,
+    /// The tree as the layer is applied, for resolving paths the way the merge does
+    merged: TreeBuilder<'a>,
+    changes: BTreeMap<String, Change>,
+}
+
+impl<'a> DiffSink<'a> {
+    fn new(below: &'a Node, options: &'a ProcessOptions) -> Self {
+        DiffSink { below, merged: TreeBuilder::on(below.clone(), options), changes: BTreeMap::new() }
+    }
+}
+
+impl EventSink for DiffSink<'_> {
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::EntryAdded { entry, .. } => {
+                let path = self.merged.resolve(&entry.path);
+                record_added(self.below, &mut self.changes, &path, entry);
+            }
+            LayerEvent::EntryRemoved { path } => {
+                let path = self.merged.resolve(path);
+                if self.below.get(&path).is_some() {
+                    self.changes.insert(path.into_owned(), Change::Deleted);
+                }
+            }
+            LayerEvent::OpaqueApplied { dir } => {
+                // Resolve the directory itself too, not just its parents
+                let marker = format!("{}/{}", dir, whiteout::OPAQUE_WHITEOUT);
+                let marker = self.merged.resolve(&marker);
+                let dir = whiteout::opaque_dir(&marker);
+                // Everything previously inside the directory is masked
+                if let Some(node) = self.below.get(dir) {
+                    for name in node.children.keys() {
+                        let child = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+                        self.changes.insert(child, Change::Deleted);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.merged.handle(event)
+    }
+}
+
+/// Classify an entry the layer writes at `path` against the tree below it
+fn record_added(below: &Node, changes: &mut BTreeMap<String, Change>, path: &str, entry: &LayerEntry) {
+    let change = match below.get(path) {
+        None => Change::Added,
+        Some(existing) if entry.kind == EntryKind::Dir && !existing.metadata.is_file => {
+            // Layers repeat parent directories of changed files; only report real changes
+            let meta = &existing.metadata;
+            if meta.mode & 0o7777 == entry.mode & 0o7777 && meta.uid == entry.uid && meta.gid == entry.gid {
+                return;
+            }
+            Change::Modified
+        }
+        Some(_) => Change::Modified,
+    };
+
+    // A path deleted by an opaque marker and re-added in the same layer was modified
+    let change = match changes.get(path) {
+        Some(Change::Deleted) => Change::Modified,
+        _ => change,
+    };
+    changes.insert(path.to_string(), change);
+}
+
+/// Print changes as `A path` lines
+pub fn render_text(diff: &LayerDiff) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for change in &diff.changes {
+        writeln!(out, "{} {}", change.change.letter(), utils::display_path(&change.path))?;
+    }
+    out.flush()
+}
+
+/// Print the diff as pretty JSON
+pub fn render_json(diff: &LayerDiff) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, diff)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn below() -> Node {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.ensure_path("etc", 0o755, 0, 0, None);
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        root.put_file("var/cache/a", 0o644, 0, 0, false, None, None);
+        root.put_file("var/cache/b", 0o644, 0, 0, false, None, None);
+        root
+    }
+
+    fn entry(path: &str, kind: EntryKind, mode: u32) -> LayerEntry {
+        LayerEntry {
+            path: path.to_string(),
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            xattrs: Default::default(),
+            content_hash: None,
+        }
+    }
+
+    fn classify(events: &[LayerEvent]) -> Vec<String> {
+        let below = below();
+        let options = ProcessOptions::default();
+        let mut sink = DiffSink::new(&below, &options);
+        for event in events {
+            sink.handle(event).unwrap();
+        }
+        sink.changes.into_iter().map(|(p, c)| format!("{} {}", c.letter(), p)).collect()
+    }
+
+    fn tar_of(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(0);
+            if !link.is_empty() {
+                header.set_link_name(link).unwrap();
+            }
+            builder.append_data(&mut header, path, &[][..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_symlinked_parent_directory() {
+        use tar::EntryType::{Directory, Regular, Symlink};
+        let base = tar_of(&[
+            ("usr/", Directory, ""),
+            ("usr/bin/", Directory, ""),
+            ("usr/bin/old", Regular, ""),
+            ("bin", Symlink, "usr/bin"),
+        ]);
+        let top = tar_of(&[("bin/tool", Regular, ""), ("bin/.wh.old", Regular, "")]);
+        let manifest = br#"[{"Config":null,"RepoTags":[],"Layers":["base/layer.tar","top/layer.tar"]}]"#;
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in [("base/layer.tar", &base[..]), ("top/layer.tar", &top[..]), ("manifest.json", &manifest[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+
+        let diff = layer_diff(&path, 1, &ProcessOptions::default()).unwrap();
+        let changes: Vec<String> = diff.changes.iter().map(|c| format!("{} {}", c.change.letter(), c.path)).collect();
+        assert_eq!(changes, ["D usr/bin/old", "A usr/bin/tool"]);
+
+        let literal = ProcessOptions { literal_paths: true, ..Default::default() };
+        let diff = layer_diff(&path, 1, &literal).unwrap();
+        assert_eq!(diff.changes, [PathChange { change: Change::Added, path: "bin/tool".to_string() }]);
+    }
+
+    #[test]
+    fn test_added_modified_deleted() {
+        let etc = entry("etc", EntryKind::Dir, 0o755);
+        let passwd = entry("etc/passwd", EntryKind::File, 0o644);
+        let group = entry("etc/group", EntryKind::File, 0o644);
+        let changes = classify(&[
+            LayerEvent::EntryAdded { entry: &etc, content: None },
+            LayerEvent::EntryAdded { entry: &passwd, content: None },
+            LayerEvent::EntryAdded { entry: &group, content: None },
+            LayerEvent::EntryRemoved { path: "var/cache" },
+            LayerEvent::EntryRemoved { path: "etc/missing" },
+        ]);
+        assert_eq!(changes, vec!["A etc/group", "M etc/passwd", "D var/cache"]);
+    }
+
+    #[test]
+    fn test_directory_metadata_change() {
+        let etc = entry("etc", EntryKind::Dir, 0o700);
+        assert_eq!(classify(&[LayerEvent::EntryAdded { entry: &etc, content: None }]), vec!["M etc"]);
+    }
+
+    #[test]
+    fn test_opaque_directory() {
+        let b = entry("var/cache/b", EntryKind::File, 0o644);
+        let changes = classify(&[
+            LayerEvent::OpaqueApplied { dir: "var/cache" },
+            LayerEvent::EntryAdded { entry: &b, content: None },
+        ]);
+        assert_eq!(changes, vec!["D var/cache/a", "M var/cache/b"]);
+    }
+}
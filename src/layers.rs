@@ -0,0 +1,398 @@
+//! Per-layer overview aligned with the image history
+
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+
+use crate::archive::{self, Compression, Layer, LayerSet, MissingLayer};
+use crate::config::HistoryEntry;
+use crate::diagnostics::{self, WarningKind};
+use crate::tarformat;
+use crate::utils;
+
+/// One build step: either a filesystem layer or a metadata-only history entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerRow {
+    /// Position among the filesystem layers (None for empty layers)
+    pub index: Option<usize>,
+    /// Abbreviated layer hash (None for empty layers)
+    pub label: Option<String>,
+    /// Creation timestamp from the history
+    pub created: Option<String>,
+    /// Build instruction from the history
+    pub created_by: String,
+    /// true for ENV/LABEL/WORKDIR-style steps without filesystem content
+    pub empty: bool,
+}
+
+/// An empty layer to announce just before the layer that follows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmptyLayer {
+    /// Position of the next filesystem layer, or None if no layer follows
+    pub before: Option<usize>,
+    /// Short form of the build instruction
+    pub command: String,
+}
+
+/// What a layer separator can say about its filesystem layer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerDetail {
+    /// Short form of the build instruction (empty when the history has none)
+    pub command: String,
+    /// Size of the layer blob as stored
+    pub size: u64,
+}
+
+/// Interleave history entries with filesystem layers
+///
+/// Non-empty history entries consume layers in order. Layers left over when
+/// the history is shorter than the layer list get rows without a command.
+pub fn align_history(labels: &[String], history: &[HistoryEntry]) -> Vec<LayerRow> {
+    let mut rows = Vec::new();
+    let mut next = 0;
+
+    for entry in history {
+        let created_by = entry.created_by.clone().unwrap_or_default();
+        if entry.empty_layer || next >= labels.len() {
+            rows.push(LayerRow {
+                index: None,
+                label: None,
+                created: entry.created.clone(),
+                created_by,
+                empty: true,
+            });
+        } else {
+            rows.push(LayerRow {
+                index: Some(next),
+                label: Some(labels[next].clone()),
+                created: entry.created.clone(),
+                created_by,
+                empty: false,
+            });
+            next += 1;
+        }
+    }
+
+    for (i, label) in labels.iter().enumerate().skip(next) {
+        rows.push(LayerRow {
+            index: Some(i),
+            label: Some(label.clone()),
+            created: None,
+            created_by: String::new(),
+            empty: false,
+        });
+    }
+
+    rows
+}
+
+/// Collect empty layers, each tied to the filesystem layer that follows it
+pub fn empty_layers(rows: &[LayerRow]) -> Vec<EmptyLayer> {
+    let mut result = Vec::new();
+    let mut pending = Vec::new();
+
+    for row in rows {
+        if row.empty {
+            pending.push(short_command(&row.created_by));
+        } else {
+            for command in pending.drain(..) {
+                result.push(EmptyLayer { before: row.index, command });
+            }
+        }
+    }
+    for command in pending {
+        result.push(EmptyLayer { before: None, command });
+    }
+
+    result
+}
+
+/// Details of each filesystem layer, by position among the layers
+pub fn layer_details(rows: &[LayerRow], sizes: &[u64]) -> Vec<LayerDetail> {
+    let mut details = vec![LayerDetail::default(); sizes.len()];
+    for row in rows {
+        if let Some(index) = row.index {
+            if details.len() <= index {
+                details.resize(index + 1, LayerDetail::default());
+            }
+            details[index].command = short_command(&row.created_by);
+        }
+    }
+    for (detail, &size) in details.iter_mut().zip(sizes) {
+        detail.size = size;
+    }
+    details
+}
+
+/// `history` without the steps of layers the archive doesn't have, so it
+/// lines up with the layers that were applied
+pub fn without_missing(history: &[HistoryEntry], missing: &[MissingLayer]) -> Vec<HistoryEntry> {
+    let mut position = 0;
+    history.iter()
+        .filter(|entry| {
+            if entry.empty_layer {
+                return true;
+            }
+            position += 1;
+            !missing.iter().any(|layer| layer.position == position - 1)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Strip the shell wrapper Docker records around build instructions
+/// Example: "/bin/sh -c #(nop)  ENV PATH=/bin" -> "ENV PATH=/bin"
+pub fn short_command(created_by: &str) -> String {
+    let command = created_by.trim();
+    let command = command.strip_prefix("/bin/sh -c ").unwrap_or(command);
+    let command = command.strip_prefix("#(nop)").unwrap_or(command);
+    command.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Longest build instruction `contree history` prints without `--no-trunc`
+const HISTORY_COMMAND_WIDTH: usize = 60;
+
+/// What `contree history` shows of a filesystem layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryLayer {
+    /// Full diff_id, or the blob name when the config has none
+    pub digest: String,
+    /// Abbreviated hash, as elsewhere in the output
+    pub label: String,
+    /// Size of the layer tar once decompressed
+    pub size: u64,
+}
+
+impl HistoryLayer {
+    /// Read `layer` through to learn its uncompressed size (plain tars are
+    /// measured without reading)
+    pub fn of(layer: &Layer) -> Result<Self> {
+        let size = if layer.compression == Compression::None {
+            layer.blob.size()
+        } else {
+            let mut reader = archive::open_blob(layer)?;
+            io::copy(&mut reader, &mut io::sink()).with_context(|| format!("Failed to read layer {}", layer.name))?
+        };
+        let digest = layer.diff_id.clone().unwrap_or_else(|| layer.name.clone());
+        Ok(HistoryLayer { digest, label: layer.label(), size })
+    }
+}
+
+/// Print the build history of an archive, oldest step first
+pub fn run_history(layer_set: &LayerSet, no_trunc: bool) -> Result<()> {
+    let layers = layer_set.layers.iter().map(HistoryLayer::of).collect::<Result<Vec<_>>>()?;
+    let labels: Vec<String> = layers.iter().map(|layer| layer.label.clone()).collect();
+    let history = layer_set.config.as_ref().map(|c| c.history.as_slice()).unwrap_or_default();
+    let rows = align_history(&labels, history);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_history(&mut out, &rows, &layers, no_trunc)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// One line per build step: its layer (or `(empty)` for metadata-only
+/// steps), when it ran, the uncompressed size, and the instruction.
+/// Without `no_trunc`, layers are abbreviated and instructions shortened.
+pub fn write_history<W: Write>(
+    out: &mut W,
+    rows: &[LayerRow],
+    layers: &[HistoryLayer],
+    no_trunc: bool,
+) -> io::Result<()> {
+    let lines: Vec<_> = rows.iter()
+        .map(|row| {
+            let layer = row.index.and_then(|i| layers.get(i));
+            let name = match layer {
+                Some(layer) if no_trunc => layer.digest.clone(),
+                Some(layer) => layer.label.clone(),
+                None => "(empty)".to_string(),
+            };
+            // Normalized to UTC, or as recorded when it doesn't parse
+            let created = match &row.created {
+                Some(created) => utils::parse_timestamp(created)
+                    .map_or_else(|| created.clone(), utils::format_timestamp),
+                None => "-".to_string(),
+            };
+            let size = utils::human_size(layer.map_or(0, |layer| layer.size));
+            let command = if no_trunc {
+                row.created_by.trim().to_string()
+            } else {
+                truncate(&short_command(&row.created_by), HISTORY_COMMAND_WIDTH)
+            };
+            (name, created, size, command)
+        })
+        .collect();
+
+    let width = lines.iter().map(|(name, ..)| name.len()).max().unwrap_or(0).max("LAYER".len());
+    writeln!(out, "{:<width$}  {:<23}  {:>10}  CREATED BY", "LAYER", "CREATED", "SIZE")?;
+    for (name, created, size, command) in lines {
+        writeln!(out, "{:<width$}  {:<23}  {:>10}  {}", name, created, size, command)?;
+    }
+    Ok(())
+}
+
+/// `text` cut to `max` characters, ending in an ellipsis when shortened
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut short: String = text.chars().take(max - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Print the layer table for an archive
+pub fn render_table(layer_set: &LayerSet) -> io::Result<()> {
+    let labels: Vec<String> = layer_set.layers.iter().map(|l| l.label()).collect();
+    let history = layer_set.config.as_ref().map(|c| c.history.as_slice()).unwrap_or_default();
+    let rows = align_history(&labels, history);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{:>3}  {:<7}  {:>10}  {:<9}  {:>6}  CREATED BY", "#", "LAYER", "SIZE", "FORMAT", "XATTRS")?;
+
+    for row in &rows {
+        let (index, label, size, format, xattrs) = match row.index {
+            Some(i) => {
+                let layer = &layer_set.layers[i];
+                let size = layer.blob.size();
+                let (format, xattrs) = match tarformat::scan_layer(layer) {
+                    Ok(format) => (format.describe(), format.xattr_entries.to_string()),
+                    Err(err) => {
+                        diagnostics::warn(WarningKind::LayerFormat, format!("{:#}", err));
+                        ("?".to_string(), "?".to_string())
+                    }
+                };
+                (i.to_string(), row.label.clone().unwrap_or_default(), utils::human_size(size), format, xattrs)
+            }
+            None => ("-".to_string(), "(empty)".to_string(), "0 B".to_string(), "-".to_string(), "-".to_string()),
+        };
+        writeln!(out, "{:>3}  {:<7}  {:>10}  {:<9}  {:>6}  {}", index, label, size, format, xattrs,
+            short_command(&row.created_by))?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(created_by: &str, empty_layer: bool) -> HistoryEntry {
+        HistoryEntry {
+            created_by: Some(created_by.to_string()),
+            empty_layer,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_align_history() {
+        let labels = vec!["aaa".to_string(), "bbb".to_string()];
+        let history = vec![
+            step("ADD rootfs", false),
+            step("ENV A=1", true),
+            step("RUN make", false),
+            step("CMD [\"sh\"]", true),
+        ];
+
+        let rows = align_history(&labels, &history);
+        let summary: Vec<_> = rows.iter().map(|r| (r.label.as_deref(), r.empty)).collect();
+        assert_eq!(summary, vec![
+            (Some("aaa"), false),
+            (None, true),
+            (Some("bbb"), false),
+            (None, true),
+        ]);
+    }
+
+    #[test]
+    fn test_align_history_without_history() {
+        let rows = align_history(&["aaa".to_string()], &[]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].index, Some(0));
+    }
+
+    #[test]
+    fn test_empty_layers() {
+        let labels = vec!["aaa".to_string(), "bbb".to_string()];
+        let history = vec![
+            step("ADD rootfs", false),
+            step("/bin/sh -c #(nop)  ENV A=1", true),
+            step("RUN make", false),
+            step("CMD [\"sh\"]", true),
+        ];
+
+        let empty = empty_layers(&align_history(&labels, &history));
+        assert_eq!(empty, vec![
+            EmptyLayer { before: Some(1), command: "ENV A=1".to_string() },
+            EmptyLayer { before: None, command: "CMD [\"sh\"]".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_layer_details() {
+        let labels = vec!["aaa".to_string(), "bbb".to_string()];
+        let history = vec![step("ADD rootfs", false), step("ENV A=1", true), step("/bin/sh -c make", false)];
+
+        let details = layer_details(&align_history(&labels, &history), &[2048, 512]);
+        assert_eq!(details, vec![
+            LayerDetail { command: "ADD rootfs".to_string(), size: 2048 },
+            LayerDetail { command: "make".to_string(), size: 512 },
+        ]);
+        // Sizes are unknown for some sources; commands still line up
+        assert_eq!(layer_details(&align_history(&labels, &history), &[])[1].command, "make");
+    }
+
+    #[test]
+    fn test_without_missing() {
+        let history = vec![step("ADD rootfs", false), step("ENV A=1", true), step("RUN make", false)];
+        let missing = [MissingLayer { position: 0, name: "foreign/layer.tar".to_string() }];
+        let rows = align_history(&["bbb".to_string()], &without_missing(&history, &missing));
+        let summary: Vec<_> = rows.iter().map(|r| (r.label.as_deref(), r.created_by.as_str())).collect();
+        assert_eq!(summary, vec![(None, "ENV A=1"), (Some("bbb"), "RUN make")]);
+    }
+
+    #[test]
+    fn test_write_history() {
+        let labels = vec!["aaaaaaa".to_string(), "bbbbbbb".to_string()];
+        let history = vec![
+            HistoryEntry {
+                created: Some("2024-01-02T03:04:05.5Z".to_string()),
+                ..step("/bin/sh -c #(nop) ADD file:0123 in / ", false)
+            },
+            step("/bin/sh -c #(nop)  ENV PATH=/usr/local/bin:/usr/bin", true),
+            step(&format!("/bin/sh -c apk add --no-cache {}", "py3-package ".repeat(8)), false),
+        ];
+        let layers = [
+            HistoryLayer { digest: "sha256:aaaaaaaaaaaa".to_string(), label: "aaaaaaa".to_string(), size: 7 << 20 },
+            HistoryLayer { digest: "sha256:bbbbbbbbbbbb".to_string(), label: "bbbbbbb".to_string(), size: 512 },
+        ];
+        let rows = align_history(&labels, &history);
+
+        let mut out = Vec::new();
+        write_history(&mut out, &rows, &layers, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "LAYER    CREATED                        SIZE  CREATED BY\n",
+            "aaaaaaa  2024-01-02 03:04:05 UTC     7.0 MiB  ADD file:0123 in /\n",
+            "(empty)  -                               0 B  ENV PATH=/usr/local/bin:/usr/bin\n",
+            "bbbbbbb  -                             512 B  apk add --no-cache py3-package py3-package py3-package py3-…\n",
+        ));
+
+        let mut out = Vec::new();
+        write_history(&mut out, &rows, &layers, true).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[1].starts_with("sha256:aaaaaaaaaaaa  2024-01-02"));
+        assert!(lines[2].ends_with("  /bin/sh -c #(nop)  ENV PATH=/usr/local/bin:/usr/bin"));
+        assert!(lines[3].ends_with(&"py3-package ".repeat(8).trim_end().to_string()));
+    }
+
+    #[test]
+    fn test_short_command() {
+        assert_eq!(short_command("/bin/sh -c #(nop)  WORKDIR /app"), "WORKDIR /app");
+        assert_eq!(short_command("/bin/sh -c apk add  curl"), "apk add curl");
+        assert_eq!(short_command("COPY . . # buildkit"), "COPY . . # buildkit");
+    }
+}
@@ -0,0 +1,105 @@
+//! Merged filesystem trees of Docker image archives
+//!
+//! contree reads `docker save` archives (plain, gzip or zstd compressed, Docker
+//! or OCI layout, packed or unpacked), applies their layers in order with
+//! overlay semantics (whiteouts, opaque directories, hard links), and renders
+//! the result. The
+//! `contree` binary is a thin command line over this library; registry UIs,
+//! CI bots and other tools can use the same pieces:
+//!
+//! - [`archive`] opens an archive, picks the image and its layers, and merges
+//!   them into a tree ([`archive::process_archive`], [`archive::merge_layers`])
+//! - [`source`] is where the layers come from: [`tarball`] archives,
+//!   unpacked [`directory`] images, archives in [`memory`] (what a
+//!   `wasm32-unknown-unknown` build reads), or your own [`source::LayerSource`]
+//! - [`tree`] is the merged tree itself ([`tree::Node`])
+//! - [`content`] reads files of the merged image back out of the layers
+//! - [`render`] draws a tree as text ([`render::render_tree`],
+//!   [`render::render_to_string`])
+//! - [`events`] streams the entries of one layer to custom sinks
+//! - [`diff`], [`layerdiff`], [`audit`], [`packages`], [`inspect`],
+//!   [`extract`] and the other modules are the analyses behind the subcommands
+//!
+//! ```no_run
+//! use contree::archive::{self, ProcessOptions};
+//! use contree::render::{self, RenderOptions};
+//!
+//! let options = ProcessOptions { record_layers: true, ..Default::default() };
+//! let image = archive::process_archive("image.tar".as_ref(), &options)?;
+//! println!("{} entries", image.root.count_descendants());
+//!
+//! let options = RenderOptions::builder().long(true).layer_labels(image.layer_labels).build();
+//! let text = render::render_to_string(&image.root, &options);
+//! print!("{}", text);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Opening archives and merging layers fail with a [`ContreeError`] saying
+//! what went wrong; the analyses report [`anyhow::Error`]s with context, and
+//! [`error::exit_code`] finds the typed error inside them.
+
+#[cfg(feature = "analyzers")]
+pub mod analyzer;
+pub mod archive;
+pub mod audit;
+pub mod baseline;
+pub mod bloat;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod capability;
+pub mod casefold;
+pub mod check;
+pub mod colordepth;
+pub mod config;
+pub mod content;
+pub mod diagnostics;
+pub mod diff;
+pub mod directory;
+pub mod dockerfile;
+#[cfg(feature = "analyzers")]
+pub mod dupes;
+#[cfg(feature = "analyzers")]
+pub mod elf;
+#[cfg(feature = "analyzers")]
+pub mod entrypoint;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod extract;
+#[cfg(feature = "analyzers")]
+pub mod find;
+pub mod ghosts;
+pub mod inspect;
+pub mod keys;
+pub mod layerdiff;
+pub mod layers;
+pub mod manifest;
+pub mod memory;
+pub mod oci;
+pub mod packages;
+pub mod paranoid;
+pub mod profile;
+pub mod progress;
+pub mod query;
+#[cfg(feature = "remote")]
+pub mod registry;
+pub mod render;
+pub mod repro;
+pub mod rpmdb;
+pub mod sarif;
+#[cfg(feature = "analyzers")]
+pub mod secrets;
+pub mod settings;
+pub mod source;
+pub mod summary;
+pub mod tarball;
+pub mod tarformat;
+pub mod tempspace;
+pub mod template;
+pub mod theme;
+pub mod tree;
+pub mod utils;
+pub mod watch;
+pub mod whiteout;
+
+pub use error::{ContreeError, Result};
@@ -1,74 +1,1427 @@
-use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
-
-mod archive;
-mod manifest;
-mod render;
-mod theme;
-mod tree;
-mod utils;
-mod whiteout;
+use anyhow::{Context, Result};
+use clap::builder::BoolishValueParser;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "analyzers")]
+use contree::{analyzer, dupes, find, secrets};
+#[cfg(feature = "remote")]
+use contree::registry;
+#[cfg(feature = "tui")]
+use contree::{progress, source};
+use contree::diagnostics::{Verbosity, WarningFormat};
+use contree::source::ImageChooser;
+use contree::{
+    archive, audit, baseline, bloat, casefold, check, colordepth::ColorDepth, diagnostics, diff, dockerfile, error,
+    export, extract, ghosts, inspect, keys, layerdiff, layers, oci, packages, paranoid, profile, query, render,
+    repro, settings, summary, tempspace, template, theme, tree, watch, whiteout,
+};
+
+mod input;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "self-update")]
+mod update;
 
 #[derive(Parser)]
 #[command(name = "contree")]
 #[command(about = "Visualize the merged filesystem tree of Docker image archives")]
-#[command(version = "0.1.0")]
+#[command(version)]
 struct Cli {
-    /// Docker archive tar file to visualize
-    archive: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration profile supplying per-command defaults (e.g. ci, local)
+    #[arg(long, global = true, env = "CONTREE_PROFILE")]
+    profile: Option<String>,
+
+    /// Directory for spooled layers and other temporary files (default: the system temp dir)
+    #[arg(long, global = true, value_name = "DIR", env = "CONTREE_TEMP_DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Stop before spooling more than SIZE of layers to disk (e.g. 10G)
+    #[arg(long, global = true, value_name = "SIZE", value_parser = tempspace::parse_size,
+        env = "CONTREE_MAX_TEMP_SIZE")]
+    max_temp_size: Option<u64>,
+
+    /// Colors the terminal shows: auto, truecolor, 256, 16 (theme colors are converted to fit) [default: auto]
+    #[arg(long, global = true, value_parser = ["auto", "truecolor", "256", "16"], env = "CONTREE_COLOR_DEPTH")]
+    color_depth: Option<String>,
+
+    /// Print every warning as it happens instead of a summary at the end
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Print no warnings or progress notes (with `diff`, nothing at all: only the exit status)
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// How to report warnings: text, or json (every warning as one array on stderr, at the end) [default: text]
+    #[arg(long, global = true, value_name = "FORMAT", value_parser = ["text", "json"], env = "CONTREE_WARNINGS")]
+    warnings: Option<String>,
+
+    /// The tree options, for `contree IMAGE` without the `tree`
+    #[command(flatten)]
+    tree: TreeArgs,
+}
+
+/// Arguments of `contree tree`
+#[derive(Args)]
+struct TreeArgs {
+    /// Docker archive tar files, unpacked archive directories, or docker://IMAGE with the remote feature, to visualize one after another (offers the only *.tar here when omitted)
+    archives: Vec<PathBuf>,
+
+    /// Without an archive, use the only *.tar in the current directory without asking
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_AUTO_DETECT_INPUT")]
+    auto_detect_input: bool,
 
-    /// Show permissions and ownership information
-    #[arg(short, long)]
+    #[command(flatten)]
+    view: ViewArgs,
+}
+
+/// Arguments of `contree render`
+#[derive(Args)]
+struct RenderArgs {
+    /// JSON trees written by `--format json` (one tree or a listing of several), or - for standard input
+    #[arg(required = true)]
+    trees: Vec<PathBuf>,
+
+    #[command(flatten)]
+    view: ViewArgs,
+}
+
+/// What `--sort` takes
+const SORT_KEYS: [&str; 5] = ["name", "size", "mtime", "layer", "extension"];
+
+/// How to merge and draw a tree, for `tree`, `last` and `render`
+#[derive(Args)]
+struct ViewArgs {
+    /// Output format: text, json (the merged tree, one document per archive), or template
+    /// (a line per entry shaped by --template) [default: text]
+    #[arg(long, value_parser = ["text", "json", "template"], env = "CONTREE_FORMAT")]
+    format: Option<String>,
+
+    /// Line written for each entry with --format template, e.g. '{path}\t{size}\t{layer}'; fields:
+    /// path, name, type, size, hsize, mode, perms, uid, gid, mtime, layer, target, owner (implies --format template)
+    #[arg(long, value_name = "TEMPLATE", env = "CONTREE_TEMPLATE")]
+    template: Option<String>,
+
+    /// Image to show from an archive holding several, by tag or position (0 = first);
+    /// without it, you are asked on a terminal, else the first is shown
+    #[arg(long, value_name = "TAG|N", env = "CONTREE_IMAGE")]
+    image: Option<String>,
+
+    /// Show permissions, ownership and sizes, then list files with capabilities
+    #[arg(short, long, value_parser = BoolishValueParser::new(), env = "CONTREE_LONG")]
     long: bool,
 
-    /// When to colorize output: auto, always, never
-    #[arg(long, default_value = "auto")]
-    color: String,
+    /// When to colorize output: auto, always, never [default: auto]
+    #[arg(long, env = "CONTREE_COLOR")]
+    color: Option<String>,
 
-    /// Icon style: none, emoji, nerd
-    #[arg(long, default_value = "nerd")]
-    icons: String,
+    /// Icon style: none, emoji, nerd [default: nerd]
+    #[arg(long, env = "CONTREE_ICONS")]
+    icons: Option<String>,
+
+    /// Tree characters: unicode, or ascii (|--, \--, |) for logs and plain-text tickets [default: unicode]
+    #[arg(long, value_parser = ["unicode", "ascii"], env = "CONTREE_CHARSET")]
+    charset: Option<String>,
+
+    /// Print each entry's full path (/etc/hosts) instead of drawing the tree, for copying paths out
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_FULL_PATHS")]
+    full_paths: bool,
 
     /// Show layer separators with abbreviated hash
-    #[arg(long)]
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_LAYERS")]
     layers: bool,
 
-    /// Custom theme as JSON string (e.g., '{"directory":"#7daea3"}')
-    #[arg(long)]
+    /// Character to draw layer separators with [default: ─, or - with --charset ascii]
+    #[arg(long, value_name = "CHAR", env = "CONTREE_SEPARATOR_FILL")]
+    separator_fill: Option<char>,
+
+    /// Layer separator label, from {hash}, {index}, {command} and {size} [default: "Layer {hash}"]
+    #[arg(long, value_name = "TEMPLATE", env = "CONTREE_SEPARATOR_LABEL")]
+    separator_label: Option<String>,
+
+    /// Where layer labels go: before (a line of their own) or inline (after the layer's first entry) [default: before]
+    #[arg(long, value_name = "WHERE", value_parser = ["before", "inline"], env = "CONTREE_SEPARATOR_PLACEMENT")]
+    separator_placement: Option<String>,
+
+    /// Prefix every entry with the layer that added it: hash (default) or index
+    #[arg(long, value_name = "STYLE", num_args = 0..=1, require_equals = true, default_missing_value = "hash",
+        value_parser = ["hash", "index"], env = "CONTREE_BLAME")]
+    blame: Option<String>,
+
+    /// Show the package (apk, dpkg, rpm) that installed each file
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_OWNERS")]
+    owners: bool,
+
+    /// Show earlier-layer versions of files that later layers overwrote
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_SHOW_SHADOWED")]
+    show_shadowed: bool,
+
+    /// List lower-layer entries hidden by opaque whiteouts
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_SHOW_OPAQUE")]
+    show_opaque: bool,
+
+    /// Order entries by name, size (largest first), mtime (newest first), layer (bottom-most first) or extension [default: name]
+    #[arg(long, value_name = "KEY", value_parser = SORT_KEYS, env = "CONTREE_SORT")]
+    sort: Option<String>,
+
+    /// Reverse the sort order
+    #[arg(short, long, value_parser = BoolishValueParser::new(), env = "CONTREE_REVERSE")]
+    reverse: bool,
+
+    /// List directories before the other entries of their parent
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_DIRS_FIRST")]
+    dirs_first: bool,
+
+    /// Order numbers in names by value, so file2 comes before file10
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_NATURAL_SORT")]
+    natural_sort: bool,
+
+    /// Order names regardless of case
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_IGNORE_CASE")]
+    ignore_case: bool,
+
+    /// Stop printing the tree after N entries (0 for no limit)
+    #[arg(long, value_name = "N", env = "CONTREE_LIMIT")]
+    limit: Option<usize>,
+
+    /// Print the image's tags, digest, platform, layer count and size above the tree (default on a terminal, or with several archives)
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_HEADER")]
+    header: bool,
+
+    /// Leave the header out, even on a terminal
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_NO_HEADER")]
+    no_header: bool,
+
+    /// Cut entries wider than the terminal short with an ellipsis, keeping the tree columns intact
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_TRUNCATE")]
+    truncate: bool,
+
+    /// Continue entries wider than the terminal on lines aligned with the name
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_WRAP")]
+    wrap: bool,
+
+    /// Show progress in the terminal title and taskbar (OSC 9;4) while processing
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_TERM_PROGRESS")]
+    term_progress: bool,
+
+    /// Print per-layer entry counts, timings, and memory use to stderr
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_PROFILE_LAYERS")]
+    profile_layers: bool,
+
+    /// Don't follow symlinked parent directories when applying layers
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_LITERAL_PATHS")]
+    literal_paths: bool,
+
+    /// How layers mark deletions: aufs (.wh. files), overlay (0:0 character
+    /// devices and opaque xattrs), or auto (either, entry by entry) [default: auto]
+    #[arg(long, value_name = "DIALECT", value_parser = ["auto", "aufs", "overlay"], env = "CONTREE_WHITEOUTS")]
+    whiteouts: Option<String>,
+
+    /// Show what the archive has when the manifest lists layers it doesn't
+    /// (foreign or stripped layers), instead of failing; the header names them
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_ALLOW_MISSING_LAYERS")]
+    allow_missing_layers: bool,
+
+    /// Fail instead of warning when the manifest and the image config
+    /// disagree on the layers (a sign of a hand-edited or corrupted archive),
+    /// or when a layer entry is corrupted, can't be applied, or has an
+    /// unsupported type
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_STRICT")]
+    strict: bool,
+
+    /// Instead of the tree, report what makes the image differ between builds
+    /// (build-time timestamps, embedded build dates and hostnames, shell history)
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_REPRO_CHECK")]
+    repro_check: bool,
+
+    /// Draw the trees again whenever an archive changes (after each `docker save`), until interrupted;
+    /// --watch=diff shows what changed since the previous version instead
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "tree",
+        value_parser = ["tree", "diff"], env = "CONTREE_WATCH")]
+    watch: Option<String>,
+
+    /// Report entries that look crafted to escape or mislead (.., absolute
+    /// paths, symlink swaps) and fail if there are any
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_PARANOID")]
+    paranoid: bool,
+
+    /// Report paths that differ only by case within a directory (they clash
+    /// when the image is extracted on macOS or Windows) and fail if there are any
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_CHECK_CASE_COLLISIONS")]
+    check_case_collisions: bool,
+
+    /// Reuse the merged tree from an earlier run on the same image, or store
+    /// this one (in $XDG_CACHE_HOME/contree/trees)
+    #[cfg(feature = "cache")]
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_CACHE")]
+    cache: bool,
+
+    /// Theme: a bundled name (see `contree themes`) or JSON (e.g., '{"directory":"#7daea3"}')
+    #[arg(long, env = "CONTREE_THEME")]
     theme: Option<String>,
+
+    /// Load the theme from a TOML or JSON file (e.g. ~/.config/contree/theme.toml)
+    #[arg(long, value_name = "PATH", env = "CONTREE_THEME_FILE")]
+    theme_file: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum Command {
+    /// Show the merged filesystem tree of an image (what plain `contree IMAGE` does)
+    Tree(TreeArgs),
 
-    // Determine if we should use color
-    let use_color = match cli.color.as_str() {
-        "always" => true,
-        "never" => false,
-        _ => atty::is(atty::Stream::Stdout),
+    /// Find identical files stored more than once across paths and layers
+    #[cfg(feature = "analyzers")]
+    Dupes {
+        /// Docker archive tar file to scan
+        archive: PathBuf,
+    },
+
+    /// Find files by SHA-256, size, or name in every layer, deleted and overwritten ones included
+    #[cfg(feature = "analyzers")]
+    Find {
+        /// Docker archive tar file to scan
+        archive: PathBuf,
+
+        /// SHA-256 of the contents, with or without a `sha256:` prefix
+        #[arg(long, value_name = "DIGEST")]
+        sha256: Option<String>,
+
+        /// Exact size of the file (e.g. 1048576, 1M)
+        #[arg(long, value_parser = tempspace::parse_size)]
+        size: Option<u64>,
+
+        /// Basename pattern, or whole-path pattern when it has a `/` (`*` and `?` are wildcards)
+        #[arg(long, value_name = "PATTERN")]
+        name: Option<String>,
+    },
+
+    /// Run content analyzers over every file in the image
+    #[cfg(feature = "analyzers")]
+    Analyze {
+        /// Docker archive tar file to scan
+        archive: PathBuf,
+
+        /// Comma-separated analyzers to run (default: all)
+        #[arg(long, value_delimiter = ',', env = "CONTREE_ANALYZE_ONLY")]
+        only: Vec<String>,
+    },
+
+    /// Scan file contents in every layer for credentials and private keys
+    #[cfg(feature = "analyzers")]
+    Secrets {
+        /// Docker archive tar file to scan
+        archive: PathBuf,
+
+        /// Output format: text, sarif [default: text]
+        #[arg(long, value_parser = ["text", "sarif"], env = "CONTREE_SECRETS_FORMAT")]
+        format: Option<String>,
+    },
+
+    /// List layers with their size, tar format, and the build step that created them
+    Layers {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Show the build steps of an image with their time, layer, and uncompressed size
+    History {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Show full layer digests and build instructions
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_HISTORY_NO_TRUNC")]
+        no_trunc: bool,
+    },
+
+    /// List package-manager caches, .git directories, core dumps, and object files left in the image
+    Bloat {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Select entries by path glob and metadata, e.g. '/usr/** where mode has setuid and size > 1M'
+    Query {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// A path glob, optionally followed by `where` and conditions on size, mode, uid, gid, mtime, path,
+        /// name, type, layer, owner, or target
+        expr: String,
+
+        /// Output format: tree, flat (one path per line), json [default: tree]
+        #[arg(long, value_parser = ["tree", "flat", "json"], env = "CONTREE_QUERY_FORMAT")]
+        format: Option<String>,
+    },
+
+    /// Chart where the bulk of an image lives: size per top-level directory, layer, and file category
+    Summary {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Guess the Dockerfile an image was built from, out of its history
+    Dockerfile {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Show an image's tags, digest, platform, and runtime config without merging its layers
+    Inspect {
+        /// Docker archive tar file, unpacked archive directory, or docker://IMAGE with the remote feature
+        archive: PathBuf,
+
+        /// Output format: text, json [default: text]
+        #[arg(long, value_parser = ["text", "json"], env = "CONTREE_INSPECT_FORMAT")]
+        format: Option<String>,
+
+        /// Image to inspect from an archive holding several, by tag or position (0 = first)
+        #[arg(long, value_name = "TAG|N", env = "CONTREE_IMAGE")]
+        image: Option<String>,
+    },
+
+    /// Show what one layer added (A), modified (M), and deleted (D)
+    LayerDiff {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Layer index in manifest order (0 = bottom layer)
+        layer: usize,
+
+        /// Output format: text, json [default: text]
+        #[arg(long, value_parser = ["text", "json"], env = "CONTREE_LAYER_DIFF_FORMAT")]
+        format: Option<String>,
+
+        /// How layers mark deletions: aufs, overlay, or auto (either) [default: auto]
+        #[arg(long, value_name = "DIALECT", value_parser = ["auto", "aufs", "overlay"],
+            env = "CONTREE_LAYER_DIFF_WHITEOUTS")]
+        whiteouts: Option<String>,
+    },
+
+    /// Replace this binary with the latest verified GitHub release
+    #[cfg(feature = "self-update")]
+    SelfUpdate {
+        /// Only report whether a newer release exists
+        #[arg(long)]
+        check: bool,
+
+        /// Reinstall even if already up to date
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Report setuid binaries, capabilities, writable paths, and stray device nodes
+    Audit {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Output format: text, sarif [default: text]
+        #[arg(long, value_parser = ["text", "sarif"], env = "CONTREE_AUDIT_FORMAT")]
+        format: Option<String>,
+    },
+
+    /// Find private keys, certificates, and keystores in every layer, including deleted ones
+    Keys {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// List files deleted by whiteouts that are still stored in lower layers
+    Ghosts {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// List installed apk, dpkg, and rpm packages and the layer that installed each
+    Packages {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// List files not installed by any package (hand-copied binaries, leftovers)
+    Orphans {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Check an archive for problems (layers mixing gzip and zstd, an Entrypoint/Cmd that can't start)
+    Check {
+        /// Docker archive tar file
+        archive: PathBuf,
+    },
+
+    /// Write a copy of an archive, optionally normalizing layer compression
+    Export {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Output archive path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Re-encode layers: gzip or zstd, with an optional level (e.g. zstd:19)
+        #[arg(long, value_name = "FORMAT[:LEVEL]", env = "CONTREE_EXPORT_RECOMPRESS")]
+        recompress: Option<String>,
+
+        /// Print what would be written, with overwrite and permission warnings, and stop
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_EXPORT_DRY_RUN")]
+        dry_run: bool,
+    },
+
+    /// Copy a file or directory of the merged image out, at the same path under the output directory
+    Extract {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Path in the image to extract (e.g. /etc/ssl, or / for everything)
+        path: String,
+
+        /// Directory to extract into; must be empty or not exist yet
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Print what would be written, and what would be skipped, and stop
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_EXTRACT_DRY_RUN")]
+        dry_run: bool,
+    },
+
+    /// Save a named snapshot of an image, or compare an archive against one
+    Baseline {
+        #[command(subcommand)]
+        action: BaselineCommand,
+    },
+
+    /// Show the tree of the most recently created image in the local Docker daemon
+    Last(ViewArgs),
+
+    /// Draw trees saved with `--format json` again, with any view options, without the archives
+    Render(RenderArgs),
+
+    /// Browse the merged tree interactively: fold directories, inspect entries, filter by layer, search
+    #[cfg(feature = "tui")]
+    Tui {
+        /// Docker archive tar file, unpacked archive directory, or docker://IMAGE with the remote feature
+        archive: PathBuf,
+        /// Archive to browse against the first one, showing what changed
+        new: Option<PathBuf>,
+
+        /// Image to browse from an archive holding several, by tag or position (0 = first)
+        #[arg(long, value_name = "TAG|N", env = "CONTREE_IMAGE")]
+        image: Option<String>,
+
+        /// Show the package (apk, dpkg, rpm) that installed each entry
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_OWNERS")]
+        owners: bool,
+
+        /// Reuse the merged tree from an earlier run on the same image, or store this one
+        #[cfg(feature = "cache")]
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_CACHE")]
+        cache: bool,
+    },
+
+    /// List the bundled color themes for --theme, with a sample of each
+    Themes {
+        /// Show the samples in color: auto, always, never [default: auto]
+        #[arg(long, value_parser = ["auto", "always", "never"], env = "CONTREE_COLOR")]
+        color: Option<String>,
+    },
+
+    /// Compare two image archives, or two platforms of one multi-arch archive
+    Diff {
+        /// Baseline archive
+        old: PathBuf,
+        /// Archive to compare against the baseline [default: the baseline archive]
+        new: Option<PathBuf>,
+
+        /// Platform to take from the baseline archive's OCI index (e.g. linux/amd64)
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]", env = "CONTREE_DIFF_PLATFORM")]
+        platform: Option<String>,
+
+        /// Platform to take from the second archive's OCI index (e.g. linux/arm64)
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]", env = "CONTREE_DIFF_PLATFORM2")]
+        platform2: Option<String>,
+
+        /// Output format: text, json [default: text]
+        #[arg(long, value_parser = ["text", "json"], env = "CONTREE_DIFF_FORMAT")]
+        format: Option<String>,
+
+        /// Colorize output: auto, always, never [default: auto]
+        #[arg(long, value_parser = ["auto", "always", "never"], env = "CONTREE_COLOR")]
+        color: Option<String>,
+
+        /// Only report entries with identical contents whose mode, owner, or xattrs changed
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_DIFF_METADATA_ONLY")]
+        metadata_only: bool,
+
+        /// Reuse merged trees from earlier runs on the same images, or store them
+        #[cfg(feature = "cache")]
+        #[arg(long, value_parser = BoolishValueParser::new(), env = "CONTREE_CACHE")]
+        cache: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum BaselineCommand {
+    /// Snapshot an archive's merged tree and config into the cache
+    Save {
+        /// Docker archive tar file
+        archive: PathBuf,
+
+        /// Name to save the baseline under (e.g. release-1.4)
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Compare an archive against a saved baseline
+    Diff {
+        /// Name of the saved baseline
+        name: String,
+
+        /// Archive to compare against the baseline
+        archive: PathBuf,
+
+        /// Output format: text, json [default: text]
+        #[arg(long, value_parser = ["text", "json"], env = "CONTREE_BASELINE_DIFF_FORMAT")]
+        format: Option<String>,
+
+        /// Colorize output: auto, always, never [default: auto]
+        #[arg(long, value_parser = ["auto", "always", "never"], env = "CONTREE_COLOR")]
+        color: Option<String>,
+    },
+}
+
+fn main() {
+    let result = try_main();
+    // Whatever went wrong during the run comes before the error that ended it
+    let _ = diagnostics::report();
+    if let Err(err) = result {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(error::exit_code(&err));
+    }
+}
+
+fn try_main() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if cli.command.is_some() {
+        reject_tree_args(&matches).unwrap_or_else(|err| err.exit());
+    }
+    let view = match (&mut cli.command, matches.subcommand()) {
+        (None, _) => Some((&mut cli.tree.view, &matches)),
+        (Some(Command::Tree(tree)), Some((_, matches))) => Some((&mut tree.view, matches)),
+        (Some(Command::Last(view)), Some((_, matches))) => Some((view, matches)),
+        _ => None,
     };
+    if let Some((view, matches)) = view {
+        settle_opposites(view, matches).unwrap_or_else(|err| err.exit());
+    }
+    diagnostics::configure(diagnostics::Reporting {
+        verbosity: if cli.quiet {
+            Verbosity::Quiet
+        } else if cli.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        },
+        format: if cli.warnings.as_deref() == Some("json") { WarningFormat::Json } else { WarningFormat::Text },
+    });
+    tempspace::configure(tempspace::TempSpace { dir: cli.temp_dir, max_size: cli.max_temp_size });
+    let settings = settings::Settings::load()?;
+    let depth = match cli.color_depth.as_deref() {
+        None | Some("auto") => ColorDepth::detect(),
+        Some(name) => ColorDepth::parse(name)?,
+    };
+
+    // `contree IMAGE` is short for `contree tree IMAGE`
+    let command = cli.command.unwrap_or(Command::Tree(cli.tree));
+    run_command(command, &settings, cli.profile.as_deref(), depth, cli.quiet)
+}
+
+/// Fail when tree options come before a subcommand, which would otherwise
+/// ignore them (`contree --long diff a.tar b.tar`)
+fn reject_tree_args(matches: &ArgMatches) -> Result<(), clap::Error> {
+    let tree = TreeArgs::augment_args(clap::Command::new("tree"));
+    let given = tree.get_arguments()
+        .find(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine));
+    match given {
+        Some(arg) => {
+            let name = arg.get_long().map_or_else(|| arg.get_id().to_string(), |long| format!("--{}", long));
+            Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                format!("{} is a tree option and can't come before a subcommand", name),
+            ))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Resolve the options that rule each other out. Clap can't tell a flag
+/// from its environment variable here, so this is done after parsing: a flag
+/// beats the variable for its opposite, the later of `--header` and
+/// `--no-header` wins, and the others can't be given together.
+fn settle_opposites(view: &mut ViewArgs, matches: &ArgMatches) -> Result<(), clap::Error> {
+    let from_env = |id: &str| matches.value_source(id) == Some(ValueSource::EnvVariable);
+    // Which of two options that are both set to drop
+    let loser = |first: &'static str, second: &'static str| match (from_env(first), from_env(second)) {
+        (true, false) => Ok(first),
+        (false, true) => Ok(second),
+        _ if first == "header" => {
+            let last = |id| matches.indices_of(id).and_then(|indices| indices.max());
+            Ok(if last(first) < last(second) { first } else { second })
+        }
+        _ => Err(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            format!("--{} can't be used with --{}", first.replace('_', "-"), second.replace('_', "-")),
+        )),
+    };
+    if view.header && view.no_header {
+        match loser("header", "no_header")? {
+            "header" => view.header = false,
+            _ => view.no_header = false,
+        }
+    }
+    if view.truncate && view.wrap {
+        match loser("truncate", "wrap")? {
+            "truncate" => view.truncate = false,
+            _ => view.wrap = false,
+        }
+    }
+    if view.theme.is_some() && view.theme_file.is_some() {
+        match loser("theme", "theme_file")? {
+            "theme" => view.theme = None,
+            _ => view.theme_file = None,
+        }
+    }
+    Ok(())
+}
 
-    // Load theme
-    let theme = if let Some(theme_json) = cli.theme {
-        theme::Theme::from_json(&theme_json)?
+/// Render the trees of the archives `tree` names, or of the one detected
+fn run_tree(tree: TreeArgs, defaults: settings::CommandDefaults, depth: ColorDepth) -> Result<()> {
+    let archives = if tree.archives.is_empty() {
+        vec![input::detect_archive(tree.auto_detect_input || defaults.auto_detect_input == Some(true))?]
+    } else {
+        tree.archives
+    };
+    render_archives(&archives, &tree.view, &defaults, depth)
+}
+
+/// Merge the layers of each archive and print the trees as `view` says,
+/// one after another or as one JSON listing
+fn render_archives(
+    archives: &[PathBuf],
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+) -> Result<()> {
+    let several = archives.len() > 1;
+    if view.repro_check {
+        for archive_path in archives {
+            if several {
+                println!("{}:", archive_path.display());
+            }
+            with_archive(archive_path, repro::run)?;
+        }
+        return Ok(());
+    }
+
+    let load = |path: &Path| -> Result<Vec<(String, archive::Image)>> {
+        let image = with_archive(path, |path| load_image(path, view, defaults))?;
+        Ok(vec![(path.to_string_lossy().into_owned(), image)])
+    };
+    match view.watch.as_deref() {
+        Some(mode) => watch_inputs(archives, view, defaults, depth, mode == "diff", load),
+        None => show_images(archives, view, defaults, depth, load),
+    }
+}
+
+/// Print trees saved with `--format json` as `view` says, each named by the
+/// archive the listing gives or else by its file
+fn render_saved(
+    files: &[PathBuf],
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+) -> Result<()> {
+    if view.repro_check || view.paranoid {
+        anyhow::bail!("--repro-check and --paranoid look at the archive, which a saved tree doesn't keep");
+    }
+    let load = |path: &Path| -> Result<Vec<(String, archive::Image)>> {
+        let trees = if path == Path::new("-") {
+            tree::trees_from_json(std::io::stdin().lock())
+        } else {
+            let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+            tree::trees_from_json(std::io::BufReader::new(file))
+        };
+        let trees = trees.with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(trees.into_iter()
+            .map(|tree| {
+                let name = tree.archive.unwrap_or_else(|| path.display().to_string());
+                (name, archive::Image::from_tree(tree.root, tree.layers))
+            })
+            .collect())
+    };
+    match view.watch.as_deref() {
+        Some(mode) => watch_inputs(files, view, defaults, depth, mode == "diff", load),
+        None => show_images(files, view, defaults, depth, load),
+    }
+}
+
+/// Show the images of `inputs` as [`show_images`] does, then again whenever
+/// one of the files changes, or with `diff` what changed since the version
+/// before; a version that fails to load is reported and waited out
+fn watch_inputs(
+    inputs: &[PathBuf],
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+    diff: bool,
+    load: impl Fn(&Path) -> Result<Vec<(String, archive::Image)>>,
+) -> Result<()> {
+    if diff && (view.template.is_some() || view.format.as_deref().is_some_and(|format| format != "text")) {
+        anyhow::bail!("--watch=diff draws text, not --format or --template output");
+    }
+    if let Some(missing) = inputs.iter().find(|path| watch::stamp(path).is_none()) {
+        anyhow::bail!("Can't watch {}: no such file", missing.display());
+    }
+    let names: Vec<String> = inputs.iter().map(|path| path.display().to_string()).collect();
+    let clear = atty::is(atty::Stream::Stdout);
+    let mut seen = watch::stamps(inputs);
+    let mut previous = None;
+    loop {
+        if clear {
+            // Each version replaces the last on screen
+            print!("\x1b[2J\x1b[H");
+        }
+        let shown = if diff {
+            show_changes(inputs, view, defaults, depth, &load, &mut previous)
+        } else {
+            show_images(inputs, view, defaults, depth, &load)
+        };
+        if let Err(err) = shown {
+            eprintln!("Error: {:#}", err);
+        }
+        let _ = diagnostics::report();
+        diagnostics::note(format!("Watching {} for changes (Ctrl-C to stop)", names.join(", ")));
+        seen = watch::wait_for_change(inputs, &seen, watch::POLL_INTERVAL);
+    }
+}
+
+/// Print what changed in each image of `inputs` since `previous`, or the
+/// trees when there is nothing to compare with yet, and keep the images as
+/// `previous` for next time
+fn show_changes(
+    inputs: &[PathBuf],
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+    load: impl Fn(&Path) -> Result<Vec<(String, archive::Image)>>,
+    previous: &mut Option<Vec<(String, archive::Image)>>,
+) -> Result<()> {
+    let mut images = Vec::new();
+    for input in inputs {
+        images.extend(load(input)?);
+    }
+    let color = settings::resolve(view.color.clone(), defaults.color.clone(), "tree.color",
+        &["auto", "always", "never"], "auto")?;
+    let color = use_color(&color).then_some(depth);
+    let several = images.len() > 1;
+    for (i, (name, image)) in images.iter().enumerate() {
+        if several {
+            println!("{}{}:", if i > 0 { "\n" } else { "" }, name);
+        }
+        let old = previous.iter().flatten().find(|(old_name, _)| old_name == name);
+        match old {
+            Some((_, old)) => {
+                let (changes, tree) = diff::diff_images(old, image, &diff::DiffOptions::default());
+                let mut out = std::io::stdout().lock();
+                diff::write_text(&mut out, &changes, &tree, color)?;
+                out.flush()?;
+            }
+            None => print_tree(image, view, defaults, depth, several)?,
+        }
+    }
+    *previous = Some(images);
+    Ok(())
+}
+
+/// Print the images `load` gets out of each of `inputs` as `view` says, one
+/// after another or as one JSON listing
+fn show_images(
+    inputs: &[PathBuf],
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+    load: impl Fn(&Path) -> Result<Vec<(String, archive::Image)>>,
+) -> Result<()> {
+    let format = match &view.template {
+        Some(_) if view.format.is_none() => "template".to_string(),
+        _ => settings::resolve(view.format.clone(), defaults.format.clone(), "tree.format",
+            &["text", "json", "template"], "text")?,
+    };
+    let template = match (format.as_str(), &view.template) {
+        ("template", Some(text)) => Some(template::Template::parse(text)?),
+        ("template", None) => anyhow::bail!("--format template needs a --template"),
+        _ => None,
+    };
+    let mut images = Vec::new();
+    let mut shown = 0;
+    for input in inputs {
+        let loaded = load(input)?;
+        let several = inputs.len() > 1 || loaded.len() > 1;
+        for (name, image) in loaded {
+            if format == "json" {
+                check_paranoid(view, &image)?;
+                check_case_collisions(view, &image)?;
+                images.push((name, image));
+                continue;
+            }
+            if several {
+                // Tags alone may not tell the images apart
+                println!("{}{}:", if shown > 0 { "\n" } else { "" }, name);
+            }
+            shown += 1;
+            match &template {
+                Some(template) => {
+                    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                    template::write_entries(&mut out, &image.root, template)?;
+                    out.flush()?;
+                }
+                None => print_tree(&image, view, defaults, depth, several)?,
+            }
+            check_paranoid(view, &image)?;
+            check_case_collisions(view, &image)?;
+        }
+    }
+
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    match images.as_slice() {
+        [] => return Ok(()),
+        [(_, image)] => image.root.to_json_with_layers(&mut out, &image.layer_labels)?,
+        _ => {
+            let trees: Vec<_> = images.iter()
+                .map(|(name, image)| (name.as_str(), &image.root, image.layer_labels.as_slice()))
+                .collect();
+            tree::trees_to_json(&mut out, &trees)?;
+        }
+    }
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Run `f` on the archive at `path`, pulling a `docker://` reference into a
+/// temporary archive first
+fn with_archive<T>(path: &Path, f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    #[cfg(feature = "remote")]
+    if let Some(reference) = path.to_str().filter(|p| p.starts_with(registry::SCHEME)) {
+        // The pulled archive is deleted once `f` is done with it
+        let pulled = input::pull_image(reference)?;
+        return f(pulled.path());
+    }
+    f(path)
+}
+
+/// Merge the layers of `archive_path`, tracking what `view` shows
+fn load_image(archive_path: &Path, view: &ViewArgs, defaults: &settings::CommandDefaults) -> Result<archive::Image> {
+    let sort = view.sort.as_deref().or(defaults.sort.as_deref());
+    // A saved tree keeps its layers for `render --layers` and `--blame`
+    let json = view.format.as_deref().or(defaults.format.as_deref()) == Some("json");
+    let process_options = archive::ProcessOptions {
+        record_layers: view.layers || view.blame.is_some() || sort == Some("layer") || view.template.is_some() || json,
+        track_shadowed: view.show_shadowed,
+        track_opaque: view.show_opaque,
+        term_progress: view.term_progress,
+        profile_layers: view.profile_layers,
+        literal_paths: view.literal_paths,
+        whiteouts: view.whiteouts.as_deref().map(whiteout::Dialect::from_name).unwrap_or_default(),
+        hash_contents: false,
+        platform: None,
+        image: view.image.clone(),
+        // Rather than silently reading the first of several images
+        choose_image: input::is_interactive().then(|| ImageChooser::new(input::choose_image)),
+        keep_devices: false,
+        resolve_owners: view.owners,
+        paranoid: view.paranoid,
+        allow_missing_layers: view.allow_missing_layers,
+        strict: view.strict,
+        #[cfg(feature = "cache")]
+        cache: view.cache,
+    };
+    let image = archive::process_archive(archive_path, &process_options)?;
+
+    if view.profile_layers {
+        profile::write_report(&mut std::io::stderr().lock(), &image.layer_profiles)?;
+    }
+    Ok(image)
+}
+
+/// With --paranoid, report the suspicious entries of `image` and fail if there are any
+fn check_paranoid(view: &ViewArgs, image: &archive::Image) -> Result<()> {
+    if view.paranoid && !image.suspicious.is_empty() {
+        paranoid::write_report(&mut std::io::stderr().lock(), &image.suspicious)?;
+        anyhow::bail!("Archive has suspicious entries");
+    }
+    Ok(())
+}
+
+/// With --check-case-collisions, report the paths of `image` that differ
+/// only by case and fail if there are any
+fn check_case_collisions(view: &ViewArgs, image: &archive::Image) -> Result<()> {
+    if !view.check_case_collisions {
+        return Ok(());
+    }
+    let collisions = casefold::find(&image.root);
+    if !collisions.is_empty() {
+        casefold::write_report(&mut std::io::stderr().lock(), &collisions)?;
+        anyhow::bail!("Image has paths that differ only by case");
+    }
+    Ok(())
+}
+
+/// Print the tree of `image` as `view` says, with a header when asked for
+/// (or by default on a terminal, or when `several` trees are printed)
+fn print_tree(
+    image: &archive::Image,
+    view: &ViewArgs,
+    defaults: &settings::CommandDefaults,
+    depth: ColorDepth,
+    several: bool,
+) -> Result<()> {
+    let color = settings::resolve(view.color.clone(), defaults.color.clone(), "tree.color",
+        &["auto", "always", "never"], "auto")?;
+    let icons = settings::resolve(view.icons.clone(), defaults.icons.clone(), "tree.icons",
+        &["none", "emoji", "nerd"], "nerd")?;
+    let charset = settings::resolve(view.charset.clone(), defaults.charset.clone(), "tree.charset",
+        &["unicode", "ascii"], "unicode")?;
+    let sort = settings::resolve(view.sort.clone(), defaults.sort.clone(), "tree.sort", &SORT_KEYS, "name")?;
+
+    let use_color = use_color(&color);
+
+    // Load theme, from the command line or else the configuration
+    if view.theme.is_none() && view.theme_file.is_none() && defaults.theme.is_some() && defaults.theme_file.is_some() {
+        anyhow::bail!("Set either 'tree.theme' or 'tree.theme-file' in configuration, not both");
+    }
+    let theme = if let Some(spec) = &view.theme {
+        theme::Theme::parse(spec)?
+    } else if let Some(path) = &view.theme_file {
+        theme::Theme::from_file(path)?
+    } else if let Some(spec) = &defaults.theme {
+        theme::Theme::parse(spec).context("Invalid 'tree.theme' in configuration")?
+    } else if let Some(path) = &defaults.theme_file {
+        theme::Theme::from_file(path)?
     } else {
         theme::Theme::default()
+    }
+    .with_depth(depth);
+
+    // Metadata-only build steps have no entries, so announce them explicitly
+    let (empty_layers, layer_details) = if view.layers {
+        let history = image.config.as_ref().map(|config| config.history.as_slice()).unwrap_or_default();
+        let history = layers::without_missing(history, &image.missing_layers);
+        let rows = layers::align_history(&image.layer_labels, &history);
+        (layers::empty_layers(&rows), layers::layer_details(&rows, &image.layer_sizes))
+    } else {
+        (Vec::new(), Vec::new())
     };
 
-    // Process the Docker archive
-    let root = archive::process_archive(&cli.archive, cli.layers)?;
+    let mut separator = render::SeparatorStyle { fill: view.separator_fill, ..Default::default() };
+    if let Some(label) = &view.separator_label {
+        render::SeparatorStyle::check_label(label)?;
+        separator.label = label.clone();
+    }
+    if let Some(placement) = &view.separator_placement {
+        separator.placement = render::SeparatorPlacement::from_name(placement);
+    }
+
+    // Declared volumes, found through symlinked parents like the runtime would
+    let volumes = image.config.iter()
+        .flat_map(|config| config.config.volumes.iter().flatten())
+        .map(|(path, _)| path.trim_matches('/'))
+        .filter(|path| !path.is_empty())
+        .map(|path| image.root.resolve_parents(path).into_owned())
+        .collect();
 
     // Render the tree
-    let options = render::RenderOptions {
-        show_long: cli.long,
-        show_layers: cli.layers,
-        use_color,
-        icon_style: render::IconStyle::from_str(&cli.icons),
-        theme,
+    let options = render::RenderOptions::builder()
+        .long(view.long)
+        .layers(view.layers)
+        .shadowed(view.show_shadowed)
+        .opaque(view.show_opaque)
+        .blame(view.blame.as_deref().map(render::BlameStyle::from_name))
+        .empty_layers(empty_layers)
+        .layer_labels(image.layer_labels.clone())
+        .layer_details(layer_details)
+        .separator(separator)
+        .color(use_color)
+        .icons(render::IconStyle::from_name(&icons))
+        .charset(render::Charset::from_name(&charset))
+        .full_paths(view.full_paths)
+        .sort(render::SortKey::from_name(&sort))
+        .reverse(view.reverse)
+        .dirs_first(view.dirs_first || defaults.dirs_first == Some(true))
+        .natural_sort(view.natural_sort || defaults.natural_sort == Some(true))
+        .ignore_case(view.ignore_case || defaults.ignore_case == Some(true))
+        .volumes(volumes)
+        .theme(theme)
+        .limit(view.limit.or(defaults.limit).filter(|&n| n > 0))
+        .width(terminal_width())
+        .overflow(if view.truncate {
+            render::Overflow::Truncate
+        } else if view.wrap {
+            render::Overflow::Wrap
+        } else {
+            render::Overflow::Extend
+        })
+        .build();
+
+    let mut stdout = std::io::stdout().lock();
+    // A partial tree says so unless told not to
+    let partial = !image.missing_layers.is_empty();
+    let header = if view.header || view.no_header {
+        view.header
+    } else {
+        several || partial || atty::is(atty::Stream::Stdout)
     };
+    if header {
+        render::write_header(&mut stdout, image, &options)?;
+    }
+    render::render_tree(&mut stdout, &image.root, &options)?;
+    Ok(())
+}
 
-    render::render_tree(&root, &options)?;
+/// Run a subcommand
+fn run_command(
+    command: Command,
+    settings: &settings::Settings,
+    profile: Option<&str>,
+    depth: ColorDepth,
+    quiet: bool,
+) -> Result<()> {
+    match command {
+        Command::Tree(tree) => run_tree(tree, settings.defaults_for(profile, "tree")?, depth),
+        Command::Last(view) => {
+            let defaults = settings.defaults_for(profile, "tree")?;
+            // Keeps the saved image on disk until rendering is done
+            if view.watch.is_some() {
+                anyhow::bail!("--watch follows archive files, and `last` reads the Docker daemon");
+            }
+            let (description, archive) = input::save_last_image()?;
+            diagnostics::note(format!("Inspecting {}", description));
+            render_archives(&[archive.path().to_path_buf()], &view, &defaults, depth)
+        }
+        Command::Render(render) => {
+            render_saved(&render.trees, &render.view, &settings.defaults_for(profile, "tree")?, depth)
+        }
+        #[cfg(feature = "tui")]
+        Command::Tui {
+            archive, new, image, owners,
+            #[cfg(feature = "cache")]
+            cache,
+        } => {
+            tui::ensure_terminal()?;
+            let process_options = archive::ProcessOptions {
+                // The layer filter needs to know where every entry came from
+                record_layers: true,
+                image,
+                choose_image: input::is_interactive().then(|| ImageChooser::new(input::choose_image)),
+                resolve_owners: owners,
+                #[cfg(feature = "cache")]
+                cache,
+                ..Default::default()
+            };
+            // Kept open, so the preview can read files back out of the layers
+            let open = |path: &Path| -> Result<_> {
+                let layer_set = source::for_path(path)
+                    .open_layers(&process_options.selector(), &mut progress::TermProgress::disabled())?;
+                let image = archive::process_layer_set(&layer_set, &process_options)?;
+                Ok((layer_set, image))
+            };
+            with_archive(&archive, |path| {
+                let (layer_set, image) = open(path)?;
+                let Some(new) = &new else {
+                    return tui::run(&image, &layer_set, &process_options);
+                };
+                with_archive(new, |new_path| {
+                    let (new_layer_set, new_image) = open(new_path)?;
+                    tui::run_diff(&image, &layer_set, &new_image, &new_layer_set)
+                })
+            })
+        }
+        #[cfg(feature = "analyzers")]
+        Command::Dupes { archive } => {
+            let layer_set = archive::open_archive(&archive)?;
+            let groups = dupes::find_duplicates(&layer_set)?;
+            dupes::render_report(&groups)?;
+            Ok(())
+        }
+        #[cfg(feature = "analyzers")]
+        Command::Find { archive, sha256, size, name } => {
+            let query = find::Query::new(sha256.as_deref(), size, name.as_deref())?;
+            let layer_set = archive::open_archive(&archive)?;
+            let matches = find::find_files(&layer_set, query)?;
+            find::write_report(&mut std::io::stdout().lock(), &matches)?;
+            if matches.is_empty() {
+                anyhow::bail!("No matching files found");
+            }
+            Ok(())
+        }
+        #[cfg(feature = "analyzers")]
+        Command::Analyze { archive, only } => {
+            let mut registry = analyzer::Registry::builtin();
+            if !only.is_empty() {
+                if let Some(unknown) = only.iter().find(|n| !registry.names().contains(&n.as_str())) {
+                    anyhow::bail!("Unknown analyzer '{}' (available: {})",
+                        unknown, registry.names().join(", "));
+                }
+                registry.retain(&only);
+            }
+            let layer_set = archive::open_archive(&archive)?;
+            let findings = registry.run(&layer_set)?;
+            analyzer::render_findings(&findings)?;
+            Ok(())
+        }
+        #[cfg(feature = "analyzers")]
+        Command::Secrets { archive, format } => {
+            let defaults = settings.defaults_for(profile, "secrets")?;
+            let format = settings::resolve(format, defaults.format, "secrets.format", &["text", "sarif"], "text")?;
+            let mut analyzer = secrets::SecretsAnalyzer::new(&settings.secrets)?;
+            let layer_set = archive::open_archive(&archive)?;
+            let findings = analyzer::scan(&layer_set, &mut [&mut analyzer])?;
+            if format == "sarif" {
+                secrets::write_sarif(&mut std::io::stdout().lock(), &analyzer, &findings)?;
+            } else {
+                analyzer::render_findings(&findings)?;
+            }
+            if !findings.is_empty() {
+                anyhow::bail!("{} potential secret(s) found", findings.len());
+            }
+            Ok(())
+        }
+        Command::Layers { archive } => {
+            let layer_set = archive::open_archive(&archive)?;
+            Ok(layers::render_table(&layer_set)?)
+        }
+        Command::History { archive, no_trunc } => {
+            let layer_set = archive::open_archive(&archive)?;
+            layers::run_history(&layer_set, no_trunc)
+        }
+        Command::Bloat { archive } => bloat::run(&archive),
+        Command::Summary { archive } => summary::run(&archive),
+        Command::Query { archive, expr, format } => {
+            let defaults = settings.defaults_for(profile, "query")?;
+            let formats = ["tree", "flat", "json"];
+            let format = settings::resolve(format, defaults.format, "query.format", &formats, "tree")?;
+            with_archive(&archive, |path| query::run(path, &expr, &format))
+        }
+        Command::Dockerfile { archive } => dockerfile::run(&archive),
+        Command::Inspect { archive, format, image } => {
+            let defaults = settings.defaults_for(profile, "inspect")?;
+            let format = settings::resolve(format, defaults.format, "inspect.format", &["text", "json"], "text")?;
+            with_archive(&archive, |path| inspect::run(path, &format, image.as_deref()))
+        }
+        Command::LayerDiff { archive, layer, format, whiteouts } => {
+            let defaults = settings.defaults_for(profile, "layer-diff")?;
+            let format = settings::resolve(format, defaults.format, "layer-diff.format", &["text", "json"], "text")?;
+            let options = archive::ProcessOptions {
+                whiteouts: whiteouts.as_deref().map(whiteout::Dialect::from_name).unwrap_or_default(),
+                ..Default::default()
+            };
+            let diff = layerdiff::layer_diff(&archive, layer, &options)?;
+            if format == "json" {
+                layerdiff::render_json(&diff)
+            } else {
+                Ok(layerdiff::render_text(&diff)?)
+            }
+        }
+        #[cfg(feature = "self-update")]
+        Command::SelfUpdate { check, force } => update::self_update(check, force),
+        Command::Diff {
+            old, new, platform, platform2, format, color, metadata_only,
+            #[cfg(feature = "cache")]
+            cache,
+        } => {
+            // Exit like diff(1): 0 identical, 1 different, 2 trouble
+            let result = settings.defaults_for(profile, "diff").and_then(|defaults| {
+                let format = settings::resolve(format, defaults.format, "diff.format", &["text", "json"], "text")?;
+                let color = settings::resolve(color, defaults.color, "diff.color", &["auto", "always", "never"], "auto")?;
+                if new.is_none() && platform2.is_none() {
+                    anyhow::bail!("Give a second archive, or --platform2 to compare two platforms of one archive");
+                }
+                let options = diff::DiffOptions {
+                    metadata_only,
+                    old_platform: platform.as_deref().map(oci::Platform::parse).transpose()?,
+                    new_platform: platform2.as_deref().map(oci::Platform::parse).transpose()?,
+                    #[cfg(feature = "cache")]
+                    cache,
+                };
+                let new = new.as_ref().unwrap_or(&old);
+                diff::run(&old, new, &format, use_color(&color).then_some(depth), quiet, &options)
+            });
+            let _ = diagnostics::report();
+            if let Err(err) = &result {
+                eprintln!("Error: {:?}", err);
+            }
+            std::process::exit(diff::exit_code(&result));
+        }
+        Command::Themes { color } => {
+            let color = use_color(color.as_deref().unwrap_or("auto")).then_some(depth);
+            Ok(theme::list_themes(&mut std::io::stdout().lock(), color)?)
+        }
+        Command::Audit { archive, format } => {
+            let defaults = settings.defaults_for(profile, "audit")?;
+            let format = settings::resolve(format, defaults.format, "audit.format", &["text", "sarif"], "text")?;
+            audit::run(&archive, &format)
+        }
+        Command::Keys { archive } => keys::run(&archive),
+        Command::Ghosts { archive } => ghosts::run(&archive),
+        Command::Packages { archive } => packages::run(&archive),
+        Command::Orphans { archive } => packages::run_orphans(&archive),
+        Command::Check { archive } => check::run(&archive),
+        Command::Export { archive, output, recompress, dry_run } => {
+            let recompress = recompress.as_deref().map(export::Recompress::parse).transpose()?;
+            if dry_run {
+                export::dry_run(&archive, &output, recompress)
+            } else {
+                export::export(&archive, &output, recompress)
+            }
+        }
+        Command::Extract { archive, path, output, dry_run } => {
+            if dry_run {
+                extract::dry_run(&archive, &path, &output)
+            } else {
+                extract::run(&archive, &path, &output)
+            }
+        }
+        Command::Baseline { action: BaselineCommand::Save { archive, name } } => {
+            baseline::save(&archive, &name)
+        }
+        Command::Baseline { action: BaselineCommand::Diff { name, archive, format, color } } => {
+            let defaults = settings.defaults_for(profile, "baseline-diff")?;
+            let format = settings::resolve(format, defaults.format, "baseline-diff.format",
+                &["text", "json"], "text")?;
+            let color = settings::resolve(color, defaults.color, "baseline-diff.color",
+                &["auto", "always", "never"], "auto")?;
+            baseline::run_diff(&name, &archive, &format, use_color(&color).then_some(depth))
+        }
+    }
+}
 
-    Ok(())
+/// Columns to fit the tree and separators in: $COLUMNS, else the width of
+/// the terminal on stdout (None when piped)
+fn terminal_width() -> Option<usize> {
+    std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()).filter(|&n| n > 0)
+        .or_else(|| terminal_size::terminal_size().map(|(width, _)| usize::from(width.0)))
+}
+
+/// Decide whether to emit color for an auto/always/never setting
+fn use_color(when: &str) -> bool {
+    match when {
+        "always" => true,
+        "never" => false,
+        _ => auto_color(|name| std::env::var_os(name), atty::is(atty::Stream::Stdout)),
+    }
+}
+
+/// Color for `auto`: NO_COLOR (no-color.org) turns it off, CLICOLOR_FORCE
+/// turns it on even when piped, CLICOLOR=0 turns it off, and otherwise only
+/// terminals get color
+fn auto_color(var: impl Fn(&str) -> Option<OsString>, is_terminal: bool) -> bool {
+    if var("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        false
+    } else if var("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0") {
+        true
+    } else if var("CLICOLOR").is_some_and(|v| v == "0") {
+        false
+    } else {
+        is_terminal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_color_env() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| OsString::from(v))
+        };
+        assert!(auto_color(env(&[]), true));
+        assert!(!auto_color(env(&[]), false));
+        assert!(!auto_color(env(&[("NO_COLOR", "1")]), true));
+        assert!(auto_color(env(&[("NO_COLOR", "")]), true));
+        assert!(auto_color(env(&[("CLICOLOR_FORCE", "1")]), false));
+        assert!(!auto_color(env(&[("CLICOLOR_FORCE", "0")]), false));
+        assert!(!auto_color(env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]), false));
+        assert!(!auto_color(env(&[("CLICOLOR", "0")]), true));
+    }
+
+    #[test]
+    fn test_subcommands_after_arguments() {
+        let cli = Cli::try_parse_from(["contree", "--long", "a.tar"]).unwrap();
+        assert!(cli.command.is_none() && cli.tree.view.long);
+        assert_eq!(cli.tree.archives, [PathBuf::from("a.tar")]);
+        assert!(matches!(Cli::try_parse_from(["contree", "diff", "a", "b"]).unwrap().command, Some(Command::Diff { .. })));
+
+        let matches = Cli::command().try_get_matches_from(["contree", "--long", "diff", "a", "b"]).unwrap();
+        assert!(reject_tree_args(&matches).is_err());
+        let matches = Cli::command().try_get_matches_from(["contree", "tree", "--long", "a"]).unwrap();
+        assert!(reject_tree_args(&matches).is_ok());
+    }
+
+    #[test]
+    fn test_global_flags_before_subcommand() {
+        let cli = Cli::try_parse_from(["contree", "--profile", "ci", "diff", "a", "b"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("ci"));
+        assert!(matches!(cli.command, Some(Command::Diff { old, new: Some(new), .. }) if old == Path::new("a") && new == Path::new("b")));
+
+        let cli = Cli::try_parse_from(["contree", "--temp-dir", "/scratch", "--color-depth", "256", "layers", "a"]).unwrap();
+        assert_eq!(cli.temp_dir.as_deref(), Some(Path::new("/scratch")));
+        assert_eq!(cli.color_depth.as_deref(), Some("256"));
+        assert!(matches!(cli.command, Some(Command::Layers { archive }) if archive == Path::new("a")));
+    }
+
+    #[test]
+    fn test_verbosity_before_subcommand() {
+        let cli = Cli::try_parse_from(["contree", "-q", "diff", "a.tar", "b.tar"]).unwrap();
+        assert!(cli.quiet && !cli.verbose);
+        assert!(matches!(cli.command, Some(Command::Diff { .. })));
+
+        let cli = Cli::try_parse_from(["contree", "-v", "layers", "a.tar"]).unwrap();
+        assert!(cli.verbose && !cli.quiet);
+        assert!(matches!(cli.command, Some(Command::Layers { .. })));
+        assert!(Cli::try_parse_from(["contree", "-v", "-q", "layers", "a.tar"]).is_err());
+    }
+
+    #[test]
+    fn test_bare_blame_before_archive() {
+        let cli = Cli::try_parse_from(["contree", "--blame", "image.tar"]).unwrap();
+        assert_eq!(cli.tree.view.blame.as_deref(), Some("hash"));
+        assert_eq!(cli.tree.archives, [PathBuf::from("image.tar")]);
+        let cli = Cli::try_parse_from(["contree", "--blame=index", "image.tar"]).unwrap();
+        assert_eq!(cli.tree.view.blame.as_deref(), Some("index"));
+    }
+
+    #[test]
+    fn test_settle_opposites() {
+        let settle = |args: &[&str]| {
+            let matches = Cli::command().try_get_matches_from(args).unwrap();
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            settle_opposites(&mut cli.tree.view, &matches).map(|()| cli.tree.view)
+        };
+        let view = settle(&["contree", "--header", "--no-header", "a.tar"]).unwrap();
+        assert!(!view.header && view.no_header);
+        let view = settle(&["contree", "--no-header", "--header", "a.tar"]).unwrap();
+        assert!(view.header && !view.no_header);
+        assert!(settle(&["contree", "--truncate", "--wrap", "a.tar"]).is_err());
+        assert!(settle(&["contree", "--theme", "nord", "--theme-file", "t.toml", "a.tar"]).is_err());
+    }
 }
@@ -1,32 +1,36 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Docker manifest.json structure
 /// The manifest is an array of image descriptors
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ManifestEntry {
     #[serde(rename = "Config")]
-    #[allow(dead_code)]
     pub config: Option<String>,
 
     #[serde(rename = "RepoTags")]
-    #[allow(dead_code)]
     pub repo_tags: Option<Vec<String>>,
 
     #[serde(rename = "Layers")]
     pub layers: Vec<String>,
 }
 
-/// Parse the manifest.json to extract ordered layer paths
-pub fn parse_manifest(manifest_bytes: &[u8]) -> Result<Vec<String>> {
-    let manifest: Vec<ManifestEntry> = serde_json::from_slice(manifest_bytes)
-        .context("Failed to parse manifest.json")?;
-
+/// Parse the manifest.json and return the image descriptor to process
+pub fn parse_manifest(manifest_bytes: &[u8]) -> Result<ManifestEntry> {
     // Get the first manifest entry (most archives have only one)
-    let entry = manifest.into_iter().next()
-        .ok_or_else(|| anyhow::anyhow!("Empty manifest"))?;
+    let entry = parse_manifests(manifest_bytes)?.swap_remove(0);
+    Ok(entry)
+}
 
-    Ok(entry.layers)
+/// Parse the manifest.json and return every image descriptor in it, in
+/// order (`docker save a b` lists two)
+pub fn parse_manifests(manifest_bytes: &[u8]) -> Result<Vec<ManifestEntry>> {
+    let manifest: Vec<ManifestEntry> = serde_json::from_slice(manifest_bytes)
+        .context("Failed to parse manifest.json")?;
+    if manifest.is_empty() {
+        anyhow::bail!("Empty manifest");
+    }
+    Ok(manifest)
 }
 
 #[cfg(test)]
@@ -45,7 +49,9 @@ mod tests {
             ]
         }]"#;
 
-        let layers = parse_manifest(manifest_json.as_bytes()).unwrap();
+        let entry = parse_manifest(manifest_json.as_bytes()).unwrap();
+        assert_eq!(entry.config.as_deref(), Some("abc123.json"));
+        let layers = entry.layers;
         assert_eq!(layers.len(), 3);
         assert_eq!(layers[0], "layer1/layer.tar");
         assert_eq!(layers[1], "layer2/layer.tar");
@@ -0,0 +1,89 @@
+//! Archives held in memory, such as a tarball dropped into a web page
+//!
+//! Nothing touches the filesystem: blobs of an uncompressed archive are read
+//! in place from the shared buffer, and a compressed archive is decompressed
+//! into memory first. This is the source a `wasm32-unknown-unknown` build
+//! reads images with.
+
+use anyhow::Context;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tar::Archive;
+
+use crate::archive::{decompress, Blob, Compression, LayerSet};
+use crate::error;
+use crate::progress::TermProgress;
+use crate::source::{self, ImageSelector, LayerSource};
+use crate::tarball::{self, Keep};
+
+/// A `docker save` tarball (Docker or OCI layout) held in memory
+pub struct MemorySource {
+    /// Name of the archive in messages, e.g. the file it was read from
+    name: PathBuf,
+    bytes: Arc<[u8]>,
+}
+
+impl MemorySource {
+    pub fn new(name: impl Into<PathBuf>, bytes: impl Into<Arc<[u8]>>) -> Self {
+        MemorySource { name: name.into(), bytes: bytes.into() }
+    }
+}
+
+impl LayerSource for MemorySource {
+    fn open_layers(&self, select: &ImageSelector, _progress: &mut TermProgress) -> error::Result<LayerSet> {
+        let buffer: Arc<[u8]> = match Compression::from_magic(&self.bytes) {
+            Compression::None => Arc::clone(&self.bytes),
+            compression => {
+                let mut bytes = Vec::new();
+                decompress(compression, Cursor::new(Arc::clone(&self.bytes)))?
+                    .read_to_end(&mut bytes)
+                    .context("Failed to decompress archive")?;
+                bytes.into()
+            }
+        };
+
+        let mut archive = Archive::new(Cursor::new(Arc::clone(&buffer)));
+        let entries = archive.entries_with_seek().context("Failed to read archive entries")?;
+        let in_buffer = |offset, size| Blob::InBuffer { buffer: Arc::clone(&buffer), offset, size };
+        let contents = tarball::collect_blobs(entries, Keep::InPlace(&in_buffer), &mut || {})?;
+        let entry = source::select_image(&self.name, &contents, select)?;
+        LayerSet::assemble(contents.blobs, entry, None, select)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{self, ProcessOptions};
+
+    #[test]
+    fn test_memory_source_reads_compressed_archive() {
+        let mut layer = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        layer.append_data(&mut header, "etc/hostname", &b"box\n"[..]).unwrap();
+        let layer = layer.into_inner().unwrap();
+
+        let manifest = br#"[{"Config":"","RepoTags":["box:1"],"Layers":["abc123/layer.tar"]}]"#;
+        let mut outer = tar::Builder::new(Vec::new());
+        for (name, data) in [("abc123/layer.tar", &layer[..]), ("manifest.json", &manifest[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            outer.append_data(&mut header, name, data).unwrap();
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, &outer.into_inner().unwrap()).unwrap();
+
+        let source = MemorySource::new("box.tar.gz", encoder.finish().unwrap());
+        let image = archive::process_source(&source, &ProcessOptions::default()).unwrap();
+        assert!(image.root.get("etc/hostname").is_some());
+
+        let err = archive::process_source(&MemorySource::new("notes.txt", &b"hello"[..]), &ProcessOptions::default());
+        assert!(matches!(err, Err(error::ContreeError::UnsupportedFormat { .. })));
+    }
+}
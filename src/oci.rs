@@ -0,0 +1,232 @@
+//! OCI image layouts: picking one platform's image out of an image index
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::error::ContreeError;
+use crate::manifest::ManifestEntry;
+
+/// Nested indexes deeper than this are treated as malformed
+const MAX_DEPTH: usize = 4;
+
+/// Annotations that carry an image's tag
+const NAME_ANNOTATIONS: [&str; 2] = ["io.containerd.image.name", "org.opencontainers.image.ref.name"];
+
+/// Target platform of an image manifest (e.g. linux/arm64/v8)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Parse `os/arch` or `os/arch/variant`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let parts: Vec<&str> = spec.split('/').collect();
+        match parts.as_slice() {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => Ok(Platform {
+                os: os.to_string(),
+                architecture: arch.to_string(),
+                variant: None,
+            }),
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => Ok(Platform {
+                os: os.to_string(),
+                architecture: arch.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => bail!("Invalid platform '{}' (expected os/arch or os/arch/variant)", spec),
+        }
+    }
+
+    /// Linux on the architecture of this machine: the image a container
+    /// runtime here would pull
+    pub fn native() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "x86" => "386",
+            other => other,
+        };
+        Platform { os: "linux".to_string(), architecture: architecture.to_string(), variant: None }
+    }
+
+    /// Whether a manifest for `other` satisfies this requested platform;
+    /// a request without a variant accepts any variant
+    fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: Option<String>,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    fn is_index(&self) -> bool {
+        self.media_type.as_deref().is_some_and(|t| {
+            t == "application/vnd.oci.image.index.v1+json"
+                || t == "application/vnd.docker.distribution.manifest.list.v2+json"
+        })
+    }
+
+    fn name(&self) -> Option<&String> {
+        NAME_ANNOTATIONS.iter().find_map(|key| self.annotations.get(*key))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// Path of a blob inside an OCI layout (`sha256:abc` -> `blobs/sha256/abc`)
+pub fn blob_name(digest: &str) -> Result<String> {
+    match digest.split_once(':') {
+        Some((algorithm, hex)) if !algorithm.is_empty() && !hex.is_empty() && !hex.contains('/') => {
+            Ok(format!("blobs/{}/{}", algorithm, hex))
+        }
+        _ => bail!("Invalid digest '{}'", digest),
+    }
+}
+
+/// Find the image for `platform` under an `index.json`, following nested
+/// indexes, and describe it like a docker-save manifest entry
+pub fn select_platform<F>(index_bytes: &[u8], platform: &Platform, read_blob: F) -> Result<ManifestEntry>
+where
+    F: Fn(&str) -> Result<Vec<u8>>,
+{
+    let (digest, name) = find_manifest(index_bytes, platform, |digest| read_blob(&blob_name(digest)?))?;
+    let bytes = read_blob(&blob_name(&digest)?)?;
+    let manifest: ImageManifest = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse image manifest {}", digest))?;
+    Ok(ManifestEntry {
+        config: Some(blob_name(&manifest.config.digest)?),
+        repo_tags: Some(name.into_iter().collect()),
+        layers: manifest.layers.iter().map(|l| blob_name(&l.digest)).collect::<Result<_>>()?,
+    })
+}
+
+/// Find the digest of the image manifest for `platform` under an image
+/// index, following nested indexes (read by digest with `read_index`), plus
+/// the image name annotated on the way
+pub fn find_manifest<F>(index_bytes: &[u8], platform: &Platform, read_index: F) -> Result<(String, Option<String>)>
+where
+    F: Fn(&str) -> Result<Vec<u8>>,
+{
+    let index: Index = serde_json::from_slice(index_bytes).context("Failed to parse index.json")?;
+
+    let mut available = Vec::new();
+    let mut pending: Vec<(Descriptor, Option<String>, usize)> =
+        index.manifests.into_iter().rev().map(|d| (d, None, 0)).collect();
+
+    while let Some((descriptor, name, depth)) = pending.pop() {
+        let name = descriptor.name().cloned().or(name);
+
+        if descriptor.is_index() {
+            if depth >= MAX_DEPTH {
+                bail!("Image index nested more than {} levels deep", MAX_DEPTH);
+            }
+            let bytes = read_index(&descriptor.digest)?;
+            let nested: Index = serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to parse image index {}", descriptor.digest))?;
+            pending.extend(nested.manifests.into_iter().rev().map(|d| (d, name.clone(), depth + 1)));
+            continue;
+        }
+
+        // Attestation manifests are listed with an unknown/unknown platform
+        let Some(found) = descriptor.platform.as_ref().filter(|p| p.os != "unknown") else {
+            continue;
+        };
+        if !platform.matches(found) {
+            available.push(found.to_string());
+            continue;
+        }
+        return Ok((descriptor.digest, name));
+    }
+
+    available.dedup();
+    Err(ContreeError::PlatformNotFound { platform: platform.to_string(), available }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = r#"{"manifests": [{
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "digest": "sha256:list",
+        "annotations": {"io.containerd.image.name": "docker.io/library/app:1.0"}
+    }]}"#;
+
+    const LIST: &str = r#"{"manifests": [
+        {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:amd",
+         "platform": {"os": "linux", "architecture": "amd64"}},
+        {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:arm",
+         "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}},
+        {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:att",
+         "platform": {"os": "unknown", "architecture": "unknown"}}
+    ]}"#;
+
+    fn read_blob(name: &str) -> Result<Vec<u8>> {
+        let text = match name {
+            "blobs/sha256/list" => LIST,
+            "blobs/sha256/arm" => r#"{"config": {"digest": "sha256:cfg"}, "layers": [{"digest": "sha256:l1"}]}"#,
+            _ => bail!("missing blob {}", name),
+        };
+        Ok(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        assert_eq!(Platform::parse("linux/arm64/v8").unwrap().to_string(), "linux/arm64/v8");
+        assert_eq!(Platform::parse("linux/amd64").unwrap().variant, None);
+        assert!(Platform::parse("amd64").is_err());
+        assert!(Platform::parse("linux//v8").is_err());
+    }
+
+    #[test]
+    fn test_select_platform_follows_nested_index() {
+        let entry = select_platform(INDEX.as_bytes(), &Platform::parse("linux/arm64").unwrap(), read_blob).unwrap();
+        assert_eq!(entry.config.as_deref(), Some("blobs/sha256/cfg"));
+        assert_eq!(entry.layers, vec!["blobs/sha256/l1"]);
+        assert_eq!(entry.repo_tags, Some(vec!["docker.io/library/app:1.0".to_string()]));
+    }
+
+    #[test]
+    fn test_select_platform_lists_available() {
+        let err = select_platform(INDEX.as_bytes(), &Platform::parse("linux/s390x").unwrap(), read_blob).unwrap_err();
+        assert_eq!(err.to_string(),
+            "Platform linux/s390x not found in image index (available: linux/amd64, linux/arm64/v8)");
+    }
+}
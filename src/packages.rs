@@ -0,0 +1,607 @@
+//! Installed package inventory from apk, dpkg, and rpm databases
+//!
+//! Databases are parsed from every layer that writes them, so each package
+//! is attributed to the layer where its current version first appeared.
+//! Their file lists also map files in the merged tree back to packages.
+//! [`PackageSink`] is also the `packages` analyzer of `contree analyze`.
+
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+
+#[cfg(feature = "analyzers")]
+use crate::analyzer::{Analyzer, EntryInfo, Finding};
+use crate::archive::{self, ProcessOptions};
+use crate::diagnostics::{self, WarningKind};
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::rpmdb;
+use crate::tree::Node;
+
+const APK_INSTALLED: &str = "lib/apk/db/installed";
+const DPKG_STATUS: &str = "var/lib/dpkg/status";
+/// One file per package, as written by distroless and other dpkg-less builds
+const DPKG_STATUS_DIR: &str = "var/lib/dpkg/status.d/";
+/// `<package>[:<arch>].list` file lists
+const DPKG_INFO_DIR: &str = "var/lib/dpkg/info/";
+const RPM_DATABASES: &[&str] = &[
+    "var/lib/rpm/rpmdb.sqlite",
+    "var/lib/rpm/Packages.db",
+    "var/lib/rpm/Packages",
+    "usr/lib/sysimage/rpm/rpmdb.sqlite",
+    "usr/lib/sysimage/rpm/Packages.db",
+    "usr/lib/sysimage/rpm/Packages",
+];
+
+/// Package manager state, never owned by a package itself
+const STATE_DIRS: &[&str] = &["lib/apk/db/", "var/lib/dpkg/", "var/lib/rpm/", "usr/lib/sysimage/rpm/"];
+
+/// Package manager owning a database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Manager {
+    Apk,
+    Dpkg,
+    Rpm,
+}
+
+impl Manager {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Manager::Apk => "apk",
+            Manager::Dpkg => "dpkg",
+            Manager::Rpm => "rpm",
+        }
+    }
+
+    /// Manager whose database lives at `path`
+    fn for_path(path: &str) -> Option<Self> {
+        let status_file = path.strip_prefix(DPKG_STATUS_DIR)
+            .is_some_and(|f| !f.contains('/') && !f.ends_with(".md5sums"));
+        if path == APK_INSTALLED {
+            Some(Manager::Apk)
+        } else if path == DPKG_STATUS || status_file {
+            Some(Manager::Dpkg)
+        } else if RPM_DATABASES.contains(&path) {
+            Some(Manager::Rpm)
+        } else {
+            None
+        }
+    }
+}
+
+/// Package named by a dpkg file list at `path`
+fn file_list_package(path: &str) -> Option<&str> {
+    let list = path.strip_prefix(DPKG_INFO_DIR).and_then(|f| f.strip_suffix(".list"))
+        .or_else(|| path.strip_prefix(DPKG_STATUS_DIR).and_then(|f| f.strip_suffix(".md5sums")))?;
+    let package = list.split(':').next().unwrap_or(list);
+    (!package.is_empty() && !package.contains('/')).then_some(package)
+}
+
+/// An installed package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub manager: Manager,
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// Label of the layer that installed this version
+    pub layer: String,
+    /// Paths the package installed, without a leading "/"
+    pub files: Vec<String>,
+}
+
+/// A package as recorded in one database, before layer attribution
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    version: String,
+    arch: String,
+    files: Vec<String>,
+}
+
+/// Contents of a package database file
+enum Database {
+    Packages(Vec<Package>),
+    /// A dpkg file list for the named package
+    FileList { package: String, files: Vec<String> },
+}
+
+/// List the packages installed in an archive's final filesystem
+pub fn run(archive_path: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let mut sink = PackageSink::default();
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_table(&mut out, &sink.into_packages())?;
+    out.flush()?;
+    Ok(())
+}
+
+/// List files in the merged tree that no package installed
+pub fn run_orphans(archive_path: &Path) -> Result<()> {
+    let options = ProcessOptions { record_layers: true, resolve_owners: true, ..Default::default() };
+    let image = archive::process_archive(archive_path, &options)?;
+    let (owned, orphans) = collect_orphans(&image.root);
+    if owned == 0 {
+        bail!("No package file lists found in the image");
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_orphans(&mut out, &orphans)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Tracks the latest copy of every package database across layers
+#[derive(Default)]
+pub struct PackageSink {
+    /// Label and index of the layer being read
+    layer: String,
+    index: usize,
+    /// Database path -> index of the layer that wrote it, and its contents
+    databases: BTreeMap<String, (usize, Database)>,
+}
+
+impl PackageSink {
+    /// Packages from every database still present, sorted by manager and name,
+    /// with dpkg file lists folded in
+    pub fn into_packages(self) -> Vec<Package> {
+        let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+        let mut packages = Vec::new();
+        for (_, database) in self.databases.into_values() {
+            match database {
+                Database::Packages(found) => packages.extend(found),
+                Database::FileList { package, files } => lists.entry(package).or_default().extend(files),
+            }
+        }
+        for package in packages.iter_mut().filter(|p| p.manager == Manager::Dpkg) {
+            if let Some(files) = lists.remove(&package.name) {
+                package.files.extend(files);
+            }
+        }
+
+        packages.sort_by(|a, b| {
+            (a.manager, &a.name, &a.arch).cmp(&(b.manager, &b.name, &b.arch))
+        });
+        packages
+    }
+
+    /// Replace the packages recorded for `path`, keeping the original layer
+    /// of packages whose version didn't change
+    fn update(&mut self, path: &str, manager: Manager, content: &[u8]) {
+        let parsed = match parse_database(manager, content) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                diagnostics::warn(WarningKind::PackageDatabase, format!("Failed to read {} in layer {}: {:#}", path, self.layer, err));
+                Vec::new()
+            }
+        };
+
+        let previous = match self.databases.remove(path) {
+            Some((_, Database::Packages(previous))) => previous,
+            _ => Vec::new(),
+        };
+        let packages = parsed.into_iter().map(|Record { name, version, arch, files }| {
+            let layer = previous.iter()
+                .find(|p| p.name == name && p.version == version && p.arch == arch)
+                .map_or_else(|| self.layer.clone(), |p| p.layer.clone());
+            Package { manager, name, version, arch, layer, files }
+        }).collect();
+        self.databases.insert(path.to_string(), (self.index, Database::Packages(packages)));
+    }
+
+    /// Forget databases at or below `path`; `lower_only` spares ones the
+    /// current layer wrote
+    fn remove(&mut self, path: &str, lower_only: bool) {
+        let current = self.index;
+        self.databases.retain(|db, (index, _)| {
+            let under = db == path || db.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'));
+            !under || (lower_only && *index == current)
+        });
+    }
+
+    /// Follow the layer being read and the databases its entries remove;
+    /// database files themselves are read by `add_file`
+    fn track(&mut self, event: &LayerEvent) {
+        match event {
+            LayerEvent::LayerStarted { index, layer, .. } => {
+                self.layer = layer.label();
+                self.index = *index;
+            }
+            LayerEvent::EntryAdded { entry, .. } if entry.kind == EntryKind::File && is_database(&entry.path) => {}
+            // Anything else at a database path (or a file over a database
+            // directory) replaces it
+            LayerEvent::EntryAdded { entry, .. } if entry.kind != EntryKind::Dir => self.remove(&entry.path, false),
+            LayerEvent::EntryRemoved { path } => self.remove(path, false),
+            LayerEvent::OpaqueApplied { dir } => self.remove(dir, true),
+            _ => {}
+        }
+    }
+
+    /// Read a database or file list the current layer wrote
+    fn add_file(&mut self, path: &str, content: &[u8]) {
+        if let Some(manager) = Manager::for_path(path) {
+            self.update(path, manager, content);
+        } else if let Some(package) = file_list_package(path) {
+            let files = parse_file_list(&String::from_utf8_lossy(content));
+            let database = Database::FileList { package: package.to_string(), files };
+            self.databases.insert(path.to_string(), (self.index, database));
+        }
+    }
+}
+
+/// Whether `path` is a package database or dpkg file list
+fn is_database(path: &str) -> bool {
+    Manager::for_path(path).is_some() || file_list_package(path).is_some()
+}
+
+impl EventSink for PackageSink {
+    fn wants_content(&self, entry: &LayerEntry) -> bool {
+        is_database(&entry.path)
+    }
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        self.track(event);
+        if let LayerEvent::EntryAdded { entry, content: Some(content) } = event {
+            if entry.kind == EntryKind::File {
+                self.add_file(&entry.path, content);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reports every installed package at the database that lists it
+#[cfg(feature = "analyzers")]
+impl Analyzer for PackageSink {
+    fn name(&self) -> &'static str {
+        "packages"
+    }
+
+    fn wants(&self, entry: &EntryInfo) -> bool {
+        is_database(entry.path)
+    }
+
+    fn observe(&mut self, event: &LayerEvent) {
+        self.track(event);
+    }
+
+    fn analyze(&mut self, entry: &EntryInfo, content: &[u8]) -> Vec<Finding> {
+        self.add_file(entry.path, content);
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for (path, (_, database)) in std::mem::take(&mut self.databases) {
+            let Database::Packages(packages) = database else {
+                continue;
+            };
+            findings.extend(packages.into_iter().map(|package| Finding {
+                analyzer: "packages",
+                path: path.clone(),
+                layer: package.layer,
+                kind: package.manager.name().to_string(),
+                detail: format!("{} {} {}", package.name, package.version, package.arch),
+                line: None,
+            }));
+        }
+        findings
+    }
+}
+
+/// Every installed package in a database file
+fn parse_database(manager: Manager, content: &[u8]) -> Result<Vec<Record>> {
+    Ok(match manager {
+        Manager::Apk => parse_apk(&String::from_utf8_lossy(content)),
+        Manager::Dpkg => parse_dpkg(&String::from_utf8_lossy(content)),
+        Manager::Rpm => rpmdb::read_packages(content)?.into_iter()
+            .map(|p| Record {
+                name: p.name,
+                version: p.version,
+                arch: p.arch,
+                files: p.files.iter().filter_map(|f| relative_path(f)).collect(),
+            })
+            .collect(),
+    })
+}
+
+/// `/lib/apk/db/installed`: blank-line separated stanzas of `X:value` lines,
+/// where `F:` names a directory and the `R:` lines after it its files
+fn parse_apk(text: &str) -> Vec<Record> {
+    text.split("\n\n")
+        .filter_map(|stanza| {
+            let field = |key: &str| stanza.lines().find_map(|l| l.strip_prefix(key)).map(str::to_string);
+            let mut files = Vec::new();
+            let mut dir = "";
+            for line in stanza.lines() {
+                if let Some(name) = line.strip_prefix("F:") {
+                    dir = name;
+                    files.push(name.to_string());
+                } else if let Some(name) = line.strip_prefix("R:") {
+                    files.push(if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) });
+                }
+            }
+            Some(Record {
+                name: field("P:")?,
+                version: field("V:").unwrap_or_default(),
+                arch: field("A:").unwrap_or_default(),
+                files,
+            })
+        })
+        .collect()
+}
+
+/// `/var/lib/dpkg/status`: RFC 822 stanzas; only fully installed packages
+/// count. Files in `status.d` have no Status field and are all installed.
+fn parse_dpkg(text: &str) -> Vec<Record> {
+    text.split("\n\n")
+        .filter_map(|stanza| {
+            let field = |key: &str| {
+                stanza.lines()
+                    .find_map(|l| l.strip_prefix(key)?.strip_prefix(':'))
+                    .map(|v| v.trim().to_string())
+            };
+            let installed = field("Status").is_none_or(|s| s.ends_with(" installed"));
+            if !installed {
+                return None;
+            }
+            Some(Record {
+                name: field("Package")?,
+                version: field("Version").unwrap_or_default(),
+                arch: field("Architecture").unwrap_or_default(),
+                files: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// A dpkg `.list` (one absolute path per line) or `.md5sums` (`<md5>  <path>`)
+fn parse_file_list(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let path = match line.split_once("  ") {
+                Some((hash, path)) if !line.starts_with('/') && !hash.contains('/') => path,
+                _ => line,
+            };
+            relative_path(path)
+        })
+        .collect()
+}
+
+/// A package file path without its leading "/"; None for the root itself
+fn relative_path(path: &str) -> Option<String> {
+    let path = path.trim_start_matches('/').trim_start_matches("./").trim_end_matches('/');
+    (!path.is_empty() && path != ".").then(|| path.to_string())
+}
+
+/// Record on every non-directory entry the package that installed it.
+/// Package paths are resolved through symlinked parents, so `/bin/sh` from a
+/// package list finds `usr/bin/sh` in a merged-/usr image.
+pub fn annotate_owners(root: &mut Node, packages: &[Package]) {
+    let mut owners: HashMap<String, &str> = HashMap::new();
+    for package in packages {
+        for file in &package.files {
+            let path = root.resolve_parents(file).into_owned();
+            owners.entry(path).or_insert(&package.name);
+        }
+    }
+    set_owners(root, "", &owners);
+}
+
+fn set_owners(node: &mut Node, prefix: &str, owners: &HashMap<String, &str>) {
+    for (name, child) in node.children.iter_mut() {
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
+        if child.metadata.is_file {
+            child.metadata.owner = owners.get(&path).map(|o| o.to_string());
+        } else {
+            set_owners(child, &path, owners);
+        }
+    }
+}
+
+/// Count owned files and collect (path, layer) of unowned ones, sorted by path
+fn collect_orphans(root: &Node) -> (usize, Vec<(String, Option<String>)>) {
+    let mut owned = 0;
+    let mut orphans = Vec::new();
+    for (path, meta) in root.walk().filter(|(_, meta)| meta.is_file) {
+        let path = path.display().to_string();
+        if meta.owner.is_some() {
+            owned += 1;
+        } else if !STATE_DIRS.iter().any(|dir| path.starts_with(dir)) {
+            orphans.push((format!("/{}", path), meta.layer_hash.as_deref().map(String::from)));
+        }
+    }
+    (owned, orphans)
+}
+
+pub fn write_orphans<W: Write>(out: &mut W, orphans: &[(String, Option<String>)]) -> io::Result<()> {
+    for (path, layer) in orphans {
+        writeln!(out, "[{}] {}", layer.as_deref().unwrap_or("-"), path)?;
+    }
+    let noun = if orphans.len() == 1 { "file" } else { "files" };
+    writeln!(out, "{} {} not owned by any package", orphans.len(), noun)
+}
+
+pub fn write_table<W: Write>(out: &mut W, packages: &[Package]) -> io::Result<()> {
+    if packages.is_empty() {
+        return writeln!(out, "No package databases found");
+    }
+
+    let name_width = packages.iter().map(|p| p.name.len()).max().unwrap_or(0).max(4);
+    let version_width = packages.iter().map(|p| p.version.len()).max().unwrap_or(0).max(7);
+    let arch_width = packages.iter().map(|p| p.arch.len()).max().unwrap_or(0).max(4);
+    writeln!(out, "{:<4}  {:<name_width$}  {:<version_width$}  {:<arch_width$}  LAYER",
+        "TYPE", "NAME", "VERSION", "ARCH")?;
+    for p in packages {
+        writeln!(out, "{:<4}  {:<name_width$}  {:<version_width$}  {:<arch_width$}  {}",
+            p.manager.name(), p.name, p.version, p.arch, p.layer)?;
+    }
+
+    let mut counts: BTreeMap<Manager, usize> = BTreeMap::new();
+    for p in packages {
+        *counts.entry(p.manager).or_default() += 1;
+    }
+    let summary: Vec<String> = counts.iter().map(|(m, n)| format!("{} {}", n, m.name())).collect();
+    let noun = if packages.len() == 1 { "package" } else { "packages" };
+    writeln!(out, "\n{} {} ({})", packages.len(), noun, summary.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Blob, Compression, Layer};
+    use std::path::PathBuf;
+
+    fn start(sink: &mut PackageSink, index: usize, name: &str) {
+        let layer = Layer {
+            name: format!("{}/layer.tar", name),
+            blob: Blob::File(PathBuf::new()),
+            diff_id: None,
+            compression: Compression::None,
+        };
+        sink.handle(&LayerEvent::LayerStarted { index, count: 3, layer: &layer }).unwrap();
+    }
+
+    fn add(sink: &mut PackageSink, path: &str, content: &str) {
+        let entry = LayerEntry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: content.len() as u64,
+            xattrs: Default::default(),
+            content_hash: None,
+        };
+        sink.handle(&LayerEvent::EntryAdded { entry: &entry, content: Some(content.as_bytes()) }).unwrap();
+    }
+
+    #[test]
+    fn test_parse_apk() {
+        let db = "C:Q1abc=\nP:musl\nV:1.2.4-r2\nA:x86_64\n\nP:busybox\nV:1.36.1-r5\nA:x86_64\nF:bin\n\n";
+        let records: Vec<(String, String, Vec<String>)> = parse_apk(db).into_iter()
+            .map(|r| (r.name, r.version, r.files))
+            .collect();
+        assert_eq!(records, vec![
+            ("musl".to_string(), "1.2.4-r2".to_string(), vec![]),
+            ("busybox".to_string(), "1.36.1-r5".to_string(), vec!["bin".to_string()]),
+        ]);
+
+        let files = parse_apk("P:busybox\nV:1\nF:bin\nR:busybox\nR:sh\nF:etc\nR:motd\n")[0].files.clone();
+        assert_eq!(files, vec!["bin", "bin/busybox", "bin/sh", "etc", "etc/motd"]);
+    }
+
+    #[test]
+    fn test_parse_dpkg() {
+        let db = "Package: libc6\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.36-9\n\
+            Description: GNU C Library\n more text\n\n\
+            Package: vim\nStatus: deinstall ok config-files\nVersion: 9.0\n";
+        let records: Vec<(String, String, String)> = parse_dpkg(db).into_iter()
+            .map(|r| (r.name, r.version, r.arch))
+            .collect();
+        assert_eq!(records, vec![
+            ("libc6".to_string(), "2.36-9".to_string(), "amd64".to_string()),
+        ]);
+        // status.d entries have no Status field
+        assert_eq!(parse_dpkg("Package: tzdata\nVersion: 2024a\nArchitecture: all\n").len(), 1);
+    }
+
+    #[test]
+    fn test_attributes_packages_to_layers() {
+        let mut sink = PackageSink::default();
+        start(&mut sink, 0, "aaaaaaa");
+        add(&mut sink, APK_INSTALLED, "P:musl\nV:1.2.4\nA:x86_64\n\nP:curl\nV:8.0\nA:x86_64\n");
+        add(&mut sink, "var/lib/dpkg/status.d/base", "Package: base-files\nVersion: 12\n");
+        start(&mut sink, 1, "bbbbbbb");
+        add(&mut sink, APK_INSTALLED, "P:musl\nV:1.2.4\nA:x86_64\n\nP:curl\nV:8.1\nA:x86_64\n\nP:jq\nV:1.7\nA:x86_64\n");
+        sink.handle(&LayerEvent::EntryRemoved { path: "var/lib/dpkg" }).unwrap();
+
+        let summary: Vec<(String, String, String)> = sink.into_packages().into_iter()
+            .map(|p| (p.name, p.version, p.layer))
+            .collect();
+        assert_eq!(summary, vec![
+            ("curl".to_string(), "8.1".to_string(), "bbbbbbb".to_string()),
+            ("jq".to_string(), "1.7".to_string(), "bbbbbbb".to_string()),
+            ("musl".to_string(), "1.2.4".to_string(), "aaaaaaa".to_string()),
+        ]);
+    }
+
+    #[cfg(feature = "analyzers")]
+    #[test]
+    fn test_analyzer() {
+        let mut sink = PackageSink::default();
+        start(&mut sink, 0, "aaaaaaa");
+        let db = "P:musl\nV:1.2.4\nA:x86_64\n";
+        let entry = EntryInfo { path: APK_INSTALLED, layer: "aaaaaaa", size: db.len() as u64, mode: 0o644 };
+        assert!(Analyzer::wants(&sink, &entry));
+        assert!(!Analyzer::wants(&sink, &EntryInfo { path: "etc/motd", ..entry }));
+        sink.analyze(&entry, db.as_bytes());
+
+        let findings: Vec<(String, String, String)> = sink.finish().into_iter()
+            .map(|f| (f.path, f.kind, f.detail))
+            .collect();
+        assert_eq!(findings, [(APK_INSTALLED.to_string(), "apk".to_string(), "musl 1.2.4 x86_64".to_string())]);
+    }
+
+    #[test]
+    fn test_file_lists() {
+        assert_eq!(file_list_package("var/lib/dpkg/info/libc6:amd64.list"), Some("libc6"));
+        assert_eq!(file_list_package("var/lib/dpkg/status.d/tzdata.md5sums"), Some("tzdata"));
+        assert_eq!(file_list_package("var/lib/dpkg/info/libc6.postinst"), None);
+        assert_eq!(Manager::for_path("var/lib/dpkg/status.d/tzdata.md5sums"), None);
+
+        assert_eq!(parse_file_list("/.\n/usr\n/usr/bin/ls\n"), vec!["usr", "usr/bin/ls"]);
+        assert_eq!(parse_file_list("0123abcd  usr/share/zoneinfo/UTC\n"), vec!["usr/share/zoneinfo/UTC"]);
+    }
+
+    #[test]
+    fn test_owners_and_orphans() {
+        let mut sink = PackageSink::default();
+        start(&mut sink, 0, "aaaaaaa");
+        add(&mut sink, DPKG_STATUS, "Package: coreutils\nStatus: install ok installed\nVersion: 9.1\n");
+        add(&mut sink, "var/lib/dpkg/info/coreutils.list", "/.\n/bin\n/bin/ls\n");
+        let packages = sink.into_packages();
+        assert_eq!(packages[0].files, vec!["bin", "bin/ls"]);
+
+        // bin -> usr/bin, as in merged-/usr images
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("bin", 0o777, 0, 0, true, Some("usr/bin".to_string()), None);
+        root.put_file("usr/bin/ls", 0o755, 0, 0, false, None, Some(&"aaaaaaa".into()));
+        root.put_file("usr/local/bin/tool", 0o755, 0, 0, false, None, Some(&"bbbbbbb".into()));
+        root.put_file("var/lib/dpkg/status", 0o644, 0, 0, false, None, Some(&"aaaaaaa".into()));
+        annotate_owners(&mut root, &packages);
+        assert_eq!(root.get("usr/bin/ls").unwrap().metadata.owner.as_deref(), Some("coreutils"));
+
+        let (owned, orphans) = collect_orphans(&root);
+        assert_eq!(owned, 2);
+        assert_eq!(orphans, vec![("/usr/local/bin/tool".to_string(), Some("bbbbbbb".to_string()))]);
+    }
+
+    #[test]
+    fn test_write_table() {
+        let packages = vec![Package {
+            manager: Manager::Apk,
+            name: "musl".to_string(),
+            version: "1.2.4-r2".to_string(),
+            arch: "x86_64".to_string(),
+            layer: "aaaaaaa".to_string(),
+            files: Vec::new(),
+        }];
+        let mut out = Vec::new();
+        write_table(&mut out, &packages).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+TYPE  NAME  VERSION   ARCH    LAYER
+apk   musl  1.2.4-r2  x86_64  aaaaaaa
+
+1 package (1 apk)
+");
+    }
+}
@@ -0,0 +1,136 @@
+//! Suspicious layer entries for `--paranoid`
+//!
+//! Layer tars come from whoever built the image. Entries with `..` or
+//! absolute paths, links pointing outside the image, and writes through a
+//! symlink planted earlier in the same layer (the classic symlink-swap
+//! extraction attack) are normalized or skipped while merging; this sink
+//! records them so they can be reported instead of passing silently.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::events::{EntryKind, EventSink, LayerEvent, Suspicion};
+
+/// A suspicious entry and the layer that holds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuspiciousEntry {
+    pub layer: String,
+    pub path: String,
+    pub reason: Suspicion,
+}
+
+/// Collects suspicious entries across layers
+#[derive(Default)]
+pub struct ParanoidSink {
+    layer: String,
+    /// Symlinks created in the current layer, by path
+    symlinks: HashMap<String, String>,
+    entries: Vec<SuspiciousEntry>,
+}
+
+impl ParanoidSink {
+    pub fn into_entries(self) -> Vec<SuspiciousEntry> {
+        self.entries
+    }
+
+    fn push(&mut self, path: &str, reason: Suspicion) {
+        self.entries.push(SuspiciousEntry { layer: self.layer.clone(), path: path.to_string(), reason });
+    }
+
+    /// Flag `path` if one of its parents is a symlink from this layer
+    fn check_parents(&mut self, path: &str) {
+        let parent = path.char_indices()
+            .filter(|&(_, c)| c == '/')
+            .map(|(i, _)| &path[..i])
+            .find_map(|dir| self.symlinks.get(dir).map(|target| (dir.to_string(), target.clone())));
+        if let Some((link, target)) = parent {
+            self.push(path, Suspicion::ThroughSymlink { link, target });
+        }
+    }
+}
+
+impl EventSink for ParanoidSink {
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { layer, .. } => {
+                self.layer = layer.label();
+                self.symlinks.clear();
+            }
+            LayerEvent::EntryAdded { entry, .. } => {
+                self.check_parents(&entry.path);
+                match &entry.kind {
+                    EntryKind::Symlink { target: Some(target) } => {
+                        self.symlinks.insert(entry.path.clone(), target.clone());
+                    }
+                    _ => {
+                        self.symlinks.remove(&entry.path);
+                    }
+                }
+            }
+            LayerEvent::EntryRemoved { path } => self.check_parents(path),
+            LayerEvent::Suspicious { path, reason } => self.push(path, reason.clone()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Print one line per suspicious entry, then a count
+pub fn write_report<W: Write>(out: &mut W, entries: &[SuspiciousEntry]) -> io::Result<()> {
+    for entry in entries {
+        writeln!(out, "  [{}] {:?}: {}", entry.layer, entry.path, entry.reason.describe())?;
+    }
+    writeln!(out, "{} suspicious entr{}", entries.len(), if entries.len() == 1 { "y" } else { "ies" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::LayerEntry;
+
+    fn entry(path: &str, kind: EntryKind) -> LayerEntry {
+        LayerEntry {
+            path: path.to_string(),
+            kind,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            xattrs: Default::default(),
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_write_through_symlink_from_same_layer() {
+        let mut sink = ParanoidSink::default();
+        let link = entry("app/conf", EntryKind::Symlink { target: Some("/etc".to_string()) });
+        let file = entry("app/conf/passwd", EntryKind::File);
+        sink.handle(&LayerEvent::EntryAdded { entry: &link, content: None }).unwrap();
+        sink.handle(&LayerEvent::EntryAdded { entry: &file, content: None }).unwrap();
+        assert_eq!(sink.entries.len(), 1);
+        assert_eq!(sink.entries[0].path, "app/conf/passwd");
+        assert_eq!(sink.entries[0].reason,
+            Suspicion::ThroughSymlink { link: "app/conf".to_string(), target: "/etc".to_string() });
+
+        // Links from lower layers are ordinary (bin -> usr/bin)
+        sink.symlinks.clear();
+        sink.handle(&LayerEvent::EntryAdded { entry: &file, content: None }).unwrap();
+        assert_eq!(sink.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let entries = vec![SuspiciousEntry {
+            layer: "aaaaaaa".to_string(),
+            path: "../../etc/cron.d/x".to_string(),
+            reason: Suspicion::EscapesRoot,
+        }];
+        let mut out = Vec::new();
+        write_report(&mut out, &entries).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "  [aaaaaaa] \"../../etc/cron.d/x\": path escapes the image root (skipped)\n1 suspicious entry\n");
+    }
+}
@@ -0,0 +1,147 @@
+//! Per-layer timing and size statistics for `--profile-layers`
+//!
+//! Meant for bug reports about slow or memory-hungry images: the table shows
+//! which layer is expensive and whether the cost is decompression, tar
+//! parsing, or merging entries into the tree.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::utils;
+
+/// Statistics for one merged layer
+#[derive(Debug, Clone, Default)]
+pub struct LayerProfile {
+    pub label: String,
+    /// Tar entries applied (including whiteouts)
+    pub entries: u64,
+    /// Uncompressed bytes read from the layer
+    pub bytes: u64,
+    /// Time spent reading and decompressing the blob
+    pub decompress: Duration,
+    /// Time spent parsing tar headers (what remains of the layer's total)
+    pub parse: Duration,
+    /// Time spent applying entries to the tree
+    pub merge: Duration,
+    /// Nodes in the merged tree after this layer
+    pub nodes: usize,
+    /// Peak resident memory after this layer, in KiB (Linux only)
+    pub peak_rss_kib: Option<u64>,
+}
+
+/// Reads the clock only when profiling: there is no clock to read on
+/// wasm32-unknown-unknown, where `Instant::now` panics
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    enabled: bool,
+}
+
+impl Clock {
+    pub fn new(enabled: bool) -> Self {
+        Clock { enabled }
+    }
+
+    /// The current time, if profiling
+    pub fn now(self) -> Option<Instant> {
+        self.enabled.then(Instant::now)
+    }
+
+    /// Time since `start`, or zero when not profiling
+    pub fn since(self, start: Option<Instant>) -> Duration {
+        start.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+/// Reader that records how long its reads take and how many bytes they return
+pub struct TimingReader<R> {
+    inner: R,
+    clock: Clock,
+    elapsed: Rc<Cell<Duration>>,
+    bytes: Rc<Cell<u64>>,
+}
+
+impl<R> TimingReader<R> {
+    pub fn new(inner: R, clock: Clock) -> Self {
+        TimingReader {
+            inner,
+            clock,
+            elapsed: Rc::new(Cell::new(Duration::ZERO)),
+            bytes: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Shared handles to the accumulated read time and byte count
+    pub fn counters(&self) -> (Rc<Cell<Duration>>, Rc<Cell<u64>>) {
+        (Rc::clone(&self.elapsed), Rc::clone(&self.bytes))
+    }
+}
+
+impl<R: Read> Read for TimingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.clock.now();
+        let n = self.inner.read(buf)?;
+        self.elapsed.set(self.elapsed.get() + self.clock.since(start));
+        self.bytes.set(self.bytes.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Peak resident set size of this process in KiB, from /proc/self/status
+pub fn peak_rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_vm_hwm(&status)
+}
+
+fn parse_vm_hwm(status: &str) -> Option<u64> {
+    status.lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kib| kib.parse().ok())
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+}
+
+/// Write the per-layer table followed by a totals row
+pub fn write_report<W: Write>(out: &mut W, profiles: &[LayerProfile]) -> io::Result<()> {
+    writeln!(out, "{:>3}  {:<7}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>8}  {:>10}",
+        "#", "LAYER", "ENTRIES", "SIZE", "DECOMPRESS", "PARSE", "MERGE", "NODES", "PEAK RSS")?;
+
+    for (i, p) in profiles.iter().enumerate() {
+        let rss = p.peak_rss_kib.map_or("-".to_string(), |kib| utils::human_size(kib * 1024));
+        writeln!(out, "{:>3}  {:<7}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>8}  {:>10}",
+            i, p.label, p.entries, utils::human_size(p.bytes),
+            millis(p.decompress), millis(p.parse), millis(p.merge), p.nodes, rss)?;
+    }
+
+    let sum = |f: fn(&LayerProfile) -> Duration| profiles.iter().map(f).sum::<Duration>();
+    writeln!(out, "{:>3}  {:<7}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "", "total",
+        profiles.iter().map(|p| p.entries).sum::<u64>(),
+        utils::human_size(profiles.iter().map(|p| p.bytes).sum()),
+        millis(sum(|p| p.decompress)), millis(sum(|p| p.parse)), millis(sum(|p| p.merge)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vm_hwm() {
+        let status = "Name:\tcontree\nVmPeak:\t  20000 kB\nVmHWM:\t    5120 kB\nVmRSS:\t 4000 kB\n";
+        assert_eq!(parse_vm_hwm(status), Some(5120));
+        assert_eq!(parse_vm_hwm("Name:\tcontree\n"), None);
+    }
+
+    #[test]
+    fn test_timing_reader_counts_bytes() {
+        let mut reader = TimingReader::new(&b"hello world"[..], Clock::new(false));
+        let (_, bytes) = reader.counters();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(bytes.get(), 11);
+    }
+}
@@ -0,0 +1,88 @@
+//! Progress reporting through the terminal title and OSC 9;4
+//!
+//! Writes to stderr only when it is a terminal. The previous window title is
+//! saved on the xterm title stack and restored when reporting ends, and the
+//! OSC 9;4 progress indicator (used by Windows Terminal, ConEmu, and others)
+//! is cleared.
+
+use std::io::{self, Write};
+
+pub struct TermProgress {
+    active: bool,
+    last: Option<(String, u8)>,
+}
+
+impl TermProgress {
+    /// Create a reporter; does nothing unless `enabled` and stderr is a TTY
+    pub fn new(enabled: bool) -> Self {
+        let active = enabled && atty::is(atty::Stream::Stderr);
+        if active {
+            // Save the current title so it can be restored afterwards
+            emit("\x1b[22;0t");
+        }
+        TermProgress { active, last: None }
+    }
+
+    /// A reporter that never writes anything
+    pub fn disabled() -> Self {
+        TermProgress { active: false, last: None }
+    }
+
+    /// Report `done` out of `total` units of work for the named stage
+    pub fn update(&mut self, stage: &str, done: u64, total: u64) {
+        if !self.active {
+            return;
+        }
+
+        let percent = percent(done, total);
+        if self.last.as_ref().is_some_and(|(s, p)| s == stage && *p == percent) {
+            return;
+        }
+
+        emit(&format!("\x1b]0;contree: {} {}%\x07\x1b]9;4;1;{}\x07", stage, percent, percent));
+        self.last = Some((stage.to_string(), percent));
+    }
+}
+
+impl Drop for TermProgress {
+    fn drop(&mut self) {
+        if self.active {
+            // Clear the progress indicator and restore the saved title
+            emit("\x1b]9;4;0;0\x07\x1b[23;0t");
+        }
+    }
+}
+
+fn percent(done: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 100;
+    }
+    (done.min(total) * 100 / total) as u8
+}
+
+fn emit(sequence: &str) {
+    let mut stderr = io::stderr();
+    // Progress is cosmetic; never fail the run because of it
+    let _ = stderr.write_all(sequence.as_bytes());
+    let _ = stderr.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(percent(0, 10), 0);
+        assert_eq!(percent(5, 10), 50);
+        assert_eq!(percent(15, 10), 100);
+        assert_eq!(percent(0, 0), 100);
+    }
+
+    #[test]
+    fn test_disabled_does_not_track() {
+        let mut progress = TermProgress::disabled();
+        progress.update("merging layers", 1, 2);
+        assert!(progress.last.is_none());
+    }
+}
@@ -0,0 +1,465 @@
+//! Selecting entries of the merged tree with a small query language, for
+//! `contree query`
+//!
+//! A query is a path glob, optionally followed by `where` and conditions on
+//! the entries it matches:
+//!
+//! ```text
+//! /usr/** where mode has setuid and size > 1M
+//! /etc/*.conf where not (uid = 0 or layer = a1b2c3d)
+//! where name ~ "*.pem" or mtime > 2024-01-01
+//! ```
+//!
+//! In the glob `*` and `?` stay within one path component and `**` spans any
+//! number of them; without a glob every entry is considered. Conditions
+//! compare a field with `=`, `!=`, `<`, `<=`, `>`, `>=`, match text fields
+//! against a glob with `~` and `!~`, or test a mode bit with `has`, and
+//! combine with `and`, `or`, `not` and parentheses.
+
+use anyhow::{bail, Context, Result};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, ProcessOptions};
+use crate::render::{self, RenderOptions};
+use crate::tempspace;
+use crate::template;
+use crate::tree::{Node, NodeMetadata};
+use crate::utils;
+
+/// Fields compared as numbers
+const NUMERIC_FIELDS: &[&str] = &["size", "mode", "uid", "gid", "mtime"];
+
+/// Fields compared as text, with the values `--template` gives them
+const TEXT_FIELDS: &[&str] = &["path", "name", "type", "layer", "owner", "target"];
+
+/// Mode bits `has` tests for; symlinks have none of them
+const MODE_FLAGS: &[(&str, u32)] = &[
+    ("setuid", 0o4000),
+    ("setgid", 0o2000),
+    ("sticky", 0o1000),
+    ("exec", 0o111),
+    ("world-writable", 0o002),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    /// A quoted string, never a keyword
+    Quoted(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPERATORS: &[&str] = &["=", "==", "!=", "<", "<=", ">", ">=", "~", "!~"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let is_op = |c: char| "=!<>~".contains(c);
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut quoted = String::new();
+            loop {
+                match chars.next() {
+                    Some(end) if end == c => break,
+                    Some(other) => quoted.push(other),
+                    None => bail!("Unclosed {} in query", c),
+                }
+            }
+            tokens.push(Token::Quoted(quoted));
+        } else if is_op(c) {
+            let mut op = String::new();
+            while let Some(&c) = chars.peek().filter(|&&c| is_op(c)) {
+                op.push(c);
+                chars.next();
+            }
+            let Some(&known) = OPERATORS.iter().find(|&&known| known == op) else {
+                bail!("Unknown operator '{}' in query", op);
+            };
+            tokens.push(Token::Op(known));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|&&c| !c.is_whitespace() && !"()\"'".contains(c) && !is_op(c)) {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare { field: &'static str, op: &'static str, value: Value },
+    /// Any of these mode bits set
+    Has(u32),
+}
+
+/// Recursive descent over the tokens after `where`
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(word)) if word == keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let expr = self.or()?;
+            if self.next() != Some(Token::Close) {
+                bail!("Missing ')' in query");
+            }
+            return Ok(expr);
+        }
+        self.condition()
+    }
+
+    fn condition(&mut self) -> Result<Expr> {
+        let name = match self.next() {
+            Some(Token::Word(name)) => name,
+            Some(other) => bail!("Expected a field in query, found {}", describe(&other)),
+            None => bail!("Expected a field at the end of the query"),
+        };
+        let Some(&field) = NUMERIC_FIELDS.iter().chain(TEXT_FIELDS).find(|&&f| f == name) else {
+            bail!("Unknown field '{}' in query (available: {}, {})", name,
+                NUMERIC_FIELDS.join(", "), TEXT_FIELDS.join(", "));
+        };
+
+        if self.keyword("has") {
+            if field != "mode" {
+                bail!("'has' tests mode bits, not {}", field);
+            }
+            let flag = match self.next() {
+                Some(Token::Word(flag)) | Some(Token::Quoted(flag)) => flag,
+                _ => bail!("Expected a mode bit after 'has'"),
+            };
+            let Some(&(_, bits)) = MODE_FLAGS.iter().find(|(name, _)| *name == flag) else {
+                let names: Vec<_> = MODE_FLAGS.iter().map(|(name, _)| *name).collect();
+                bail!("Unknown mode bit '{}' in query (available: {})", flag, names.join(", "));
+            };
+            return Ok(Expr::Has(bits));
+        }
+
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => bail!("Expected an operator or 'has' after '{}'", field),
+        };
+        let text = match self.next() {
+            Some(Token::Word(text)) | Some(Token::Quoted(text)) => text,
+            _ => bail!("Expected a value after '{} {}'", field, op),
+        };
+        let value = if NUMERIC_FIELDS.contains(&field) {
+            if op.contains('~') {
+                bail!("'{}' matches text, and {} is a number", op, field);
+            }
+            Value::Number(number(field, &text)?)
+        } else {
+            if op.contains('<') || op.contains('>') {
+                bail!("'{}' compares numbers, and {} is text", op, field);
+            }
+            Value::Text(text)
+        };
+        Ok(Expr::Compare { field, op: if op == "==" { "=" } else { op }, value })
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("'{}'", word),
+        Token::Quoted(text) => format!("\"{}\"", text),
+        Token::Op(op) => format!("'{}'", op),
+        Token::Open => "'('".to_string(),
+        Token::Close => "')'".to_string(),
+    }
+}
+
+/// The value of a numeric field in a condition: sizes take units, modes
+/// are octal, times are dates, RFC 3339 timestamps or seconds
+fn number(field: &str, text: &str) -> Result<u64> {
+    let parsed = match field {
+        "size" => tempspace::parse_size(text).ok(),
+        "mode" => u64::from_str_radix(text, 8).ok(),
+        "mtime" => text.parse().ok().or_else(|| {
+            let timestamp = if text.len() == 10 { format!("{}T00:00:00Z", text) } else { text.to_string() };
+            utils::parse_timestamp(&timestamp).and_then(|secs| u64::try_from(secs).ok())
+        }),
+        _ => text.parse().ok(),
+    };
+    parsed.with_context(|| format!("Invalid {} '{}' in query", field, text))
+}
+
+/// A parsed query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// Components of the path glob
+    glob: Vec<String>,
+    filter: Option<Expr>,
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let glob = match parser.peek() {
+            Some(Token::Word(word)) if word != "where" => Some(word.clone()),
+            Some(Token::Quoted(glob)) => Some(glob.clone()),
+            _ => None,
+        };
+        if glob.is_some() {
+            parser.pos += 1;
+        }
+        let glob = glob.as_deref().unwrap_or("**")
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let filter = if parser.keyword("where") { Some(parser.or()?) } else { None };
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {} in query (conditions follow 'where')", describe(token));
+        }
+        Ok(Query { glob, filter })
+    }
+
+    /// Whether the query looks at `field`
+    pub fn uses(&self, field: &str) -> bool {
+        fn uses(expr: &Expr, field: &str) -> bool {
+            match expr {
+                Expr::And(a, b) | Expr::Or(a, b) => uses(a, field) || uses(b, field),
+                Expr::Not(a) => uses(a, field),
+                Expr::Compare { field: compared, .. } => *compared == field,
+                Expr::Has(_) => field == "mode",
+            }
+        }
+        self.filter.as_ref().is_some_and(|expr| uses(expr, field))
+    }
+
+    /// Whether the entry at `path` (without a leading "/") is selected
+    pub fn matches(&self, path: &str, meta: &NodeMetadata) -> bool {
+        let components: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        glob_matches(&self.glob, &components) && self.filter.as_ref().is_none_or(|expr| eval(expr, path, meta))
+    }
+
+    /// Paths of the selected entries below `root`, parents before children
+    pub fn matching_paths(&self, root: &Node) -> Vec<String> {
+        root.walk()
+            .map(|(path, meta)| (path.display().to_string(), meta))
+            .filter(|(path, meta)| self.matches(path, meta))
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// The selected entries below `root` with the directories leading to
+    /// them, or None when nothing is selected
+    pub fn select(&self, root: &Node) -> Option<Node> {
+        self.select_in(root, "")
+    }
+
+    fn select_in(&self, node: &Node, dir: &str) -> Option<Node> {
+        let mut children = std::collections::BTreeMap::new();
+        for (name, child) in &node.children {
+            let path = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+            let selected = self.select_in(child, &path);
+            if selected.is_some() || self.matches(&path, &child.metadata) {
+                let selected = selected
+                    .unwrap_or_else(|| Node { children: Default::default(), metadata: child.metadata.clone() });
+                children.insert(name.clone(), Box::new(selected));
+            }
+        }
+        (!children.is_empty()).then(|| Node { children, metadata: node.metadata.clone() })
+    }
+}
+
+/// Whether path components match glob components, `**` standing for any
+/// number of them
+fn glob_matches(glob: &[String], path: &[&str]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| glob_matches(rest, &path[skip..])),
+        Some((first, rest)) => path.split_first()
+            .is_some_and(|(component, tail)| utils::glob_match(first, component) && glob_matches(rest, tail)),
+    }
+}
+
+fn eval(expr: &Expr, path: &str, meta: &NodeMetadata) -> bool {
+    match expr {
+        Expr::And(a, b) => eval(a, path, meta) && eval(b, path, meta),
+        Expr::Or(a, b) => eval(a, path, meta) || eval(b, path, meta),
+        Expr::Not(a) => !eval(a, path, meta),
+        Expr::Has(bits) => !meta.is_symlink && meta.mode & bits != 0,
+        Expr::Compare { field, op, value: Value::Number(wanted) } => {
+            let actual = match *field {
+                "size" => meta.size,
+                "mode" => u64::from(meta.mode & 0o7777),
+                "uid" => meta.uid,
+                "gid" => meta.gid,
+                _ => meta.mtime,
+            };
+            match *op {
+                "=" => actual == *wanted,
+                "!=" => actual != *wanted,
+                "<" => actual < *wanted,
+                "<=" => actual <= *wanted,
+                ">" => actual > *wanted,
+                _ => actual >= *wanted,
+            }
+        }
+        Expr::Compare { field, op, value: Value::Text(wanted) } => {
+            let actual = template::field_value(field, path, meta);
+            match *op {
+                "=" => actual == *wanted,
+                "!=" => actual != *wanted,
+                "~" => utils::glob_match(wanted, &actual),
+                _ => !utils::glob_match(wanted, &actual),
+            }
+        }
+    }
+}
+
+/// Print the entries of an archive's merged tree that `text` selects, as a
+/// tree, one path per line (`flat`), or a JSON tree; fails when none match
+pub fn run(archive_path: &Path, text: &str, format: &str) -> Result<()> {
+    let query = Query::parse(text)?;
+    let options = ProcessOptions { record_layers: true, resolve_owners: query.uses("owner"), ..Default::default() };
+    let image = archive::process_archive(archive_path, &options)?;
+    let Some(selected) = query.select(&image.root) else {
+        bail!("No entries match the query");
+    };
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match format {
+        "json" => {
+            selected.to_json(&mut out)?;
+            writeln!(out)?;
+        }
+        "flat" => {
+            for path in query.matching_paths(&image.root) {
+                writeln!(out, "/{}", utils::display_path(&path))?;
+            }
+        }
+        _ => render::render_tree(&mut out, &selected, &RenderOptions::default())?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn image() -> Node {
+        let base: Arc<str> = Arc::from("aaaaaaa");
+        let app: Arc<str> = Arc::from("bbbbbbb");
+        let mut root = Node::new_dir(0o755, 0, 0);
+        for (path, mode, uid, size, layer) in [
+            ("usr/bin/su", 0o4755, 0, 2 << 20, &base),
+            ("usr/bin/passwd", 0o4755, 0, 60_000, &base),
+            ("usr/local/bin/tool", 0o2755, 0, 3 << 20, &app),
+            ("etc/nginx/nginx.conf", 0o644, 0, 2_000, &base),
+            ("etc/app.conf", 0o600, 1000, 100, &app),
+        ] {
+            root.put_file(path, mode, uid, 0, false, None, Some(layer));
+            root.get_mut(path).unwrap().metadata.size = size;
+        }
+        root
+    }
+
+    fn select(query: &str) -> Vec<String> {
+        Query::parse(query).unwrap().matching_paths(&image())
+    }
+
+    #[test]
+    fn test_globs_and_conditions() {
+        assert_eq!(select("/usr/** where mode has setuid and size > 1M"), ["usr/bin/su"]);
+        assert_eq!(select("/usr/** where mode has setuid or mode has setgid"),
+            ["usr/bin/passwd", "usr/bin/su", "usr/local/bin/tool"]);
+        assert_eq!(select("/etc/*.conf"), ["etc/app.conf"]);
+        assert_eq!(select("/etc/** where type = file and not (uid = 0 or layer = bbbbbbb)"), Vec::<String>::new());
+        assert_eq!(select("where name ~ '*.conf' and mode = 0644"), ["etc/nginx/nginx.conf"]);
+        assert_eq!(select("/usr/*/*"), ["usr/bin/passwd", "usr/bin/su", "usr/local/bin"]);
+        assert_eq!(select("where path ~ /usr/local/* and size >= 3M"), ["usr/local/bin/tool"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |query: &str| Query::parse(query).unwrap_err().to_string();
+        assert_eq!(error("/usr where colour = red"), concat!("Unknown field 'colour' in query ",
+            "(available: size, mode, uid, gid, mtime, path, name, type, layer, owner, target)"));
+        assert_eq!(error("where size > big"), "Invalid size 'big' in query");
+        assert_eq!(error("where name > a"), "'>' compares numbers, and name is text");
+        assert_eq!(error("where (size > 1"), "Missing ')' in query");
+        assert_eq!(error("/usr size > 1"), "Unexpected 'size' in query (conditions follow 'where')");
+        assert_eq!(error("where mode has suid"),
+            "Unknown mode bit 'suid' in query (available: setuid, setgid, sticky, exec, world-writable)");
+    }
+
+    #[test]
+    fn test_select_keeps_parents() {
+        let selected = Query::parse("where size > 1M").unwrap().select(&image()).unwrap();
+        assert_eq!(render::render_to_string(&selected, &RenderOptions::default()), concat!(
+            "└── usr\n",
+            "    ├── bin\n",
+            "    │   └── su\n",
+            "    └── local\n",
+            "        └── bin\n",
+            "            └── tool\n",
+        ));
+        assert!(Query::parse("where uid = 42").unwrap().select(&image()).is_none());
+    }
+}
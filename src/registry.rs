@@ -0,0 +1,487 @@
+//! Pulling images straight from a registry (`remote` feature)
+//!
+//! [`RegistryClient::pull`] resolves a tag to the image for one platform and
+//! downloads its config and layers concurrently into a directory laid out like
+//! an unpacked `docker save` archive, which
+//! [`DirectorySource`](crate::directory::DirectorySource) then reads. The
+//! client is async: each HTTP request runs on tokio's blocking pool, and
+//! [`pull`] wraps it in a runtime of its own for synchronous callers such as
+//! the CLI. Pulls are anonymous; the client answers a registry's bearer token
+//! challenge but has no credentials to send.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::manifest::ManifestEntry;
+use crate::oci::{self, Platform};
+use crate::tempspace;
+
+/// Prefix marking an image reference where an archive path is expected
+pub const SCHEME: &str = "docker://";
+
+/// Blobs downloaded at the same time unless configured otherwise
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Manifests and indexes are small; anything larger is not one
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Media types of the indexes and manifests the client asks for
+const MANIFEST_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// Docker Hub's name in references, and the host serving its API
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_HOST: &str = "registry-1.docker.io";
+
+/// An image in a registry: `[registry/]repository[:tag][@digest]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Registry host, with port if any (`docker.io` for Docker Hub)
+    pub registry: String,
+    /// Repository path (`library/alpine`)
+    pub repository: String,
+    /// Tag or digest naming the image (`latest`, `sha256:...`)
+    pub reference: String,
+}
+
+impl Reference {
+    /// Parse a reference the way `docker pull` does, with or without the
+    /// `docker://` prefix
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid image reference '{}'", spec);
+        let trimmed = spec.strip_prefix(SCHEME).unwrap_or(spec);
+        let (name, digest) = match trimmed.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (trimmed, None),
+        };
+        // A colon before the last '/' belongs to a registry port, not a tag
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (name, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => (first, rest.to_string()),
+            _ => (DOCKER_HUB, name.to_string()),
+        };
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        let repository_ok = !repository.is_empty()
+            && repository.split('/').all(|part| !part.is_empty())
+            && repository.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c));
+        let tag_ok = tag.is_none_or(|t| !t.is_empty() && t.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)));
+        if !repository_ok || !tag_ok {
+            return Err(invalid());
+        }
+        let reference = match digest {
+            Some(digest) => {
+                sha256_hex(digest).map_err(|_| invalid())?;
+                digest.to_string()
+            }
+            None => tag.unwrap_or("latest").to_string(),
+        };
+        Ok(Reference { registry: registry.to_string(), repository, reference })
+    }
+
+    /// URL of the repository under the registry API
+    fn base_url(&self) -> String {
+        let host = if self.registry == DOCKER_HUB { DOCKER_HUB_HOST } else { &self.registry };
+        // Like docker, only talk plain HTTP to a registry on this machine
+        let local = host.starts_with("[::1]")
+            || matches!(host.split(':').next(), Some("localhost" | "127.0.0.1"));
+        let scheme = if local { "http" } else { "https" };
+        format!("{}://{}/v2/{}", scheme, host, self.repository)
+    }
+}
+
+impl fmt::Display for Reference {
+    /// The short form `docker images` shows (`alpine:3.19`, `ghcr.io/org/app:1`)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.registry == DOCKER_HUB {
+            write!(f, "{}", self.repository.strip_prefix("library/").unwrap_or(&self.repository))?;
+        } else {
+            write!(f, "{}/{}", self.registry, self.repository)?;
+        }
+        let separator = if self.reference.contains(':') { '@' } else { ':' };
+        write!(f, "{}{}", separator, self.reference)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// Client for the registry HTTP API (v2); clones share their tokens
+#[derive(Clone)]
+pub struct RegistryClient {
+    agent: ureq::Agent,
+    /// Bearer tokens by repository URL
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    concurrency: usize,
+}
+
+impl Default for RegistryClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegistryClient {
+    pub fn new() -> Self {
+        RegistryClient {
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("contree/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            tokens: Arc::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    /// Download at most `concurrency` blobs at a time
+    #[must_use]
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Download the image `reference` names for `platform` into `dir`, and
+    /// describe it in `dir/manifest.json` as well as the returned entry
+    pub async fn pull(&self, reference: &Reference, platform: &Platform, dir: &Path) -> Result<ManifestEntry> {
+        let manifest = {
+            let (client, reference, platform) = (self.clone(), reference.clone(), platform.clone());
+            blocking(move || client.resolve(&reference, &platform)).await?
+        };
+        let blobs: Vec<&Descriptor> = std::iter::once(&manifest.config).chain(&manifest.layers).collect();
+        tempspace::reserve(blobs.iter().map(|blob| blob.size).sum())?;
+
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut downloads = JoinSet::new();
+        let mut seen = HashSet::new();
+        for blob in blobs {
+            // The same layer may be listed twice
+            if !seen.insert(&blob.digest) {
+                continue;
+            }
+            let path = dir.join(oci::blob_name(&blob.digest)?);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            let (client, reference, digest) = (self.clone(), reference.clone(), blob.digest.clone());
+            let permits = Arc::clone(&permits);
+            downloads.spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                blocking(move || client.download(&reference, &digest, &path)).await
+            });
+        }
+        while let Some(result) = downloads.join_next().await {
+            result.context("Download task failed")??;
+        }
+
+        let entry = ManifestEntry {
+            config: Some(oci::blob_name(&manifest.config.digest)?),
+            repo_tags: Some(vec![reference.to_string()]),
+            layers: manifest.layers.iter().map(|l| oci::blob_name(&l.digest)).collect::<Result<_>>()?,
+        };
+        let manifest_path = dir.join("manifest.json");
+        fs::write(&manifest_path, serde_json::to_vec(&[&entry])?)
+            .with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+        Ok(entry)
+    }
+
+    /// The image manifest for `platform`, picked out of an image index when
+    /// the reference names one
+    fn resolve(&self, reference: &Reference, platform: &Platform) -> Result<ImageManifest> {
+        let bytes = self.fetch_manifest(reference, &reference.reference)?;
+        let document: serde_json::Value = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse manifest of {}", reference))?;
+        let bytes = if document.get("manifests").is_some() {
+            let (digest, _) = oci::find_manifest(&bytes, platform, |digest| self.fetch_manifest(reference, digest))?;
+            self.fetch_manifest(reference, &digest)?
+        } else {
+            bytes
+        };
+        let manifest: ImageManifest = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse image manifest of {}", reference))?;
+        for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            sha256_hex(&blob.digest)?;
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest or index named by a tag or digest, checked against the
+    /// digest when there is one
+    fn fetch_manifest(&self, reference: &Reference, name: &str) -> Result<Vec<u8>> {
+        let response = self.get(reference, &format!("manifests/{}", name), &MANIFEST_TYPES.join(", "))?;
+        let mut bytes = Vec::new();
+        response.into_reader().take(MAX_MANIFEST_SIZE).read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to download manifest {}", name))?;
+        if name.contains(':') {
+            verify(name, &Sha256::digest(&bytes))?;
+        }
+        Ok(bytes)
+    }
+
+    /// Stream the blob `digest` into `path`, checking it against the digest
+    fn download(&self, reference: &Reference, digest: &str, path: &Path) -> Result<()> {
+        let response = self.get(reference, &format!("blobs/{}", digest), "*/*")?;
+        let mut reader = response.into_reader();
+        let mut file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).with_context(|| format!("Failed to download {}", digest))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).map_err(tempspace::explain)?;
+        }
+        verify(digest, &hasher.finalize())
+    }
+
+    /// GET `path` under the repository, answering a token challenge once
+    fn get(&self, reference: &Reference, path: &str, accept: &str) -> Result<ureq::Response> {
+        let base = reference.base_url();
+        let url = format!("{}/{}", base, path);
+        let request = |token: Option<&str>| {
+            let request = self.agent.get(&url).set("Accept", accept);
+            match token {
+                Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+                None => request,
+            }
+        };
+
+        let cached = self.tokens.lock().unwrap().get(&base).cloned();
+        let response = match request(cached.as_deref()).call() {
+            Err(ureq::Error::Status(401, response)) => {
+                let challenge = response.header("www-authenticate").unwrap_or_default();
+                let token = self.token(challenge)
+                    .with_context(|| format!("Failed to authenticate to {}", reference.registry))?;
+                let response = request(Some(&token)).call();
+                self.tokens.lock().unwrap().insert(base, token);
+                response
+            }
+            response => response,
+        };
+        response.with_context(|| format!("Failed to fetch {}", url))
+    }
+
+    /// Get an anonymous token for a `Bearer realm="...",service="...",scope="..."` challenge
+    fn token(&self, challenge: &str) -> Result<String> {
+        let Some(params) = challenge.strip_prefix("Bearer ") else {
+            bail!("Registry asks for credentials ({}); only anonymous pulls are supported",
+                challenge.split(' ').next().unwrap_or("no challenge"));
+        };
+        let params = parse_challenge(params);
+        let realm = params.get("realm").context("Token challenge has no realm")?;
+        let mut request = self.agent.get(realm);
+        for key in ["service", "scope"] {
+            if let Some(value) = params.get(key) {
+                request = request.query(key, value);
+            }
+        }
+        let response = request.call().with_context(|| format!("Failed to fetch token from {}", realm))?;
+        let response: TokenResponse = serde_json::from_reader(response.into_reader())
+            .context("Failed to parse token response")?;
+        response.token.or(response.access_token).context("Token response has no token")
+    }
+}
+
+/// Pull `reference` for `platform` into `dir` from synchronous code, on a
+/// runtime of its own
+pub fn pull(reference: &Reference, platform: &Platform, dir: &Path) -> Result<ManifestEntry> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .context("Failed to start async runtime")?;
+    runtime.block_on(RegistryClient::new().pull(reference, platform, dir))
+}
+
+/// Run blocking registry I/O off the async threads
+async fn blocking<T: Send + 'static>(task: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(task).await.context("Registry request did not finish")?
+}
+
+/// The `key="value"` pairs of a token challenge
+fn parse_challenge(params: &str) -> HashMap<&str, &str> {
+    let mut found = HashMap::new();
+    let mut rest = params;
+    while let Some((key, after)) = rest.split_once("=\"") {
+        let Some((value, after)) = after.split_once('"') else { break };
+        found.insert(key.trim_start_matches([',', ' ']), value);
+        rest = after;
+    }
+    found
+}
+
+/// Hex of a `sha256:` digest, the only algorithm registries use
+fn sha256_hex(digest: &str) -> Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex),
+        _ => bail!("Unsupported digest '{}'", digest),
+    }
+}
+
+fn verify(digest: &str, actual: &[u8]) -> Result<()> {
+    let actual = actual.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    if !sha256_hex(digest)?.eq_ignore_ascii_case(&actual) {
+        bail!("Digest mismatch: expected {}, got sha256:{}", digest, actual);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    fn digest_of(bytes: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(bytes))
+    }
+
+    /// Serve `routes` (path -> body) on a local port, asking for a bearer
+    /// token before anything else
+    fn serve(routes: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let realm = format!("http://{}/token", addr);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut authorized = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    authorized |= line.eq_ignore_ascii_case("authorization: bearer secret\r\n");
+                }
+
+                let path = request.split(' ').nth(1).unwrap();
+                let (status, header, body) = if path.starts_with("/token?service=test&scope=") {
+                    ("200 OK", String::new(), br#"{"token":"secret"}"#.to_vec())
+                } else if !authorized {
+                    let challenge = format!("WWW-Authenticate: Bearer realm=\"{}\",service=\"test\",scope=\"repository:app:pull\"\r\n", realm);
+                    ("401 Unauthorized", challenge, Vec::new())
+                } else if let Some(body) = routes.get(path) {
+                    ("200 OK", String::new(), body.clone())
+                } else {
+                    ("404 Not Found", String::new(), Vec::new())
+                };
+                write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, header, body.len()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let alpine = Reference::parse("docker://alpine").unwrap();
+        assert_eq!((alpine.registry.as_str(), alpine.repository.as_str(), alpine.reference.as_str()),
+            ("docker.io", "library/alpine", "latest"));
+        assert_eq!(alpine.to_string(), "alpine:latest");
+        assert_eq!(alpine.base_url(), "https://registry-1.docker.io/v2/library/alpine");
+
+        let local = Reference::parse("localhost:5000/team/app:1.2").unwrap();
+        assert_eq!((local.registry.as_str(), local.repository.as_str(), local.reference.as_str()),
+            ("localhost:5000", "team/app", "1.2"));
+        assert_eq!(local.base_url(), "http://localhost:5000/v2/team/app");
+
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let pinned = Reference::parse(&format!("ghcr.io/org/tool:1@{}", digest)).unwrap();
+        assert_eq!(pinned.reference, digest);
+        assert_eq!(pinned.to_string(), format!("ghcr.io/org/tool@{}", digest));
+
+        assert!(Reference::parse("Alpine").is_err());
+        assert!(Reference::parse("alpine@sha256:short").is_err());
+        assert!(Reference::parse("alpine:").is_err());
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let params = parse_challenge(r#"realm="https://auth.example/token",service="reg",scope="repository:a:pull""#);
+        assert_eq!(params["realm"], "https://auth.example/token");
+        assert_eq!(params["scope"], "repository:a:pull");
+    }
+
+    #[test]
+    fn test_pull_selects_platform_and_verifies_blobs() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, "etc/hostname", &b"box\n"[..]).unwrap();
+        let layer = builder.into_inner().unwrap();
+        let config = br#"{"rootfs":{"type":"layers","diff_ids":["sha256:abcdef0123"]}}"#.to_vec();
+        let manifest = format!(r#"{{"config":{{"digest":"{}","size":{}}},"layers":[{{"digest":"{}","size":{}}}]}}"#,
+            digest_of(&config), config.len(), digest_of(&layer), layer.len()).into_bytes();
+        let index = format!(r#"{{"manifests":[
+            {{"digest":"{}","platform":{{"os":"linux","architecture":"amd64"}}}},
+            {{"digest":"{}","platform":{{"os":"linux","architecture":"arm64"}}}}
+        ]}}"#, digest_of(b"elsewhere"), digest_of(&manifest)).into_bytes();
+
+        let mut routes = HashMap::new();
+        routes.insert("/v2/app/manifests/1.0".to_string(), index);
+        routes.insert(format!("/v2/app/manifests/{}", digest_of(&manifest)), manifest);
+        routes.insert(format!("/v2/app/blobs/{}", digest_of(&config)), config);
+        routes.insert(format!("/v2/app/blobs/{}", digest_of(&layer)), b"tampered".to_vec());
+        let addr = serve(routes.clone());
+
+        let reference = Reference::parse(&format!("{}/app:1.0", addr)).unwrap();
+        let arm64 = Platform::parse("linux/arm64").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let err = pull(&reference, &arm64, dir.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Digest mismatch"));
+
+        routes.insert(format!("/v2/app/blobs/{}", digest_of(&layer)), layer);
+        let addr = serve(routes);
+        let reference = Reference::parse(&format!("{}/app:1.0", addr)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let entry = pull(&reference, &arm64, dir.path()).unwrap();
+        assert_eq!(entry.repo_tags, Some(vec![format!("{}/app:1.0", addr)]));
+
+        let image = crate::archive::process_archive(dir.path(), &Default::default()).unwrap();
+        assert_eq!(image.layer_labels, vec!["abcdef0"]);
+        assert!(image.root.get("etc/hostname").is_some());
+    }
+}
@@ -1,15 +1,275 @@
+//! Drawing a merged tree as indented text, optionally with permissions,
+//! layer separators, blame, and shadowed or masked entries
+
+use crate::archive::Image;
+use crate::capability;
+use crate::layers::{EmptyLayer, LayerDetail};
+use crate::oci::Platform;
 use crate::theme::Theme;
-use crate::tree::Node;
-use std::io::{self, Write};
+use crate::tree::{MaskedEntry, Node, NodeMetadata};
+use crate::utils;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeMap;
+use std::io::{self, BufWriter, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::Arc;
+use unicode_width::UnicodeWidthChar;
 
 const COLOR_RESET: &str = "\x1b[0m";
 
+/// Output is written in blocks this large instead of line by line
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// What to show and how. Outside this crate, start from
+/// [`RenderOptions::builder`] or `RenderOptions::default()`; new options may
+/// be added in any release.
+#[non_exhaustive]
 pub struct RenderOptions {
     pub show_long: bool,
     pub show_layers: bool,
+    pub show_shadowed: bool,
+    /// List entries hidden by opaque whiteouts under their directory
+    pub show_opaque: bool,
+    /// Show the owning layer to the left of every entry
+    pub blame: Option<BlameStyle>,
+    /// Metadata-only layers to announce alongside the layer separators
+    pub empty_layers: Vec<EmptyLayer>,
+    /// Labels of the image's layers, bottom-most first (used for blame indices)
+    pub layer_labels: Vec<String>,
+    /// Build step and size of each layer, in the order of `layer_labels`
+    pub layer_details: Vec<LayerDetail>,
+    /// How layer separators look
+    pub separator: SeparatorStyle,
     pub use_color: bool,
     pub icon_style: IconStyle,
+    pub charset: Charset,
+    /// Print each entry's full path instead of drawing the tree
+    pub full_paths: bool,
     pub theme: Theme,
+    /// Stop after this many entries, so huge trees can't flood the terminal
+    pub limit: Option<usize>,
+    /// Columns available, e.g. the terminal's width (None for no limit)
+    pub width: Option<usize>,
+    /// What to do with entries wider than `width`
+    pub overflow: Overflow,
+    /// What the entries of each directory are ordered by
+    pub sort: SortKey,
+    /// Reverse the order `sort` gives
+    pub reverse: bool,
+    /// List directories before the other entries of their parent
+    pub dirs_first: bool,
+    /// Compare runs of digits in names by value, so `file2` comes before `file10`
+    pub natural_sort: bool,
+    /// Compare names regardless of case
+    pub ignore_case: bool,
+    /// Directories the image declares as volumes, without a leading "/"
+    pub volumes: Vec<String>,
+}
+
+impl Default for RenderOptions {
+    /// Names only: plain text without color, icons, or layer details
+    fn default() -> Self {
+        RenderOptions {
+            show_long: false,
+            show_layers: false,
+            show_shadowed: false,
+            show_opaque: false,
+            blame: None,
+            empty_layers: Vec::new(),
+            layer_labels: Vec::new(),
+            layer_details: Vec::new(),
+            separator: SeparatorStyle::default(),
+            use_color: false,
+            icon_style: IconStyle::None,
+            charset: Charset::Unicode,
+            full_paths: false,
+            theme: Theme::default(),
+            limit: None,
+            width: None,
+            overflow: Overflow::Extend,
+            sort: SortKey::Name,
+            reverse: false,
+            dirs_first: false,
+            natural_sort: false,
+            ignore_case: false,
+            volumes: Vec::new(),
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Options starting from the defaults
+    pub fn builder() -> RenderOptionsBuilder {
+        RenderOptionsBuilder { options: RenderOptions::default() }
+    }
+}
+
+/// Chainable setters for [`RenderOptions`]
+#[must_use]
+pub struct RenderOptionsBuilder {
+    options: RenderOptions,
+}
+
+impl RenderOptionsBuilder {
+    /// Show permissions, ownership and sizes, then list files with capabilities
+    pub fn long(mut self, on: bool) -> Self {
+        self.options.show_long = on;
+        self
+    }
+
+    /// Separate the entries of each layer
+    pub fn layers(mut self, on: bool) -> Self {
+        self.options.show_layers = on;
+        self
+    }
+
+    /// Show earlier-layer versions of overwritten files
+    pub fn shadowed(mut self, on: bool) -> Self {
+        self.options.show_shadowed = on;
+        self
+    }
+
+    /// List entries hidden by opaque whiteouts
+    pub fn opaque(mut self, on: bool) -> Self {
+        self.options.show_opaque = on;
+        self
+    }
+
+    /// Prefix every entry with its layer
+    pub fn blame(mut self, style: Option<BlameStyle>) -> Self {
+        self.options.blame = style;
+        self
+    }
+
+    /// Metadata-only layers to announce with the layer separators
+    pub fn empty_layers(mut self, layers: Vec<EmptyLayer>) -> Self {
+        self.options.empty_layers = layers;
+        self
+    }
+
+    /// Labels of the image's layers, bottom-most first
+    pub fn layer_labels(mut self, labels: Vec<String>) -> Self {
+        self.options.layer_labels = labels;
+        self
+    }
+
+    /// Build steps and sizes of the layers, for separator labels
+    pub fn layer_details(mut self, details: Vec<LayerDetail>) -> Self {
+        self.options.layer_details = details;
+        self
+    }
+
+    /// How layer separators look
+    pub fn separator(mut self, style: SeparatorStyle) -> Self {
+        self.options.separator = style;
+        self
+    }
+
+    /// Colorize with the theme
+    pub fn color(mut self, on: bool) -> Self {
+        self.options.use_color = on;
+        self
+    }
+
+    pub fn icons(mut self, style: IconStyle) -> Self {
+        self.options.icon_style = style;
+        self
+    }
+
+    /// Draw the tree with box-drawing characters or plain ASCII
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.options.charset = charset;
+        self
+    }
+
+    /// Print each entry's full path instead of drawing the tree
+    pub fn full_paths(mut self, on: bool) -> Self {
+        self.options.full_paths = on;
+        self
+    }
+
+    pub fn theme(mut self, theme: Theme) -> Self {
+        self.options.theme = theme;
+        self
+    }
+
+    /// Stop after this many entries (None for no limit)
+    pub fn limit(mut self, limit: Option<usize>) -> Self {
+        self.options.limit = limit;
+        self
+    }
+
+    /// Columns available, e.g. the terminal's width (None for no limit)
+    pub fn width(mut self, width: Option<usize>) -> Self {
+        self.options.width = width;
+        self
+    }
+
+    /// What to do with entries wider than the width
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.options.overflow = overflow;
+        self
+    }
+
+    /// Order the entries of each directory by `key`
+    pub fn sort(mut self, key: SortKey) -> Self {
+        self.options.sort = key;
+        self
+    }
+
+    /// Reverse the sort order
+    pub fn reverse(mut self, on: bool) -> Self {
+        self.options.reverse = on;
+        self
+    }
+
+    /// List directories before files, links and devices
+    pub fn dirs_first(mut self, on: bool) -> Self {
+        self.options.dirs_first = on;
+        self
+    }
+
+    /// Order numbers in names by value (`file2` before `file10`)
+    pub fn natural_sort(mut self, on: bool) -> Self {
+        self.options.natural_sort = on;
+        self
+    }
+
+    /// Order names regardless of case
+    pub fn ignore_case(mut self, on: bool) -> Self {
+        self.options.ignore_case = on;
+        self
+    }
+
+    /// Mark these directories (paths without a leading "/") as volumes
+    pub fn volumes(mut self, paths: Vec<String>) -> Self {
+        self.options.volumes = paths;
+        self
+    }
+
+    pub fn build(self) -> RenderOptions {
+        self.options
+    }
+}
+
+/// How the blame column identifies a layer
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlameStyle {
+    /// Abbreviated layer hash
+    Hash,
+    /// Position of the layer in the manifest (0 = bottom)
+    Index,
+}
+
+impl BlameStyle {
+    /// `index` or `hash`; anything else means the hash
+    pub fn from_name(s: &str) -> Self {
+        match s {
+            "index" => BlameStyle::Index,
+            _ => BlameStyle::Hash,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -20,7 +280,8 @@ pub enum IconStyle {
 }
 
 impl IconStyle {
-    pub fn from_str(s: &str) -> Self {
+    /// `emoji` or `nerd`; anything else means no icons
+    pub fn from_name(s: &str) -> Self {
         match s {
             "emoji" => IconStyle::Emoji,
             "nerd" => IconStyle::Nerd,
@@ -45,37 +306,463 @@ impl IconStyle {
     }
 }
 
-pub fn render_tree(root: &Node, options: &RenderOptions) -> io::Result<()> {
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
+/// How layer separators look
+#[derive(Clone)]
+pub struct SeparatorStyle {
+    /// Character the line is drawn with (None for the charset's line)
+    pub fill: Option<char>,
+    /// Label of a layer, with `{hash}`, `{index}`, `{command}` and `{size}`
+    /// filled in
+    pub label: String,
+    pub placement: SeparatorPlacement,
+}
+
+impl Default for SeparatorStyle {
+    fn default() -> Self {
+        SeparatorStyle { fill: None, label: "Layer {hash}".to_string(), placement: SeparatorPlacement::Before }
+    }
+}
+
+impl SeparatorStyle {
+    const PLACEHOLDERS: [&'static str; 4] = ["hash", "index", "command", "size"];
 
-    // Calculate max ownership width if showing long format
-    let max_ownership_width = if options.show_long {
-        calculate_max_ownership_width(root)
+    /// Check that a label template only uses known placeholders
+    pub fn check_label(template: &str) -> anyhow::Result<()> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                anyhow::bail!("Unclosed '{{' in separator label '{}'", template);
+            };
+            let name = &rest[start + 1..start + len];
+            if !Self::PLACEHOLDERS.contains(&name) {
+                anyhow::bail!("Unknown placeholder '{{{}}}' in separator label (available: {{{}}})",
+                    name, Self::PLACEHOLDERS.join("}, {"));
+            }
+            rest = &rest[start + len + 1..];
+        }
+        Ok(())
+    }
+}
+
+/// Where a layer's label goes
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SeparatorPlacement {
+    /// On a line of its own before the layer's first entry
+    Before,
+    /// At the end of the layer's first entry
+    Inline,
+}
+
+impl SeparatorPlacement {
+    /// `inline`; anything else means before
+    pub fn from_name(s: &str) -> Self {
+        match s {
+            "inline" => SeparatorPlacement::Inline,
+            _ => SeparatorPlacement::Before,
+        }
+    }
+}
+
+/// Separators span this many columns when the width is unknown
+const DEFAULT_SEPARATOR_WIDTH: usize = 60;
+
+/// What to do with an entry wider than [`RenderOptions::width`]. Only the
+/// name and what follows it (link targets, owners, markers) give way; the
+/// blame, permission and tree columns are always drawn in full.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Write it out and let the terminal wrap it
+    Extend,
+    /// Cut it short with an ellipsis
+    Truncate,
+    /// Continue on following lines, aligned with the name
+    Wrap,
+}
+
+/// Room left for names however deep the tree is
+const MIN_NAME_WIDTH: usize = 10;
+
+/// What the entries of a directory are ordered by. Entries that tie stay
+/// in name order, as `natural_sort` and `ignore_case` compare names.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Name, byte by byte unless `natural_sort` or `ignore_case` say otherwise
+    Name,
+    /// Largest first; directories by the size of all the files beneath them
+    Size,
+    /// Most recently modified first
+    Mtime,
+    /// Layer that added the entry, bottom-most first
+    Layer,
+    /// What follows the last dot of the name (none for dotfiles)
+    Extension,
+}
+
+impl SortKey {
+    /// `size`, `mtime`, `layer` or `extension`; anything else means the name
+    pub fn from_name(s: &str) -> Self {
+        match s {
+            "size" => SortKey::Size,
+            "mtime" => SortKey::Mtime,
+            "layer" => SortKey::Layer,
+            "extension" => SortKey::Extension,
+            _ => SortKey::Name,
+        }
+    }
+}
+
+/// The children of `node` in the order `options` asks for
+fn sorted_children<'a>(node: &'a Node, options: &RenderOptions) -> Vec<(&'a Arc<str>, &'a Node)> {
+    let mut children: Vec<(&Arc<str>, &Node)> = node.children.iter().map(|(name, child)| (name, &**child)).collect();
+    let compare = |a: &str, b: &str| compare_names(a, b, options.natural_sort, options.ignore_case);
+    if options.natural_sort || options.ignore_case {
+        children.sort_by(|(a, _), (b, _)| compare(a, b));
+    }
+    // The sorts are stable, so ties keep the name order from above
+    match options.sort {
+        SortKey::Name => {}
+        SortKey::Size => children.sort_by_cached_key(|(_, child)| Reverse(child.total_size())),
+        SortKey::Mtime => children.sort_by_key(|(_, child)| Reverse(child.metadata.mtime)),
+        // Entries of unknown layers go last
+        SortKey::Layer => children.sort_by_key(|(_, child)| child.metadata.layer_index.unwrap_or(usize::MAX)),
+        SortKey::Extension => children.sort_by(|(a, _), (b, _)| compare(extension(a), extension(b))),
+    }
+    if options.reverse {
+        children.reverse();
+    }
+    if options.dirs_first {
+        children.sort_by_key(|(_, child)| child.metadata.is_file);
+    }
+    children
+}
+
+/// Compare names character by character, with runs of digits compared by
+/// value when `natural` (`file2` before `file10`) and letters compared
+/// regardless of case when `ignore_case`. Names these call equal, like
+/// `a01` and `a1` or `A` and `a`, fall back to byte order.
+fn compare_names(a: &str, b: &str, natural: bool, ignore_case: bool) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if natural && x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digit_run(&mut a_chars), digit_run(&mut b_chars));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(&x), Some(&y)) => {
+                a_chars.next();
+                b_chars.next();
+                if ignore_case {
+                    x.to_lowercase().cmp(y.to_lowercase())
+                } else {
+                    x.cmp(&y)
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Take the digits at the front of `chars`
+fn digit_run(chars: &mut Peekable<Chars>) -> String {
+    let mut run = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        run.push(digit);
+    }
+    run
+}
+
+/// The extension of a file name, without the dot; dotfiles like `.bashrc`
+/// have none
+fn extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => &name[dot + 1..],
+        _ => "",
+    }
+}
+
+/// Characters the tree lines are drawn with
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// Box-drawing characters (`├──`, `└──`, `│`)
+    Unicode,
+    /// `|--`, `\--` and `|`, for logs, tickets, and terminals that mangle UTF-8
+    Ascii,
+}
+
+impl Charset {
+    /// `ascii`; anything else means box-drawing characters
+    pub fn from_name(s: &str) -> Self {
+        match s {
+            "ascii" => Charset::Ascii,
+            _ => Charset::Unicode,
+        }
+    }
+
+    fn branch(&self, is_last: bool) -> &'static str {
+        match (self, is_last) {
+            (Charset::Unicode, false) => "├── ",
+            (Charset::Unicode, true) => "└── ",
+            (Charset::Ascii, false) => "|-- ",
+            (Charset::Ascii, true) => "\\-- ",
+        }
+    }
+
+    fn vertical(&self) -> &'static str {
+        match self {
+            Charset::Unicode => "│",
+            Charset::Ascii => "|",
+        }
+    }
+
+    fn horizontal(&self) -> &'static str {
+        match self {
+            Charset::Unicode => "─",
+            Charset::Ascii => "-",
+        }
+    }
+
+    /// Marks an overwritten version under the winning entry
+    fn shadowed(&self) -> &'static str {
+        match self {
+            Charset::Unicode => "↳",
+            Charset::Ascii => "\\_",
+        }
+    }
+
+    /// Marks an entry hidden by an opaque whiteout
+    fn masked(&self) -> &'static str {
+        match self {
+            Charset::Unicode => "⊘",
+            Charset::Ascii => "x",
+        }
+    }
+
+    fn ellipsis(&self) -> &'static str {
+        match self {
+            Charset::Unicode => "…",
+            Charset::Ascii => "...",
+        }
+    }
+}
+
+/// Write the tree under `root` to `writer`, followed by the capabilities
+/// report when `show_long` is set. Output is buffered, so an unbuffered
+/// writer such as a stdout lock needs no wrapping.
+pub fn render_tree<W: Write>(writer: &mut W, root: &Node, options: &RenderOptions) -> io::Result<()> {
+    let mut buffered = BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    write_tree(&mut buffered, root, options)?;
+    buffered.flush()
+}
+
+/// Write what identifies `image` (tags, digest, platform, layers and their
+/// size) above its tree, so pasted output says what it shows
+pub fn write_header<W: Write>(writer: &mut W, image: &Image, options: &RenderOptions) -> io::Result<()> {
+    let tags = if image.repo_tags.is_empty() { "(untagged)".to_string() } else { image.repo_tags.join(", ") };
+    let platform = image.config.as_ref().and_then(|config| {
+        Some(Platform {
+            os: config.os.clone()?,
+            architecture: config.architecture.clone()?,
+            variant: config.variant.clone(),
+        })
+    });
+    let layers = format!("{}, {}", image.layer_labels.len(), utils::human_size(image.layer_sizes.iter().sum()));
+    // Numbered as in the manifest, so the gaps show where the tree is partial
+    let missing = (!image.missing_layers.is_empty()).then(|| {
+        let total = image.layer_labels.len() + image.missing_layers.len();
+        let listed: Vec<String> = image.missing_layers.iter()
+            .map(|layer| format!("{} of {} ({})", layer.position + 1, total, layer.name))
+            .collect();
+        let whose = if listed.len() == 1 { "its" } else { "their" };
+        format!("{}; not in the archive, so the tree lacks {} changes", listed.join(", "), whose)
+    });
+
+    let container = image.config.as_ref().map(|config| &config.config);
+    let keys = |map: Option<&BTreeMap<String, serde_json::Value>>| {
+        map.filter(|map| !map.is_empty()).map(|map| map.keys().cloned().collect::<Vec<_>>().join(", "))
+    };
+
+    let fields = [
+        ("Image", Some(tags)),
+        ("Digest", image.digest.clone()),
+        ("Platform", platform.map(|p| p.to_string())),
+        ("Layers", Some(layers)),
+        ("Missing", missing),
+        ("Ports", keys(container.and_then(|c| c.exposed_ports.as_ref()))),
+        ("Volumes", keys(container.and_then(|c| c.volumes.as_ref()))),
+    ];
+    for (label, value) in fields {
+        let Some(value) = value else { continue };
+        let label = format!("{:<10}", format!("{}:", label));
+        if options.use_color {
+            writeln!(writer, "{}{}{}{}", options.theme.tree_chars, label, COLOR_RESET, value)?;
+        } else {
+            writeln!(writer, "{}{}", label, value)?;
+        }
+    }
+    writeln!(writer)
+}
+
+/// The tree under `root` as text, as `render_tree` would write it
+pub fn render_to_string(root: &Node, options: &RenderOptions) -> String {
+    let mut out = Vec::new();
+    write_tree(&mut out, root, options).expect("writing to a Vec cannot fail");
+    // Names, link targets, and theme colors are all Strings
+    String::from_utf8(out).expect("rendered tree is UTF-8")
+}
+
+fn write_tree<W: Write>(writer: &mut W, root: &Node, options: &RenderOptions) -> io::Result<()> {
+    // Calculate the column widths if showing long format
+    let columns = if options.show_long {
+        LongColumns::measure(root)
     } else {
-        0
+        LongColumns::default()
+    };
+
+    let mut state = RenderState {
+        announced: 0,
+        remaining: options.limit,
+        truncated: false,
+        line: Vec::new(),
+        dir: String::new(),
+    };
+    // With full paths, the prefix is the path of the directory being listed
+    let prefix = if options.full_paths { "/" } else { "" };
+    render_node(writer, root, prefix, options, None, columns, &mut state)?;
+
+    if state.truncated {
+        let limit = options.limit.unwrap_or(0);
+        writeln!(writer, "\n{} stopped after {} of {} entries (raise --limit, or 0 for no limit)",
+            options.charset.ellipsis(), limit, root.count_descendants())?;
+    } else if options.show_layers {
+        // Empty layers after the last filesystem layer have no separator to
+        // precede, and those before layers with nothing left to show none either
+        for label in empty_layer_labels(None, options, &mut state) {
+            write_layer_separator(writer, &label, options)?;
+        }
+    }
+
+    if options.show_long {
+        write_capabilities(writer, root)?;
+    }
+    Ok(())
+}
+
+/// Mutable state carried through one rendering pass
+struct RenderState {
+    /// How many of the empty layers were announced; they go in layer order
+    announced: usize,
+    /// Entries that may still be printed (None for no limit)
+    remaining: Option<usize>,
+    /// Whether entries were left out because of the limit
+    truncated: bool,
+    /// Reused buffer each entry's line is assembled in
+    line: Vec<u8>,
+    /// Path of the directory being listed, without a leading "/"
+    dir: String,
+}
+
+/// Width of the blame column, excluding the trailing space
+fn blame_width(options: &RenderOptions) -> usize {
+    match options.blame {
+        Some(BlameStyle::Hash) => options.layer_labels.iter().map(|l| l.len()).max().unwrap_or(0),
+        Some(BlameStyle::Index) => format!("#{}", options.layer_labels.len().saturating_sub(1)).len(),
+        None => 0,
+    }
+}
+
+/// Write the blame column for an entry owned by layer `layer_index`, labeled `layer_hash`
+fn write_blame<W: Write>(
+    writer: &mut W,
+    layer_hash: Option<&str>,
+    layer_index: Option<usize>,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let Some(style) = options.blame else {
+        return Ok(());
+    };
+
+    let label = match style {
+        BlameStyle::Hash => layer_hash.unwrap_or_default().to_string(),
+        BlameStyle::Index => layer_index.map(|i| format!("#{}", i)).unwrap_or_default(),
     };
+    let label = format!("{:<width$}", label, width = blame_width(options));
 
-    render_node(&mut handle, root, "", options, None, max_ownership_width)?;
-    handle.flush()
+    if options.use_color {
+        write!(writer, "{}{}{} ", options.theme.layer_separator, label, COLOR_RESET)
+    } else {
+        write!(writer, "{} ", label)
+    }
 }
 
 /// Calculate the maximum width needed for the ownership column
-fn calculate_max_ownership_width(node: &Node) -> usize {
-    let mut max_width = 0;
+/// Widths of the right-aligned columns of long output
+#[derive(Clone, Copy, Default)]
+struct LongColumns {
+    ownership: usize,
+    size: usize,
+}
 
-    for child in node.children.values() {
-        let owner_str = format!("{}:{}", child.metadata.uid, child.metadata.gid);
-        max_width = max_width.max(owner_str.len());
+impl LongColumns {
+    /// The widest ownership and size under `node`
+    fn measure(node: &Node) -> Self {
+        let mut columns = LongColumns::default();
+        for child in node.children.values() {
+            let owner_str = format!("{}:{}", child.metadata.uid, child.metadata.gid);
+            columns.ownership = columns.ownership.max(owner_str.len());
+            columns.size = columns.size.max(format_size(&child.metadata).len());
+            for previous in &child.metadata.shadowed {
+                columns.ownership = columns.ownership.max(format!("{}:{}", previous.uid, previous.gid).len());
+                columns.size = columns.size.max(format_size(previous).len());
+            }
 
-        // Recursively check children
-        if !child.metadata.is_file {
-            let child_max = calculate_max_ownership_width(child);
-            max_width = max_width.max(child_max);
+            // Recursively check children
+            if !child.metadata.is_file {
+                let nested = Self::measure(child);
+                columns.ownership = columns.ownership.max(nested.ownership);
+                columns.size = columns.size.max(nested.size);
+            }
         }
+        columns
     }
+}
 
-    max_width
+/// Write the permissions, ownership and size columns of long output
+fn write_long_columns<W: Write>(
+    writer: &mut W,
+    meta: &NodeMetadata,
+    columns: LongColumns,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let perms = format_permissions(meta.mode, meta.is_file);
+    // Right-align ownership and size using the calculated max widths
+    let owner_str = format!("{}:{}", meta.uid, meta.gid);
+    let owner = format!("{:>width$}", owner_str, width = columns.ownership);
+    let size = format!("{:>width$}", format_size(meta), width = columns.size);
+
+    if options.use_color {
+        let size_color = if meta.is_file { options.theme.size_color(meta.size) } else { &options.theme.tree_chars };
+        write!(writer, "{}{}{} {}{}{} {}{}{} ",
+            options.theme.permissions, perms, COLOR_RESET,
+            options.theme.ownership, owner, COLOR_RESET,
+            size_color, size, COLOR_RESET)
+    } else {
+        write!(writer, "{} {} {} ", perms, owner, size)
+    }
+}
+
+/// The size column of an entry; directories have none
+fn format_size(meta: &NodeMetadata) -> String {
+    if meta.is_file {
+        utils::human_size(meta.size)
+    } else {
+        "-".to_string()
+    }
 }
 
 fn render_node<W: Write>(
@@ -83,58 +770,85 @@ fn render_node<W: Write>(
     node: &Node,
     prefix: &str,
     options: &RenderOptions,
-    prev_layer: Option<&str>,
-    max_ownership_width: usize,
-) -> io::Result<Option<String>> {
-    // Collect and sort children
-    let mut children: Vec<_> = node.children.iter().collect();
-    children.sort_by_key(|(name, _)| *name);
-
-    let mut last_layer = prev_layer.map(|s| s.to_string());
+    prev_layer: Option<usize>,
+    columns: LongColumns,
+    state: &mut RenderState,
+) -> io::Result<Option<usize>> {
+    let mut last_layer = prev_layer;
 
-    for (idx, (name, child)) in children.iter().enumerate() {
+    let children = sorted_children(node, options);
+    for (idx, &(name, child)) in children.iter().enumerate() {
         let is_last = idx + 1 == children.len();
 
+        if let Some(remaining) = state.remaining.as_mut() {
+            if *remaining == 0 {
+                state.truncated = true;
+                return Ok(last_layer);
+            }
+            *remaining -= 1;
+        }
+
         // Check if we need to print a layer separator
+        let mut inline_labels = Vec::new();
         if options.show_layers {
-            let current_layer = child.metadata.layer_hash.as_deref();
-            if current_layer != last_layer.as_deref() {
+            let current_layer = child.metadata.layer_index;
+            if current_layer != last_layer {
                 if let Some(layer) = current_layer {
-                    write_layer_separator(writer, layer, options)?;
-                    last_layer = Some(layer.to_string());
+                    let mut labels = empty_layer_labels(Some(layer), options, state);
+                    labels.push(layer_label(child.metadata.layer_hash.as_deref().unwrap_or_default(), layer, options));
+                    if options.separator.placement == SeparatorPlacement::Inline {
+                        inline_labels = labels;
+                    } else {
+                        for label in &labels {
+                            write_layer_separator(writer, label, options)?;
+                        }
+                    }
+                    last_layer = Some(layer);
                 }
             }
         }
 
-        // Show permissions and ownership first if requested
+        // Files baked into a volume are hidden once something is mounted there
+        let is_volume = !child.metadata.is_file && !options.volumes.is_empty() && {
+            let path = if state.dir.is_empty() { name.to_string() } else { format!("{}/{}", state.dir, name) };
+            options.volumes.contains(&path)
+        };
+        let line = &mut state.line;
+        line.clear();
+        write_blame(line, child.metadata.layer_hash.as_deref(), child.metadata.layer_index, options)?;
+        let raw_name = name;
+        let name = utils::display_path(name);
+        let name: &str = &name;
+
+        // Show permissions, ownership and size first if requested
         if options.show_long {
-            let perms = format_permissions(child.metadata.mode, child.metadata.is_file);
-            // Right-align ownership using the calculated max width
-            let owner_str = format!("{}:{}", child.metadata.uid, child.metadata.gid);
-            let owner = format!("{:>width$}", owner_str, width = max_ownership_width);
+            write_long_columns(line, &child.metadata, columns, options)?;
+        }
 
+        // Draw tree structure
+        let tree_start = line.len();
+        let path;
+        let name: &str = if options.full_paths {
+            path = format!("{}{}", prefix, name);
+            &path
+        } else {
+            let branch = options.charset.branch(is_last);
             if options.use_color {
-                write!(writer, "{}{}{} {}{}{} ",
-                    options.theme.permissions, perms, COLOR_RESET,
-                    options.theme.ownership, owner, COLOR_RESET)?;
+                write!(line, "{}{}{}{}",
+                    options.theme.tree_chars, prefix, branch, COLOR_RESET)?;
             } else {
-                write!(writer, "{} {} ", perms, owner)?;
+                write!(line, "{}{}", prefix, branch)?;
             }
-        }
-
-        // Draw tree structure
-        let branch = if is_last { "└── " } else { "├── " };
+            name
+        };
 
-        if options.use_color {
-            write!(writer, "{}{}{}{}",
-                options.theme.tree_chars, prefix, branch, COLOR_RESET)?;
-        } else {
-            write!(writer, "{}{}", prefix, branch)?;
-        }
+        let name_start = line.len();
 
         // Determine color based on file type
         let color = if options.use_color {
-            if child.metadata.is_symlink {
+            if let Some(highlight) = security_highlight(&child.metadata, &options.theme) {
+                highlight
+            } else if child.metadata.is_symlink {
                 &options.theme.symlink
             } else if !child.metadata.is_file {
                 &options.theme.directory
@@ -154,87 +868,418 @@ fn render_node<W: Write>(
             options.icon_style.dir_icon()
         };
 
-        if !color.is_empty() {
-            write!(writer, "{}{}", color, icon)?;
-        } else {
-            write!(writer, "{}", icon)?;
-        }
-
         // Print filename with same color
         if !color.is_empty() {
-            write!(writer, "{}{}", name, COLOR_RESET)?;
+            write!(line, "{}{}{}{}", color, icon, name, COLOR_RESET)?;
         } else {
-            write!(writer, "{}", name)?;
+            write!(line, "{}{}", icon, name)?;
         }
 
         // Show symlink target
         if child.metadata.is_symlink {
-            if let Some(ref target) = child.metadata.symlink_target {
+            if let Some(target) = child.metadata.symlink_target.as_deref().map(utils::display_path) {
                 if options.use_color {
-                    write!(writer, " -> {}{}{}", options.theme.symlink, target, COLOR_RESET)?;
+                    write!(line, " -> {}{}{}", options.theme.symlink, target, COLOR_RESET)?;
                 } else {
-                    write!(writer, " -> {}", target)?;
+                    write!(line, " -> {}", target)?;
                 }
             }
         }
 
         // Show hard link target
-        if let Some(ref target) = child.metadata.hardlink_target {
+        if let Some(target) = child.metadata.hardlink_target.as_deref().map(utils::display_path) {
             if options.use_color {
-                write!(writer, " => {}{}{}", options.theme.hardlink, target, COLOR_RESET)?;
+                write!(line, " => {}{}{}", options.theme.hardlink, target, COLOR_RESET)?;
             } else {
-                write!(writer, " => {}", target)?;
+                write!(line, " => {}", target)?;
             }
         }
 
-        writeln!(writer)?;
-
-        // Recurse into directories
-        if !child.metadata.is_file && !child.children.is_empty() {
-            let new_prefix = if is_last {
-                format!("{}    ", prefix)
-            } else if options.use_color {
-                format!("{}{}│{}   ", prefix, options.theme.tree_chars, COLOR_RESET)
+        // Package that installed this file
+        if let Some(ref owner) = child.metadata.owner {
+            if options.use_color {
+                write!(line, " {}[{}]{}", options.theme.ownership, owner, COLOR_RESET)?;
             } else {
-                format!("{}│   ", prefix)
+                write!(line, " [{}]", owner)?;
+            }
+        }
+
+        // Flag directories whose lower-layer content was masked
+        if child.metadata.opaque {
+            let marker = match &child.metadata.opaque_layer {
+                Some(layer) => format!(" [opaque, layer {}]", layer),
+                None => " [opaque]".to_string(),
             };
+            if options.use_color {
+                write!(line, "{}{}{}", options.theme.layer_separator, marker, COLOR_RESET)?;
+            } else {
+                write!(line, "{}", marker)?;
+            }
+        }
+
+        if is_volume {
+            if options.use_color {
+                write!(line, " {}[VOLUME]{}", options.theme.layer_separator, COLOR_RESET)?;
+            } else {
+                write!(line, " [VOLUME]")?;
+            }
+        }
+
+        if let Some(width) = options.width.filter(|_| options.overflow != Overflow::Extend) {
+            let continuation = || if options.full_paths { String::new() } else { child_prefix(prefix, is_last, options) };
+            fit_line(line, tree_start, name_start, width, continuation, options);
+        }
+        write_inline_labels(line, &inline_labels, options)?;
+        writeln!(line)?;
+        writer.write_all(line)?;
 
-            last_layer = render_node(writer, child, &new_prefix, options, last_layer.as_deref(), max_ownership_width)?
+        // Most entries are files with nothing drawn beneath them
+        let shadowed = if options.show_shadowed { child.metadata.shadowed.as_slice() } else { &[] };
+        let masked = if options.show_opaque { child.metadata.masked.as_slice() } else { &[] };
+        let has_children = !child.metadata.is_file && !child.children.is_empty();
+        if shadowed.is_empty() && masked.is_empty() && !has_children {
+            continue;
+        }
+
+        // Prefix for anything drawn beneath this entry
+        let new_prefix = if options.full_paths {
+            format!("{}/", name)
+        } else {
+            child_prefix(prefix, is_last, options)
+        };
+        let (beneath, dir) = if options.full_paths { ("", new_prefix.as_str()) } else { (new_prefix.as_str(), "") };
+
+        // Show earlier versions this entry overwrote
+        for previous in shadowed {
+            write_shadowed(writer, name, previous, beneath, options, columns)?;
+        }
+
+        // Show what an opaque whiteout hid in this directory
+        for masked in masked {
+            write_masked(writer, masked, dir, beneath, options)?;
+        }
+
+        // Recurse into directories
+        if has_children {
+            let parent_len = state.dir.len();
+            if parent_len > 0 {
+                state.dir.push('/');
+            }
+            state.dir.push_str(raw_name);
+            last_layer = render_node(writer, child, &new_prefix, options, last_layer, columns, state)?
                 .or(last_layer);
+            state.dir.truncate(parent_len);
         }
     }
 
     Ok(last_layer)
 }
 
+/// The theme's highlight for an entry with dangerous permissions, as GNU
+/// ls picks them: setuid, then setgid, then world-writable
+fn security_highlight<'a>(meta: &NodeMetadata, theme: &'a Theme) -> Option<&'a str> {
+    // Symlinks are always 0777, and device nodes are judged by their device
+    if meta.is_symlink || meta.device.is_some() {
+        return None;
+    }
+    let highlight = if meta.is_file && meta.mode & 0o4000 != 0 {
+        &theme.setuid
+    } else if meta.is_file && meta.mode & 0o2000 != 0 {
+        &theme.setgid
+    } else if meta.mode & 0o002 == 0 {
+        return None;
+    } else if !meta.is_file && meta.mode & 0o1000 != 0 {
+        &theme.sticky_writable
+    } else {
+        &theme.world_writable
+    };
+    Some(highlight.as_str()).filter(|h| !h.is_empty())
+}
+
+/// The tree prefix of what is drawn beneath an entry: its children, and the
+/// continuation lines of a wrapped entry
+fn child_prefix(prefix: &str, is_last: bool, options: &RenderOptions) -> String {
+    if is_last {
+        format!("{}    ", prefix)
+    } else if options.use_color {
+        format!("{}{}{}{}   ", prefix, options.theme.tree_chars, options.charset.vertical(), COLOR_RESET)
+    } else {
+        format!("{}{}   ", prefix, options.charset.vertical())
+    }
+}
+
+/// Make the part of `line` from `name_start` on fit in `width` columns,
+/// by truncating or wrapping it as `options.overflow` says. Continuation
+/// lines start below the name: blanks under the blame and permission
+/// columns, then the tree prefix `continuation` gives.
+fn fit_line(
+    line: &mut Vec<u8>,
+    tree_start: usize,
+    name_start: usize,
+    width: usize,
+    continuation: impl FnOnce() -> String,
+    options: &RenderOptions,
+) {
+    let text = String::from_utf8_lossy(&line[name_start..]).into_owned();
+    let room = width.saturating_sub(display_width(&String::from_utf8_lossy(&line[..name_start]))).max(MIN_NAME_WIDTH);
+    if display_width(&text) <= room {
+        return;
+    }
+
+    line.truncate(name_start);
+    if options.overflow == Overflow::Truncate {
+        let ellipsis = options.charset.ellipsis();
+        let pieces = split_columns(&text, room - ellipsis.chars().count());
+        line.extend_from_slice(pieces[0].as_bytes());
+        line.extend_from_slice(ellipsis.as_bytes());
+        return;
+    }
+
+    let indent = " ".repeat(display_width(&String::from_utf8_lossy(&line[..tree_start])));
+    let continuation = format!("\n{}{}", indent, continuation());
+    for (i, piece) in split_columns(&text, room).iter().enumerate() {
+        if i > 0 {
+            line.extend_from_slice(continuation.as_bytes());
+        }
+        line.extend_from_slice(piece.as_bytes());
+    }
+}
+
+/// Length of the control sequence `text` starts with, e.g. `\x1b[38;5;73m`
+fn escape_len(text: &str) -> usize {
+    match text.strip_prefix("\x1b[") {
+        Some(rest) => rest.find(|c: char| ('@'..='~').contains(&c)).map_or(text.len(), |i| i + 3),
+        None => 1,
+    }
+}
+
+/// Columns `text` takes up on a terminal; color codes take none
+fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '\x1b' {
+            rest = &rest[escape_len(rest)..];
+        } else {
+            width += c.width().unwrap_or(0);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    width
+}
+
+/// Split `text` into pieces at most `width` columns wide. A color in effect
+/// at a break is reset at the end of one piece and set again at the start of
+/// the next, so every piece can be written on a line of its own.
+fn split_columns(text: &str, width: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut used = 0;
+    let mut color = "";
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let piece = pieces.last_mut().expect("at least one piece");
+        if c == '\x1b' {
+            let code = &rest[..escape_len(rest)];
+            color = if code == COLOR_RESET { "" } else { code };
+            piece.push_str(code);
+            rest = &rest[code.len()..];
+            continue;
+        }
+
+        let columns = c.width().unwrap_or(0);
+        if used + columns > width && used > 0 {
+            if !color.is_empty() {
+                piece.push_str(COLOR_RESET);
+            }
+            pieces.push(color.to_string());
+            used = 0;
+        }
+        pieces.last_mut().expect("at least one piece").push(c);
+        used += columns;
+        rest = &rest[c.len_utf8()..];
+    }
+    pieces
+}
+
+/// List files carrying capabilities below the tree, like `getcap -r`
+fn write_capabilities<W: Write>(writer: &mut W, root: &Node) -> io::Result<()> {
+    let found: Vec<(String, String)> = root.walk()
+        .filter_map(|(path, meta)| {
+            let value = meta.xattrs.get(capability::XATTR)?;
+            Some((format!("/{}", path.display()), capability::describe(value)))
+        })
+        .collect();
+    if found.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(writer)?;
+    writeln!(writer, "files with capabilities ({}):", found.len())?;
+    for (path, caps) in found {
+        writeln!(writer, "  {}: {}", path, caps)?;
+    }
+    Ok(())
+}
+
+/// Write one overwritten version of a file, indented under the winning entry
+fn write_shadowed<W: Write>(
+    writer: &mut W,
+    name: &str,
+    previous: &NodeMetadata,
+    prefix: &str,
+    options: &RenderOptions,
+    columns: LongColumns,
+) -> io::Result<()> {
+    write_blame(writer, previous.layer_hash.as_deref(), previous.layer_index, options)?;
+
+    if options.show_long {
+        write_long_columns(writer, previous, columns, options)?;
+    }
+
+    let layer = previous.layer_hash.as_deref().unwrap_or("unknown");
+    let target = match &previous.symlink_target {
+        Some(target) if previous.is_symlink => format!(" -> {}", utils::display_path(target)),
+        _ => String::new(),
+    };
+
+    if options.use_color {
+        writeln!(writer, "{}{}{} {}{} (shadowed, layer {}){}",
+            prefix, options.theme.hardlink, options.charset.shadowed(), name, target, layer, COLOR_RESET)
+    } else {
+        writeln!(writer, "{}{} {}{} (shadowed, layer {})", prefix, options.charset.shadowed(), name, target, layer)
+    }
+}
+
+/// Write one entry hidden by an opaque whiteout, indented under its directory
+/// (or named with its directory's path `dir` in front, for full paths)
+fn write_masked<W: Write>(
+    writer: &mut W,
+    masked: &MaskedEntry,
+    dir: &str,
+    prefix: &str,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    write_blame(writer, masked.layer_hash.as_deref(), masked.layer_index, options)?;
+
+    let layer = masked.layer_hash.as_deref().unwrap_or("unknown");
+    let name = utils::display_path(&masked.name);
+    let name = if masked.is_dir { format!("{}{}/", dir, name) } else { format!("{}{}", dir, name) };
+    let contents = match masked.descendants {
+        0 => String::new(),
+        1 => ", 1 entry".to_string(),
+        n => format!(", {} entries", n),
+    };
+
+    if options.use_color {
+        writeln!(writer, "{}{}{} {} (masked, layer {}{}){}",
+            prefix, options.theme.hardlink, options.charset.masked(), name, layer, contents, COLOR_RESET)
+    } else {
+        writeln!(writer, "{}{} {} (masked, layer {}{})", prefix, options.charset.masked(), name, layer, contents)
+    }
+}
+
+/// Labels announcing the no-op empty layers not announced yet that come
+/// before filesystem layer `before` (all of them for None). Going by layer
+/// position, an empty layer is announced even when the layer after it has
+/// nothing left to show.
+fn empty_layer_labels(before: Option<usize>, options: &RenderOptions, state: &mut RenderState) -> Vec<String> {
+    let pending = options.empty_layers.get(state.announced..).unwrap_or_default();
+    let count = pending.iter()
+        .take_while(|empty| match (before, empty.before) {
+            (None, _) => true,
+            (Some(before), Some(next)) => next <= before,
+            (Some(_), None) => false,
+        })
+        .count();
+    state.announced += count;
+    pending[..count].iter()
+        .map(|empty| format!("Layer (empty) {}", shorten_command(&empty.command, options)))
+        .collect()
+}
+
+/// The separator label of filesystem layer `index`, labeled `layer`, from the label template
+fn layer_label(layer: &str, index: usize, options: &RenderOptions) -> String {
+    let detail = options.layer_details.get(index);
+    // The command goes last, so placeholders inside it are left alone
+    options.separator.label
+        .replace("{hash}", layer)
+        .replace("{index}", &index.to_string())
+        .replace("{size}", &detail.map_or_else(String::new, |d| utils::human_size(d.size)))
+        .replace("{command}", &detail.map_or_else(String::new, |d| shorten_command(&d.command, options)))
+}
+
+/// A build instruction cut down to fit in a separator
+fn shorten_command(command: &str, options: &RenderOptions) -> String {
+    const MAX_COMMAND: usize = 40;
+
+    if command.chars().count() > MAX_COMMAND {
+        let ellipsis = options.charset.ellipsis();
+        let kept = MAX_COMMAND - ellipsis.chars().count();
+        format!("{}{}", command.chars().take(kept).collect::<String>(), ellipsis)
+    } else {
+        command.to_string()
+    }
+}
+
+/// The character separators are drawn with
+fn separator_fill(options: &RenderOptions) -> String {
+    match options.separator.fill {
+        Some(fill) => fill.to_string(),
+        None => options.charset.horizontal().to_string(),
+    }
+}
+
+/// Append layer labels to an entry's line, for inline separators
+fn write_inline_labels<W: Write>(writer: &mut W, labels: &[String], options: &RenderOptions) -> io::Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+    let fill = separator_fill(options).repeat(2);
+    let text: String = labels.iter().map(|label| format!(" {} {}", fill, label)).collect();
+    if options.use_color {
+        write!(writer, " {}{}{}", options.theme.layer_separator, text, COLOR_RESET)
+    } else {
+        write!(writer, " {}", text)
+    }
+}
+
+/// Write a separator line across the width, with `label` in the middle
 fn write_layer_separator<W: Write>(
     writer: &mut W,
-    layer_hash: &str,
+    label: &str,
     options: &RenderOptions,
 ) -> io::Result<()> {
-    let label = format!(" Layer {} ", layer_hash);
-    let total_width: usize = 60;
-    let padding = total_width.saturating_sub(label.len()) / 2;
-    let right_padding = total_width.saturating_sub(label.len() + padding);
+    let label = format!(" {} ", label);
+    let total_width = options.width.unwrap_or(DEFAULT_SEPARATOR_WIDTH);
+    let label_width = display_width(&label);
+    let padding = total_width.saturating_sub(label_width) / 2;
+    let right_padding = total_width.saturating_sub(label_width + padding);
+    let line = separator_fill(options);
+    // A wide fill character covers two columns at a time
+    let (padding, right_padding) = match display_width(&line) {
+        0 | 1 => (padding, right_padding),
+        fill_width => (padding / fill_width, right_padding / fill_width),
+    };
 
     writeln!(writer)?;
 
     if options.use_color {
         write!(writer, "{}", options.theme.layer_separator)?;
-        write!(writer, "{}", "─".repeat(padding))?;
+        write!(writer, "{}", line.repeat(padding))?;
         write!(writer, "{}", label)?;
-        write!(writer, "{}", "─".repeat(right_padding))?;
+        write!(writer, "{}", line.repeat(right_padding))?;
         writeln!(writer, "{}", COLOR_RESET)?;
     } else {
-        write!(writer, "{}", "─".repeat(padding))?;
+        write!(writer, "{}", line.repeat(padding))?;
         write!(writer, "{}", label)?;
-        writeln!(writer, "{}", "─".repeat(right_padding))?;
+        writeln!(writer, "{}", line.repeat(right_padding))?;
     }
 
     Ok(())
 }
 
-fn format_permissions(mode: u32, is_file: bool) -> String {
+/// `ls -l` style permissions, e.g. `drwxr-xr-x`
+pub fn format_permissions(mode: u32, is_file: bool) -> String {
     let file_type = if is_file { '-' } else { 'd' };
 
     format!(
@@ -251,3 +1296,342 @@ fn format_permissions(mode: u32, is_file: bool) -> String {
         if mode & 0o001 != 0 { 'x' } else { '-' },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::MissingLayer;
+    use std::sync::Arc;
+
+    fn options(limit: Option<usize>) -> RenderOptions {
+        RenderOptions { limit, ..Default::default() }
+    }
+
+    fn render(root: &Node, limit: Option<usize>) -> String {
+        render_to_string(root, &options(limit))
+    }
+
+    #[test]
+    fn test_builder() {
+        let options = RenderOptions::builder().long(true).icons(IconStyle::Emoji).limit(Some(1)).build();
+        assert!(options.show_long && !options.show_layers && !options.use_color);
+        assert!(matches!(options.icon_style, IconStyle::Emoji));
+        assert_eq!(options.limit, Some(1));
+        assert!(options.blame.is_none());
+    }
+
+    #[test]
+    fn test_write_tree_limit() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/bin/sh", 0o755, 0, 0, true, Some("busybox".to_string()), None);
+
+        assert_eq!(render(&root, None), concat!(
+            "├── etc\n",
+            "│   ├── hosts\n",
+            "│   └── passwd\n",
+            "└── usr\n",
+            "    └── bin\n",
+            "        └── sh -> busybox\n",
+        ));
+        assert_eq!(render(&root, Some(2)), concat!(
+            "├── etc\n",
+            "│   ├── hosts\n",
+            "\n… stopped after 2 of 6 entries (raise --limit, or 0 for no limit)\n",
+        ));
+        // A limit the tree fits in changes nothing
+        assert_eq!(render(&root, Some(6)), render(&root, None));
+    }
+
+    #[test]
+    fn test_fit_to_width() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/lib/libexample.so", 0o644, 0, 0, true, Some("libexample.so.1.2.3".to_string()), None);
+
+        let options = RenderOptions::builder().width(Some(24)).overflow(Overflow::Truncate).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "├── etc\n",
+            "│   └── hosts\n",
+            "└── usr\n",
+            "    └── lib\n",
+            "        └── libexample.…\n",
+        ));
+
+        let options = RenderOptions::builder().width(Some(24)).overflow(Overflow::Wrap).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "├── etc\n",
+            "│   └── hosts\n",
+            "└── usr\n",
+            "    └── lib\n",
+            "        └── libexample.s\n",
+            "            o -> libexam\n",
+            "            ple.so.1.2.3\n",
+        ));
+    }
+
+    #[test]
+    fn test_split_columns_keeps_colors() {
+        let text = "\x1b[1mabcdef\x1b[0m gh";
+        assert_eq!(display_width(text), 9);
+        assert_eq!(split_columns(text, 4), ["\x1b[1mabcd\x1b[0m", "\x1b[1mef\x1b[0m g", "h"]);
+        assert_eq!(display_width("日本"), 4);
+        assert_eq!(split_columns("日本語", 5), ["日本", "語"]);
+    }
+
+    #[test]
+    fn test_security_highlights() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("usr/bin/su", 0o4755, 0, 0, false, None, None);
+        root.put_file("usr/bin/ls", 0o755, 0, 0, false, None, None);
+        root.put_file("usr/lib/link", 0o777, 0, 0, true, Some("ls".to_string()), None);
+        root.ensure_path("tmp", 0o1777, 0, 0, None);
+
+        let theme = Theme::default();
+        let options = RenderOptions::builder().color(true).theme(theme.clone()).build();
+        let text = render_to_string(&root, &options);
+        assert!(text.contains(&format!("{}tmp{}", theme.sticky_writable, COLOR_RESET)), "{}", text);
+        assert!(text.contains(&format!("{}su{}", theme.setuid, COLOR_RESET)), "{}", text);
+        assert!(text.contains(&format!("{}ls{}", theme.executable, COLOR_RESET)), "{}", text);
+        assert!(text.contains(&format!("{}link{}", theme.symlink, COLOR_RESET)), "{}", text);
+    }
+
+    #[test]
+    fn test_write_header() {
+        let config: crate::config::ImageConfig =
+            serde_json::from_str(r#"{"os": "linux", "architecture": "arm64", "variant": "v8", "config": {
+                "ExposedPorts": {"5432/tcp": {}, "8080/tcp": {}}, "Volumes": {"/var/lib/postgresql/data": {}}}}"#)
+            .unwrap();
+        let image = Image {
+            root: Node::new_dir(0o755, 0, 0),
+            layer_labels: vec!["a1b2c3d".to_string(), "e4f5a6b".to_string()],
+            config: Some(config),
+            repo_tags: vec!["app:1.0".to_string(), "app:latest".to_string()],
+            digest: Some("sha256:abc".to_string()),
+            layer_sizes: vec![1024 * 1024, 2 * 1024 * 1024],
+            layer_profiles: Vec::new(),
+            suspicious: Vec::new(),
+            missing_layers: Vec::new(),
+        };
+        let mut out = Vec::new();
+        write_header(&mut out, &image, &RenderOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "Image:    app:1.0, app:latest\n",
+            "Digest:   sha256:abc\n",
+            "Platform: linux/arm64/v8\n",
+            "Layers:   2, 3.0 MiB\n",
+            "Ports:    5432/tcp, 8080/tcp\n",
+            "Volumes:  /var/lib/postgresql/data\n",
+            "\n",
+        ));
+
+        let image = Image {
+            config: None,
+            digest: None,
+            missing_layers: vec![MissingLayer { position: 1, name: "f00/layer.tar".to_string() }],
+            ..image
+        };
+        let mut out = Vec::new();
+        write_header(&mut out, &image, &RenderOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "Image:    app:1.0, app:latest\n",
+            "Layers:   2, 3.0 MiB\n",
+            "Missing:  2 of 3 (f00/layer.tar); not in the archive, so the tree lacks its changes\n",
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn test_volume_markers() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("data/seed.sql", 0o644, 0, 0, false, None, None);
+        root.put_file("var/lib/data/PG_VERSION", 0o644, 0, 0, false, None, None);
+        root.put_file("var/log/data", 0o644, 0, 0, false, None, None);
+
+        let volumes = vec!["data".to_string(), "var/lib/data".to_string(), "var/log/data".to_string()];
+        let options = RenderOptions::builder().volumes(volumes).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "├── data [VOLUME]\n",
+            "│   └── seed.sql\n",
+            "└── var\n",
+            "    ├── lib\n",
+            "    │   └── data [VOLUME]\n",
+            "    │       └── PG_VERSION\n",
+            "    └── log\n",
+            "        └── data\n",
+        ));
+    }
+
+    #[test]
+    fn test_long_columns() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/lib/libc.so", 0o755, 1000, 1000, false, None, None);
+        root.get_mut("usr/lib/libc.so").unwrap().metadata.size = 3 << 20;
+
+        let options = RenderOptions::builder().long(true).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "drwxr-xr-x       0:0       - ├── etc\n",
+            "-rw-r--r--       0:0     0 B │   └── hosts\n",
+            "drwxr-xr-x       0:0       - └── usr\n",
+            "drwxr-xr-x       0:0       -     └── lib\n",
+            "-rwxr-xr-x 1000:1000 3.0 MiB         └── libc.so\n",
+        ));
+    }
+
+    #[test]
+    fn test_layer_separators() {
+        let (base, app): (Arc<str>, Arc<str>) = ("a1b2c3d".into(), "e4f5a6b".into());
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.ensure_path("bin", 0o755, 0, 0, Some(&base));
+        root.ensure_path("srv", 0o755, 0, 0, Some(&app));
+        root.number_layers(&[base.clone(), app.clone()]);
+
+        let details = vec![
+            LayerDetail { command: "ADD rootfs /".to_string(), size: 2048 },
+            LayerDetail { command: "COPY app /srv".to_string(), size: 512 },
+        ];
+        let separator = SeparatorStyle { fill: Some('='), label: "#{index} {size}: {command}".to_string(), ..Default::default() };
+        let options = RenderOptions::builder()
+            .layers(true)
+            .layer_labels(vec![base.to_string(), app.to_string()])
+            .layer_details(details.clone())
+            .separator(separator)
+            .width(Some(30))
+            .build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "\n",
+            "== #0 2.0 KiB: ADD rootfs / ==\n",
+            "├── bin\n",
+            "\n",
+            "== #1 512 B: COPY app /srv ===\n",
+            "└── srv\n",
+        ));
+
+        let separator = SeparatorStyle { placement: SeparatorPlacement::Inline, ..Default::default() };
+        let options = RenderOptions::builder()
+            .layers(true)
+            .layer_labels(vec![base.to_string(), app.to_string()])
+            .separator(separator)
+            .build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "├── bin  ── Layer a1b2c3d\n",
+            "└── srv  ── Layer e4f5a6b\n",
+        ));
+
+        assert!(SeparatorStyle::check_label("{hash} {index} {command} {size}").is_ok());
+        assert!(SeparatorStyle::check_label("{digest}").is_err());
+    }
+
+    #[test]
+    fn test_empty_layers_before_hidden_layers() {
+        let layers: Vec<Arc<str>> = vec!["a1b2c3d".into(), "e4f5a6b".into(), "c7d8e9f".into()];
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("bin", 0o644, 0, 0, false, None, Some(&layers[0]));
+        // Everything the middle layer wrote is overwritten by the last one
+        root.put_file("srv", 0o644, 0, 0, false, None, Some(&layers[1]));
+        root.put_file("srv", 0o644, 0, 0, false, None, Some(&layers[2]));
+        root.number_layers(&layers);
+
+        let empty = |before, command: &str| EmptyLayer { before, command: command.to_string() };
+        let options = RenderOptions::builder()
+            .layers(true)
+            .layer_labels(layers.iter().map(|l| l.to_string()).collect())
+            .empty_layers(vec![empty(Some(1), "ENV A=1"), empty(Some(2), "WORKDIR /srv"), empty(None, "CMD sh")])
+            .separator(SeparatorStyle { placement: SeparatorPlacement::Inline, ..Default::default() })
+            .build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "├── bin  ── Layer a1b2c3d\n",
+            "└── srv  ── Layer (empty) ENV A=1 ── Layer (empty) WORKDIR /srv ── Layer c7d8e9f\n",
+            "\n─────────────────── Layer (empty) CMD sh ───────────────────\n",
+        ));
+    }
+
+    #[test]
+    fn test_blame_index_with_identical_layers() {
+        let layers: Vec<Arc<str>> = vec!["a1b2c3d".into(), "a1b2c3d".into()];
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("a", 0o644, 0, 0, false, None, Some(&layers[0]));
+        root.put_file("b", 0o644, 0, 0, false, None, Some(&layers[1]));
+        root.number_layers(&layers);
+
+        let options = RenderOptions::builder()
+            .blame(Some(BlameStyle::Index))
+            .layer_labels(layers.iter().map(|l| l.to_string()).collect())
+            .build();
+        assert_eq!(render_to_string(&root, &options), "#0 ├── a\n#1 └── b\n");
+    }
+
+    #[test]
+    fn test_full_paths() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/bin/sh", 0o755, 0, 0, true, Some("busybox".to_string()), None);
+
+        let options = RenderOptions::builder().full_paths(true).limit(Some(4)).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "/etc\n",
+            "/etc/hosts\n",
+            "/usr\n",
+            "/usr/bin\n",
+            "\n… stopped after 4 of 5 entries (raise --limit, or 0 for no limit)\n",
+        ));
+    }
+
+    #[test]
+    fn test_sort_orders() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        let entries = [("a.txt", 30, 3, Some(2)), ("b.log", 10, 1, Some(0)), ("c", 0, 2, Some(1)), ("d/e.bin", 20, 4, None)];
+        for (path, size, mtime, layer_index) in entries {
+            root.put_file(path, 0o644, 0, 0, false, None, None);
+            let meta = &mut root.get_mut(path).unwrap().metadata;
+            (meta.size, meta.mtime, meta.layer_index) = (size, mtime, layer_index);
+        }
+        let names = |options: RenderOptions| {
+            let children = sorted_children(&root, &options);
+            children.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>().join(" ")
+        };
+        let sorted = |key| RenderOptions::builder().sort(key);
+        assert_eq!(names(sorted(SortKey::Name).build()), "a.txt b.log c d");
+        assert_eq!(names(sorted(SortKey::Size).build()), "a.txt d b.log c");
+        assert_eq!(names(sorted(SortKey::Mtime).build()), "a.txt c b.log d");
+        assert_eq!(names(sorted(SortKey::Extension).build()), "c d b.log a.txt");
+        // By layer position, not label: entries of no known layer go last
+        assert_eq!(names(sorted(SortKey::Layer).build()), "b.log c a.txt d");
+        assert_eq!(names(sorted(SortKey::Size).reverse(true).build()), "c b.log d a.txt");
+        assert_eq!(names(sorted(SortKey::Size).dirs_first(true).build()), "d a.txt b.log c");
+    }
+
+    #[test]
+    fn test_compare_names() {
+        let sort = |names: &[&str], natural, ignore_case| {
+            let mut names = names.to_vec();
+            names.sort_by(|a, b| compare_names(a, b, natural, ignore_case));
+            names.join(" ")
+        };
+        let names = ["file10", "File2", "file2", "file1", "file01", "a"];
+        assert_eq!(sort(&names, false, false), "File2 a file01 file1 file10 file2");
+        assert_eq!(sort(&names, true, false), "File2 a file01 file1 file2 file10");
+        assert_eq!(sort(&names, false, true), "a file01 file1 file10 File2 file2");
+        assert_eq!(sort(&names, true, true), "a file01 file1 File2 file2 file10");
+        assert_eq!(sort(&["v1.10.2", "v1.9.12", "v1.9.2"], true, false), "v1.9.2 v1.9.12 v1.10.2");
+    }
+
+    #[test]
+    fn test_ascii_charset() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/bin/sh", 0o755, 0, 0, true, Some("busybox".to_string()), None);
+
+        let options = RenderOptions::builder().charset(Charset::Ascii).limit(Some(4)).build();
+        assert_eq!(render_to_string(&root, &options), concat!(
+            "|-- etc\n",
+            "|   |-- hosts\n",
+            "|   \\-- passwd\n",
+            "\\-- usr\n",
+            "\n... stopped after 4 of 6 entries (raise --limit, or 0 for no limit)\n",
+        ));
+    }
+}
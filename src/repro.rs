@@ -0,0 +1,378 @@
+//! Reproducibility check for `--repro-check`
+//!
+//! Two builds of the same Dockerfile only produce identical layers when
+//! nothing in them depends on when or where the build ran. This flags the
+//! usual offenders: file timestamps from the build itself (no
+//! `SOURCE_DATE_EPOCH` clamping), files embedding the build date or the build
+//! container's hostname, and leftovers such as shell history and logs.
+
+use anyhow::Result;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::config::ImageConfig;
+use crate::events::{self, EntryKind, EventSink, LayerEntry, LayerEvent, ReadOptions};
+use crate::layers;
+use crate::utils;
+
+/// Files larger than this aren't searched for build dates and hostnames
+const MAX_CONTENT_SIZE: u64 = 1024 * 1024;
+
+/// Hostname BuildKit gives every build container
+const BUILDKIT_HOSTNAME: &str = "buildkitsandbox";
+
+/// Seconds before the first layer's timestamp that count as its build
+const FIRST_STEP_WINDOW: i64 = 3600;
+
+const HISTORY_FILES: &[&str] = &[
+    ".bash_history", ".ash_history", ".sh_history", ".zsh_history", ".python_history",
+    ".node_repl_history", ".mysql_history", ".psql_history", ".lesshst", ".viminfo", ".wget-hsts",
+];
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Kinds of reproducibility issues, in report order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Timestamps,
+    BuildDate,
+    Hostname,
+    Artifact,
+}
+
+impl Category {
+    pub fn title(&self) -> &'static str {
+        match self {
+            Category::Timestamps => "timestamps from the build",
+            Category::BuildDate => "files embedding the build date",
+            Category::Hostname => "files embedding the build hostname",
+            Category::Artifact => "build artifacts",
+        }
+    }
+}
+
+/// One reproducibility issue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub category: Category,
+    pub layer: String,
+    /// Path inside the layer, without a leading "/" (None for layer-wide issues)
+    pub path: Option<String>,
+    pub detail: String,
+}
+
+/// What varies between builds of one layer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct LayerBuild {
+    /// When the build step ran (end excluded), if the history shows it ran
+    /// at a real time
+    window: Option<(i64, i64)>,
+    /// Strings that change from build to build, e.g. the build date
+    needles: Vec<(Category, String)>,
+}
+
+/// Scan every layer of an archive and fail if anything varies between builds
+pub fn run(archive_path: &Path) -> Result<()> {
+    let layer_set = archive::open_archive(archive_path)?;
+    let labels: Vec<String> = layer_set.layers.iter().map(|l| l.label()).collect();
+    let mut sink = ReproSink::new(layer_builds(&labels, layer_set.config.as_ref()));
+    let count = layer_set.layers.len();
+    for (i, layer) in layer_set.layers.iter().enumerate() {
+        events::read_layer(layer, i, count, ReadOptions::default(), &mut [&mut sink], None)?;
+    }
+
+    let findings = sink.into_findings();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_report(&mut out, &findings)?;
+    out.flush()?;
+    if !findings.is_empty() {
+        anyhow::bail!("{} reproducibility issue(s) found", findings.len());
+    }
+    Ok(())
+}
+
+/// Build windows and volatile strings for each filesystem layer
+///
+/// A step runs between the previous history entry's timestamp and its own.
+/// With `SOURCE_DATE_EPOCH` every entry carries the same timestamp, so the
+/// window is empty and nothing time-dependent is looked for.
+fn layer_builds(labels: &[String], config: Option<&ImageConfig>) -> Vec<LayerBuild> {
+    let mut builds = vec![LayerBuild::default(); labels.len()];
+    let Some(config) = config else {
+        return builds;
+    };
+
+    let mut hostnames = vec![BUILDKIT_HOSTNAME.to_string()];
+    hostnames.extend(config.config.hostname.clone().filter(|h| !h.is_empty()));
+
+    let mut previous = None;
+    for row in layers::align_history(labels, &config.history) {
+        let created = row.created.as_deref().and_then(utils::parse_timestamp);
+        if let Some(build) = row.index.and_then(|i| builds.get_mut(i)) {
+            build.window = match (previous, created) {
+                (Some(start), Some(end)) if end > start => Some((start, end)),
+                (None, Some(end)) => Some((end - FIRST_STEP_WINDOW, end)),
+                _ => None,
+            };
+            if let Some((_, end)) = build.window {
+                build.needles.extend(date_strings(end).into_iter().map(|s| (Category::BuildDate, s)));
+            }
+            build.needles.extend(hostnames.iter().map(|h| (Category::Hostname, h.clone())));
+        }
+        previous = created.or(previous);
+    }
+    builds
+}
+
+/// The date of `secs` as written by `date -I` and C's `__DATE__`
+fn date_strings(secs: i64) -> Vec<String> {
+    let (year, month, day) = utils::civil_date(secs);
+    vec![
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!("{} {:>2} {}", MONTHS[month as usize - 1], day, year),
+    ]
+}
+
+/// Files whose contents differ on every build
+fn artifact(entry: &LayerEntry) -> Option<&'static str> {
+    let basename = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+    if HISTORY_FILES.contains(&basename) {
+        return Some("shell history");
+    }
+    if entry.size == 0 {
+        return None;
+    }
+    match entry.path.as_str() {
+        "etc/machine-id" | "var/lib/dbus/machine-id" => Some("machine id generated at build time"),
+        "var/lib/systemd/random-seed" => Some("random seed"),
+        "var/cache/ldconfig/aux-cache" => Some("ldconfig cache (records inode numbers and mtimes)"),
+        path if path.starts_with("var/log/") => Some("log file"),
+        _ => None,
+    }
+}
+
+/// Entries with mtimes inside a layer's build window
+#[derive(Default)]
+struct Stamped {
+    count: usize,
+    first: i64,
+    last: i64,
+}
+
+/// Collects reproducibility findings from layer events
+struct ReproSink {
+    builds: Vec<LayerBuild>,
+    layer: String,
+    index: usize,
+    stamped: Stamped,
+    findings: Vec<Finding>,
+}
+
+impl ReproSink {
+    fn new(builds: Vec<LayerBuild>) -> Self {
+        ReproSink { builds, layer: String::new(), index: 0, stamped: Stamped::default(), findings: Vec::new() }
+    }
+
+    fn build(&self) -> Option<&LayerBuild> {
+        self.builds.get(self.index)
+    }
+
+    fn push(&mut self, category: Category, path: Option<&str>, detail: String) {
+        self.findings.push(Finding {
+            category,
+            layer: self.layer.clone(),
+            path: path.map(|p| p.to_string()),
+            detail,
+        });
+    }
+
+    /// Findings sorted by category; layers and paths keep archive order
+    fn into_findings(self) -> Vec<Finding> {
+        let mut findings = self.findings;
+        findings.sort_by_key(|f| f.category);
+        findings
+    }
+}
+
+impl EventSink for ReproSink {
+    fn wants_content(&self, entry: &LayerEntry) -> bool {
+        entry.size <= MAX_CONTENT_SIZE
+            && self.build().is_some_and(|b| !b.needles.is_empty())
+            && artifact(entry).is_none()
+    }
+
+    fn handle(&mut self, event: &LayerEvent) -> Result<()> {
+        match event {
+            LayerEvent::LayerStarted { index, layer, .. } => {
+                self.layer = layer.label();
+                self.index = *index;
+                self.stamped = Stamped::default();
+            }
+            LayerEvent::EntryAdded { entry, content } => {
+                let window = self.build().and_then(|b| b.window);
+                let mtime = entry.mtime as i64;
+                // Clamped builds stamp entries with exactly the step's own time
+                if window.is_some_and(|(start, end)| (start..end).contains(&mtime)) {
+                    let stamped = &mut self.stamped;
+                    stamped.first = if stamped.count == 0 { mtime } else { stamped.first.min(mtime) };
+                    stamped.last = stamped.last.max(mtime);
+                    stamped.count += 1;
+                }
+
+                if entry.kind != EntryKind::File {
+                    return Ok(());
+                }
+                if let Some(detail) = artifact(entry) {
+                    self.push(Category::Artifact, Some(&entry.path), detail.to_string());
+                } else if let Some(content) = content {
+                    let found = self.build().into_iter()
+                        .flat_map(|b| b.needles.iter())
+                        .find(|(_, needle)| content.windows(needle.len()).any(|w| w == needle.as_bytes()))
+                        .cloned();
+                    if let Some((category, needle)) = found {
+                        self.push(category, Some(&entry.path), format!("contains \"{}\"", needle));
+                    }
+                }
+            }
+            LayerEvent::LayerFinished { .. } if self.stamped.count > 0 => {
+                let Stamped { count, first, last } = self.stamped;
+                let noun = if count == 1 { "entry has an mtime" } else { "entries have mtimes" };
+                let detail = format!("{} {} from the build ({} to {}); set SOURCE_DATE_EPOCH and rewrite timestamps",
+                    count, noun, utils::format_timestamp(first), utils::format_timestamp(last));
+                self.push(Category::Timestamps, None, detail);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn write_report<W: Write>(out: &mut W, findings: &[Finding]) -> io::Result<()> {
+    if findings.is_empty() {
+        return writeln!(out, "No reproducibility issues found");
+    }
+
+    let mut current = None;
+    for finding in findings {
+        if current != Some(finding.category) {
+            if current.is_some() {
+                writeln!(out)?;
+            }
+            let count = findings.iter().filter(|f| f.category == finding.category).count();
+            writeln!(out, "{} ({}):", finding.category.title(), count)?;
+            current = Some(finding.category);
+        }
+        match &finding.path {
+            Some(path) => writeln!(out, "  [{}] /{}: {}", finding.layer, path, finding.detail)?,
+            None => writeln!(out, "  [{}] {}", finding.layer, finding.detail)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Blob, Compression, Layer};
+    use crate::config::HistoryEntry;
+    use std::path::PathBuf;
+
+    fn history(created: &str, empty_layer: bool) -> HistoryEntry {
+        HistoryEntry {
+            created: Some(created.to_string()),
+            created_by: None,
+            comment: None,
+            empty_layer,
+        }
+    }
+
+    fn file(path: &str, mtime: u64, size: u64) -> LayerEntry {
+        LayerEntry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime,
+            size,
+            xattrs: Default::default(),
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_layer_builds() {
+        let labels = vec!["aaaaaaa".to_string(), "bbbbbbb".to_string(), "ccccccc".to_string()];
+        let config = ImageConfig {
+            history: vec![
+                history("2024-01-02T03:00:00Z", false),
+                history("2024-03-04T10:00:00Z", true),
+                history("2024-03-04T10:05:00Z", false),
+                // Clamped to the previous step's time
+                history("2024-03-04T10:05:00Z", false),
+            ],
+            ..Default::default()
+        };
+        let builds = layer_builds(&labels, Some(&config));
+        let t = |s| utils::parse_timestamp(s).unwrap();
+        assert_eq!(builds[0].window, Some((t("2024-01-02T02:00:00Z"), t("2024-01-02T03:00:00Z"))));
+        assert_eq!(builds[1].window, Some((t("2024-03-04T10:00:00Z"), t("2024-03-04T10:05:00Z"))));
+        assert_eq!(builds[1].needles, vec![
+            (Category::BuildDate, "2024-03-04".to_string()),
+            (Category::BuildDate, "Mar  4 2024".to_string()),
+            (Category::Hostname, BUILDKIT_HOSTNAME.to_string()),
+        ]);
+        assert_eq!(builds[2].window, None);
+        assert_eq!(builds[2].needles, vec![(Category::Hostname, BUILDKIT_HOSTNAME.to_string())]);
+    }
+
+    #[test]
+    fn test_repro_sink() {
+        let end = utils::parse_timestamp("2024-03-04T10:05:00Z").unwrap();
+        let mut sink = ReproSink::new(vec![LayerBuild {
+            window: Some((end - 300, end)),
+            needles: date_strings(end).into_iter().map(|s| (Category::BuildDate, s)).collect(),
+        }]);
+        let layer = Layer {
+            name: "aaaaaaa/layer.tar".to_string(),
+            blob: Blob::File(PathBuf::new()),
+            diff_id: None,
+            compression: Compression::None,
+        };
+        sink.handle(&LayerEvent::LayerStarted { index: 0, count: 1, layer: &layer }).unwrap();
+        let entries = [
+            (file("app/version.txt", end as u64 - 10, 30), Some(&b"built on 2024-03-04 by ci"[..])),
+            (file("app/main", 1_600_000_000, 4), Some(&b"main"[..])),
+            (file("root/.bash_history", end as u64, 20), None),
+        ];
+        for (entry, content) in &entries {
+            sink.handle(&LayerEvent::EntryAdded { entry, content: *content }).unwrap();
+        }
+        sink.handle(&LayerEvent::LayerFinished { index: 0 }).unwrap();
+
+        let summary: Vec<(Category, Option<String>)> = sink.into_findings().into_iter()
+            .map(|f| (f.category, f.path))
+            .collect();
+        assert_eq!(summary, vec![
+            (Category::Timestamps, None),
+            (Category::BuildDate, Some("app/version.txt".to_string())),
+            (Category::Artifact, Some("root/.bash_history".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let findings = vec![Finding {
+            category: Category::Artifact,
+            layer: "aaaaaaa".to_string(),
+            path: Some("root/.bash_history".to_string()),
+            detail: "shell history".to_string(),
+        }];
+        let mut out = Vec::new();
+        write_report(&mut out, &findings).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "build artifacts (1):\n  [aaaaaaa] /root/.bash_history: shell history\n");
+    }
+}
@@ -0,0 +1,451 @@
+//! Reading installed packages from an rpm database without librpm
+//!
+//! Supports the SQLite backend (`rpmdb.sqlite`, RHEL 9, Fedora 33+) and the
+//! NDB backend (`Packages.db`, SUSE). Both store one rpm header blob per
+//! package; the legacy Berkeley DB backend (`Packages`) isn't readable here.
+
+use anyhow::{bail, Context, Result};
+
+/// Name, version, and architecture from one rpm header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpmPackage {
+    pub name: String,
+    /// `[epoch:]version-release`
+    pub version: String,
+    pub arch: String,
+    /// Absolute paths of the files and directories the package installed
+    pub files: Vec<String>,
+}
+
+const TAG_NAME: u32 = 1000;
+const TAG_VERSION: u32 = 1001;
+const TAG_RELEASE: u32 = 1002;
+const TAG_EPOCH: u32 = 1003;
+const TAG_ARCH: u32 = 1022;
+const TAG_DIRINDEXES: u32 = 1116;
+const TAG_BASENAMES: u32 = 1117;
+const TAG_DIRNAMES: u32 = 1118;
+
+const TYPE_INT32: u32 = 4;
+const TYPE_STRING: u32 = 6;
+const TYPE_STRING_ARRAY: u32 = 8;
+const TYPE_I18NSTRING: u32 = 9;
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const NDB_MAGIC: &[u8] = b"RpmP";
+const NDB_BLOB_MAGIC: &[u8] = b"BlbS";
+
+/// Packages in an rpm database file, detected by its magic bytes
+pub fn read_packages(db: &[u8]) -> Result<Vec<RpmPackage>> {
+    let blobs = if db.starts_with(SQLITE_MAGIC) {
+        sqlite_table_blobs(db, "Packages")?
+    } else if db.starts_with(NDB_MAGIC) {
+        ndb_blobs(db)
+    } else {
+        bail!("Unsupported rpm database format (only sqlite and ndb are readable)");
+    };
+
+    let mut packages = Vec::new();
+    for blob in blobs {
+        // gpg-pubkey entries are imported signing keys, not packages
+        match parse_header(&blob)? {
+            Some(package) if package.name != "gpg-pubkey" => packages.push(package),
+            _ => {}
+        }
+    }
+    Ok(packages)
+}
+
+/// Decode the tags we need from an rpm header blob (`il`, `dl`, index, store)
+pub fn parse_header(blob: &[u8]) -> Result<Option<RpmPackage>> {
+    let count = be32(blob, 0).context("Truncated rpm header")? as usize;
+    let data_len = be32(blob, 4).context("Truncated rpm header")? as usize;
+    let store_start = 8 + count.checked_mul(16).context("Corrupt rpm header")?;
+    let store = blob.get(store_start..store_start + data_len).context("Truncated rpm header store")?;
+
+    let (mut name, mut version, mut release, mut epoch, mut arch) = (None, None, None, None, None);
+    let (mut dir_indexes, mut basenames, mut dirnames) = (Vec::new(), Vec::new(), Vec::new());
+    for i in 0..count {
+        let entry = 8 + i * 16;
+        let fields = (be32(blob, entry), be32(blob, entry + 4), be32(blob, entry + 8), be32(blob, entry + 12));
+        let (Some(tag), Some(kind), Some(offset), Some(values)) = fields else {
+            bail!("Truncated rpm header index");
+        };
+        let offset = offset as usize;
+        match (tag, kind) {
+            (TAG_EPOCH, TYPE_INT32) => epoch = be32(store, offset),
+            (TAG_DIRINDEXES, TYPE_INT32) => {
+                dir_indexes = (0..values as usize).map_while(|n| be32(store, offset + n * 4)).collect();
+            }
+            (TAG_BASENAMES, TYPE_STRING_ARRAY) => basenames = string_array(store, offset, values),
+            (TAG_DIRNAMES, TYPE_STRING_ARRAY) => dirnames = string_array(store, offset, values),
+            (TAG_NAME | TAG_VERSION | TAG_RELEASE | TAG_ARCH, TYPE_STRING | TYPE_I18NSTRING) => {
+                let value = store.get(offset..)
+                    .and_then(|s| s.split(|&b| b == 0).next())
+                    .map(|s| String::from_utf8_lossy(s).to_string());
+                match tag {
+                    TAG_NAME => name = value,
+                    TAG_VERSION => version = value,
+                    TAG_RELEASE => release = value,
+                    _ => arch = value,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let mut full_version = version.unwrap_or_default();
+    if let Some(release) = release {
+        full_version = format!("{}-{}", full_version, release);
+    }
+    if let Some(epoch) = epoch.filter(|&e| e != 0) {
+        full_version = format!("{}:{}", epoch, full_version);
+    }
+    let files = basenames.iter().zip(&dir_indexes)
+        .filter_map(|(base, &dir)| Some(format!("{}{}", dirnames.get(dir as usize)?, base)))
+        .collect();
+    Ok(Some(RpmPackage { name, version: full_version, arch: arch.unwrap_or_default(), files }))
+}
+
+/// `count` NUL-terminated strings starting at `offset`
+fn string_array(store: &[u8], offset: usize, count: u32) -> Vec<String> {
+    store.get(offset..).unwrap_or_default()
+        .split(|&b| b == 0)
+        .take(count as usize)
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect()
+}
+
+/// Header blobs in an NDB `Packages.db`, each stored contiguously after a
+/// `BlbS` blob header (magic, package index, generation, length)
+fn ndb_blobs(db: &[u8]) -> Vec<Vec<u8>> {
+    let mut blobs = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = db[pos..].windows(4).position(|w| w == NDB_BLOB_MAGIC) {
+        let start = pos + found;
+        pos = start + 4;
+        let Some(len) = le32(db, start + 12) else { break };
+        if let Some(blob) = db.get(start + 16..start + 16 + len as usize) {
+            blobs.push(blob.to_vec());
+            pos = start + 16 + len as usize;
+        }
+    }
+    blobs
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// NDB blob headers are little-endian, unlike the rpm headers inside them
+fn le32(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Every blob column value of a SQLite table, read straight from the b-tree
+fn sqlite_table_blobs(db: &[u8], table: &str) -> Result<Vec<Vec<u8>>> {
+    let file = SqliteFile::new(db)?;
+
+    // The schema table lives at page 1: (type, name, tbl_name, rootpage, sql)
+    let mut root = None;
+    file.walk_table(1, 0, &mut |record| {
+        let values = decode_record(record)?;
+        if let [Value::Text(kind), Value::Text(name), _, Value::Int(page), ..] = values.as_slice() {
+            if kind == "table" && name == table {
+                root = Some(*page as u32);
+            }
+        }
+        Ok(())
+    })?;
+    let root = root.with_context(|| format!("No {} table in rpm database", table))?;
+
+    let mut blobs = Vec::new();
+    file.walk_table(root, 0, &mut |record| {
+        for value in decode_record(record)? {
+            if let Value::Blob(blob) = value {
+                blobs.push(blob);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(blobs)
+}
+
+/// A SQLite database image
+struct SqliteFile<'a> {
+    db: &'a [u8],
+    page_size: usize,
+    /// Page size minus the reserved bytes at the end of each page
+    usable: usize,
+}
+
+/// Interior pages nest at most this deep in practice; guards against cycles
+const SQLITE_MAX_DEPTH: usize = 32;
+
+impl<'a> SqliteFile<'a> {
+    fn new(db: &'a [u8]) -> Result<Self> {
+        if db.len() < 100 {
+            bail!("Truncated sqlite database");
+        }
+        let page_size = match u16::from_be_bytes([db[16], db[17]]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if page_size < 512 {
+            bail!("Invalid sqlite page size {}", page_size);
+        }
+        Ok(SqliteFile { db, page_size, usable: page_size - db[20] as usize })
+    }
+
+    fn page(&self, number: u32) -> Result<&'a [u8]> {
+        let start = (number as usize).checked_sub(1).context("Invalid sqlite page number 0")? * self.page_size;
+        self.db.get(start..start + self.page_size)
+            .with_context(|| format!("sqlite page {} is past the end of the file", number))
+    }
+
+    /// Call `visit` with the payload of every row in the table b-tree at `root`
+    fn walk_table(&self, root: u32, depth: usize, visit: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        if depth > SQLITE_MAX_DEPTH {
+            bail!("sqlite b-tree is too deep");
+        }
+        let page = self.page(root)?;
+        // Page 1 starts with the 100-byte file header
+        let header = if root == 1 { 100 } else { 0 };
+        let kind = page[header];
+        let cell_count = u16::from_be_bytes([page[header + 3], page[header + 4]]) as usize;
+        let (pointers, is_leaf) = match kind {
+            0x0d => (header + 8, true),
+            0x05 => (header + 12, false),
+            _ => bail!("Unexpected sqlite page type {:#04x} in table b-tree", kind),
+        };
+
+        for i in 0..cell_count {
+            let at = pointers + i * 2;
+            let cell = u16::from_be_bytes([page[at], page[at + 1]]) as usize;
+            let cell = page.get(cell..).context("Corrupt sqlite cell pointer")?;
+            if is_leaf {
+                let (payload_len, n) = varint(cell);
+                let (_rowid, m) = varint(&cell[n..]);
+                let payload = self.payload(&cell[n + m..], payload_len as usize)?;
+                visit(&payload)?;
+            } else {
+                let child = be32(cell, 0).context("Corrupt sqlite interior cell")?;
+                self.walk_table(child, depth + 1, visit)?;
+            }
+        }
+        if !is_leaf {
+            let right = be32(page, header + 8).context("Corrupt sqlite interior page")?;
+            self.walk_table(right, depth + 1, visit)?;
+        }
+        Ok(())
+    }
+
+    /// Reassemble a cell payload that may continue on overflow pages
+    fn payload(&self, cell: &[u8], len: usize) -> Result<Vec<u8>> {
+        let max_local = self.usable - 35;
+        let local = if len <= max_local {
+            len
+        } else {
+            let min_local = (self.usable - 12) * 32 / 255 - 23;
+            let k = min_local + (len - min_local) % (self.usable - 4);
+            if k <= max_local { k } else { min_local }
+        };
+
+        let mut payload = cell.get(..local).context("Truncated sqlite cell")?.to_vec();
+        let mut next = if local < len { be32(cell, local).context("Truncated sqlite cell")? } else { 0 };
+        while payload.len() < len {
+            let page = self.page(next)?;
+            let take = (len - payload.len()).min(self.usable - 4);
+            payload.extend_from_slice(&page[4..4 + take]);
+            next = be32(page, 0).unwrap_or(0);
+            if next == 0 && payload.len() < len {
+                bail!("sqlite overflow chain ends early");
+            }
+        }
+        Ok(payload)
+    }
+}
+
+/// A column value in a SQLite record
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Int(i64),
+    Text(String),
+    Blob(Vec<u8>),
+    Other,
+}
+
+/// Split a record into its column values
+fn decode_record(record: &[u8]) -> Result<Vec<Value>> {
+    let (header_len, n) = varint(record);
+    let header = record.get(n..header_len as usize).context("Corrupt sqlite record header")?;
+    let mut body = record.get(header_len as usize..).context("Corrupt sqlite record")?;
+
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < header.len() {
+        let (serial, m) = varint(&header[pos..]);
+        pos += m;
+        let size = match serial {
+            0 | 8 | 9 => 0,
+            1..=4 => serial as usize,
+            5 => 6,
+            6 | 7 => 8,
+            n if n >= 12 => (n as usize - 12) / 2,
+            _ => bail!("Unsupported sqlite serial type {}", serial),
+        };
+        let bytes = body.get(..size).context("Truncated sqlite record")?;
+        body = &body[size..];
+        values.push(match serial {
+            0 => Value::Null,
+            1..=6 => {
+                // Big-endian two's complement, sign-extended from `size` bytes
+                let mut value = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+                for &b in bytes {
+                    value = (value << 8) | b as i64;
+                }
+                Value::Int(value)
+            }
+            8 => Value::Int(0),
+            9 => Value::Int(1),
+            n if n >= 12 && n % 2 == 0 => Value::Blob(bytes.to_vec()),
+            n if n >= 13 => Value::Text(String::from_utf8_lossy(bytes).to_string()),
+            _ => Value::Other,
+        });
+    }
+    Ok(values)
+}
+
+/// SQLite variable-length integer: returns the value and bytes consumed
+fn varint(bytes: &[u8]) -> (u64, usize) {
+    let mut value = 0u64;
+    for (i, &b) in bytes.iter().enumerate().take(9) {
+        if i == 8 {
+            return ((value << 8) | b as u64, 9);
+        }
+        value = (value << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            return (value, i + 1);
+        }
+    }
+    (value, bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a header blob with string tags, an optional epoch, and a file list
+    fn header_with_files(tags: &[(u32, &str)], epoch: Option<u32>, dirs: &[&str], files: &[(u32, &str)]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut store = Vec::new();
+        let mut count = 0u32;
+        if let Some(epoch) = epoch {
+            for v in [TAG_EPOCH, TYPE_INT32, store.len() as u32, 1] {
+                index.extend_from_slice(&v.to_be_bytes());
+            }
+            store.extend_from_slice(&epoch.to_be_bytes());
+            count += 1;
+        }
+        for (tag, value) in tags {
+            for v in [*tag, TYPE_STRING, store.len() as u32, 1] {
+                index.extend_from_slice(&v.to_be_bytes());
+            }
+            store.extend_from_slice(value.as_bytes());
+            store.push(0);
+            count += 1;
+        }
+        if !files.is_empty() {
+            // String arrays first: int32 data must stay 4-byte aligned
+            for (tag, values) in [(TAG_DIRNAMES, dirs.to_vec()), (TAG_BASENAMES, files.iter().map(|f| f.1).collect())] {
+                for v in [tag, TYPE_STRING_ARRAY, store.len() as u32, values.len() as u32] {
+                    index.extend_from_slice(&v.to_be_bytes());
+                }
+                for value in values {
+                    store.extend_from_slice(value.as_bytes());
+                    store.push(0);
+                }
+                count += 1;
+            }
+            while store.len() % 4 != 0 {
+                store.push(0);
+            }
+            for v in [TAG_DIRINDEXES, TYPE_INT32, store.len() as u32, files.len() as u32] {
+                index.extend_from_slice(&v.to_be_bytes());
+            }
+            for (dir, _) in files {
+                store.extend_from_slice(&dir.to_be_bytes());
+            }
+            count += 1;
+        }
+        let mut blob = count.to_be_bytes().to_vec();
+        blob.extend_from_slice(&(store.len() as u32).to_be_bytes());
+        blob.extend(index);
+        blob.extend(store);
+        blob
+    }
+
+    fn header(tags: &[(u32, &str)], epoch: Option<u32>) -> Vec<u8> {
+        header_with_files(tags, epoch, &[], &[])
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let blob = header(&[(TAG_NAME, "bash"), (TAG_VERSION, "5.1.8"), (TAG_RELEASE, "9.el9"),
+            (TAG_ARCH, "x86_64")], Some(1));
+        assert_eq!(parse_header(&blob).unwrap(), Some(RpmPackage {
+            name: "bash".to_string(),
+            version: "1:5.1.8-9.el9".to_string(),
+            arch: "x86_64".to_string(),
+            files: Vec::new(),
+        }));
+        assert!(parse_header(&blob[..12]).is_err());
+    }
+
+    #[test]
+    fn test_parse_header_files() {
+        let blob = header_with_files(&[(TAG_NAME, "bash")], None, &["/usr/bin/", "/etc/"],
+            &[(0, "bash"), (1, "bashrc"), (0, "sh")]);
+        let package = parse_header(&blob).unwrap().unwrap();
+        assert_eq!(package.files, vec!["/usr/bin/bash", "/etc/bashrc", "/usr/bin/sh"]);
+    }
+
+    #[test]
+    fn test_ndb_blobs() {
+        let blob = header(&[(TAG_NAME, "zlib"), (TAG_VERSION, "1.2.13"), (TAG_RELEASE, "1.1"),
+            (TAG_ARCH, "x86_64")], None);
+        let mut db = NDB_MAGIC.to_vec();
+        db.extend_from_slice(&[0; 12]);
+        db.extend_from_slice(NDB_BLOB_MAGIC);
+        db.extend_from_slice(&1u32.to_le_bytes());
+        db.extend_from_slice(&1u32.to_le_bytes());
+        db.extend_from_slice(&(blob.len() as u32).to_le_bytes());
+        db.extend_from_slice(&blob);
+        let packages = read_packages(&db).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].version, "1.2.13-1.1");
+    }
+
+    #[test]
+    fn test_decode_record() {
+        // Header: length 4, serial types 1 (int8), 19 (3-byte text), 16 (2-byte blob)
+        let record = [4, 1, 19, 16, 0xff, b'a', b'b', b'c', 0xde, 0xad];
+        assert_eq!(decode_record(&record).unwrap(), vec![
+            Value::Int(-1),
+            Value::Text("abc".to_string()),
+            Value::Blob(vec![0xde, 0xad]),
+        ]);
+        assert_eq!(varint(&[0x81, 0x00]), (128, 2));
+    }
+
+    #[test]
+    fn test_rejects_berkeley_db() {
+        let mut db = vec![0u8; 64];
+        db[12..16].copy_from_slice(&0x061561u32.to_le_bytes());
+        assert!(read_packages(&db).is_err());
+    }
+}
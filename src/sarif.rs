@@ -0,0 +1,189 @@
+//! SARIF 2.1.0 output for uploading findings to code-scanning dashboards
+//!
+//! Locations are paths inside the image, so dashboards show them relative to
+//! the image root rather than the repository.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const INFORMATION_URI: &str = "https://github.com/blark/contree";
+
+/// Severity of a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    #[cfg_attr(not(feature = "analyzers"), allow(dead_code))]
+    Error,
+    Warning,
+    Note,
+}
+
+/// A kind of finding a command can report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub id: String,
+    pub description: String,
+}
+
+/// One finding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub rule_id: String,
+    pub level: Level,
+    pub message: String,
+    /// Path inside the image; a leading "/" is dropped from the artifact URI
+    pub path: String,
+    pub line: Option<usize>,
+    /// Label of the layer holding the file
+    pub layer: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Log<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    version: &'static str,
+    runs: [Run<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct Run<'a> {
+    tool: Tool<'a>,
+    results: Vec<SarifResult<'a>>,
+}
+
+#[derive(Serialize)]
+struct Tool<'a> {
+    driver: Driver<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Driver<'a> {
+    name: &'static str,
+    version: &'static str,
+    information_uri: &'static str,
+    rules: Vec<ReportingDescriptor<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportingDescriptor<'a> {
+    id: &'a str,
+    short_description: Message<'a>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    text: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult<'a> {
+    rule_id: &'a str,
+    level: Level,
+    message: Message<'a>,
+    locations: [Location<'a>; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<Properties<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location<'a> {
+    physical_location: PhysicalLocation<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PhysicalLocation<'a> {
+    artifact_location: ArtifactLocation<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<Region>,
+}
+
+#[derive(Serialize)]
+struct ArtifactLocation<'a> {
+    uri: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    start_line: usize,
+}
+
+#[derive(Serialize)]
+struct Properties<'a> {
+    layer: &'a str,
+}
+
+/// Write a SARIF log with one run of contree
+pub fn write_log<W: Write>(out: &mut W, rules: &[Rule], findings: &[Finding]) -> Result<()> {
+    let log = Log {
+        schema: SCHEMA,
+        version: "2.1.0",
+        runs: [Run {
+            tool: Tool {
+                driver: Driver {
+                    name: "contree",
+                    version: env!("CARGO_PKG_VERSION"),
+                    information_uri: INFORMATION_URI,
+                    rules: rules.iter()
+                        .map(|r| ReportingDescriptor { id: &r.id, short_description: Message { text: &r.description } })
+                        .collect(),
+                },
+            },
+            results: findings.iter().map(|f| SarifResult {
+                rule_id: &f.rule_id,
+                level: f.level,
+                message: Message { text: &f.message },
+                locations: [Location {
+                    physical_location: PhysicalLocation {
+                        artifact_location: ArtifactLocation { uri: f.path.trim_start_matches('/') },
+                        region: f.line.map(|start_line| Region { start_line }),
+                    },
+                }],
+                properties: f.layer.as_deref().map(|layer| Properties { layer }),
+            }).collect(),
+        }],
+    };
+    serde_json::to_writer_pretty(&mut *out, &log)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_log() {
+        let rules = vec![Rule { id: "setuid".to_string(), description: "setuid/setgid binaries".to_string() }];
+        let findings = vec![Finding {
+            rule_id: "setuid".to_string(),
+            level: Level::Warning,
+            message: "setuid, mode 4755".to_string(),
+            path: "/usr/bin/su".to_string(),
+            line: Some(3),
+            layer: Some("aaaaaaa".to_string()),
+        }];
+        let mut out = Vec::new();
+        write_log(&mut out, &rules, &findings).unwrap();
+
+        let log: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "setuid");
+        let result = &run["results"][0];
+        assert_eq!(result["ruleId"], "setuid");
+        assert_eq!(result["level"], "warning");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "usr/bin/su");
+        assert_eq!(location["region"]["startLine"], 3);
+        assert_eq!(result["properties"]["layer"], "aaaaaaa");
+    }
+}
@@ -0,0 +1,311 @@
+//! User configuration file (`~/.config/contree/config.toml`)
+//!
+//! ```toml
+//! # Defaults for every profile; a profile's own values take precedence
+//! [defaults]
+//! tree.icons = "emoji"
+//! tree.theme = "gruvbox-dark"
+//!
+//! # Profile used when neither --profile nor $CONTREE_PROFILE is given
+//! # (defaults to "ci" when $CI is set, otherwise "local")
+//! default_profile = "local"
+//!
+//! [profiles.ci]
+//! tree.icons = "none"
+//! tree.color = "never"
+//! tree.charset = "ascii"
+//! diff.format = "json"
+//!
+//! [profiles.local]
+//! tree.auto-detect-input = true
+//! tree.limit = 5000
+//!
+//! # Extra detectors for `contree secrets`
+//! [[secrets.rules]]
+//! name = "internal-token"
+//! pattern = "itk_[a-z0-9]{32}"
+//! ```
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Parsed configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default)]
+    pub default_profile: Option<String>,
+
+    /// Defaults under every profile, mapping subcommand names to their defaults
+    #[serde(default)]
+    pub defaults: BTreeMap<String, CommandDefaults>,
+
+    /// Named profiles, each mapping subcommand names to their defaults
+    #[serde(default)]
+    pub profiles: BTreeMap<String, BTreeMap<String, CommandDefaults>>,
+
+    /// Detectors used by the `secrets` command
+    #[serde(default)]
+    #[cfg_attr(not(feature = "analyzers"), allow(dead_code))]
+    pub secrets: SecretsSettings,
+}
+
+/// Secret detector configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[cfg_attr(not(feature = "analyzers"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct SecretsSettings {
+    /// Additional regex detectors
+    #[serde(default)]
+    pub rules: Vec<SecretRule>,
+    /// Built-in detectors to turn off, by name
+    #[serde(default)]
+    pub disable: Vec<String>,
+    /// Minimum entropy (bits per character) for generic `key = value` secrets
+    #[serde(rename = "entropy-threshold")]
+    pub entropy_threshold: Option<f64>,
+}
+
+/// A user-defined secret detector
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "analyzers"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct SecretRule {
+    pub name: String,
+    /// Regex matched against file contents
+    pub pattern: String,
+    /// Regex the file's basename must match, if set
+    pub path: Option<String>,
+}
+
+/// Default option values for one subcommand
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandDefaults {
+    pub format: Option<String>,
+    pub icons: Option<String>,
+    /// Tree characters: unicode or ascii (tree only)
+    pub charset: Option<String>,
+    pub color: Option<String>,
+    /// Use the only archive in the current directory when none is given (tree only)
+    #[serde(rename = "auto-detect-input")]
+    pub auto_detect_input: Option<bool>,
+    /// Stop printing after this many entries (tree only; 0 for no limit)
+    pub limit: Option<usize>,
+    /// What entries are ordered by, as for --sort (tree only)
+    pub sort: Option<String>,
+    /// List directories first, as with --dirs-first (tree only)
+    #[serde(rename = "dirs-first")]
+    pub dirs_first: Option<bool>,
+    /// Order numbers in names by value, as with --natural-sort (tree only)
+    #[serde(rename = "natural-sort")]
+    pub natural_sort: Option<bool>,
+    /// Order names regardless of case, as with --ignore-case (tree only)
+    #[serde(rename = "ignore-case")]
+    pub ignore_case: Option<bool>,
+    /// Bundled theme name or JSON, as for --theme (tree only)
+    pub theme: Option<String>,
+    /// Theme file, as for --theme-file; relative paths are from the
+    /// configuration file's directory (tree only)
+    #[serde(rename = "theme-file")]
+    pub theme_file: Option<PathBuf>,
+}
+
+impl CommandDefaults {
+    /// These defaults, with `base` filling the ones not set
+    fn or(self, base: CommandDefaults) -> CommandDefaults {
+        CommandDefaults {
+            format: self.format.or(base.format),
+            icons: self.icons.or(base.icons),
+            charset: self.charset.or(base.charset),
+            color: self.color.or(base.color),
+            auto_detect_input: self.auto_detect_input.or(base.auto_detect_input),
+            limit: self.limit.or(base.limit),
+            sort: self.sort.or(base.sort),
+            dirs_first: self.dirs_first.or(base.dirs_first),
+            natural_sort: self.natural_sort.or(base.natural_sort),
+            ignore_case: self.ignore_case.or(base.ignore_case),
+            theme: self.theme.or(base.theme),
+            theme_file: self.theme_file.or(base.theme_file),
+        }
+    }
+}
+
+/// Location of the configuration file, honoring $XDG_CONFIG_HOME
+pub fn config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("contree").join("config.toml"))
+}
+
+impl Settings {
+    /// Load the user's configuration file; a missing file yields empty settings
+    pub fn load() -> Result<Self> {
+        match config_path() {
+            Some(path) if path.exists() => Self::from_file(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut settings =
+            Self::from_toml(&text).with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            let commands = settings.defaults.values_mut().chain(settings.profiles.values_mut().flat_map(|p| p.values_mut()));
+            for defaults in commands {
+                if let Some(file) = defaults.theme_file.as_mut() {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Parse settings from TOML text
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Resolve the defaults for `command` under the selected profile, over
+    /// those in `[defaults]`
+    ///
+    /// An explicitly requested profile must exist; the implicit default may not.
+    pub fn defaults_for(&self, profile: Option<&str>, command: &str) -> Result<CommandDefaults> {
+        let (name, explicit) = match profile {
+            Some(name) => (name.to_string(), true),
+            None => (self.implicit_profile(), false),
+        };
+        let base = self.defaults.get(command).cloned().unwrap_or_default();
+
+        let Some(commands) = self.profiles.get(&name) else {
+            if explicit {
+                let known: Vec<&str> = self.profiles.keys().map(|k| k.as_str()).collect();
+                bail!("Unknown profile '{}' (configured: {})", name,
+                    if known.is_empty() { "none".to_string() } else { known.join(", ") });
+            }
+            return Ok(base);
+        };
+
+        Ok(commands.get(command).cloned().unwrap_or_default().or(base))
+    }
+
+    fn implicit_profile(&self) -> String {
+        if let Some(name) = &self.default_profile {
+            return name.clone();
+        }
+        if std::env::var_os("CI").is_some_and(|v| !v.is_empty()) {
+            "ci".to_string()
+        } else {
+            "local".to_string()
+        }
+    }
+}
+
+/// Pick the command-line value (a flag or its CONTREE_* variable), then the configured
+/// default, then the built-in one, validating configured values against the accepted choices
+pub fn resolve(
+    cli: Option<String>,
+    configured: Option<String>,
+    key: &str,
+    allowed: &[&str],
+    fallback: &str,
+) -> Result<String> {
+    if let Some(value) = cli {
+        return Ok(value);
+    }
+    match configured {
+        Some(value) if allowed.contains(&value.as_str()) => Ok(value),
+        Some(value) => bail!("Invalid value '{}' for '{}' in configuration (expected one of: {})",
+            value, key, allowed.join(", ")),
+        None => Ok(fallback.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+        default_profile = "local"
+
+        [defaults]
+        tree.icons = "emoji"
+        tree.theme = "gruvbox-dark"
+
+        [profiles.ci]
+        tree.icons = "none"
+        diff.format = "json"
+
+        [profiles.local]
+        tree.icons = "nerd"
+        tree.auto-detect-input = true
+        tree.limit = 5000
+    "#;
+
+    #[test]
+    fn test_defaults_for_profile() {
+        let settings = Settings::from_toml(SAMPLE).unwrap();
+        assert_eq!(settings.defaults_for(Some("ci"), "diff").unwrap().format.as_deref(), Some("json"));
+        assert_eq!(settings.defaults_for(None, "tree").unwrap().icons.as_deref(), Some("nerd"));
+        assert!(settings.defaults_for(None, "diff").unwrap().format.is_none());
+        assert_eq!(settings.defaults_for(None, "tree").unwrap().auto_detect_input, Some(true));
+        assert_eq!(settings.defaults_for(None, "tree").unwrap().limit, Some(5000));
+    }
+
+    #[test]
+    fn test_defaults_under_every_profile() {
+        let settings = Settings::from_toml(SAMPLE).unwrap();
+        // The profile's own value wins over [defaults]
+        assert_eq!(settings.defaults_for(Some("ci"), "tree").unwrap().icons.as_deref(), Some("none"));
+        assert_eq!(settings.defaults_for(Some("ci"), "tree").unwrap().theme.as_deref(), Some("gruvbox-dark"));
+
+        let settings = Settings::from_toml("[defaults]\ntree.color = \"never\"").unwrap();
+        assert_eq!(settings.defaults_for(None, "tree").unwrap().color.as_deref(), Some("never"));
+        assert!(settings.defaults_for(Some("ci"), "tree").is_err());
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let settings = Settings::from_toml(SAMPLE).unwrap();
+        let err = settings.defaults_for(Some("prod"), "tree").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown profile 'prod' (configured: ci, local)");
+    }
+
+    #[test]
+    fn test_secrets_settings() {
+        let settings = Settings::from_toml(r#"
+            [secrets]
+            disable = ["env-file"]
+            entropy-threshold = 4.0
+
+            [[secrets.rules]]
+            name = "internal-token"
+            pattern = "itk_[a-z0-9]{32}"
+            path = "\\.conf$"
+        "#).unwrap();
+        assert_eq!(settings.secrets.disable, vec!["env-file"]);
+        assert_eq!(settings.secrets.entropy_threshold, Some(4.0));
+        assert_eq!(settings.secrets.rules[0].path.as_deref(), Some("\\.conf$"));
+        assert!(Settings::from_toml("").unwrap().secrets.rules.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        assert!(Settings::from_toml("[profiles.ci]\ntree.icon = \"none\"").is_err());
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let allowed = ["text", "json"];
+        assert_eq!(resolve(Some("text".into()), Some("json".into()), "diff.format", &allowed, "text").unwrap(), "text");
+        assert_eq!(resolve(None, Some("json".into()), "diff.format", &allowed, "text").unwrap(), "json");
+        assert_eq!(resolve(None, None, "diff.format", &allowed, "text").unwrap(), "text");
+        assert!(resolve(None, Some("yaml".into()), "diff.format", &allowed, "text").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Where an image's layers come from
+//!
+//! A [`LayerSource`] finds the manifest, config and layer blobs of one kind of
+//! input and hands them over as a [`LayerSet`]; merging only needs to open
+//! each layer's [`Blob`] for reading, wherever it lives.
+//! `docker save` tarballs ([`TarballSource`]) and unpacked image directories
+//! ([`DirectorySource`]) are built in, and [`for_path`] picks between them.
+//! Other inputs implement the trait and go through
+//! [`process_source`](crate::archive::process_source).
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::archive::{Blob, LayerSet};
+use crate::config;
+use crate::directory::DirectorySource;
+use crate::error::{self, ContreeError};
+use crate::manifest::{self, ManifestEntry};
+use crate::oci::{self, Platform};
+use crate::progress::TermProgress;
+use crate::tarball::TarballSource;
+
+/// An input holding the manifest, config and layer blobs of an image
+pub trait LayerSource {
+    /// Locate the layers and config of the image `select` picks, without
+    /// reading the layers
+    fn open_layers(&self, select: &ImageSelector, progress: &mut TermProgress) -> error::Result<LayerSet>;
+}
+
+/// Which image of a source to read
+#[derive(Debug, Clone, Default)]
+pub struct ImageSelector {
+    /// Read the image for this platform from the OCI image index instead of manifest.json
+    pub platform: Option<Platform>,
+    /// Read the manifest.json entry with this repo tag, or at this position (0 = first)
+    pub image: Option<String>,
+    /// Without `image`, ask this which of several manifest.json entries to read;
+    /// otherwise the first one is read
+    pub choose: Option<ImageChooser>,
+    /// Leave out layers the manifest lists but the source doesn't have,
+    /// instead of failing (see [`LayerSet::missing_layers`])
+    pub allow_missing_layers: bool,
+    /// Fail when the manifest and the image config disagree on how many
+    /// layers there are, instead of warning
+    pub strict: bool,
+}
+
+/// One image of an archive that holds several, as shown when choosing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSummary {
+    /// Repo tags from manifest.json; empty for an untagged image
+    pub tags: Vec<String>,
+    /// Platform from the image config, when it names one
+    pub platform: Option<Platform>,
+    pub layers: usize,
+}
+
+/// Picks one of several images, returning its position in the list
+type ChooseFn = dyn Fn(&[ImageSummary]) -> anyhow::Result<usize> + Send + Sync;
+
+/// Asks which of several images to read, by position in the list it is given
+#[derive(Clone)]
+pub struct ImageChooser(Arc<ChooseFn>);
+
+impl ImageChooser {
+    pub fn new(choose: impl Fn(&[ImageSummary]) -> anyhow::Result<usize> + Send + Sync + 'static) -> Self {
+        ImageChooser(Arc::new(choose))
+    }
+}
+
+impl fmt::Debug for ImageChooser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ImageChooser")
+    }
+}
+
+/// The source for `path`: an unpacked image if it is a directory, else a
+/// (possibly compressed) tarball
+pub fn for_path(path: &Path) -> Box<dyn LayerSource> {
+    if path.is_dir() {
+        Box::new(DirectorySource::new(path))
+    } else {
+        Box::new(TarballSource::new(path))
+    }
+}
+
+/// Blobs of a source by name, plus the Docker manifest
+#[derive(Default)]
+pub(crate) struct Contents {
+    pub manifest: Option<Vec<u8>>,
+    pub blobs: HashMap<String, Blob>,
+    /// Size of every regular file, kept or not
+    pub sizes: HashMap<String, u64>,
+}
+
+/// The manifest entry of the image `select` picks: one of those in
+/// manifest.json, or the one for its platform under the OCI index
+pub(crate) fn select_image(archive_path: &Path, contents: &Contents, select: &ImageSelector) -> error::Result<ManifestEntry> {
+    let blobs = &contents.blobs;
+    if contents.manifest.is_none() && !contents.sizes.contains_key("index.json") {
+        return Err(ContreeError::UnsupportedFormat { path: archive_path.to_path_buf() });
+    }
+    let missing = |name: &str| ContreeError::ManifestMissing { name: name.to_string() };
+    let invalid = |name: &str| {
+        let name = name.to_string();
+        move |err| ContreeError::or_else(err, |source| ContreeError::InvalidManifest { name, source })
+    };
+    match &select.platform {
+        Some(platform) => {
+            let index = blobs.get("index.json").ok_or_else(|| missing("index.json"))?;
+            let index_bytes = index.read_all().map_err(invalid("index.json"))?;
+            oci::select_platform(&index_bytes, platform, |name| {
+                let blob = blobs.get(name).with_context(|| format!("Blob {} not found in archive", name))?;
+                blob.read_all().with_context(|| format!("Failed to read {}", name))
+            }).map_err(invalid("index.json"))
+        }
+        None => {
+            let manifest_bytes = contents.manifest.as_ref().ok_or_else(|| missing("manifest.json"))?;
+            let mut entries = manifest::parse_manifests(manifest_bytes).map_err(invalid("manifest.json"))?;
+            let index = pick_entry(&entries, contents, select)?;
+            Ok(entries.swap_remove(index))
+        }
+    }
+}
+
+/// Position in manifest.json of the image `select` asks for: the entry with
+/// its tag or at its position, else the one its chooser picks when there are
+/// several, else the first
+fn pick_entry(entries: &[ManifestEntry], contents: &Contents, select: &ImageSelector) -> error::Result<usize> {
+    if let Some(image) = &select.image {
+        let tagged = entries.iter().position(|entry| entry.repo_tags.iter().flatten().any(|tag| tag == image));
+        return tagged.or_else(|| image.parse().ok().filter(|&index| index < entries.len())).ok_or_else(|| {
+            let available = entries.iter().enumerate().map(|(index, entry)| match &entry.repo_tags {
+                Some(tags) if !tags.is_empty() => tags.join(", "),
+                _ => index.to_string(),
+            });
+            ContreeError::ImageNotFound { image: image.clone(), available: available.collect() }
+        });
+    }
+    match &select.choose {
+        Some(ImageChooser(choose)) if entries.len() > 1 => {
+            let summaries: Vec<ImageSummary> = entries.iter().map(|entry| summarize(entry, contents)).collect();
+            let index = choose(&summaries)?;
+            if index >= entries.len() {
+                return Err(anyhow::anyhow!("No image {} among the {} in the archive", index, entries.len()).into());
+            }
+            Ok(index)
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Tags, platform and layer count of a manifest entry, reading the platform
+/// from its config when the config can be read
+fn summarize(entry: &ManifestEntry, contents: &Contents) -> ImageSummary {
+    let config = entry.config.as_ref()
+        .and_then(|name| contents.blobs.get(name))
+        .and_then(|blob| blob.read_all().ok())
+        .and_then(|bytes| config::parse_config(&bytes).ok());
+    let platform = config.and_then(|config| {
+        Some(Platform { os: config.os?, architecture: config.architecture?, variant: config.variant })
+    });
+    ImageSummary { tags: entry.repo_tags.clone().unwrap_or_default(), platform, layers: entry.layers.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_entry() {
+        let entries = manifest::parse_manifests(br#"[
+            {"Config": "a.json", "RepoTags": ["app:1.0"], "Layers": ["1.tar"]},
+            {"Config": "b.json", "RepoTags": ["app:2.0", "app:latest"], "Layers": ["1.tar", "2.tar"]}
+        ]"#).unwrap();
+        let contents = Contents::default();
+        let pick = |image: Option<&str>, choose: Option<ImageChooser>| {
+            let select = ImageSelector { image: image.map(str::to_string), choose, ..Default::default() };
+            pick_entry(&entries, &contents, &select)
+        };
+        assert_eq!(pick(None, None).unwrap(), 0);
+        assert_eq!(pick(Some("app:latest"), None).unwrap(), 1);
+        assert_eq!(pick(Some("1"), None).unwrap(), 1);
+        let err = pick(Some("app:3.0"), None).unwrap_err();
+        assert_eq!(err.to_string(), "Image app:3.0 not found in archive (available: app:1.0, app:2.0, app:latest)");
+
+        let chooser = ImageChooser::new(|images| {
+            assert_eq!(images[1].tags, ["app:2.0", "app:latest"]);
+            assert_eq!(images[1].layers, 2);
+            Ok(1)
+        });
+        assert_eq!(pick(None, Some(chooser.clone())).unwrap(), 1);
+        assert_eq!(pick(Some("0"), Some(chooser)).unwrap(), 0);
+    }
+}
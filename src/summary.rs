@@ -0,0 +1,243 @@
+//! Where the bulk of an image lives, for `contree summary`
+//!
+//! Sizes are those of the files in the merged tree, so content a later layer
+//! deleted or overwrote counts for nothing: a layer's share is what it still
+//! contributes, not the size of its blob.
+
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive::{self, Image, ProcessOptions};
+use crate::layers;
+use crate::tree::NodeMetadata;
+use crate::utils;
+
+/// Width of the bars, in characters
+const BAR_WIDTH: usize = 30;
+
+/// What kind of content a file is, judged by its path and mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Binaries,
+    Libraries,
+    Docs,
+    Caches,
+    Other,
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Binaries => "binaries",
+            Category::Libraries => "libraries",
+            Category::Docs => "docs",
+            Category::Caches => "caches",
+            Category::Other => "other",
+        }
+    }
+
+    /// The category of the file at `path` (without a leading "/")
+    pub fn of(path: &str, meta: &NodeMetadata) -> Self {
+        let dirs = path.rsplit_once('/').map_or("", |(dirs, _)| dirs);
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let under = |dir: &str| dirs == dir || dirs.starts_with(&format!("{}/", dir));
+        let component = |part: &str| dirs.split('/').any(|c| c == part);
+
+        if ["var/cache", "root/.cache", "var/lib/apt/lists", "tmp"].iter().any(|dir| under(dir))
+            || ["__pycache__", ".cache", ".npm", "_cacache"].iter().any(|part| component(part))
+        {
+            Category::Caches
+        } else if ["usr/share/doc", "usr/share/man", "usr/share/info", "usr/share/licenses"].iter()
+            .any(|dir| under(dir))
+            || ["README", "LICENSE", "CHANGELOG", "COPYING"].iter().any(|prefix| name.starts_with(prefix))
+        {
+            Category::Docs
+        } else if name.ends_with(".so") || name.contains(".so.") || name.ends_with(".a") || name.ends_with(".jar")
+            || ["node_modules", "site-packages", "dist-packages"].iter().any(|part| component(part))
+        {
+            Category::Libraries
+        } else if !meta.is_symlink && meta.mode & 0o111 != 0
+            || ["bin", "sbin"].contains(&dirs.rsplit('/').next().unwrap_or(dirs))
+        {
+            Category::Binaries
+        } else {
+            Category::Other
+        }
+    }
+}
+
+/// One bar of the chart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub name: String,
+    pub size: u64,
+    /// Said after the size, e.g. a layer's build step
+    pub note: String,
+}
+
+/// The image's size split three ways
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Breakdown {
+    /// Bytes in every file of the merged tree
+    pub total: u64,
+    pub files: usize,
+    /// Top-level directories (and files), biggest first
+    pub dirs: Vec<Share>,
+    /// Filesystem layers, bottom-most first
+    pub layers: Vec<Share>,
+    /// File categories, biggest first
+    pub categories: Vec<Share>,
+}
+
+/// Split the merged tree of `image` by top-level directory, by the layer
+/// that provides each file, and by category
+pub fn breakdown(image: &Image) -> Breakdown {
+    let mut result = Breakdown::default();
+    let mut by_layer: HashMap<&str, u64> = HashMap::new();
+    let mut by_category: BTreeMap<Category, u64> = BTreeMap::new();
+    for (path, meta) in image.root.walk().filter(|(_, meta)| meta.is_file) {
+        result.total += meta.size;
+        result.files += 1;
+        if let Some(layer) = meta.layer_hash.as_deref() {
+            *by_layer.entry(layer).or_default() += meta.size;
+        }
+        *by_category.entry(Category::of(&path.display().to_string(), meta)).or_default() += meta.size;
+    }
+
+    result.dirs = image.root.children.iter()
+        .map(|(name, child)| {
+            let name = if child.metadata.is_file { format!("/{}", name) } else { format!("/{}/", name) };
+            Share { name, size: child.total_size(), note: String::new() }
+        })
+        .filter(|share| share.size > 0)
+        .collect();
+    result.dirs.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+
+    let history = image.config.as_ref().map(|config| config.history.as_slice()).unwrap_or_default();
+    let history = layers::without_missing(history, &image.missing_layers);
+    let details = layers::layer_details(&layers::align_history(&image.layer_labels, &history), &image.layer_sizes);
+    result.layers = image.layer_labels.iter().zip(details)
+        .map(|(label, detail)| Share {
+            name: label.clone(),
+            size: by_layer.get(label.as_str()).copied().unwrap_or_default(),
+            note: detail.command,
+        })
+        .collect();
+
+    result.categories = by_category.into_iter()
+        .map(|(category, size)| Share { name: category.name().to_string(), size, note: String::new() })
+        .collect();
+    result.categories.sort_by_key(|share| Reverse(share.size));
+    result
+}
+
+/// A bar `fraction` of the full width long
+fn bar(fraction: f64) -> String {
+    let filled = (fraction.clamp(0.0, 1.0) * BAR_WIDTH as f64).round() as usize;
+    format!("{}{}", "█".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+}
+
+/// Print the breakdown as three bar charts, each bar's length its share of the total
+pub fn write_summary<W: Write>(out: &mut W, breakdown: &Breakdown) -> io::Result<()> {
+    writeln!(out, "Total: {} in {} files", utils::human_size(breakdown.total), breakdown.files)?;
+    let sections = [
+        ("By directory", &breakdown.dirs),
+        ("By layer (what each still provides)", &breakdown.layers),
+        ("By category", &breakdown.categories),
+    ];
+    for (title, shares) in sections {
+        writeln!(out, "\n{}", title)?;
+        let width = shares.iter().map(|share| share.name.chars().count()).max().unwrap_or(0);
+        for share in shares {
+            let fraction = if breakdown.total == 0 { 0.0 } else { share.size as f64 / breakdown.total as f64 };
+            let line = format!("  {:<width$}  {}  {:>10}  {:>3.0}%  {}", share.name, bar(fraction),
+                utils::human_size(share.size), fraction * 100.0, share.note);
+            writeln!(out, "{}", line.trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+/// Print where the bulk of an archive's image lives
+pub fn run(archive_path: &Path) -> Result<()> {
+    let options = ProcessOptions { record_layers: true, ..Default::default() };
+    let image = archive::process_archive(archive_path, &options)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_summary(&mut out, &breakdown(&image))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::Node;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_categories() {
+        let meta = |mode| NodeMetadata { is_file: true, mode, ..Node::new_dir(0, 0, 0).metadata };
+        assert_eq!(Category::of("usr/bin/python3", &meta(0o755)), Category::Binaries);
+        assert_eq!(Category::of("usr/local/bin/script.sh", &meta(0o644)), Category::Binaries);
+        assert_eq!(Category::of("usr/lib/libssl.so.3", &meta(0o755)), Category::Libraries);
+        assert_eq!(Category::of("app/node_modules/x/index.js", &meta(0o644)), Category::Libraries);
+        assert_eq!(Category::of("usr/share/man/man1/ls.1.gz", &meta(0o644)), Category::Docs);
+        assert_eq!(Category::of("app/LICENSE.txt", &meta(0o644)), Category::Docs);
+        assert_eq!(Category::of("var/cache/apk/APKINDEX.tar.gz", &meta(0o644)), Category::Caches);
+        assert_eq!(Category::of("app/__pycache__/main.cpython-312.pyc", &meta(0o644)), Category::Caches);
+        assert_eq!(Category::of("etc/passwd", &meta(0o644)), Category::Other);
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        let base: Arc<str> = Arc::from("aaaaaaa");
+        let app: Arc<str> = Arc::from("bbbbbbb");
+        for (path, mode, size, layer) in [
+            ("usr/bin/app", 0o755, 3000, &app),
+            ("usr/share/doc/app/README", 0o644, 500, &base),
+            ("etc/os-release", 0o644, 500, &base),
+        ] {
+            root.put_file(path, mode, 0, 0, false, None, Some(layer));
+            root.get_mut(path).unwrap().metadata.size = size;
+        }
+        let image = Image {
+            root,
+            layer_labels: vec![base.to_string(), app.to_string()],
+            config: serde_json::from_str(r#"{"history": [{"created_by": "/bin/sh -c #(nop) ADD file:01 in / "},
+                {"created_by": "COPY app /usr/bin/app"}]}"#).unwrap(),
+            repo_tags: Vec::new(),
+            digest: None,
+            layer_sizes: vec![2048, 4096],
+            layer_profiles: Vec::new(),
+            suspicious: Vec::new(),
+            missing_layers: Vec::new(),
+        };
+
+        let mut out = Vec::new();
+        write_summary(&mut out, &breakdown(&image)).unwrap();
+        let bar = |filled: usize| format!("{}{}", "█".repeat(filled), " ".repeat(30 - filled));
+        assert_eq!(String::from_utf8(out).unwrap(), [
+            "Total: 3.9 KiB in 3 files".to_string(),
+            String::new(),
+            "By directory".to_string(),
+            format!("  /usr/  {}     3.4 KiB   88%", bar(26)),
+            format!("  /etc/  {}       500 B   12%", bar(4)),
+            String::new(),
+            "By layer (what each still provides)".to_string(),
+            format!("  aaaaaaa  {}      1000 B   25%  ADD file:01 in /", bar(8)),
+            format!("  bbbbbbb  {}     2.9 KiB   75%  COPY app /usr/bin/app", bar(23)),
+            String::new(),
+            "By category".to_string(),
+            format!("  binaries  {}     2.9 KiB   75%", bar(23)),
+            format!("  docs      {}       500 B   12%", bar(4)),
+            format!("  other     {}       500 B   12%", bar(4)),
+            String::new(),
+        ].join("\n"));
+    }
+}
@@ -0,0 +1,225 @@
+//! `docker save` archives, plain or gzip/zstd compressed
+//!
+//! Blobs of an uncompressed archive are read in place by offset. A compressed
+//! archive can't be read by offset, so the layers of the selected image are
+//! spooled to a temporary directory.
+
+use anyhow::{Context, Result};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tar::Archive;
+
+use crate::archive::{decompress, Blob, Compression, LayerSet};
+use crate::diagnostics::{self, WarningKind};
+use crate::error::{self, io_error};
+use crate::progress::TermProgress;
+use crate::source::{self, Contents, ImageSelector, LayerSource};
+use crate::tempspace;
+
+/// Larger entries of a compressed archive are never manifests or configs
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A `docker save` tarball (Docker or OCI layout)
+pub struct TarballSource {
+    path: PathBuf,
+}
+
+impl TarballSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TarballSource { path: path.into() }
+    }
+}
+
+impl LayerSource for TarballSource {
+    fn open_layers(&self, select: &ImageSelector, progress: &mut TermProgress) -> error::Result<LayerSet> {
+        let compression = {
+            let mut file = File::open(&self.path).map_err(io_error(&self.path))?;
+            outer_compression(&self.path, &mut file)
+        };
+
+        let (blobs, entry, temp_dir) = if compression == Compression::None {
+            let in_archive = |offset, size| Blob::InArchive { archive: self.path.clone(), offset, size };
+            let contents = scan_archive(&self.path, compression, Keep::InPlace(&in_archive), "reading archive", progress)?;
+            let entry = source::select_image(&self.path, &contents, select)?;
+            (contents.blobs, entry, None)
+        } else {
+            // The manifest comes after the layers and a compressed archive can't
+            // be read by offset, so read it once for the manifests, then again to
+            // spool only the layers of the selected image. Decompressing twice is
+            // cheaper than writing out every image of a multi-image archive.
+            let mut contents = scan_archive(&self.path, compression, Keep::Metadata, "reading archive", progress)?;
+            let entry = source::select_image(&self.path, &contents, select)?;
+            let wanted: HashSet<String> = entry.layers.iter().chain(&entry.config)
+                .filter(|name| !contents.blobs.contains_key(*name))
+                .cloned()
+                .collect();
+
+            tempspace::reserve(wanted.iter().filter_map(|name| contents.sizes.get(name)).sum())?;
+            let dir = tempspace::dir();
+            let temp_dir = tempfile::tempdir_in(&dir).map_err(io_error(&dir))?;
+            if !wanted.is_empty() {
+                let keep = Keep::Spool { dir: temp_dir.path(), wanted: &wanted };
+                let spooled = scan_archive(&self.path, compression, keep, "extracting layers", progress)?;
+                contents.blobs.extend(spooled.blobs);
+            }
+            (contents.blobs, entry, Some(temp_dir))
+        };
+
+        LayerSet::assemble(blobs, entry, temp_dir, select)
+    }
+}
+
+/// Which blobs `collect_blobs` keeps, and how
+pub(crate) enum Keep<'a> {
+    /// Every blob, by offset and size in the (uncompressed) archive
+    InPlace(&'a dyn Fn(u64, u64) -> Blob),
+    /// Only JSON documents (indexes, manifests, configs), read into memory
+    Metadata,
+    /// Only the `wanted` blobs, copied into `dir`
+    Spool { dir: &'a Path, wanted: &'a HashSet<String> },
+}
+
+/// Read through the outer archive once, reporting progress as `label`
+fn scan_archive(
+    archive_path: &Path,
+    compression: Compression,
+    keep: Keep,
+    label: &str,
+    progress: &mut TermProgress,
+) -> Result<Contents> {
+    let file = File::open(archive_path)
+        .map_err(io_error(archive_path))?;
+    let total_bytes = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let file = CountingReader::new(file);
+    let bytes_read = file.counter();
+    let mut report = || progress.update(label, bytes_read.get(), total_bytes);
+
+    if compression == Compression::None {
+        let mut archive = Archive::new(file);
+        let entries = archive.entries_with_seek().context("Failed to read archive entries")?;
+        collect_blobs(entries, keep, &mut report)
+    } else {
+        let mut archive = Archive::new(decompress(compression, file)?);
+        let entries = archive.entries().context("Failed to read archive entries")?;
+        collect_blobs(entries, keep, &mut report)
+    }
+}
+
+/// Compression of the outer archive: the extension first, then the magic bytes
+fn outer_compression(path: &Path, file: &mut File) -> Compression {
+    let name = path.to_string_lossy();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Compression::Gzip
+    } else if name.ends_with(".zst") {
+        Compression::Zstd
+    } else {
+        Compression::detect(file)
+    }
+}
+
+/// Find the manifest and the regular files of an outer archive that `keep`
+/// asks for
+pub(crate) fn collect_blobs<'a, R: Read + 'a>(
+    entries: tar::Entries<'a, R>,
+    keep: Keep,
+    report: &mut dyn FnMut(),
+) -> Result<Contents> {
+    let mut contents = Contents::default();
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(e) => e,
+            Err(err) => {
+                diagnostics::warn(WarningKind::CorruptEntry, format!("Skipping corrupted archive entry: {}", err));
+                continue;
+            }
+        };
+        report();
+
+        let path = match entry.path() {
+            Ok(p) => p.to_string_lossy().to_string(),
+            Err(err) => {
+                diagnostics::warn(WarningKind::InvalidPath, format!("Skipping entry with invalid path: {}", err));
+                continue;
+            }
+        };
+
+        if path == "manifest.json" {
+            let mut buf = Vec::new();
+            entry.read_to_end(&mut buf).context("Failed to read manifest.json")?;
+            contents.manifest = Some(buf);
+            continue;
+        }
+
+        // Record everything else; the manifest decides later which entries
+        // are layers and which one is the image config
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        contents.sizes.insert(path.clone(), entry.size());
+        let blob = match keep {
+            Keep::InPlace(blob_at) => blob_at(entry.raw_file_position(), entry.size()),
+            Keep::Metadata => {
+                if entry.size() > MAX_METADATA_SIZE {
+                    continue;
+                }
+                let mut first = [0u8; 1];
+                if entry.read(&mut first)? == 0 || !matches!(first[0], b'{' | b'[') {
+                    continue;
+                }
+                let mut bytes = first.to_vec();
+                entry.read_to_end(&mut bytes).with_context(|| format!("Failed to read {}", path))?;
+                Blob::Memory(bytes.into())
+            }
+            Keep::Spool { dir, wanted } => {
+                if !wanted.contains(&path) {
+                    continue;
+                }
+                let spooled = dir.join(format!("blob-{}", contents.blobs.len()));
+                let mut file = File::create(&spooled).context("Failed to create temp file")?;
+                std::io::copy(&mut entry, &mut file)
+                    .map_err(tempspace::explain)
+                    .context("Failed to copy layer to temp file")?;
+                Blob::File(spooled)
+            }
+        };
+        contents.blobs.insert(path, blob);
+    }
+    Ok(contents)
+}
+
+/// Reader that counts the bytes passing through it
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader { inner, count: Rc::new(Cell::new(0)) }
+    }
+
+    /// Shared handle to the running byte count
+    fn counter(&self) -> Rc<Cell<u64>> {
+        Rc::clone(&self.count)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let offset = self.inner.seek(pos)?;
+        self.count.set(offset);
+        Ok(offset)
+    }
+}
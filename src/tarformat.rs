@@ -0,0 +1,149 @@
+//! Tar header formats used by layer tarballs
+//!
+//! Older runtimes and registries sometimes choke on GNU long-name records or
+//! PAX extended headers, so `layers` reports which ones each layer uses.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::io::Read;
+use tar::Archive;
+
+use crate::archive::{self, Layer};
+
+/// Tar header dialect of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeaderFormat {
+    /// Pre-POSIX headers without a magic field
+    V7,
+    Ustar,
+    /// GNU headers, including long-name and long-link records
+    Gnu,
+    /// POSIX ustar headers extended by PAX records
+    Pax,
+}
+
+impl HeaderFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeaderFormat::V7 => "v7",
+            HeaderFormat::Ustar => "ustar",
+            HeaderFormat::Gnu => "gnu",
+            HeaderFormat::Pax => "pax",
+        }
+    }
+}
+
+/// Header formats and extensions found in one layer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerFormat {
+    pub formats: BTreeSet<HeaderFormat>,
+    /// Entries carrying extended attributes in PAX records
+    pub xattr_entries: u64,
+}
+
+impl LayerFormat {
+    /// Formats joined with `+`, e.g. `pax` or `gnu+pax`; `-` for an empty layer.
+    /// PAX records sit on ustar headers, so ustar isn't listed alongside pax.
+    pub fn describe(&self) -> String {
+        let names: Vec<&str> = self.formats.iter()
+            .filter(|f| !(**f == HeaderFormat::Ustar && self.formats.contains(&HeaderFormat::Pax)))
+            .map(|f| f.name())
+            .collect();
+        if names.is_empty() {
+            "-".to_string()
+        } else {
+            names.join("+")
+        }
+    }
+}
+
+/// Read every header of a layer and summarize its format
+pub fn scan_layer(layer: &Layer) -> Result<LayerFormat> {
+    scan(archive::open_layer(layer)?)
+        .with_context(|| format!("Failed to read layer {}", layer.name))
+}
+
+fn scan<R: Read>(mut archive: Archive<R>) -> Result<LayerFormat> {
+    let mut result = LayerFormat::default();
+
+    // Raw entries keep PAX and GNU long-name records instead of folding them
+    // into the entry they describe
+    for entry in archive.entries()?.raw(true) {
+        let mut entry = entry?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+
+        let format = if entry_type.is_pax_local_extensions() || entry_type.is_pax_global_extensions() {
+            HeaderFormat::Pax
+        } else if entry_type.is_gnu_longname() || entry_type.is_gnu_longlink() || header.as_gnu().is_some() {
+            HeaderFormat::Gnu
+        } else if header.as_ustar().is_some() {
+            HeaderFormat::Ustar
+        } else {
+            HeaderFormat::V7
+        };
+        result.formats.insert(format);
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            let has_xattr = extensions.filter_map(|ext| ext.ok()).any(|ext| {
+                let key = ext.key_bytes();
+                key.starts_with(b"SCHILY.xattr.") || key.starts_with(b"LIBARCHIVE.xattr.")
+            });
+            if has_xattr {
+                result.xattr_entries += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_header(size: u64, mut header: tar::Header) -> tar::Header {
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        header
+    }
+
+    fn scan_bytes(bytes: Vec<u8>) -> LayerFormat {
+        scan(Archive::new(&bytes[..])).unwrap()
+    }
+
+    #[test]
+    fn test_scan_ustar_and_gnu() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_data(&mut file_header(1, tar::Header::new_ustar()), "a", &b"a"[..]).unwrap();
+        let ustar = scan_bytes(builder.into_inner().unwrap());
+        assert_eq!(ustar.describe(), "ustar");
+
+        // Names over 100 bytes need a GNU long-name record
+        let mut builder = tar::Builder::new(Vec::new());
+        let long = "d/".repeat(60) + "file";
+        builder.append_data(&mut file_header(1, tar::Header::new_gnu()), &long, &b"a"[..]).unwrap();
+        let gnu = scan_bytes(builder.into_inner().unwrap());
+        assert_eq!(gnu.describe(), "gnu");
+        assert_eq!(gnu.xattr_entries, 0);
+    }
+
+    #[test]
+    fn test_scan_pax_xattrs() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_pax_extensions([("SCHILY.xattr.security.capability", &b"\x01"[..])]).unwrap();
+        builder.append_data(&mut file_header(1, tar::Header::new_ustar()), "ping", &b"a"[..]).unwrap();
+        builder.append_data(&mut file_header(1, tar::Header::new_ustar()), "other", &b"a"[..]).unwrap();
+
+        let format = scan_bytes(builder.into_inner().unwrap());
+        assert_eq!(format.describe(), "pax");
+        assert_eq!(format.xattr_entries, 1);
+    }
+
+    #[test]
+    fn test_describe_empty_layer() {
+        assert_eq!(LayerFormat::default().describe(), "-");
+    }
+}
@@ -0,0 +1,166 @@
+//! Per-entry output shaped by a template, for `--format template`
+//!
+//! A template is text with `{field}` placeholders, written once per entry of
+//! the merged tree in path order: `'{path}\t{size}\t{layer}'`. `\t`, `\n` and
+//! `\\` are escapes, since shells pass them through quotes untouched, and
+//! `{{` and `}}` stand for literal braces. A newline ends every entry.
+
+use anyhow::{bail, Result};
+use std::io::{self, Write};
+
+use crate::render;
+use crate::tree::{DeviceKind, Node, NodeMetadata};
+use crate::utils;
+
+/// What a placeholder can be replaced with
+const FIELDS: &[&str] = &[
+    "path", "name", "type", "size", "hsize", "mode", "perms", "uid", "gid", "mtime", "layer", "target", "owner",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// Index into [`FIELDS`]
+    Field(usize),
+}
+
+/// A parsed template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse `template`, rejecting unknown fields and unbalanced braces
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    text.push(c);
+                }
+                ('\\', Some(&escaped @ ('t' | 'n' | '\\'))) => {
+                    chars.next();
+                    text.push(match escaped {
+                        't' => '\t',
+                        'n' => '\n',
+                        _ => '\\',
+                    });
+                }
+                ('{', _) => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unclosed '{{' in template '{}' (write '{{{{' for a brace)", template),
+                        }
+                    }
+                    let Some(field) = FIELDS.iter().position(|&f| f == name) else {
+                        bail!("Unknown field '{{{}}}' in template (available: {{{}}})", name, FIELDS.join("}, {"));
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                ('}', _) => bail!("Unmatched '}}' in template '{}' (write '}}}}' for a brace)", template),
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    /// The template filled in for the entry at `path` (without a leading "/")
+    pub fn render(&self, path: &str, meta: &NodeMetadata) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Field(field) => out.push_str(&field_value(FIELDS[*field], path, meta)),
+            }
+        }
+        out
+    }
+}
+
+/// What `field` (one of the template fields) says about an entry
+pub(crate) fn field_value(field: &str, path: &str, meta: &NodeMetadata) -> String {
+    match field {
+        "path" => format!("/{}", utils::display_path(path)),
+        "name" => utils::display_path(utils::split_path(path).1).into_owned(),
+        "type" => entry_type(meta).to_string(),
+        "size" => meta.size.to_string(),
+        "hsize" => utils::human_size(meta.size),
+        "mode" => format!("{:04o}", meta.mode & 0o7777),
+        "perms" => render::format_permissions(meta.mode, meta.is_file),
+        "uid" => meta.uid.to_string(),
+        "gid" => meta.gid.to_string(),
+        "mtime" => utils::format_timestamp(meta.mtime as i64),
+        "layer" => meta.layer_hash.as_deref().unwrap_or_default().to_string(),
+        "target" => meta.symlink_target.as_deref().or(meta.hardlink_target.as_deref())
+            .map(|target| utils::display_path(target).into_owned())
+            .unwrap_or_default(),
+        "owner" => meta.owner.clone().unwrap_or_default(),
+        _ => unreachable!("fields are checked when parsing"),
+    }
+}
+
+fn entry_type(meta: &NodeMetadata) -> &'static str {
+    match meta.device {
+        Some(device) if device.kind == DeviceKind::Char => "char",
+        Some(_) => "block",
+        None if meta.is_symlink => "symlink",
+        None if meta.hardlink_target.is_some() => "hardlink",
+        None if meta.is_file => "file",
+        None => "dir",
+    }
+}
+
+/// Write `template` for every entry below `root`, parents before children
+pub fn write_entries<W: Write>(out: &mut W, root: &Node, template: &Template) -> io::Result<()> {
+    for (path, meta) in root.walk() {
+        writeln!(out, "{}", template.render(&path.display().to_string(), meta))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Template::parse("{path} {size}").is_ok());
+        let err = Template::parse("{path} {sise}").unwrap_err().to_string();
+        assert!(err.starts_with("Unknown field '{sise}' in template (available: {path}, {name},"), "{}", err);
+        assert!(Template::parse("{path} }").is_err());
+        assert!(Template::parse("{path").is_err());
+    }
+
+    #[test]
+    fn test_write_entries() {
+        let layer: Arc<str> = Arc::from("a1b2c3d");
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, Some(&layer));
+        root.get_mut("etc/hosts").unwrap().metadata.size = 2048;
+        root.put_file("bin/sh", 0o777, 0, 0, true, Some("busybox".to_string()), Some(&layer));
+
+        let template = Template::parse(r"{path}\t{type}\t{size}\t{hsize}\t{mode}\t{layer}{{{target}}}").unwrap();
+        let mut out = Vec::new();
+        write_entries(&mut out, &root, &template).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "/bin\tdir\t0\t0 B\t0755\ta1b2c3d{}\n",
+            "/bin/sh\tsymlink\t0\t0 B\t0777\ta1b2c3d{busybox}\n",
+            "/etc\tdir\t0\t0 B\t0755\ta1b2c3d{}\n",
+            "/etc/hosts\tfile\t2048\t2.0 KiB\t0644\ta1b2c3d{}\n",
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+//! Scratch disk space: where spooled layers and other temporary files go
+//! (`--temp-dir`) and how much a run may write there (`--max-temp-size`)
+//!
+//! Layers of a compressed archive are unpacked before they are read, which
+//! for large images can need more room than a small root or tmpfs /tmp has.
+//! Checking the total up front turns a mid-run "No space left on device"
+//! into an error that says what to do instead.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::error::{ContreeError, Result};
+
+/// errno for "No space left on device" (Linux and macOS)
+const ENOSPC: i32 = 28;
+
+/// Limits on temporary files, set once from the command line
+#[derive(Debug, Clone, Default)]
+pub struct TempSpace {
+    /// Directory for temporary files instead of the system default
+    pub dir: Option<PathBuf>,
+    /// Most bytes a run may spool
+    pub max_size: Option<u64>,
+}
+
+static SETTINGS: OnceLock<TempSpace> = OnceLock::new();
+
+/// Apply `settings` for the rest of the run
+pub fn configure(settings: TempSpace) {
+    SETTINGS.set(settings).expect("temp space is configured once");
+}
+
+fn settings() -> &'static TempSpace {
+    SETTINGS.get_or_init(TempSpace::default)
+}
+
+/// Directory to create temporary files in
+pub fn dir() -> PathBuf {
+    settings().dir.clone().unwrap_or_else(std::env::temp_dir)
+}
+
+/// Fail unless `needed` bytes fit in the --max-temp-size budget
+pub fn reserve(needed: u64) -> Result<()> {
+    match settings().max_size {
+        Some(max) if needed > max => Err(ContreeError::TempSpaceExceeded { needed, max }),
+        _ => Ok(()),
+    }
+}
+
+/// Explain a write error that ran out of disk space
+pub fn explain(err: io::Error) -> ContreeError {
+    if err.raw_os_error() == Some(ENOSPC) {
+        ContreeError::NoSpace { dir: dir(), source: err }
+    } else {
+        ContreeError::Other(err.into())
+    }
+}
+
+/// Parse a size like `512M`, `10G`, `1.5GiB`, or a plain byte count
+/// (units are binary: 1K = 1024 bytes)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let split = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("unknown unit in '{}' (use K, M, G, or T)", s)),
+    };
+    let value: f64 = number.parse().map_err(|_| format!("invalid size '{}'", s))?;
+    Ok((value * (1u64 << shift) as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("10g"), Ok(10 << 30));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size(" 2 TB "), Ok(2 << 40));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn test_explain_no_space() {
+        let err = explain(io::Error::from_raw_os_error(ENOSPC));
+        assert!(err.to_string().starts_with("No space left in "));
+        assert!(err.to_string().contains("--temp-dir"));
+
+        let err = explain(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert_eq!(err.to_string(), "denied");
+    }
+}
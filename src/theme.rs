@@ -1,8 +1,18 @@
 use anyhow::{Context, Result};
+use serde::de::{self, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::colordepth::ColorDepth;
+
+/// Names of the bundled themes, the default first
+pub const THEME_NAMES: [&str; 6] = ["gruvbox-dark", "gruvbox-light", "dracula", "nord", "solarized-dark", "monochrome"];
 
 /// Color theme configuration
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Theme {
     #[serde(default = "default_directory", deserialize_with = "deserialize_color")]
     pub directory: String,
@@ -27,45 +37,148 @@ pub struct Theme {
 
     #[serde(default = "default_hardlink", deserialize_with = "deserialize_color")]
     pub hardlink: String,
+
+    #[serde(default = "default_added", deserialize_with = "deserialize_color")]
+    pub added: String,
+
+    #[serde(default = "default_removed", deserialize_with = "deserialize_color")]
+    pub removed: String,
+
+    #[serde(default = "default_changed", deserialize_with = "deserialize_color")]
+    pub changed: String,
+
+    /// Highlights for entries with dangerous permissions, like GNU ls:
+    /// setuid and setgid files, and world-writable entries (sticky
+    /// directories such as /tmp apart). Empty means no highlight.
+    #[serde(default = "default_setuid", deserialize_with = "deserialize_highlight")]
+    pub setuid: String,
+
+    #[serde(default = "default_setgid", deserialize_with = "deserialize_highlight")]
+    pub setgid: String,
+
+    #[serde(default = "default_world_writable", deserialize_with = "deserialize_highlight")]
+    pub world_writable: String,
+
+    #[serde(default = "default_sticky_writable", deserialize_with = "deserialize_highlight")]
+    pub sticky_writable: String,
+
+    /// File sizes in long output, by magnitude: under 1 KiB, under 1 MiB,
+    /// under 1 GiB, and larger
+    #[serde(default = "default_size_small", deserialize_with = "deserialize_color")]
+    pub size_small: String,
+
+    #[serde(default = "default_size_medium", deserialize_with = "deserialize_color")]
+    pub size_medium: String,
+
+    #[serde(default = "default_size_large", deserialize_with = "deserialize_color")]
+    pub size_large: String,
+
+    #[serde(default = "default_size_huge", deserialize_with = "deserialize_color")]
+    pub size_huge: String,
 }
 
-/// Deserialize a color from either hex string (#RRGGBB) or RGB array [r, g, b]
+/// A color from either hex string (#RRGGBB) or RGB array [r, g, b]
+#[derive(Clone, Copy)]
+struct Rgb([u8; 3]);
+
+impl<'de> Deserialize<'de> for Rgb {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
+
+struct ColorVisitor;
+
+impl<'de> Visitor<'de> for ColorVisitor {
+    type Value = Rgb;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(r##"a color as "#RRGGBB" or [r, g, b]"##)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Rgb, E> {
+        // Parse hex color like "#7daea3" or "7daea3"
+        let hex = value.trim_start_matches('#');
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Rgb([r, g, b])),
+            _ => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Rgb, A::Error> {
+        let mut rgb = [0u8; 3];
+        for (i, channel) in rgb.iter_mut().enumerate() {
+            *channel = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(Rgb(rgb))
+    }
+}
+
+/// Deserialize a text color
 fn deserialize_color<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
-    use serde::de::Error;
+    let Rgb([r, g, b]) = Rgb::deserialize(deserializer)?;
+    Ok(rgb_to_ansi(r, g, b))
+}
+
+/// Deserialize a highlight: a background color, or a table of any of
+/// `foreground`, `background` and `bold`
+fn deserialize_highlight<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct HighlightVisitor;
 
     #[derive(Deserialize)]
-    #[serde(untagged)]
-    enum ColorValue {
-        Hex(String),
-        Rgb([u8; 3]),
+    #[serde(deny_unknown_fields)]
+    struct Style {
+        foreground: Option<Rgb>,
+        background: Option<Rgb>,
+        #[serde(default)]
+        bold: bool,
     }
 
-    let value = ColorValue::deserialize(deserializer)?;
+    impl<'de> Visitor<'de> for HighlightVisitor {
+        type Value = String;
 
-    match value {
-        ColorValue::Hex(hex) => {
-            // Parse hex color like "#7daea3" or "7daea3"
-            let hex = hex.trim_start_matches('#');
-            if hex.len() != 6 {
-                return Err(D::Error::custom(format!("Invalid hex color: {}", hex)));
-            }
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(r##"a background color as "#RRGGBB" or [r, g, b], or a table of foreground, background and bold"##)
+        }
 
-            let r = u8::from_str_radix(&hex[0..2], 16)
-                .map_err(|_| D::Error::custom(format!("Invalid hex color: {}", hex)))?;
-            let g = u8::from_str_radix(&hex[2..4], 16)
-                .map_err(|_| D::Error::custom(format!("Invalid hex color: {}", hex)))?;
-            let b = u8::from_str_radix(&hex[4..6], 16)
-                .map_err(|_| D::Error::custom(format!("Invalid hex color: {}", hex)))?;
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+            ColorVisitor.visit_str(value).map(|Rgb([r, g, b])| highlight(u32::from_be_bytes([0, r, g, b])))
+        }
 
-            Ok(rgb_to_ansi(r, g, b))
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<String, A::Error> {
+            ColorVisitor.visit_seq(seq).map(|Rgb([r, g, b])| highlight(u32::from_be_bytes([0, r, g, b])))
         }
-        ColorValue::Rgb([r, g, b]) => {
-            Ok(rgb_to_ansi(r, g, b))
+
+        fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<String, A::Error> {
+            let style = Style::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            let mut params = Vec::new();
+            if style.bold {
+                params.push("1".to_string());
+            }
+            if let Some(Rgb([r, g, b])) = style.foreground {
+                params.push(format!("38;2;{};{};{}", r, g, b));
+            }
+            if let Some(Rgb([r, g, b])) = style.background {
+                params.push(format!("48;2;{};{};{}", r, g, b));
+            }
+            if params.is_empty() {
+                return Ok(String::new());
+            }
+            Ok(format!("\x1b[{}m", params.join(";")))
         }
     }
+
+    deserializer.deserialize_any(HighlightVisitor)
 }
 
 /// Convert RGB values to ANSI escape code
@@ -73,6 +186,20 @@ fn rgb_to_ansi(r: u8, g: u8, b: u8) -> String {
     format!("\x1b[38;2;{};{};{}m", r, g, b)
 }
 
+/// Text on a `0xRRGGBB` background, dark or light to stand out against it
+fn highlight(background: u32) -> String {
+    let [_, r, g, b] = background.to_be_bytes();
+    let luma = 299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b);
+    let text = if luma > 128_000 { "29;32;33" } else { "251;241;199" };
+    format!("\x1b[38;2;{};48;2;{};{};{}m", text, r, g, b)
+}
+
+/// Bold text in a `0xRRGGBB` color
+fn bold(color: u32) -> String {
+    let [_, r, g, b] = color.to_be_bytes();
+    format!("\x1b[1;38;2;{};{};{}m", r, g, b)
+}
+
 // Default Gruvbox Material Dark theme colors
 fn default_directory() -> String {
     "\x1b[38;2;125;174;163m".to_string() // #7daea3
@@ -106,6 +233,53 @@ fn default_hardlink() -> String {
     "\x1b[38;2;146;131;116m".to_string() // #928374
 }
 
+fn default_added() -> String {
+    "\x1b[38;2;169;182;101m".to_string() // #a9b665
+}
+
+fn default_removed() -> String {
+    "\x1b[38;2;234;105;98m".to_string() // #ea6962
+}
+
+fn default_changed() -> String {
+    "\x1b[38;2;216;166;87m".to_string() // #d8a657
+}
+
+// Like GNU ls: setuid on red, setgid on yellow, sticky world-writable
+// directories on green; other world-writable entries in bold red
+fn default_setuid() -> String {
+    highlight(0xea6962)
+}
+
+fn default_setgid() -> String {
+    highlight(0xd8a657)
+}
+
+fn default_world_writable() -> String {
+    bold(0xea6962)
+}
+
+fn default_sticky_writable() -> String {
+    highlight(0xa9b665)
+}
+
+// Sizes from dim gray to red, like eza
+fn default_size_small() -> String {
+    default_tree_chars()
+}
+
+fn default_size_medium() -> String {
+    default_permissions()
+}
+
+fn default_size_large() -> String {
+    default_changed()
+}
+
+fn default_size_huge() -> String {
+    default_removed()
+}
+
 impl Default for Theme {
     fn default() -> Self {
         Theme {
@@ -117,19 +291,192 @@ impl Default for Theme {
             ownership: default_ownership(),
             layer_separator: default_layer_separator(),
             hardlink: default_hardlink(),
+            added: default_added(),
+            removed: default_removed(),
+            changed: default_changed(),
+            setuid: default_setuid(),
+            setgid: default_setgid(),
+            world_writable: default_world_writable(),
+            sticky_writable: default_sticky_writable(),
+            size_small: default_size_small(),
+            size_medium: default_size_medium(),
+            size_large: default_size_large(),
+            size_huge: default_size_huge(),
         }
     }
 }
 
 impl Theme {
+    /// A `--theme` value: the name of a bundled theme, or inline JSON
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec.trim_start().starts_with('{') {
+            return Self::from_json(spec);
+        }
+        Self::named(spec)
+            .with_context(|| format!("Unknown theme '{}' (available: {})", spec, THEME_NAMES.join(", ")))
+    }
+
+    /// A bundled theme by name
+    pub fn named(name: &str) -> Option<Self> {
+        // directory, executable, symlink, tree_chars, permissions, ownership,
+        // layer_separator, hardlink, added, removed, changed
+        let palette = match name {
+            "gruvbox-dark" => return Some(Self::default()),
+            "gruvbox-light" => [0x45707a, 0x6c782e, 0x4c7a5d, 0x928374, 0x654735, 0xb47109,
+                0x945e80, 0x928374, 0x6c782e, 0xc14a4a, 0xb47109],
+            "dracula" => [0xbd93f9, 0x50fa7b, 0x8be9fd, 0x6272a4, 0xf8f8f2, 0xf1fa8c,
+                0xff79c6, 0x6272a4, 0x50fa7b, 0xff5555, 0xffb86c],
+            "nord" => [0x81a1c1, 0xa3be8c, 0x88c0d0, 0x616e88, 0xd8dee9, 0xebcb8b,
+                0xb48ead, 0x616e88, 0xa3be8c, 0xbf616a, 0xebcb8b],
+            "solarized-dark" => [0x268bd2, 0x859900, 0x2aa198, 0x586e75, 0x93a1a1, 0xb58900,
+                0xd33682, 0x586e75, 0x859900, 0xdc322f, 0xcb4b16],
+            "monochrome" => return Some(Self::monochrome()),
+            _ => return None,
+        };
+        let [.., added_rgb, removed_rgb, changed_rgb] = palette;
+        let [directory, executable, symlink, tree_chars, permissions, ownership,
+            layer_separator, hardlink, added, removed, changed] = palette.map(|rgb: u32| {
+            let [_, r, g, b] = rgb.to_be_bytes();
+            rgb_to_ansi(r, g, b)
+        });
+        Some(Theme {
+            size_small: tree_chars.clone(),
+            size_medium: permissions.clone(),
+            size_large: changed.clone(),
+            size_huge: removed.clone(),
+            directory, executable, symlink, tree_chars, permissions, ownership,
+            layer_separator, hardlink, added, removed, changed,
+            setuid: highlight(removed_rgb),
+            setgid: highlight(changed_rgb),
+            world_writable: bold(removed_rgb),
+            sticky_writable: highlight(added_rgb),
+        })
+    }
+
+    /// Text attributes instead of colors, for terminals without true color
+    fn monochrome() -> Self {
+        let (bold, dim, italic, reverse, strike) = ("\x1b[1m", "\x1b[2m", "\x1b[3m", "\x1b[7m", "\x1b[9m");
+        let (bold_reverse, underline, bold_underline) = ("\x1b[1;7m", "\x1b[4m", "\x1b[1;4m");
+        Theme {
+            directory: bold.to_string(),
+            executable: bold.to_string(),
+            symlink: italic.to_string(),
+            tree_chars: dim.to_string(),
+            permissions: dim.to_string(),
+            ownership: dim.to_string(),
+            layer_separator: reverse.to_string(),
+            hardlink: italic.to_string(),
+            added: bold.to_string(),
+            removed: strike.to_string(),
+            changed: italic.to_string(),
+            setuid: bold_reverse.to_string(),
+            setgid: reverse.to_string(),
+            world_writable: bold_underline.to_string(),
+            sticky_writable: underline.to_string(),
+            size_small: dim.to_string(),
+            size_medium: "\x1b[22m".to_string(),
+            size_large: bold.to_string(),
+            size_huge: bold_underline.to_string(),
+        }
+    }
+
+    /// The color of a file size in long output
+    pub fn size_color(&self, size: u64) -> &str {
+        match size {
+            0..=1023 => &self.size_small,
+            1024..=0xfffff => &self.size_medium,
+            0x100000..=0x3fffffff => &self.size_large,
+            _ => &self.size_huge,
+        }
+    }
+
+    /// One line showing every color of the theme on sample entries
+    pub fn sample(&self) -> String {
+        let reset = "\x1b[0m";
+        format!(
+            "{}├──{r} {}drwxr-xr-x{r} {}0:0{r} {}etc{r} {}run.sh{r} {}lib -> usr/lib{r} {}=> bin/sh{r} {}[a1b2c3d]{r} {}+added{r} {}-removed{r} {}~changed{r} {}su{r} {}tmp{r}",
+            self.tree_chars, self.permissions, self.ownership, self.directory, self.executable, self.symlink,
+            self.hardlink, self.layer_separator, self.added, self.removed, self.changed, self.setuid,
+            self.sticky_writable, r = reset,
+        )
+    }
+
     /// Parse a theme from a JSON string
     pub fn from_json(json: &str) -> Result<Self> {
-        let theme: Theme = serde_json::from_str(json)
+        let theme: Theme = serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(json))
             .context("Failed to parse theme JSON")?;
 
         Ok(theme)
     }
 
+    /// Parse a theme from TOML text (`directory = "#7daea3"`)
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).context("Failed to parse theme TOML")
+    }
+
+    /// Load a theme file: JSON if its extension is `.json`, otherwise TOML
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read theme file {}", path.display()))?;
+        let theme = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        };
+        theme.with_context(|| format!("Invalid theme in {}", path.display()))
+    }
+
+    /// The theme with its RGB colors converted for a terminal of `depth`;
+    /// text attributes are kept as they are
+    pub fn with_depth(self, depth: ColorDepth) -> Self {
+        let fit = |code: String| {
+            let Some(params) = code.strip_prefix("\x1b[").and_then(|rest| rest.strip_suffix('m')) else {
+                return code;
+            };
+            let params: Vec<&str> = params.split(';').collect();
+            let rgb = |i: usize| match params.get(i..i + 4)? {
+                ["2", r, g, b] => Some((r.parse().ok()?, g.parse().ok()?, b.parse().ok()?)),
+                _ => None,
+            };
+            let mut fitted = Vec::new();
+            let mut i = 0;
+            while i < params.len() {
+                match (params[i], rgb(i + 1)) {
+                    ("38", Some((r, g, b))) => fitted.push(depth.foreground(r, g, b)),
+                    ("48", Some((r, g, b))) => fitted.push(depth.background(r, g, b)),
+                    (param, _) => {
+                        fitted.push(param.to_string());
+                        i += 1;
+                        continue;
+                    }
+                }
+                i += 5;
+            }
+            format!("\x1b[{}m", fitted.join(";"))
+        };
+        Theme {
+            directory: fit(self.directory),
+            executable: fit(self.executable),
+            symlink: fit(self.symlink),
+            tree_chars: fit(self.tree_chars),
+            permissions: fit(self.permissions),
+            ownership: fit(self.ownership),
+            layer_separator: fit(self.layer_separator),
+            hardlink: fit(self.hardlink),
+            added: fit(self.added),
+            removed: fit(self.removed),
+            changed: fit(self.changed),
+            setuid: fit(self.setuid),
+            setgid: fit(self.setgid),
+            world_writable: fit(self.world_writable),
+            sticky_writable: fit(self.sticky_writable),
+            size_small: fit(self.size_small),
+            size_medium: fit(self.size_medium),
+            size_large: fit(self.size_large),
+            size_huge: fit(self.size_huge),
+        }
+    }
+
     /// Get the default Gruvbox Material Dark theme
     #[allow(dead_code)]
     pub fn gruvbox_dark() -> Self {
@@ -137,6 +484,21 @@ impl Theme {
     }
 }
 
+/// List the bundled themes, each followed by a sample in its colors when
+/// `color` gives the terminal's depth
+pub fn list_themes<W: Write>(out: &mut W, color: Option<ColorDepth>) -> io::Result<()> {
+    for name in THEME_NAMES {
+        let label = if name == THEME_NAMES[0] { format!("{} (default)", name) } else { name.to_string() };
+        if let Some(depth) = color {
+            let theme = Theme::named(name).expect("bundled theme").with_depth(depth);
+            writeln!(out, "{:<24}{}", label, theme.sample())?;
+        } else {
+            writeln!(out, "{}", label)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -166,6 +528,77 @@ mod tests {
         assert!(theme.directory.contains("125;174;163"));
     }
 
+    #[test]
+    fn test_named_themes() {
+        for name in THEME_NAMES {
+            assert!(Theme::parse(name).is_ok(), "{}", name);
+        }
+        assert_eq!(Theme::parse("nord").unwrap().directory, "\x1b[38;2;129;161;193m");
+        assert_eq!(Theme::parse("gruvbox-dark").unwrap().directory, Theme::default().directory);
+        assert_eq!(Theme::parse(r##"{"directory": "#ff0000"}"##).unwrap().directory, "\x1b[38;2;255;0;0m");
+
+        let err = Theme::parse("solarized").unwrap_err().to_string();
+        assert!(err.starts_with("Unknown theme 'solarized' (available: gruvbox-dark, "), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let theme = Theme::from_toml("directory = \"#ff0000\"\nsymlink = [0, 128, 255]\n").unwrap();
+        assert_eq!(theme.directory, "\x1b[38;2;255;0;0m");
+        assert_eq!(theme.symlink, "\x1b[38;2;0;128;255m");
+    }
+
+    #[test]
+    fn test_errors_name_the_key_and_value() {
+        let err = format!("{:#}", Theme::from_json(r##"{"symlink": "#12345z"}"##).unwrap_err());
+        assert!(err.contains("symlink: invalid value: string \"#12345z\""), "{}", err);
+
+        let err = format!("{:#}", Theme::from_json(r#"{"added": [1, 2]}"#).unwrap_err());
+        assert!(err.contains("added: invalid length 2"), "{}", err);
+
+        let err = format!("{:#}", Theme::from_toml("directroy = \"#ff0000\"\n").unwrap_err());
+        assert!(err.contains("unknown field `directroy`"), "{}", err);
+        assert!(err.contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_with_depth() {
+        let theme = Theme::parse(r##"{"directory": "#ff0000", "removed": "#ea6962"}"##).unwrap();
+        assert_eq!(theme.clone().with_depth(ColorDepth::TrueColor).directory, "\x1b[38;2;255;0;0m");
+        assert_eq!(theme.clone().with_depth(ColorDepth::Ansi256).directory, "\x1b[38;5;196m");
+        assert_eq!(theme.with_depth(ColorDepth::Ansi16).removed, "\x1b[91m");
+        assert_eq!(Theme::monochrome().with_depth(ColorDepth::Ansi16).directory, "\x1b[1m");
+    }
+
+    #[test]
+    fn test_parse_highlights() {
+        let theme = Theme::from_toml(concat!(
+            "setuid = \"#ffffff\"\n",
+            "setgid = { foreground = \"#ff0000\", bold = true }\n",
+            "world_writable = {}\n",
+        )).unwrap();
+        assert_eq!(theme.setuid, "\x1b[38;2;29;32;33;48;2;255;255;255m");
+        assert_eq!(theme.setgid, "\x1b[1;38;2;255;0;0m");
+        assert_eq!(theme.world_writable, "");
+        assert_eq!(theme.sticky_writable, Theme::default().sticky_writable);
+
+        let err = format!("{:#}", Theme::from_json(r#"{"setuid": {"background": "red"}}"#).unwrap_err());
+        assert!(err.contains("setuid.background: invalid value"), "{}", err);
+
+        let theme = theme.with_depth(ColorDepth::Ansi16);
+        assert_eq!(theme.setuid, "\x1b[30;107m");
+        assert_eq!(theme.setgid, "\x1b[1;91m");
+    }
+
+    #[test]
+    fn test_size_colors() {
+        let theme = Theme::from_toml("size_huge = \"#ff0000\"\n").unwrap();
+        assert_eq!(theme.size_color(512), theme.size_small);
+        assert_eq!(theme.size_color(4096), theme.size_medium);
+        assert_eq!(theme.size_color(200 << 20), theme.size_large);
+        assert_eq!(theme.size_color(3 << 30), "\x1b[38;2;255;0;0m");
+    }
+
     #[test]
     fn test_parse_hex_without_hash() {
         let json = r#"{"symlink": "89b482"}"#;
@@ -1,16 +1,97 @@
-use std::collections::HashMap;
+//! The merged filesystem tree
+//!
+//! A [`Node`] is a directory or a file with its metadata; directories own
+//! their children by name, in order. Layers are applied onto one root with
+//! `put_file`, `remove` and `mark_opaque`, which follow overlay semantics.
+//!
+//! Trees serialize with serde field for field: a node is `{"children":
+//! {name: node, ...}, "metadata": {...}}` with the fields of
+//! [`NodeMetadata`] as documented there. [`Node::to_json`] wraps the root
+//! as `{"schema": SCHEMA_VERSION, "root": ...}` so readers can tell which
+//! layout they have, with `"layers"` naming the layers it was merged from
+//! when they are known; [`trees_to_json`] lists several such documents, and
+//! [`trees_from_json`] reads either back.
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use crate::utils;
 
+/// Version of the serialized tree layout. Bumped whenever a field of
+/// [`Node`], [`NodeMetadata`], [`MaskedEntry`] or [`Device`] is added,
+/// renamed, removed, or changes meaning.
+pub const SCHEMA_VERSION: u32 = 3;
+
+thread_local! {
+    /// Basenames and layer labels of the trees alive. Names like `__init__.py`, `index.js` and
+    /// `LICENSE` repeat thousands of times in large images; interned, every
+    /// copy shares one allocation.
+    static NAMES: RefCell<Interner> = RefCell::new(Interner { names: HashSet::new(), prune_at: MIN_PRUNE });
+}
+
+/// Fewest names the interner holds before it looks for unused ones
+const MIN_PRUNE: usize = 4096;
+
+struct Interner {
+    names: HashSet<Arc<str>>,
+    /// Size at which names no tree holds any more are dropped, so redrawing
+    /// a changing archive (`--watch`, the TUI) doesn't keep every old name
+    prune_at: usize,
+}
+
+/// Shared copy of `name`, allocated only the first time it is seen
+fn intern(name: &str) -> Arc<str> {
+    NAMES.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(existing) = interner.names.get(name) {
+            return Arc::clone(existing);
+        }
+        if interner.names.len() >= interner.prune_at {
+            interner.names.retain(|name| Arc::strong_count(name) > 1);
+            interner.prune_at = MIN_PRUNE.max(interner.names.len() * 2);
+        }
+        let name: Arc<str> = Arc::from(name);
+        interner.names.insert(Arc::clone(&name));
+        name
+    })
+}
+
+/// Components of a path inside the tree, ignoring empty and `.` parts
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|p| !p.is_empty() && *p != ".")
+}
+
+/// Child map whose names go through the interner, like a freshly built tree
+fn deserialize_children<'de, D>(deserializer: D) -> Result<BTreeMap<Arc<str>, Box<Node>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let children = BTreeMap::<String, Box<Node>>::deserialize(deserializer)?;
+    Ok(children.into_iter().map(|(name, node)| (intern(&name), node)).collect())
+}
+
+/// Layer label shared with every other entry from the same layer
+fn deserialize_layer<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Arc<str>>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(|label| intern(&label)))
+}
+
 /// Represents a node in the merged filesystem tree
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
-    /// Child entries keyed by basename
-    pub children: HashMap<String, Node>,
+    /// Child entries keyed by interned basename, iterated in name order.
+    /// Boxed so B-tree nodes, which reserve room for eleven values, stay small.
+    #[serde(deserialize_with = "deserialize_children")]
+    pub children: BTreeMap<Arc<str>, Box<Node>>,
     /// File metadata
     pub metadata: NodeMetadata,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeMetadata {
     /// true for files/symlinks, false for directories
     pub is_file: bool,
@@ -26,21 +107,72 @@ pub struct NodeMetadata {
     pub uid: u64,
     /// Group ID of the owner
     pub gid: u64,
-    /// User name (if available)
-    #[allow(dead_code)]
-    pub uname: Option<String>,
-    /// Group name (if available)
-    #[allow(dead_code)]
-    pub gname: Option<String>,
-    /// Layer hash that added/modified this entry
-    pub layer_hash: Option<String>,
+    /// Content size in bytes (regular files only)
+    pub size: u64,
+    /// Modification time in seconds since the epoch, as the last layer to
+    /// touch the entry recorded it
+    pub mtime: u64,
+    /// Fingerprint of the file contents, when requested while merging
+    pub content_hash: Option<u64>,
+    /// Extended attributes (from PAX `SCHILY.xattr.*` records)
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Layer hash that added/modified this entry, shared by every entry of
+    /// that layer
+    #[serde(deserialize_with = "deserialize_layer")]
+    pub layer_hash: Option<Arc<str>>,
+    /// Position of that layer in the image, bottom-most first; unlike the
+    /// hash, it tells apart identical layers
+    pub layer_index: Option<usize>,
+    /// Earlier versions of this file overwritten by later layers, oldest first
+    pub shadowed: Vec<NodeMetadata>,
+    /// Whether an opaque whiteout cleared this directory
+    pub opaque: bool,
+    /// Layer that applied the opaque whiteout (when layers are recorded)
+    #[serde(deserialize_with = "deserialize_layer")]
+    pub opaque_layer: Option<Arc<str>>,
+    /// Lower-layer entries hidden by an opaque whiteout (only when tracked)
+    pub masked: Vec<MaskedEntry>,
+    /// Device numbers, for character and block devices (only when kept)
+    pub device: Option<Device>,
+    /// Package that installed this file (only when owners are resolved)
+    pub owner: Option<String>,
+}
+
+/// A character or block device node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Char,
+    Block,
+}
+
+/// Summary of an entry hidden by an opaque whiteout
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaskedEntry {
+    /// Basename of the hidden entry
+    pub name: String,
+    pub is_dir: bool,
+    /// Number of entries beneath it (directories only)
+    pub descendants: usize,
+    /// Layer that had added it
+    #[serde(deserialize_with = "deserialize_layer")]
+    pub layer_hash: Option<Arc<str>>,
+    /// Position of that layer in the image
+    pub layer_index: Option<usize>,
 }
 
 impl Node {
     /// Create a new directory node
     pub fn new_dir(mode: u32, uid: u64, gid: u64) -> Self {
         Node {
-            children: HashMap::new(),
+            children: BTreeMap::new(),
             metadata: NodeMetadata {
                 is_file: false,
                 is_symlink: false,
@@ -49,9 +181,18 @@ impl Node {
                 mode,
                 uid,
                 gid,
-                uname: None,
-                gname: None,
+                size: 0,
+                mtime: 0,
+                content_hash: None,
+                xattrs: BTreeMap::new(),
                 layer_hash: None,
+                layer_index: None,
+                shadowed: Vec::new(),
+                opaque: false,
+                opaque_layer: None,
+                masked: Vec::new(),
+                device: None,
+                owner: None,
             },
         }
     }
@@ -59,7 +200,7 @@ impl Node {
     /// Create a new file node
     pub fn new_file(mode: u32, uid: u64, gid: u64) -> Self {
         Node {
-            children: HashMap::new(),
+            children: BTreeMap::new(),
             metadata: NodeMetadata {
                 is_file: true,
                 is_symlink: false,
@@ -68,31 +209,162 @@ impl Node {
                 mode,
                 uid,
                 gid,
-                uname: None,
-                gname: None,
+                size: 0,
+                mtime: 0,
+                content_hash: None,
+                xattrs: BTreeMap::new(),
                 layer_hash: None,
+                layer_index: None,
+                shadowed: Vec::new(),
+                opaque: false,
+                opaque_layer: None,
+                masked: Vec::new(),
+                device: None,
+                owner: None,
             },
         }
     }
 
-    /// Ensure a directory path exists in the tree, creating intermediate dirs as needed
-    pub fn ensure_path(&mut self, path: &str, mode: u32, uid: u64, gid: u64, layer_hash: Option<&str>) {
-        if path.is_empty() || path == "." {
-            return;
+    /// Look up the node at the given path (relative to this node)
+    /// An empty path returns this node itself
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        let mut current = self;
+        for part in components(path) {
+            current = current.children.get(part)?;
         }
+        Some(current)
+    }
 
-        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty() && *p != ".").collect();
+    /// Mutable counterpart of [`Node::get`]
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Node> {
         let mut current = self;
+        for part in components(path) {
+            current = current.children.get_mut(part)?;
+        }
+        Some(current)
+    }
+
+    /// A copy of the tree keeping only the entry at `path`, everything below
+    /// it, and the directories leading to it, so it renders with its full
+    /// path. None if nothing is at `path`.
+    pub fn subtree(&self, path: &str) -> Option<Node> {
+        fn prune(node: &Node, parts: &[&str]) -> Option<Node> {
+            let Some((first, rest)) = parts.split_first() else {
+                return Some(node.clone());
+            };
+            let (name, child) = node.children.get_key_value(*first)?;
+            let mut copy = Node { children: BTreeMap::new(), metadata: node.metadata.clone() };
+            copy.children.insert(Arc::clone(name), Box::new(prune(child, rest)?));
+            Some(copy)
+        }
+        prune(self, &components(path).collect::<Vec<_>>())
+    }
+
+    /// Rewrite `path` so none of its parent components is a symlink, the way
+    /// a container runtime follows links when extracting a layer. The final
+    /// component is left as is. Absolute targets resolve from this node,
+    /// relative ones from the link's directory, and `..` never climbs above
+    /// this node. Paths without symlinked parents are returned unchanged.
+    pub fn resolve_parents<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        // Same limit as Linux's MAXSYMLINKS
+        const MAX_HOPS: usize = 40;
+
+        let mut pending: VecDeque<String> = components(path)
+            .map(|p| p.to_string())
+            .collect();
+        let Some(last) = pending.pop_back() else {
+            return Cow::Borrowed(path);
+        };
+
+        let mut resolved: Vec<String> = Vec::new();
+        let mut stack: Vec<&Node> = vec![self];
+        let mut hops = 0;
+        let mut rewritten = false;
+        while let Some(part) = pending.pop_front() {
+            if part == ".." {
+                if resolved.pop().is_some() {
+                    stack.pop();
+                }
+                rewritten = true;
+                continue;
+            }
+
+            let Some(child) = stack.last().and_then(|node| node.children.get(part.as_str())) else {
+                // Nothing below here exists yet, so there are no more links to follow
+                resolved.push(part);
+                resolved.extend(pending.drain(..));
+                break;
+            };
+
+            match &child.metadata.symlink_target {
+                Some(target) if child.metadata.is_symlink && hops < MAX_HOPS => {
+                    hops += 1;
+                    rewritten = true;
+                    if target.starts_with('/') {
+                        resolved.clear();
+                        stack.truncate(1);
+                    }
+                    for component in components(target).rev() {
+                        pending.push_front(component.to_string());
+                    }
+                }
+                _ => {
+                    resolved.push(part);
+                    stack.push(child);
+                }
+            }
+        }
 
-        for part in parts {
-            current = current.children
-                .entry(part.to_string())
-                .or_insert_with(|| Node::new_dir(mode, uid, gid));
-            current.metadata.layer_hash = layer_hash.map(|s| s.to_string());
+        if !rewritten {
+            return Cow::Borrowed(path);
+        }
+        resolved.push(last);
+        Cow::Owned(resolved.join("/"))
+    }
+
+    /// Ensure a directory path exists in the tree, creating intermediate dirs as needed.
+    /// The directory takes `mode`, ownership and layer, whether it is new or
+    /// not, and keeps its children; whatever else is at `path` is replaced by
+    /// a new directory, as extracting a layer over it would. Parents are
+    /// left as they are, or created as root-owned 0755 directories.
+    pub fn ensure_path(&mut self, path: &str, mode: u32, uid: u64, gid: u64, layer_hash: Option<&Arc<str>>) {
+        let (dir_path, name) = utils::split_path(path);
+        if components(name).next().is_none() {
+            return;
+        }
+        let dir = self.ensure_dir(dir_path, layer_hash).child_or_insert(name, || Node::new_dir(mode, uid, gid));
+        if dir.metadata.is_file {
+            *dir = Node::new_dir(mode, uid, gid);
         }
+        let meta = &mut dir.metadata;
+        (meta.mode, meta.uid, meta.gid) = (mode, uid, gid);
+        meta.layer_hash = layer_hash.cloned();
     }
 
-    /// Add or update a file at the given path
+    /// The directory at `path`, creating what is missing as root-owned 0755
+    /// directories added by `layer_hash`; existing directories are left as
+    /// they are. A file or device in the way is replaced by a new directory
+    /// rather than growing children; a symlink is kept, since paths through
+    /// one only get here when they are applied literally.
+    fn ensure_dir(&mut self, path: &str, layer_hash: Option<&Arc<str>>) -> &mut Node {
+        let new_dir = || {
+            let mut dir = Node::new_dir(0o755, 0, 0);
+            dir.metadata.layer_hash = layer_hash.cloned();
+            dir
+        };
+        let mut current = self;
+        for part in components(path) {
+            current = current.child_or_insert(part, new_dir);
+            if current.metadata.is_file && !current.metadata.is_symlink {
+                *current = new_dir();
+            }
+        }
+        current
+    }
+
+    /// Add or update a file at the given path, replacing whatever was there:
+    /// a directory goes with everything beneath it
+    /// Returns the node previously stored at that path, if any
     #[allow(clippy::too_many_arguments)]
     pub fn put_file(
         &mut self,
@@ -102,53 +374,72 @@ impl Node {
         gid: u64,
         is_symlink: bool,
         link_target: Option<String>,
-        layer_hash: Option<&str>,
-    ) {
+        layer_hash: Option<&Arc<str>>,
+    ) -> Option<Node> {
         let (dir_path, basename) = utils::split_path(path);
-
-        // Ensure parent directory exists
-        if !dir_path.is_empty() {
-            self.ensure_path(dir_path, 0o755, 0, 0, layer_hash);
-        }
-
-        // Navigate to parent directory
-        let mut parent = self;
-        if !dir_path.is_empty() {
-            for part in dir_path.split('/').filter(|p| !p.is_empty() && *p != ".") {
-                // If parent doesn't exist, create it
-                parent = parent.children
-                    .entry(part.to_string())
-                    .or_insert_with(|| Node::new_dir(0o755, 0, 0));
-            }
-        }
+        let parent = self.ensure_dir(dir_path, layer_hash);
 
         // Create or update the file node
         let mut file_node = Node::new_file(mode, uid, gid);
         file_node.metadata.is_symlink = is_symlink;
         file_node.metadata.symlink_target = link_target;
-        file_node.metadata.layer_hash = layer_hash.map(|s| s.to_string());
+        file_node.metadata.layer_hash = layer_hash.cloned();
 
-        parent.children.insert(basename.to_string(), file_node);
+        match parent.children.get_mut(basename) {
+            Some(existing) => Some(std::mem::replace(&mut **existing, file_node)),
+            None => {
+                parent.children.insert(intern(basename), Box::new(file_node));
+                None
+            }
+        }
     }
 
-    /// Set hard link target for a file node
-    /// Returns Ok(()) if successful, Err if the path doesn't exist
-    pub fn set_hardlink_target(&mut self, path: &str, target: String) -> anyhow::Result<()> {
-        let (dir_path, basename) = utils::split_path(path);
+    /// Record an overwritten version of the file at `path`
+    /// Versions the old node had already shadowed are carried over, so the
+    /// history stays ordered oldest first.
+    pub fn push_shadowed(&mut self, path: &str, mut previous: NodeMetadata) {
+        let Some(current) = self.get_mut(path) else {
+            return;
+        };
 
-        let mut parent = self;
-        if !dir_path.is_empty() {
-            for part in dir_path.split('/').filter(|p| !p.is_empty() && *p != ".") {
-                parent = parent.children
-                    .get_mut(part)
-                    .ok_or_else(|| anyhow::anyhow!("Parent directory '{}' not found", part))?;
+        let mut history = std::mem::take(&mut previous.shadowed);
+        history.push(previous);
+        history.append(&mut current.metadata.shadowed);
+        current.metadata.shadowed = history;
+    }
+
+    /// Set `layer_index` on every entry, shadowed version and masked entry
+    /// recorded with one of `layers`, its position there. Layers are told
+    /// apart by the label they were recorded with, not its text, so identical
+    /// layers get their own positions.
+    pub fn number_layers(&mut self, layers: &[Arc<str>]) {
+        fn number(meta: &mut NodeMetadata, positions: &HashMap<*const u8, usize>) {
+            let position = |hash: &Option<Arc<str>>| hash.as_ref().and_then(|h| positions.get(&h.as_ptr()).copied());
+            meta.layer_index = position(&meta.layer_hash);
+            for previous in &mut meta.shadowed {
+                number(previous, positions);
+            }
+            for masked in &mut meta.masked {
+                masked.layer_index = position(&masked.layer_hash);
+            }
+        }
+        fn number_children(node: &mut Node, positions: &HashMap<*const u8, usize>) {
+            for child in node.children.values_mut() {
+                number(&mut child.metadata, positions);
+                number_children(child, positions);
             }
         }
 
-        let node = parent.children
-            .get_mut(basename)
-            .ok_or_else(|| anyhow::anyhow!("File '{}' not found", basename))?;
+        let positions: HashMap<*const u8, usize> = layers.iter().enumerate()
+            .map(|(i, layer)| (layer.as_ptr(), i))
+            .collect();
+        number_children(self, &positions);
+    }
 
+    /// Set hard link target for a file node
+    /// Returns Ok(()) if successful, Err if the path doesn't exist
+    pub fn set_hardlink_target(&mut self, path: &str, target: String) -> anyhow::Result<()> {
+        let node = self.get_mut(path).ok_or_else(|| anyhow::anyhow!("'{}' not found", path))?;
         node.metadata.hardlink_target = Some(target);
         Ok(())
     }
@@ -156,39 +447,214 @@ impl Node {
     /// Remove a node at the given path (for whiteouts)
     pub fn remove(&mut self, path: &str) {
         let (dir_path, basename) = utils::split_path(path);
+        if let Some(parent) = self.get_mut(dir_path) {
+            parent.children.remove(basename);
+        }
+    }
+
+    /// Mark a directory as opaque by clearing all its children. With
+    /// `keep_masked`, a summary of the hidden entries is kept on the directory.
+    pub fn mark_opaque(&mut self, path: &str, layer_hash: Option<&Arc<str>>, keep_masked: bool) {
+        let Some(current) = self.get_mut(path) else {
+            return;
+        };
 
-        let mut parent = self;
-        if !dir_path.is_empty() {
-            for part in dir_path.split('/').filter(|p| !p.is_empty() && *p != ".") {
-                if let Some(node) = parent.children.get_mut(part) {
-                    parent = node;
-                } else {
-                    return; // Path doesn't exist, nothing to remove
+        if keep_masked {
+            let masked = current.children.iter()
+                .map(|(name, child)| MaskedEntry {
+                    name: name.to_string(),
+                    is_dir: !child.metadata.is_file,
+                    descendants: child.count_descendants(),
+                    layer_hash: child.metadata.layer_hash.clone(),
+                    layer_index: child.metadata.layer_index,
+                });
+            current.metadata.masked.extend(masked);
+        }
+
+        current.metadata.opaque = true;
+        current.metadata.opaque_layer = layer_hash.cloned();
+        current.children.clear();
+    }
+
+    /// The child named `name`, created with `make` if it doesn't exist yet
+    fn child_or_insert(&mut self, name: &str, make: impl FnOnce() -> Node) -> &mut Node {
+        if !self.children.contains_key(name) {
+            self.children.insert(intern(name), Box::new(make()));
+        }
+        self.children.get_mut(name).expect("child was just inserted")
+    }
+
+    /// Count all entries below this node
+    pub fn count_descendants(&self) -> usize {
+        self.children.values().map(|c| 1 + c.count_descendants()).sum()
+    }
+
+    /// Bytes of content in this file, or in every file below this directory
+    pub fn total_size(&self) -> u64 {
+        self.metadata.size + self.children.values().map(|c| c.total_size()).sum::<u64>()
+    }
+
+    /// Every entry below this node with its path relative to it, parents
+    /// before children and siblings in name order
+    pub fn walk(&self) -> Walk<'_> {
+        Walk { stack: vec![self.children.iter()], dir: PathBuf::new() }
+    }
+
+    /// Depth-first traversal of the entries below this node, calling
+    /// `visitor` on the way into and out of each
+    pub fn visit<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        fn visit_children<V: Visitor + ?Sized>(node: &Node, path: &mut PathBuf, visitor: &mut V) {
+            for (name, child) in &node.children {
+                path.push(&**name);
+                if visitor.enter(path, child) {
+                    visit_children(child, path, visitor);
                 }
+                visitor.leave(path, child);
+                path.pop();
             }
         }
+        visit_children(self, &mut PathBuf::new(), visitor);
+    }
 
-        parent.children.remove(basename);
+    /// Write the tree as JSON, tagged with [`SCHEMA_VERSION`]
+    pub fn to_json<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        self.to_json_with_layers(writer, &[])
     }
 
-    /// Mark a directory as opaque by clearing all its children
-    pub fn mark_opaque(&mut self, path: &str) {
-        if path.is_empty() || path == "." {
-            self.children.clear();
-            return;
-        }
+    /// Write the tree as JSON like [`Node::to_json`], with the labels of the
+    /// layers it was merged from, bottom-most first
+    pub fn to_json_with_layers<W: Write>(&self, writer: W, layers: &[String]) -> anyhow::Result<()> {
+        let document = TreeDocument { schema: SCHEMA_VERSION, layers: layers.to_vec(), root: self };
+        serde_json::to_writer(writer, &document).context("Failed to serialize tree")
+    }
 
-        let mut current = self;
-        for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
-            if let Some(node) = current.children.get_mut(part) {
-                current = node;
-            } else {
-                return; // Path doesn't exist
+    /// Read a tree written by [`Node::to_json`], refusing other schema versions
+    pub fn from_json<R: Read>(mut reader: R) -> anyhow::Result<Node> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).context("Failed to read tree")?;
+        Ok(read_document(&bytes)?.root)
+    }
+}
+
+/// Parse one [`TreeDocument`], refusing other schema versions
+fn read_document(bytes: &[u8]) -> anyhow::Result<TreeDocument<Node>> {
+    // Check the version first so a layout change reads as one, not as a parse error
+    let header: SchemaHeader = serde_json::from_slice(bytes).context("Failed to parse tree")?;
+    if header.schema != SCHEMA_VERSION {
+        bail!("Tree uses schema version {} (expected {})", header.schema, SCHEMA_VERSION);
+    }
+    serde_json::from_slice(bytes).context("Failed to parse tree")
+}
+
+/// Write several trees, each with its layer labels, as one JSON array of
+/// [`Node::to_json_with_layers`] documents, each also naming the archive it came from
+pub fn trees_to_json<W: Write>(writer: W, trees: &[(&str, &Node, &[String])]) -> anyhow::Result<()> {
+    let documents: Vec<_> = trees.iter()
+        .map(|&(archive, root, layers)| NamedTreeDocument {
+            archive,
+            schema: SCHEMA_VERSION,
+            layers: layers.to_vec(),
+            root,
+        })
+        .collect();
+    serde_json::to_writer(writer, &documents).context("Failed to serialize trees")
+}
+
+/// A tree read back by [`trees_from_json`]
+#[derive(Debug)]
+pub struct SavedTree {
+    /// The archive the tree came from, when a listing names it
+    pub archive: Option<String>,
+    /// Labels of the layers the tree was merged from, bottom-most first, if saved
+    pub layers: Vec<String>,
+    pub root: Node,
+}
+
+/// Read a tree written by [`Node::to_json`], or the listing written by
+/// [`trees_to_json`], refusing other schema versions
+pub fn trees_from_json<R: Read>(mut reader: R) -> anyhow::Result<Vec<SavedTree>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).context("Failed to read trees")?;
+    if bytes.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[') {
+        let document = read_document(&bytes)?;
+        return Ok(vec![SavedTree { archive: None, layers: document.layers, root: document.root }]);
+    }
+    let headers: Vec<SchemaHeader> = serde_json::from_slice(&bytes).context("Failed to parse trees")?;
+    if let Some(header) = headers.iter().find(|header| header.schema != SCHEMA_VERSION) {
+        bail!("Tree uses schema version {} (expected {})", header.schema, SCHEMA_VERSION);
+    }
+    let documents: Vec<NamedTreeDocument<String, Node>> =
+        serde_json::from_slice(&bytes).context("Failed to parse trees")?;
+    Ok(documents.into_iter()
+        .map(|document| SavedTree { archive: Some(document.archive), layers: document.layers, root: document.root })
+        .collect())
+}
+
+/// Iterator over the entries below a node, from [`Node::walk`]
+pub struct Walk<'a> {
+    /// Remaining children of each directory being walked, innermost last
+    stack: Vec<btree_map::Iter<'a, Arc<str>, Box<Node>>>,
+    /// Path of the innermost directory
+    dir: PathBuf,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = (PathBuf, &'a NodeMetadata);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                Some((name, child)) => {
+                    let path = self.dir.join(&**name);
+                    if !child.children.is_empty() {
+                        self.stack.push(child.children.iter());
+                        self.dir = path.clone();
+                    }
+                    return Some((path, &child.metadata));
+                }
+                None => {
+                    self.stack.pop();
+                    self.dir.pop();
+                }
             }
         }
+    }
+}
 
-        current.children.clear();
+/// Callbacks for [`Node::visit`]. Paths are relative to the node visited.
+pub trait Visitor {
+    /// Called for each entry before anything below it; returning false skips
+    /// its children
+    fn enter(&mut self, _path: &Path, _node: &Node) -> bool {
+        true
     }
+
+    /// Called for each entry after everything below it
+    fn leave(&mut self, _path: &Path, _node: &Node) {}
+}
+
+/// A serialized tree: the schema version, the layer labels if known, then the root
+#[derive(Serialize, Deserialize)]
+struct TreeDocument<N> {
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layers: Vec<String>,
+    root: N,
+}
+
+/// A serialized tree in a listing of several
+#[derive(Serialize, Deserialize)]
+struct NamedTreeDocument<S, N> {
+    archive: S,
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layers: Vec<String>,
+    root: N,
+}
+
+#[derive(Deserialize)]
+struct SchemaHeader {
+    schema: u32,
 }
 
 #[cfg(test)]
@@ -205,6 +671,28 @@ mod tests {
         assert!(root.children["foo"].children["bar"].children.contains_key("baz"));
     }
 
+    #[test]
+    fn test_ensure_path_updates_only_the_entry() {
+        let (base, app): (Arc<str>, Arc<str>) = ("aaa".into(), "bbb".into());
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.ensure_path("srv", 0o700, 0, 0, Some(&base));
+        root.put_file("srv/www/index.html", 0o644, 33, 33, false, None, Some(&base));
+
+        // A later entry for an existing directory changes it, not its children
+        root.ensure_path("srv/www", 0o750, 33, 33, Some(&app));
+        let www = root.get("srv/www").unwrap();
+        assert_eq!((www.metadata.mode, www.metadata.uid, www.metadata.layer_hash.as_deref()), (0o750, 33, Some("bbb")));
+        assert!(www.children.contains_key("index.html"));
+        // Parents keep their own metadata
+        let srv = &root.get("srv").unwrap().metadata;
+        assert_eq!((srv.mode, srv.layer_hash.as_deref()), (0o700, Some("aaa")));
+
+        // Implicit parents are plain directories of the layer that needed them
+        root.ensure_path("opt/app/data", 0o700, 1000, 1000, Some(&app));
+        let opt = &root.get("opt/app").unwrap().metadata;
+        assert_eq!((opt.mode, opt.uid, opt.layer_hash.as_deref()), (0o755, 0, Some("bbb")));
+    }
+
     #[test]
     fn test_put_file() {
         let mut root = Node::new_dir(0o755, 0, 0);
@@ -214,4 +702,280 @@ mod tests {
         assert!(root.children["foo"].children.contains_key("bar.txt"));
         assert!(root.children["foo"].children["bar.txt"].metadata.is_file);
     }
+
+    #[test]
+    fn test_type_changes() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("opt/app/run", 0o755, 0, 0, false, None, None);
+        root.put_file("etc/motd", 0o644, 0, 0, true, Some("issue".to_string()), None);
+        root.get_mut("etc/motd").unwrap().metadata.size = 5;
+
+        // A directory replaced by a file leaves nothing beneath it
+        let replaced = root.put_file("opt/app", 0o644, 0, 0, false, None, None).unwrap();
+        assert_eq!(replaced.count_descendants(), 1);
+        root.ensure_path("opt/app", 0o700, 0, 0, None);
+        assert!(root.get("opt/app/run").is_none());
+
+        // A file replaced by a directory keeps none of the file's metadata
+        root.ensure_path("etc/motd", 0o750, 1, 1, None);
+        let motd = &root.get("etc/motd").unwrap().metadata;
+        assert!(!motd.is_file && !motd.is_symlink);
+        assert_eq!((motd.mode, motd.uid, motd.size, motd.symlink_target.as_deref()), (0o750, 1, 0, None));
+
+        // Also when the file is only in the way of a deeper path, but not a
+        // symlink, which only a literal path leads through
+        root.put_file("etc/issue", 0o644, 0, 0, false, None, None);
+        root.put_file("etc/issue/net", 0o644, 0, 0, false, None, None);
+        assert!(!root.get("etc/issue").unwrap().metadata.is_file);
+        assert!(root.get("etc/issue/net").unwrap().metadata.is_file);
+        root.put_file("data", 0o777, 0, 0, true, Some("/var/lib/data".to_string()), None);
+        root.put_file("data/file", 0o644, 0, 0, false, None, None);
+        assert!(root.get("data").unwrap().metadata.is_symlink);
+    }
+
+    #[test]
+    fn test_names_and_layers_are_shared() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        let layer: Arc<str> = "aaa".into();
+        root.put_file("a/index.js", 0o644, 0, 0, false, None, Some(&layer));
+        root.put_file("b/index.js", 0o644, 0, 0, false, None, Some(&layer));
+
+        let (a, _) = root.children["a"].children.get_key_value("index.js").unwrap();
+        let (b, node) = root.children["b"].children.get_key_value("index.js").unwrap();
+        assert!(Arc::ptr_eq(a, b));
+        assert!(Arc::ptr_eq(node.metadata.layer_hash.as_ref().unwrap(), &layer));
+    }
+
+    #[test]
+    fn test_dropped_trees_free_their_names() {
+        let mut kept = Node::new_dir(0o755, 0, 0);
+        kept.put_file("kept", 0o644, 0, 0, false, None, None);
+        // Trees built and dropped one after another, as redraws do
+        for round in 0..10 {
+            let mut root = Node::new_dir(0o755, 0, 0);
+            for i in 0..MIN_PRUNE {
+                root.put_file(&format!("r{}-{}", round, i), 0o644, 0, 0, false, None, None);
+            }
+        }
+        assert!(NAMES.with(|interner| interner.borrow().names.len()) <= 2 * MIN_PRUNE);
+
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("kept", 0o644, 0, 0, false, None, None);
+        let (a, _) = kept.children.get_key_value("kept").unwrap();
+        let (b, _) = root.children.get_key_value("kept").unwrap();
+        assert!(Arc::ptr_eq(a, b));
+    }
+
+    #[test]
+    fn test_get() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("foo/bar.txt", 0o644, 0, 0, false, None, None);
+
+        assert!(root.get("foo/bar.txt").unwrap().metadata.is_file);
+        assert!(!root.get("./foo/").unwrap().metadata.is_file);
+        assert!(root.get("").is_some());
+        assert!(root.get("foo/missing").is_none());
+    }
+
+    #[test]
+    fn test_mark_opaque_records_masked() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        let (aaa, bbb): (Arc<str>, Arc<str>) = ("aaa".into(), "bbb".into());
+        root.put_file("var/cache/apk/index", 0o644, 0, 0, false, None, Some(&aaa));
+        root.put_file("var/cache/motd", 0o644, 0, 0, false, None, Some(&aaa));
+
+        root.mark_opaque("var/cache", Some(&bbb), true);
+
+        let cache = root.get("var/cache").unwrap();
+        assert!(cache.children.is_empty());
+        assert!(cache.metadata.opaque);
+        assert_eq!(cache.metadata.opaque_layer.as_deref(), Some("bbb"));
+        assert_eq!(cache.metadata.masked, vec![
+            MaskedEntry {
+                name: "apk".to_string(), is_dir: true, descendants: 1, layer_hash: Some(aaa.clone()), layer_index: None,
+            },
+            MaskedEntry {
+                name: "motd".to_string(), is_dir: false, descendants: 0, layer_hash: Some(aaa.clone()), layer_index: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_number_layers_tells_identical_labels_apart() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        // The same layer applied twice, then one that clears a directory
+        let layers: Vec<Arc<str>> = vec!["aaa".into(), "aaa".into(), "bbb".into()];
+        root.put_file("etc/motd", 0o644, 0, 0, false, None, Some(&layers[0]));
+        root.put_file("var/cache/index", 0o644, 0, 0, false, None, Some(&layers[0]));
+        let previous = root.put_file("etc/motd", 0o644, 0, 0, false, None, Some(&layers[1])).unwrap();
+        root.push_shadowed("etc/motd", previous.metadata);
+        root.mark_opaque("var/cache", Some(&layers[2]), true);
+        root.number_layers(&layers);
+
+        let motd = &root.get("etc/motd").unwrap().metadata;
+        assert_eq!((motd.layer_hash.as_deref(), motd.layer_index), (Some("aaa"), Some(1)));
+        assert_eq!(motd.shadowed[0].layer_index, Some(0));
+        assert_eq!(root.get("etc").unwrap().metadata.layer_index, Some(0));
+        assert_eq!(root.get("var/cache").unwrap().metadata.masked[0].layer_index, Some(0));
+    }
+
+    #[test]
+    fn test_resolve_parents_follows_symlinked_dirs() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.ensure_path("var/lib/data", 0o755, 0, 0, None);
+        root.put_file("data", 0o777, 0, 0, true, Some("/var/lib/data".to_string()), None);
+        root.put_file("var/lib/cache", 0o777, 0, 0, true, Some("../../tmp".to_string()), None);
+        root.ensure_path("tmp", 0o755, 0, 0, None);
+        root.put_file("loop", 0o777, 0, 0, true, Some("loop".to_string()), None);
+
+        assert_eq!(root.resolve_parents("data/file"), "var/lib/data/file");
+        assert_eq!(root.resolve_parents("data/sub/dir/file"), "var/lib/data/sub/dir/file");
+        assert_eq!(root.resolve_parents("var/lib/cache/x"), "tmp/x");
+        // The final component is never followed
+        assert_eq!(root.resolve_parents("data"), "data");
+        assert!(matches!(root.resolve_parents("etc/passwd"), Cow::Borrowed("etc/passwd")));
+        // Self-referencing links give up instead of spinning
+        assert_eq!(root.resolve_parents("loop/file"), "loop/file");
+    }
+
+    #[test]
+    fn test_push_shadowed_keeps_order() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, Some(&"aaa".into()));
+
+        for layer in ["bbb", "ccc"] {
+            let previous = root.put_file("etc/passwd", 0o600, 0, 0, false, None, Some(&layer.into())).unwrap();
+            root.push_shadowed("etc/passwd", previous.metadata);
+        }
+
+        let node = &root.children["etc"].children["passwd"];
+        assert_eq!(node.metadata.layer_hash.as_deref(), Some("ccc"));
+        let layers: Vec<_> = node.metadata.shadowed.iter()
+            .map(|m| m.layer_hash.as_deref().unwrap())
+            .collect();
+        assert_eq!(layers, vec!["aaa", "bbb"]);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let layer: Arc<str> = "aaaaaaa".into();
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("dev/null", 0o666, 0, 0, false, None, Some(&layer));
+        root.get_mut("dev/null").unwrap().metadata.device = Some(Device { kind: DeviceKind::Char, major: 1, minor: 3 });
+        root.put_file("etc/passwd", 0o644, 0, 0, false, None, Some(&layer));
+
+        let mut json = Vec::new();
+        root.to_json(&mut json).unwrap();
+        let text = String::from_utf8(json.clone()).unwrap();
+        assert!(text.starts_with(&format!("{{\"schema\":{},\"root\":{{\"children\":", SCHEMA_VERSION)));
+        assert!(text.contains("\"kind\":\"char\""));
+
+        let decoded = Node::from_json(json.as_slice()).unwrap();
+        assert_eq!(decoded.count_descendants(), 4);
+        assert_eq!(decoded.get("dev/null").unwrap().metadata.device, root.get("dev/null").unwrap().metadata.device);
+        assert_eq!(decoded.get("etc/passwd").unwrap().metadata.layer_hash.as_deref(), Some("aaaaaaa"));
+
+        let future = text.replacen(&format!("\"schema\":{}", SCHEMA_VERSION), "\"schema\":99", 1);
+        let err = Node::from_json(future.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), format!("Tree uses schema version 99 (expected {})", SCHEMA_VERSION));
+
+        let mut json = Vec::new();
+        let layers = vec!["aaaaaaa".to_string()];
+        trees_to_json(&mut json, &[("a.tar", &root, &layers), ("b.tar", &decoded, &[])]).unwrap();
+        let listing: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(listing[1]["archive"], "b.tar");
+        assert_eq!(listing[1]["schema"], SCHEMA_VERSION);
+
+        assert_eq!(listing[0]["layers"][0], "aaaaaaa");
+        assert!(listing[1].get("layers").is_none());
+
+        let trees = trees_from_json(json.as_slice()).unwrap();
+        let archives: Vec<_> = trees.iter().map(|tree| tree.archive.as_deref()).collect();
+        assert_eq!(archives, [Some("a.tar"), Some("b.tar")]);
+        assert_eq!(trees[0].layers, layers);
+        assert_eq!(trees[1].root.count_descendants(), 4);
+        let single = trees_from_json(text.as_bytes()).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!((single[0].archive.as_deref(), single[0].layers.len()), (None, 0));
+        let listing = String::from_utf8(json).unwrap();
+        let past = listing.replacen(&format!("\"schema\":{}", SCHEMA_VERSION), "\"schema\":1", 1);
+        assert!(trees_from_json(past.as_bytes()).is_err());
+    }
+
+    fn sample_tree() -> Node {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        for path in ["usr/bin/sh", "etc/passwd", "usr/lib/libc.so", "etc/hosts", "usr/bin/env"] {
+            root.put_file(path, 0o644, 0, 0, false, None, None);
+        }
+        root.get_mut("usr/bin/sh").unwrap().metadata.size = 100;
+        root.get_mut("usr/lib/libc.so").unwrap().metadata.size = 2000;
+        root
+    }
+
+    #[test]
+    fn test_walk_is_sorted_parents_first() {
+        let root = sample_tree();
+        let paths: Vec<String> = root.walk().map(|(path, _)| path.display().to_string()).collect();
+        assert_eq!(paths, vec![
+            "etc", "etc/hosts", "etc/passwd",
+            "usr", "usr/bin", "usr/bin/env", "usr/bin/sh", "usr/lib", "usr/lib/libc.so",
+        ]);
+        let (_, sh) = root.walk().find(|(path, _)| path == Path::new("usr/bin/sh")).unwrap();
+        assert_eq!(sh.size, 100);
+        assert_eq!(Node::new_dir(0o755, 0, 0).walk().count(), 0);
+    }
+
+    #[test]
+    fn test_visitor_enter_and_leave() {
+        /// Total file size of each directory, skipping usr/lib
+        #[derive(Default)]
+        struct DirSizes {
+            open: Vec<u64>,
+            sizes: Vec<(String, u64)>,
+        }
+        impl Visitor for DirSizes {
+            fn enter(&mut self, path: &Path, node: &Node) -> bool {
+                if node.metadata.is_file {
+                    if let Some(total) = self.open.last_mut() {
+                        *total += node.metadata.size;
+                    }
+                    return true;
+                }
+                self.open.push(0);
+                path != Path::new("usr/lib")
+            }
+
+            fn leave(&mut self, path: &Path, node: &Node) {
+                if !node.metadata.is_file {
+                    let total = self.open.pop().unwrap();
+                    if let Some(parent) = self.open.last_mut() {
+                        *parent += total;
+                    }
+                    self.sizes.push((path.display().to_string(), total));
+                }
+            }
+        }
+
+        let mut sizes = DirSizes::default();
+        sample_tree().visit(&mut sizes);
+        assert_eq!(sizes.sizes, vec![
+            ("etc".to_string(), 0),
+            ("usr/bin".to_string(), 100),
+            ("usr/lib".to_string(), 0),
+            ("usr".to_string(), 100),
+        ]);
+    }
+
+    #[test]
+    fn test_subtree() {
+        let root = sample_tree();
+        let bin = root.subtree("/usr/bin/").unwrap();
+        let paths: Vec<String> = bin.walk().map(|(path, _)| path.display().to_string()).collect();
+        assert_eq!(paths, vec!["usr", "usr/bin", "usr/bin/env", "usr/bin/sh"]);
+        assert_eq!(bin.get("usr/bin/sh").unwrap().metadata.size, 100);
+
+        assert_eq!(root.subtree("etc/passwd").unwrap().count_descendants(), 2);
+        assert_eq!(root.subtree("").unwrap().count_descendants(), root.count_descendants());
+        assert!(root.subtree("usr/sbin").is_none());
+    }
 }
@@ -0,0 +1,1119 @@
+//! `contree tui`: browse the merged tree of an image interactively
+//!
+//! Directories fold and unfold under the cursor, the panel beside the tree
+//! shows the metadata of the selected entry, and `/` searches names as you
+//! type. The layer sidebar scrubs through the build: Tab shows the image as
+//! it was after the next layer (merged on demand), `m` narrows that to what
+//! the layer itself changed. Enter on a file reads its contents back out of
+//! the layers ([`content`]) into a preview: text with comments and strings
+//! picked out, or a hexdump.
+//!
+//! Given two images, it browses the new one against the old instead: added,
+//! deleted and modified entries in color, with everything unchanged hidden
+//! until `m` shows it.
+//!
+//! Space marks entries, and `x` exports the marked ones: as a list of paths,
+//! as files extracted to a directory ([`extract`]), or as a filter of globs
+//! to hand to later runs.
+
+use anyhow::{bail, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::cell::OnceCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use contree::archive::{self, Image, LayerSet, ProcessOptions};
+use contree::content::{self, FileContent};
+use contree::diff::{self, DiffOptions, FileChange};
+use contree::extract::{self, ExtractSummary};
+use contree::layerdiff::Change;
+use contree::tree::{DeviceKind, Node};
+use contree::{layers, render, utils};
+
+/// How much of a file the preview reads
+const PREVIEW_LIMIT: usize = 256 << 10;
+
+/// Reads the start of a file of the image as of a layer (None for the
+/// whole image), for the preview
+type ReadFn<'a> = dyn Fn(&str, Option<usize>) -> Result<FileContent> + 'a;
+
+/// Merges the layers up to and including an index
+type MergeFn<'a> = dyn Fn(usize) -> Result<Node> + 'a;
+
+/// Extracts paths of the image into a directory
+type ExtractFn<'a> = dyn Fn(&[&str], &Path) -> Result<ExtractSummary> + 'a;
+
+/// Fail unless there is a terminal to browse in, before the image is loaded for nothing
+pub fn ensure_terminal() -> Result<()> {
+    if !atty::is(atty::Stream::Stdout) || !atty::is(atty::Stream::Stdin) {
+        bail!("contree tui needs a terminal");
+    }
+    Ok(())
+}
+
+/// Browse `image`, merged from `layer_set` with `options`, until the user quits
+pub fn run(image: &Image, layer_set: &LayerSet, options: &ProcessOptions) -> Result<()> {
+    let screen = Screen { title: image_name(image), layers: layer_items(image) };
+    let merge = |index: usize| Ok(archive::merge_layers(&layer_set.layers[..=index], options)?);
+    let timeline = Timeline::new(&image.root, image.layer_labels.len(), &merge);
+    let read = |path: &str, layer: Option<usize>| match layer {
+        Some(index) => content::read_file_in(&layer_set.layers[..=index], path, PREVIEW_LIMIT),
+        None => content::read_file(layer_set, path, PREVIEW_LIMIT),
+    };
+    let extract = |paths: &[&str], output: &Path| extract::extract_paths(layer_set, paths, output);
+    let mut browser = Browser::new(&timeline, &image.layer_labels, &read, &extract);
+    let mut terminal = ratatui::try_init()?;
+    let result = browse(&mut terminal, &mut browser, &screen);
+    ratatui::try_restore()?;
+    result
+}
+
+/// Browse `new` against `old`, each merged from its layer set, until the user quits
+pub fn run_diff(old: &Image, old_layers: &LayerSet, new: &Image, new_layers: &LayerSet) -> Result<()> {
+    let screen = Screen { title: format!("{} → {}", image_name(old), image_name(new)), layers: Vec::new() };
+    let comparison = Comparison::new(&old.root, &new.root);
+    let root = combine(&old.root, &new.root, &comparison);
+    let merge = |_: usize| bail!("There are no layers to merge when comparing images");
+    let timeline = Timeline::new(&root, 0, &merge);
+    // Deleted files are only in the old image
+    let read = |path: &str, _: Option<usize>| match comparison.changes.get(path) {
+        Some(change) if change.change == Change::Deleted => content::read_file(old_layers, path, PREVIEW_LIMIT),
+        _ => content::read_file(new_layers, path, PREVIEW_LIMIT),
+    };
+    let extract = |paths: &[&str], output: &Path| extract::extract_paths(new_layers, paths, output);
+    let mut browser = Browser::new(&timeline, &new.layer_labels, &read, &extract).comparing(&comparison);
+    let mut terminal = ratatui::try_init()?;
+    let result = browse(&mut terminal, &mut browser, &screen);
+    ratatui::try_restore()?;
+    result
+}
+
+fn image_name(image: &Image) -> String {
+    if image.repo_tags.is_empty() { "(untagged)".to_string() } else { image.repo_tags.join(", ") }
+}
+
+/// What the screen shows besides the tree: the image's name and its layers
+struct Screen {
+    title: String,
+    /// The sidebar: the whole image, then each layer; none when comparing
+    layers: Vec<ListItem<'static>>,
+}
+
+/// What changed between two images, by path
+struct Comparison {
+    changes: HashMap<String, FileChange>,
+}
+
+impl Comparison {
+    fn new(old: &Node, new: &Node) -> Self {
+        let changes = diff::diff_trees(old, new, &DiffOptions::default()).flatten();
+        Comparison { changes: changes.into_iter().map(|change| (change.path.clone(), change)).collect() }
+    }
+}
+
+/// The new tree with the entries deleted from the old one put back, so the
+/// browser shows both
+fn combine(old: &Node, new: &Node, comparison: &Comparison) -> Node {
+    let mut root = new.clone();
+    let mut deleted: Vec<&str> = comparison.changes.values()
+        .filter(|change| change.change == Change::Deleted)
+        .map(|change| change.path.as_str())
+        .collect();
+    // Parents first; their copies bring the deleted entries beneath them along
+    deleted.sort_unstable();
+    for path in deleted {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let (Some(node), Some(parent)) = (old.get(path), root.get_mut(parent)) else {
+            continue;
+        };
+        if !parent.metadata.is_file && !parent.children.contains_key(name) {
+            parent.children.insert(name.into(), Box::new(node.clone()));
+        }
+    }
+    root
+}
+
+/// The sidebar entries of an image: size and build step of each layer
+fn layer_items(image: &Image) -> Vec<ListItem<'static>> {
+    let history = image.config.as_ref().map(|config| config.history.as_slice()).unwrap_or_default();
+    let history = layers::without_missing(history, &image.missing_layers);
+    let details = layers::layer_details(&layers::align_history(&image.layer_labels, &history), &image.layer_sizes);
+    let whole = ListItem::new(vec![Line::raw("Whole image"), Line::raw("")]);
+    let layers = image.layer_labels.iter().enumerate().map(|(index, label)| {
+        let detail = details.get(index).cloned().unwrap_or_default();
+        ListItem::new(vec![
+            Line::raw(format!("#{} {} {}", index, label, utils::human_size(detail.size))),
+            Line::styled(format!("  {}", detail.command), Style::new().fg(Color::DarkGray)),
+        ])
+    });
+    std::iter::once(whole).chain(layers).collect()
+}
+
+/// Draw and handle keys until the user quits
+fn browse(terminal: &mut DefaultTerminal, browser: &mut Browser, screen: &Screen) -> Result<()> {
+    // Kept between frames, so the list scrolls only when the cursor leaves it
+    let mut state = ListState::default();
+    loop {
+        state.select(Some(browser.selected));
+        terminal.draw(|frame| draw(frame, browser, &mut state, screen))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && !browser.handle_key(key) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// One visible line of the tree
+struct Row<'a> {
+    /// Path from the root, without the leading slash
+    path: String,
+    name: &'a str,
+    depth: usize,
+    node: &'a Node,
+}
+
+/// The image as of each layer, merged the first time it is shown
+struct Timeline<'a> {
+    image: &'a Node,
+    /// Layers below the top one; the image itself is the top one's
+    snapshots: Vec<OnceCell<Node>>,
+    merge: &'a MergeFn<'a>,
+}
+
+impl<'a> Timeline<'a> {
+    fn new(image: &'a Node, layers: usize, merge: &'a MergeFn<'a>) -> Self {
+        Timeline { image, snapshots: (1..layers).map(|_| OnceCell::new()).collect(), merge }
+    }
+
+    /// The tree as of layer `index` (None for the whole image)
+    fn tree(&self, index: Option<usize>) -> Result<&Node> {
+        let Some(snapshot) = index.and_then(|index| self.snapshots.get(index)) else {
+            return Ok(self.image);
+        };
+        if snapshot.get().is_none() {
+            let _ = snapshot.set((self.merge)(index.unwrap_or_default())?);
+        }
+        Ok(snapshot.get().expect("snapshot was just merged"))
+    }
+}
+
+/// A question in the status line, answered before the tree gets keys again
+enum Prompt {
+    /// Which form to export the marked entries in
+    ExportAs,
+    /// Where to export them, as typed so far
+    ExportTo(Export, String),
+}
+
+/// A form the marked entries can be exported in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Export {
+    /// Their paths, one per line
+    Paths,
+    /// Their files, extracted into a directory
+    Files,
+    /// A line of globs matching them
+    Filter,
+}
+
+/// A file's contents, ready to draw
+struct Preview {
+    title: String,
+    lines: Vec<Line<'static>>,
+    /// The first line shown
+    scroll: usize,
+}
+
+/// What is unfolded, selected, searched for and previewed in the tree
+struct Browser<'a> {
+    timeline: &'a Timeline<'a>,
+    /// The tree shown: the image as of `layer`
+    root: &'a Node,
+    layer_labels: &'a [String],
+    read: &'a ReadFn<'a>,
+    extract: &'a ExtractFn<'a>,
+    /// When browsing one image against another
+    comparison: Option<&'a Comparison>,
+    /// Shown instead of the details while open; keys scroll it
+    preview: Option<Preview>,
+    /// Paths of the unfolded directories
+    expanded: HashSet<String>,
+    /// Index into `layer_labels` of the layer picked in the sidebar
+    layer: Option<usize>,
+    /// Show only the entries the picked layer (or the new image) added or changed
+    changes_only: bool,
+    /// With changes only: paths of the changed entries and of the directories holding them
+    kept: HashSet<String>,
+    rows: Vec<Row<'a>>,
+    selected: usize,
+    /// Paths of the entries marked for export
+    marked: BTreeSet<String>,
+    prompt: Option<Prompt>,
+    query: String,
+    /// Whether keys go to the search query
+    typing: bool,
+    /// Rows that fit on the screen, for Page Up and Page Down
+    page: usize,
+    /// Shown in the status line until the next key
+    message: Option<String>,
+}
+
+impl<'a> Browser<'a> {
+    fn new(
+        timeline: &'a Timeline<'a>,
+        layer_labels: &'a [String],
+        read: &'a ReadFn<'a>,
+        extract: &'a ExtractFn<'a>,
+    ) -> Self {
+        let mut browser = Browser {
+            timeline,
+            root: timeline.image,
+            layer_labels,
+            read,
+            extract,
+            comparison: None,
+            preview: None,
+            expanded: HashSet::new(),
+            layer: None,
+            changes_only: false,
+            kept: HashSet::new(),
+            rows: Vec::new(),
+            selected: 0,
+            marked: BTreeSet::new(),
+            prompt: None,
+            query: String::new(),
+            typing: false,
+            page: 10,
+            message: None,
+        };
+        browser.refresh();
+        browser
+    }
+
+    /// Browse the changes of `comparison`, hiding what is unchanged at first
+    fn comparing(mut self, comparison: &'a Comparison) -> Self {
+        self.comparison = Some(comparison);
+        self.changes_only = true;
+        self.narrow();
+        self
+    }
+
+    /// Act on a key; false once the user quits
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        self.message = None;
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        if self.typing {
+            match key.code {
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.search(true, false);
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.search(true, false);
+                }
+                KeyCode::Enter => self.typing = false,
+                KeyCode::Esc => {
+                    self.typing = false;
+                    self.query.clear();
+                }
+                _ => {}
+            }
+            return true;
+        }
+        if let Some(prompt) = self.prompt.take() {
+            self.answer(prompt, key);
+            return true;
+        }
+        if let Some(preview) = &mut self.preview {
+            let last = preview.lines.len().saturating_sub(1);
+            match key.code {
+                KeyCode::Up | KeyCode::Char('k') => preview.scroll = preview.scroll.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => preview.scroll = (preview.scroll + 1).min(last),
+                KeyCode::PageUp => preview.scroll = preview.scroll.saturating_sub(self.page),
+                KeyCode::PageDown | KeyCode::Char(' ') => preview.scroll = (preview.scroll + self.page).min(last),
+                KeyCode::Home | KeyCode::Char('g') => preview.scroll = 0,
+                KeyCode::End | KeyCode::Char('G') => preview.scroll = last,
+                KeyCode::Esc | KeyCode::Enter | KeyCode::Left | KeyCode::Char('h' | 'q') => self.preview = None,
+                _ => {}
+            }
+            return true;
+        }
+        let last = self.rows.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.selected = (self.selected + 1).min(last),
+            KeyCode::PageUp => self.selected = self.selected.saturating_sub(self.page),
+            KeyCode::PageDown => self.selected = (self.selected + self.page).min(last),
+            KeyCode::Home | KeyCode::Char('g') => self.selected = 0,
+            KeyCode::End | KeyCode::Char('G') => self.selected = last,
+            KeyCode::Right | KeyCode::Char('l') => self.unfold(),
+            KeyCode::Left | KeyCode::Char('h') => self.fold(),
+            KeyCode::Enter if self.rows.get(self.selected).is_some_and(|row| row.node.metadata.is_file) => {
+                self.open_preview();
+            }
+            KeyCode::Enter => self.toggle(),
+            KeyCode::Char(' ') => self.mark(),
+            KeyCode::Char('x') if self.marked.is_empty() => {
+                self.message = Some("Mark entries with Space to export them".to_string());
+            }
+            KeyCode::Char('x') => self.prompt = Some(Prompt::ExportAs),
+            KeyCode::Char('E') => self.expand_all(),
+            KeyCode::Char('C') => {
+                self.expanded.clear();
+                self.refresh();
+            }
+            KeyCode::Tab => self.next_layer(true),
+            KeyCode::BackTab => self.next_layer(false),
+            KeyCode::Char('m') => {
+                self.changes_only = !self.changes_only;
+                self.narrow();
+            }
+            KeyCode::Char('/') => {
+                self.typing = true;
+                self.query.clear();
+            }
+            KeyCode::Char('n') => self.search(false, false),
+            KeyCode::Char('N') => self.search(false, true),
+            _ => {}
+        }
+        true
+    }
+
+    /// The path of the selected row
+    fn selected_path(&self) -> Option<&str> {
+        self.rows.get(self.selected).map(|row| row.path.as_str())
+    }
+
+    /// Rebuild the visible rows, keeping the cursor on the same entry when it is still shown
+    fn refresh(&mut self) {
+        let selected = self.selected_path().map(str::to_string);
+        self.rows = self.walk(false);
+        self.selected = selected
+            .and_then(|path| self.rows.iter().position(|row| row.path == path))
+            .unwrap_or(self.selected)
+            .min(self.rows.len().saturating_sub(1));
+    }
+
+    /// The rows of the tree in order: the unfolded part, or with `all` everything
+    fn walk(&self, all: bool) -> Vec<Row<'a>> {
+        let mut rows = Vec::new();
+        self.walk_into(self.root, "", 0, all, &mut rows);
+        rows
+    }
+
+    fn walk_into(&self, node: &'a Node, prefix: &str, depth: usize, all: bool, rows: &mut Vec<Row<'a>>) {
+        for (name, child) in &node.children {
+            let path = join(prefix, name);
+            if self.changes_only && (self.layer.is_some() || self.comparison.is_some()) && !self.kept.contains(&path) {
+                continue;
+            }
+            let descend = !child.metadata.is_file && (all || self.expanded.contains(&path));
+            rows.push(Row { path: path.clone(), name, depth, node: child });
+            if descend {
+                self.walk_into(child, &path, depth + 1, all, rows);
+            }
+        }
+    }
+
+    fn unfold(&mut self) {
+        let Some(row) = self.rows.get(self.selected) else {
+            return;
+        };
+        if row.node.metadata.is_file {
+            return;
+        }
+        if self.expanded.insert(row.path.clone()) {
+            self.refresh();
+        } else if self.rows.get(self.selected + 1).is_some_and(|next| next.depth > row.depth) {
+            self.selected += 1;
+        }
+    }
+
+    /// Fold the selected directory, or else move to its parent
+    fn fold(&mut self) {
+        let Some(row) = self.rows.get(self.selected) else {
+            return;
+        };
+        if self.expanded.remove(&row.path) {
+            self.refresh();
+        } else if let Some(parent) = self.rows[..self.selected].iter().rposition(|r| r.depth < row.depth) {
+            self.selected = parent;
+        }
+    }
+
+    fn toggle(&mut self) {
+        match self.rows.get(self.selected) {
+            Some(row) if self.expanded.contains(&row.path) => self.fold(),
+            _ => self.unfold(),
+        }
+    }
+
+    /// Mark or unmark the selected entry, and move on to the next
+    fn mark(&mut self) {
+        let Some(row) = self.rows.get(self.selected) else {
+            return;
+        };
+        if !self.marked.remove(&row.path) {
+            self.marked.insert(row.path.clone());
+        }
+        self.selected = (self.selected + 1).min(self.rows.len() - 1);
+    }
+
+    /// Act on a key while `prompt` is shown; it stays up until answered or cancelled
+    fn answer(&mut self, prompt: Prompt, key: KeyEvent) {
+        self.prompt = match (prompt, key.code) {
+            (_, KeyCode::Esc) => None,
+            (Prompt::ExportAs, KeyCode::Char('p')) => Some(Prompt::ExportTo(Export::Paths, "marked.txt".to_string())),
+            (Prompt::ExportAs, KeyCode::Char('e')) => Some(Prompt::ExportTo(Export::Files, "marked".to_string())),
+            (Prompt::ExportAs, KeyCode::Char('f')) => Some(Prompt::ExportTo(Export::Filter, "filter.txt".to_string())),
+            (Prompt::ExportTo(export, mut to), KeyCode::Char(c)) => {
+                to.push(c);
+                Some(Prompt::ExportTo(export, to))
+            }
+            (Prompt::ExportTo(export, mut to), KeyCode::Backspace) => {
+                to.pop();
+                Some(Prompt::ExportTo(export, to))
+            }
+            (Prompt::ExportTo(export, to), KeyCode::Enter) => {
+                self.message = Some(match self.export(export, Path::new(&to)) {
+                    Ok(done) => done,
+                    Err(err) => format!("{:#}", err),
+                });
+                None
+            }
+            (prompt, _) => Some(prompt),
+        };
+    }
+
+    /// Write the marked entries to `to`, and say what was done
+    fn export(&self, export: Export, to: &Path) -> Result<String> {
+        let count = self.marked.len();
+        match export {
+            Export::Paths => {
+                let list: Vec<u8> = self.marked.iter()
+                    .flat_map(|path| [b"/", &*utils::path_bytes(path), b"\n"].concat())
+                    .collect();
+                fs::write(to, list)?;
+                Ok(format!("Wrote {} path(s) to {}", count, to.display()))
+            }
+            Export::Files => {
+                let paths: Vec<&str> = self.marked.iter().map(String::as_str).collect();
+                let summary = (self.extract)(&paths, to)?;
+                let mut done = format!("Extracted {} entries into {}", summary.written, to.display());
+                if !summary.skipped.is_empty() {
+                    done.push_str(&format!("; skipped {} that can't be extracted", summary.skipped.len()));
+                }
+                Ok(done)
+            }
+            Export::Filter => {
+                let filter = filter_string(&self.marked, self.root);
+                fs::write(to, format!("{}\n", filter))?;
+                Ok(format!("Wrote {} to {}", filter, to.display()))
+            }
+        }
+    }
+
+    /// Read the selected file into the preview
+    fn open_preview(&mut self) {
+        let Some(row) = self.rows.get(self.selected) else {
+            return;
+        };
+        match (self.read)(&row.path, self.layer) {
+            Ok(content) => self.preview = Some(preview(&row.path, &content)),
+            Err(err) => self.message = Some(format!("{:#}", err)),
+        }
+    }
+
+    fn expand_all(&mut self) {
+        let dirs = self.walk(true).into_iter().filter(|row| !row.node.metadata.is_file).map(|row| row.path);
+        self.expanded.extend(dirs);
+        self.refresh();
+    }
+
+    /// Show the image as of the next (or previous) layer, then the whole image
+    fn next_layer(&mut self, forward: bool) {
+        let count = self.layer_labels.len();
+        if self.comparison.is_some() {
+            self.message = Some("Layers can't be picked while comparing images".to_string());
+            return;
+        }
+        if count == 0 {
+            self.message = Some("The image has no layers".to_string());
+            return;
+        }
+        // The whole image sits between the last layer and the first
+        let layer = match (self.layer, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) => Some(i + 1).filter(|&i| i < count),
+            (Some(i), false) => i.checked_sub(1),
+        };
+        match self.timeline.tree(layer) {
+            Ok(root) => {
+                self.root = root;
+                self.layer = layer;
+                self.narrow();
+            }
+            Err(err) => self.message = Some(format!("{:#}", err)),
+        }
+    }
+
+    /// The layer whose changes alone are shown
+    fn narrowed(&self) -> Option<usize> {
+        self.layer.filter(|_| self.changes_only)
+    }
+
+    /// The change of an entry from the old image to the new one
+    fn change(&self, path: &str) -> Option<&'a FileChange> {
+        self.comparison.and_then(|comparison| comparison.changes.get(path))
+    }
+
+    /// Collect what the narrowed-to layer (or the new image) changed, and show it
+    fn narrow(&mut self) {
+        self.kept.clear();
+        if let Some(comparison) = self.comparison {
+            for path in comparison.changes.keys() {
+                let mut path = path.as_str();
+                self.kept.insert(path.to_string());
+                while let Some((parent, _)) = path.rsplit_once('/') {
+                    self.kept.insert(parent.to_string());
+                    path = parent;
+                }
+            }
+        } else if let Some(layer) = self.narrowed() {
+            collect_kept(self.root, "", &self.layer_labels[layer], &mut self.kept);
+        }
+        self.refresh();
+    }
+
+    /// Select the next entry whose name contains the query, unfolding the
+    /// directories above it. While typing, the selected entry itself counts.
+    fn search(&mut self, here: bool, backward: bool) {
+        if self.query.is_empty() {
+            return;
+        }
+        let query = self.query.to_lowercase();
+        let entries = self.walk(true);
+        let start = self.selected_path()
+            .and_then(|path| entries.iter().position(|row| row.path == path))
+            .unwrap_or(0);
+        let count = entries.len();
+        let found = (0..count)
+            .map(|step| {
+                let step = if here { step } else { step + 1 };
+                if backward { (start + count * 2 - step) % count } else { (start + step) % count }
+            })
+            .find(|&i| entries[i].name.to_lowercase().contains(&query));
+        let Some(found) = found else {
+            self.message = Some(format!("Nothing matches '{}'", self.query));
+            return;
+        };
+        let path = &entries[found].path;
+        let mut parent = path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            self.expanded.insert(dir.to_string());
+            parent = dir;
+        }
+        self.rows = self.walk(false);
+        self.selected = self.rows.iter().position(|row| row.path == *path).unwrap_or(0);
+    }
+}
+
+/// Add the paths under `node` that `layer` added, and the directories
+/// holding them, to `kept`; whether there were any
+fn collect_kept(node: &Node, path: &str, layer: &str, kept: &mut HashSet<String>) -> bool {
+    let mut keep = node.metadata.layer_hash.as_deref() == Some(layer);
+    for (name, child) in &node.children {
+        keep |= collect_kept(child, &join(path, name), layer, kept);
+    }
+    if keep {
+        kept.insert(path.to_string());
+    }
+    keep
+}
+
+/// The marked paths as comma-separated globs: files as they are, and
+/// directories with everything beneath them
+fn filter_string(marked: &BTreeSet<String>, root: &Node) -> String {
+    let globs: Vec<String> = marked.iter()
+        .map(|path| match root.get(path) {
+            Some(node) if !node.metadata.is_file => format!("/{}/**", utils::display_path(path)),
+            _ => format!("/{}", utils::display_path(path)),
+        })
+        .collect();
+    globs.join(",")
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) }
+}
+
+fn draw(frame: &mut Frame, browser: &mut Browser, state: &mut ListState, screen: &Screen) {
+    let [main, status] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+    // Files need the room more than the tree does
+    let tree_width = if browser.preview.is_some() { 40 } else { 60 };
+    let sidebar_width = if screen.layers.is_empty() { 0 } else { 28 };
+    let [sidebar_area, main] =
+        Layout::horizontal([Constraint::Length(sidebar_width), Constraint::Fill(1)]).areas(main);
+    let [tree_area, details_area] =
+        Layout::horizontal([Constraint::Percentage(tree_width), Constraint::Fill(1)]).areas(main);
+    browser.page = usize::from(tree_area.height.saturating_sub(2)).max(1);
+
+    if !screen.layers.is_empty() {
+        let sidebar = List::new(screen.layers.clone())
+            .block(Block::bordered().title(" Layers "))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut sidebar_state = ListState::default().with_selected(Some(browser.layer.map_or(0, |layer| layer + 1)));
+        frame.render_stateful_widget(sidebar, sidebar_area, &mut sidebar_state);
+    }
+
+    let mut title = format!(" {} ", screen.title);
+    if !browser.marked.is_empty() {
+        title.push_str(&format!("─ {} marked ", browser.marked.len()));
+    }
+    match (browser.comparison, browser.layer, browser.changes_only) {
+        (Some(comparison), _, true) => title.push_str(&format!("─ {} changes ", comparison.changes.len())),
+        (Some(_), _, false) => title.push_str("─ unchanged entries too "),
+        (None, Some(layer), false) => title.push_str(&format!("─ as of layer #{} ", layer)),
+        (None, Some(layer), true) => title.push_str(&format!("─ changed by layer #{} ", layer)),
+        (None, None, _) => {}
+    }
+    let items: Vec<ListItem> = browser.rows.iter().map(|row| ListItem::new(row_line(row, browser))).collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(title))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, tree_area, state);
+
+    if let Some(preview) = &browser.preview {
+        let height = usize::from(details_area.height.saturating_sub(2));
+        let lines: Vec<Line> = preview.lines.iter().skip(preview.scroll).take(height).cloned().collect();
+        let block = Block::bordered().title(preview.title.as_str());
+        frame.render_widget(Paragraph::new(lines).block(block), details_area);
+    } else {
+        let details = browser.rows.get(browser.selected)
+            .map(|row| details(row, browser.layer_labels, browser.change(&row.path)))
+            .unwrap_or_default();
+        frame.render_widget(Paragraph::new(details).block(Block::bordered().title(" Details ")), details_area);
+    }
+
+    let status_line = if browser.typing {
+        format!("/{}", browser.query)
+    } else if let Some(prompt) = &browser.prompt {
+        match prompt {
+            Prompt::ExportAs => format!(
+                "Export {} marked as: p a list of paths, e extracted files, f a filter (Esc cancels)",
+                browser.marked.len()
+            ),
+            Prompt::ExportTo(Export::Paths, to) => format!("Write the paths to: {}", to),
+            Prompt::ExportTo(Export::Files, to) => format!("Extract into the empty directory: {}", to),
+            Prompt::ExportTo(Export::Filter, to) => format!("Write the filter to: {}", to),
+        }
+    } else if let Some(message) = &browser.message {
+        message.clone()
+    } else if browser.preview.is_some() {
+        "↑↓ PgUp/PgDn scroll  Esc back to the tree".to_string()
+    } else if browser.comparison.is_some() {
+        "↑↓ move  ←→ fold  Enter preview  E/C unfold/fold all  / search  n/N next/previous  \
+            m show/hide unchanged  Space mark  x export  q quit".to_string()
+    } else {
+        "↑↓ move  ←→ fold  Enter preview  E/C unfold/fold all  / search  n/N next/previous  \
+            Tab/Shift-Tab layer  m changes only  Space mark  x export  q quit".to_string()
+    };
+    frame.render_widget(Paragraph::new(status_line), status);
+}
+
+/// A row of the tree: indentation, a fold marker for directories, and the name
+fn row_line<'a>(row: &Row<'a>, browser: &Browser) -> Line<'a> {
+    let meta = &row.node.metadata;
+    let marker = match (meta.is_file, browser.expanded.contains(&row.path)) {
+        (true, _) => "  ",
+        (false, true) => "▾ ",
+        (false, false) => "▸ ",
+    };
+    let mut style = if !meta.is_file {
+        Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD)
+    } else if meta.is_symlink {
+        Style::new().fg(Color::Cyan)
+    } else if meta.mode & 0o111 != 0 {
+        Style::new().fg(Color::Green)
+    } else {
+        Style::new()
+    };
+    // Directories shown only for what the layer put beneath them
+    if browser.narrowed().is_some_and(|layer| meta.layer_hash.as_deref() != Some(browser.layer_labels[layer].as_str())) {
+        style = style.fg(Color::DarkGray);
+    }
+    let mut change_marker = "";
+    if browser.comparison.is_some() {
+        (change_marker, style) = match browser.change(&row.path).map(|change| change.change) {
+            Some(Change::Added) => ("+ ", style.fg(Color::Green)),
+            Some(Change::Deleted) => ("− ", style.fg(Color::Red)),
+            Some(Change::Modified) => ("~ ", style.fg(Color::Yellow)),
+            // Directories leading to changes, and unchanged entries when shown
+            None if browser.kept.contains(&row.path) => ("", style),
+            None => ("", style.fg(Color::DarkGray)),
+        };
+    }
+    if !browser.query.is_empty() && row.name.to_lowercase().contains(&browser.query.to_lowercase()) {
+        style = style.add_modifier(Modifier::UNDERLINED);
+    }
+    let mut spans = Vec::new();
+    // A column for the marks, once there are any
+    if !browser.marked.is_empty() {
+        spans.push(match browser.marked.contains(&row.path) {
+            true => Span::styled("* ", Style::new().fg(Color::Magenta).add_modifier(Modifier::BOLD)),
+            false => Span::raw("  "),
+        });
+    }
+    spans.extend([
+        Span::raw("  ".repeat(row.depth)),
+        Span::raw(marker),
+        Span::styled(change_marker, style),
+        Span::styled(utils::display_path(row.name), style),
+    ]);
+    if let Some(target) = &meta.symlink_target {
+        spans.push(Span::raw(format!(" -> {}", utils::display_path(target))));
+    }
+    Line::from(spans)
+}
+
+/// The preview of a file: its text, or a hexdump when it looks binary
+fn preview(path: &str, content: &FileContent) -> Preview {
+    let mut about = utils::human_size(content.size);
+    if content.truncated() {
+        about = format!("first {} of {}", utils::human_size(content.bytes.len() as u64), about);
+    }
+    let lines = if content.is_binary() {
+        about.push_str(", binary");
+        hexdump(&content.bytes).into_iter().map(Line::from).collect()
+    } else {
+        String::from_utf8_lossy(&content.bytes).lines().map(highlight).collect()
+    };
+    Preview { title: format!(" /{} ({}) ", path, about), lines, scroll: 0 }
+}
+
+/// `hexdump -C` lines: offset, sixteen bytes in hex, and the printable ones
+fn hexdump(bytes: &[u8]) -> Vec<String> {
+    bytes.chunks(16).enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = String::new();
+            for (j, byte) in chunk.iter().enumerate() {
+                hex.push_str(if j == 8 { "  " } else { " " });
+                hex.push_str(&format!("{:02x}", byte));
+            }
+            let text: String = chunk.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { char::from(b) } else { '.' })
+                .collect();
+            format!("{:08x} {:<49}  |{}|", i * 16, hex, text)
+        })
+        .collect()
+}
+
+/// A line of text with light highlighting that suits most scripts and
+/// configuration files: comment lines dimmed, the key of `key = value` and
+/// `key: value` lines, and double-quoted strings
+fn highlight(line: &str) -> Line<'static> {
+    // Tabs and control characters would throw the columns off
+    let line: String = line.chars()
+        .flat_map(|c| match c {
+            '\t' => "    ".chars().collect::<Vec<_>>(),
+            c if c.is_control() => vec!['·'],
+            c => vec![c],
+        })
+        .collect();
+    let trimmed = line.trim_start();
+    if ["#", "//", ";"].iter().any(|marker| trimmed.starts_with(marker)) {
+        return Line::styled(line, Style::new().fg(Color::DarkGray));
+    }
+
+    let mut spans = Vec::new();
+    let mut rest = line.as_str();
+    // `user:x:0:0` has no key, `user: web` does
+    let key_end = trimmed.char_indices()
+        .find(|&(i, c)| c == '=' || c == ':' && trimmed[i + 1..].chars().next().is_none_or(char::is_whitespace))
+        .map(|(i, _)| i)
+        .filter(|&end| end > 0 && trimmed[..end].trim_end().chars().all(|c| c.is_alphanumeric() || "_.-".contains(c)))
+        .map(|end| line.len() - trimmed.len() + end);
+    if let Some(end) = key_end {
+        spans.push(Span::styled(line[..end].to_string(), Style::new().fg(Color::Cyan)));
+        rest = &line[end..];
+    }
+    let mut quoted = false;
+    for (i, part) in rest.split('"').enumerate() {
+        if i > 0 {
+            spans.push(Span::styled("\"", Style::new().fg(Color::Yellow)));
+            quoted = !quoted;
+        }
+        let style = if quoted { Style::new().fg(Color::Yellow) } else { Style::new() };
+        spans.push(Span::styled(part.to_string(), style));
+    }
+    Line::from(spans)
+}
+
+/// The metadata panel of an entry, one `Label value` line per field, and
+/// how it changed when comparing images
+fn details(row: &Row, layer_labels: &[String], change: Option<&FileChange>) -> Vec<Line<'static>> {
+    let meta = &row.node.metadata;
+    let kind = match (&meta.device, meta.is_file, meta.is_symlink, &meta.hardlink_target) {
+        (Some(device), ..) => format!(
+            "{} device {}:{}",
+            if device.kind == DeviceKind::Char { "character" } else { "block" },
+            device.major,
+            device.minor
+        ),
+        (None, false, ..) => "directory".to_string(),
+        (None, true, true, _) => "symlink".to_string(),
+        (None, true, false, Some(_)) => "hard link".to_string(),
+        (None, true, false, None) => "file".to_string(),
+    };
+    let size = if meta.is_file {
+        utils::human_size(meta.size)
+    } else {
+        format!("{} in {} entries", utils::human_size(row.node.total_size()), row.node.count_descendants())
+    };
+    let layer = meta.layer_hash.as_deref().map(|hash| {
+        match layer_labels.iter().position(|label| label == hash) {
+            Some(index) => format!("#{} {}", index, hash),
+            None => hash.to_string(),
+        }
+    });
+
+    let mut fields = vec![
+        ("Path", Some(format!("/{}", utils::display_path(&row.path)))),
+        ("Type", Some(kind)),
+        ("Target", meta.symlink_target.as_deref().or(meta.hardlink_target.as_deref())
+            .map(|target| utils::display_path(target).into_owned())),
+        ("Mode", Some(format!("{} ({:04o})", render::format_permissions(meta.mode, meta.is_file), meta.mode & 0o7777))),
+        ("Owner", Some(format!("{}:{}", meta.uid, meta.gid))),
+        ("Size", Some(size)),
+        ("Modified", Some(utils::format_timestamp(meta.mtime as i64))),
+        ("Layer", layer),
+        ("Package", meta.owner.clone()),
+    ];
+    if let Some(change) = change {
+        let kind = match change.change {
+            Change::Added => "added",
+            Change::Deleted => "deleted",
+            Change::Modified => "modified",
+        };
+        fields.push(("Change", Some(kind.to_string())));
+        fields.extend(change.fields.iter().map(|field| ("", Some(format!("{} {} -> {}", field.field, field.old, field.new)))));
+    }
+    if !meta.shadowed.is_empty() {
+        fields.push(("Shadows", Some(format!("{} earlier version(s)", meta.shadowed.len()))));
+    }
+    fields.extend(meta.xattrs.keys().map(|name| ("Xattr", Some(name.clone()))));
+    fields.into_iter()
+        .filter_map(|(label, value)| {
+            let value = value?;
+            Some(Line::from(vec![
+                Span::styled(format!("{:<10}", label), Style::new().add_modifier(Modifier::BOLD)),
+                Span::raw(value),
+            ]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn paths(browser: &Browser) -> Vec<String> {
+        browser.rows.iter().map(|row| row.path.clone()).collect()
+    }
+
+    #[test]
+    fn test_browser() {
+        let (base, app): (Arc<str>, Arc<str>) = ("aaa".into(), "bbb".into());
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, Some(&base));
+        root.put_file("usr/bin/sh", 0o755, 0, 0, false, None, Some(&base));
+        root.put_file("usr/local/app", 0o755, 0, 0, false, None, Some(&app));
+        let labels = vec![base.to_string(), app.to_string()];
+        let merge = |index: usize| {
+            assert_eq!(index, 0, "the top layer's tree is the image");
+            let mut root = Node::new_dir(0o755, 0, 0);
+            root.put_file("usr/bin/sh", 0o755, 0, 0, false, None, Some(&base));
+            Ok(root)
+        };
+        let timeline = Timeline::new(&root, labels.len(), &merge);
+        let read = |path: &str, _: Option<usize>| match path {
+            "etc/hosts" => Ok(FileContent { bytes: b"127.0.0.1 localhost\n".to_vec(), size: 20 }),
+            _ => anyhow::bail!("/{} is a directory", path),
+        };
+        let extract = |_: &[&str], _: &Path| bail!("nothing to extract");
+        let mut browser = Browser::new(&timeline, &labels, &read, &extract);
+        assert_eq!(paths(&browser), ["etc", "usr"]);
+
+        // Unfold usr, step into it, and fold it again from its child
+        browser.handle_key(key(KeyCode::Down));
+        browser.handle_key(key(KeyCode::Right));
+        assert_eq!(paths(&browser), ["etc", "usr", "usr/bin", "usr/local"]);
+        browser.handle_key(key(KeyCode::Right));
+        assert_eq!(browser.selected_path(), Some("usr/bin"));
+        browser.handle_key(key(KeyCode::Left));
+        assert_eq!(browser.selected_path(), Some("usr"));
+        browser.handle_key(key(KeyCode::Left));
+        assert_eq!(paths(&browser), ["etc", "usr"]);
+
+        // As of the first layer, then only what the second one changed
+        browser.handle_key(key(KeyCode::Tab));
+        browser.handle_key(key(KeyCode::Char('E')));
+        assert_eq!(paths(&browser), ["usr", "usr/bin", "usr/bin/sh"]);
+        browser.handle_key(key(KeyCode::Tab));
+        browser.handle_key(key(KeyCode::Char('m')));
+        browser.handle_key(key(KeyCode::Char('E')));
+        assert_eq!(paths(&browser), ["usr", "usr/local", "usr/local/app"]);
+        browser.handle_key(key(KeyCode::Tab));
+        browser.handle_key(key(KeyCode::Char('m')));
+        assert_eq!(browser.layer, None);
+
+        // Searching unfolds the directories above the match
+        browser.handle_key(key(KeyCode::Char('C')));
+        browser.handle_key(key(KeyCode::Home));
+        for c in "/sh".chars() {
+            browser.handle_key(key(KeyCode::Char(c)));
+        }
+        browser.handle_key(key(KeyCode::Enter));
+        assert_eq!(browser.selected_path(), Some("usr/bin/sh"));
+        browser.handle_key(key(KeyCode::Char('n')));
+        assert_eq!(browser.selected_path(), Some("usr/bin/sh"));
+        assert!(browser.handle_key(key(KeyCode::Char('/'))));
+        browser.handle_key(key(KeyCode::Char('x')));
+        assert_eq!(browser.message.as_deref(), Some("Nothing matches 'x'"));
+        browser.handle_key(key(KeyCode::Esc));
+
+        // Enter previews files, and q leaves the preview before the browser
+        browser.handle_key(key(KeyCode::Char('/')));
+        browser.handle_key(key(KeyCode::Char('h')));
+        browser.handle_key(key(KeyCode::Char('o')));
+        browser.handle_key(key(KeyCode::Enter));
+        browser.handle_key(key(KeyCode::Enter));
+        assert_eq!(browser.preview.as_ref().map(|p| p.title.as_str()), Some(" /etc/hosts (20 B) "));
+        assert!(browser.handle_key(key(KeyCode::Char('q'))));
+        assert!(browser.preview.is_none());
+        assert!(!browser.handle_key(key(KeyCode::Char('q'))));
+    }
+
+    #[test]
+    fn test_comparison() {
+        let mut old = Node::new_dir(0o755, 0, 0);
+        old.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        old.put_file("etc/motd", 0o644, 0, 0, false, None, None);
+        old.put_file("usr/bin/sh", 0o755, 0, 0, false, None, None);
+        old.put_file("tmp/cache/a", 0o644, 0, 0, false, None, None);
+        let mut new = Node::new_dir(0o755, 0, 0);
+        new.put_file("etc/hosts", 0o600, 0, 0, false, None, None);
+        new.put_file("etc/motd", 0o644, 0, 0, false, None, None);
+        new.put_file("usr/bin/sh", 0o755, 0, 0, false, None, None);
+        new.put_file("usr/bin/curl", 0o755, 0, 0, false, None, None);
+
+        let comparison = Comparison::new(&old, &new);
+        let root = combine(&old, &new, &comparison);
+        assert!(root.get("tmp/cache/a").is_some());
+        let merge = |_: usize| bail!("no layers");
+        let timeline = Timeline::new(&root, 0, &merge);
+        let read = |path: &str, _: Option<usize>| bail!("/{} is not in the image", path);
+        let extract = |_: &[&str], _: &Path| bail!("nothing to extract");
+        let mut browser = Browser::new(&timeline, &[], &read, &extract).comparing(&comparison);
+
+        // Unchanged entries are hidden until m shows them
+        browser.handle_key(key(KeyCode::Char('E')));
+        assert_eq!(paths(&browser), ["etc", "etc/hosts", "tmp", "tmp/cache", "tmp/cache/a", "usr", "usr/bin", "usr/bin/curl"]);
+        assert_eq!(browser.change("tmp/cache").map(|change| change.change), Some(Change::Deleted));
+        let hosts = details(&browser.rows[1], &[], browser.change("etc/hosts"));
+        let text = |line: &Line| line.spans.iter().map(|span| span.content.to_string()).collect::<String>();
+        assert_eq!(hosts.iter().rev().take(2).map(text).collect::<Vec<_>>(), ["          mode 0644 -> 0600", "Change    modified"]);
+        browser.handle_key(key(KeyCode::Char('m')));
+        assert_eq!(paths(&browser).len(), 10);
+        browser.handle_key(key(KeyCode::Tab));
+        assert_eq!(browser.message.as_deref(), Some("Layers can't be picked while comparing images"));
+    }
+
+    /// Export the marked entries of `browser` in the form `choice` picks, to `to`
+    fn export(browser: &mut Browser, choice: char, to: &Path) -> String {
+        browser.handle_key(key(KeyCode::Char('x')));
+        browser.handle_key(key(KeyCode::Char(choice)));
+        // Replace the suggested destination
+        while matches!(&browser.prompt, Some(Prompt::ExportTo(_, to)) if !to.is_empty()) {
+            browser.handle_key(key(KeyCode::Backspace));
+        }
+        for c in to.to_str().unwrap().chars() {
+            browser.handle_key(key(KeyCode::Char(c)));
+        }
+        browser.handle_key(key(KeyCode::Enter));
+        browser.message.take().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_export() {
+        let mut root = Node::new_dir(0o755, 0, 0);
+        root.put_file("etc/hosts", 0o644, 0, 0, false, None, None);
+        root.put_file("usr/bin/sh", 0o755, 0, 0, false, None, None);
+        let merge = |_: usize| bail!("no layers");
+        let timeline = Timeline::new(&root, 0, &merge);
+        let read = |path: &str, _: Option<usize>| bail!("/{} is not in the image", path);
+        let extract = |paths: &[&str], _: &Path| {
+            Ok(ExtractSummary { written: paths.len(), skipped: vec!["/dev/null (device node)".to_string()] })
+        };
+        let mut browser = Browser::new(&timeline, &[], &read, &extract);
+        browser.handle_key(key(KeyCode::Char('x')));
+        assert_eq!(browser.message.as_deref(), Some("Mark entries with Space to export them"));
+
+        // Space marks and moves on; marking again unmarks
+        browser.handle_key(key(KeyCode::Char(' ')));
+        browser.handle_key(key(KeyCode::Char(' ')));
+        browser.handle_key(key(KeyCode::Char('E')));
+        browser.handle_key(key(KeyCode::End));
+        browser.handle_key(key(KeyCode::Char(' ')));
+        browser.handle_key(key(KeyCode::Home));
+        browser.handle_key(key(KeyCode::Char(' ')));
+        assert_eq!(browser.marked.iter().collect::<Vec<_>>(), ["usr", "usr/bin/sh"]);
+
+        let out = tempfile::tempdir().unwrap();
+        let paths = out.path().join("paths.txt");
+        assert_eq!(export(&mut browser, 'p', &paths), format!("Wrote 2 path(s) to {}", paths.display()));
+        assert_eq!(fs::read_to_string(&paths).unwrap(), "/usr\n/usr/bin/sh\n");
+        let filter = out.path().join("filter.txt");
+        export(&mut browser, 'f', &filter);
+        assert_eq!(fs::read_to_string(&filter).unwrap(), "/usr/**,/usr/bin/sh\n");
+        let files = out.path().join("files");
+        assert_eq!(
+            export(&mut browser, 'e', &files),
+            format!("Extracted 2 entries into {}; skipped 1 that can't be extracted", files.display())
+        );
+
+        // Esc backs out of the prompt without exporting
+        browser.handle_key(key(KeyCode::Char('x')));
+        browser.handle_key(key(KeyCode::Esc));
+        assert!(browser.prompt.is_none() && browser.message.is_none());
+    }
+
+    #[test]
+    fn test_preview_lines() {
+        assert_eq!(hexdump(b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03\x00"), [
+            "00000000  7f 45 4c 46 02 01 01 00  00 00 00 00 00 00 00 00  |.ELF............|",
+            "00000010  03 00                                             |..|",
+        ]);
+        let text = |line: Line| line.spans.iter().map(|span| span.content.to_string()).collect::<Vec<_>>();
+        assert_eq!(text(highlight("  # a comment")), ["  # a comment"]);
+        assert_eq!(text(highlight("root:x:0:0")), ["root:x:0:0"]);
+        assert_eq!(text(highlight("name = \"web\"\t# app")), ["name ", "= ", "\"", "web", "\"", "    # app"]);
+    }
+}
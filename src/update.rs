@@ -0,0 +1,171 @@
+//! Self-update from GitHub releases
+//!
+//! Release artifacts are named `contree-<target>` (static musl builds on Linux)
+//! and published alongside a `SHA256SUMS` file, which is used to verify the
+//! download before the running binary is replaced.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use contree::diagnostics;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/blark/contree/releases/latest";
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Release asset name for the platform this binary was built for
+pub fn asset_name() -> Option<String> {
+    let arch = match std::env::consts::ARCH {
+        arch @ ("x86_64" | "aarch64") => arch,
+        _ => return None,
+    };
+    let platform = match std::env::consts::OS {
+        "linux" => "unknown-linux-musl",
+        "macos" => "apple-darwin",
+        _ => return None,
+    };
+    Some(format!("contree-{}-{}", arch, platform))
+}
+
+/// Parse a "v1.2.3" or "1.2.3" tag into its numeric parts
+fn parse_version(tag: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = tag.trim_start_matches('v').splitn(3, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    // Ignore pre-release/build suffixes like "3-rc1"
+    let patch = parts.next()?.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()?;
+    Some((major, minor, patch))
+}
+
+/// Find the expected checksum of `asset` in a `sha256sum`-style listing
+fn find_checksum(listing: &str, asset: &str) -> Option<String> {
+    listing.lines().find_map(|line| {
+        let (digest, name) = line.split_once(char::is_whitespace)?;
+        // sha256sum marks binary mode with a leading '*'
+        let name = name.trim().trim_start_matches('*');
+        (name == asset).then(|| digest.to_ascii_lowercase())
+    })
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let response = ureq::get(url)
+        .set("User-Agent", concat!("contree/", env!("CARGO_PKG_VERSION")))
+        .call()
+        .with_context(|| format!("Failed to fetch {}", url))?;
+
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)
+        .with_context(|| format!("Failed to download {}", url))?;
+    Ok(body)
+}
+
+/// Check GitHub for a newer release and install it over the running binary
+pub fn self_update(check_only: bool, force: bool) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let asset = asset_name().context("No release artifacts are published for this platform")?;
+
+    let release: Release = serde_json::from_slice(&fetch(RELEASES_URL)?)
+        .context("Failed to parse GitHub release metadata")?;
+    let latest = parse_version(&release.tag_name)
+        .with_context(|| format!("Unrecognized release tag: {}", release.tag_name))?;
+
+    if !force && parse_version(current).is_some_and(|v| v >= latest) {
+        println!("contree {} is up to date", current);
+        return Ok(());
+    }
+    if check_only {
+        println!("contree {} is available (installed: {})", release.tag_name, current);
+        return Ok(());
+    }
+
+    let url_of = |name: &str| {
+        release.assets.iter()
+            .find(|a| a.name == name)
+            .map(|a| a.browser_download_url.clone())
+            .with_context(|| format!("Release {} has no {} asset", release.tag_name, name))
+    };
+
+    let listing = String::from_utf8(fetch(&url_of(CHECKSUMS_ASSET)?)?)
+        .context("Checksum file is not valid UTF-8")?;
+    let expected = find_checksum(&listing, &asset)
+        .with_context(|| format!("{} has no entry for {}", CHECKSUMS_ASSET, asset))?;
+
+    diagnostics::note(format!("Downloading {} {}...", asset, release.tag_name));
+    let binary = fetch(&url_of(&asset)?)?;
+    let actual = format!("{:x}", Sha256::digest(&binary));
+    if actual != expected {
+        bail!("Checksum mismatch for {}: expected {}, got {}", asset, expected, actual);
+    }
+
+    let exe = std::env::current_exe().context("Failed to locate the running executable")?;
+    replace_executable(&exe, &binary)?;
+    println!("Updated contree {} -> {}", current, release.tag_name);
+    Ok(())
+}
+
+/// Atomically replace `exe` with `contents`
+/// The new binary is written next to the old one and renamed over it, so an
+/// interrupted update never leaves a truncated executable behind.
+fn replace_executable(exe: &Path, contents: &[u8]) -> Result<()> {
+    let dir = exe.parent().context("Executable has no parent directory")?;
+    let mut staged = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Cannot write to {} (try running with more privileges)", dir.display()))?;
+    staged.write_all(contents).context("Failed to write new executable")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(staged.path(), std::fs::Permissions::from_mode(0o755))
+            .context("Failed to mark new executable as executable")?;
+    }
+
+    staged.persist(exe)
+        .with_context(|| format!("Failed to replace {}", exe.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v1.2.3"), Some((1, 2, 3)));
+        assert_eq!(parse_version("0.10.0"), Some((0, 10, 0)));
+        assert_eq!(parse_version("v2.0.1-rc1"), Some((2, 0, 1)));
+        assert_eq!(parse_version("latest"), None);
+        assert!(parse_version("v0.10.0") > parse_version("v0.9.9"));
+    }
+
+    #[test]
+    fn test_find_checksum() {
+        let listing = "abc123  contree-x86_64-unknown-linux-musl\nDEF456 *contree-aarch64-apple-darwin\n";
+        assert_eq!(find_checksum(listing, "contree-x86_64-unknown-linux-musl").as_deref(), Some("abc123"));
+        assert_eq!(find_checksum(listing, "contree-aarch64-apple-darwin").as_deref(), Some("def456"));
+        assert_eq!(find_checksum(listing, "contree-other"), None);
+    }
+
+    #[test]
+    fn test_replace_executable() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("contree");
+        std::fs::write(&exe, b"old").unwrap();
+
+        replace_executable(&exe, b"new").unwrap();
+        assert_eq!(std::fs::read(&exe).unwrap(), b"new");
+    }
+}
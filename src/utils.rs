@@ -1,5 +1,9 @@
 //! Common utility functions
 
+use anyhow::Context;
+use std::borrow::Cow;
+use std::path::PathBuf;
+
 /// Split a path into (directory, basename)
 /// Examples:
 ///   "foo/bar" -> ("foo", "bar")
@@ -15,6 +19,260 @@ pub fn split_path(path: &str) -> (&str, &str) {
     }
 }
 
+/// Normalize a tar entry path (strip leading ./ segments and trailing slashes)
+/// Examples:
+///   "./etc/passwd" -> "etc/passwd"
+///   "usr/lib/" -> "usr/lib"
+pub fn normalize_path(path: &str) -> &str {
+    path.trim_start_matches("./").trim_end_matches('/')
+}
+
+/// A path with `.`, `..`, empty, and leading `/` components resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CleanPath {
+    pub path: String,
+    /// The path started with "/"
+    pub absolute: bool,
+    /// The path had `..` components
+    pub dot_dot: bool,
+    /// A `..` climbed above the root; `path` is clamped at the root
+    pub escapes: bool,
+}
+
+/// Resolve a path lexically, without looking at what it points to
+/// Examples:
+///   "./usr//lib/../bin" -> "usr/bin"
+///   "/etc/passwd" -> "etc/passwd" (absolute)
+///   "../../etc/passwd" -> "etc/passwd" (escapes)
+pub fn clean_path(path: &str) -> CleanPath {
+    let mut parts: Vec<&str> = Vec::new();
+    let mut dot_dot = false;
+    let mut escapes = false;
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                dot_dot = true;
+                escapes |= parts.pop().is_none();
+            }
+            _ => parts.push(part),
+        }
+    }
+    CleanPath { path: parts.join("/"), absolute: path.starts_with('/'), dot_dot, escapes }
+}
+
+/// Tar paths are bytes. Bytes that aren't UTF-8 are kept in the tree's `str`
+/// paths as the code points `U+10FF00..=U+10FFFF` at the end of a private use
+/// plane, so they survive merging, whiteouts and extraction unchanged.
+const RAW_BYTE_BASE: u32 = 0x10FF00;
+
+/// A path from the raw bytes of a tar entry, without losing any of them
+/// Examples:
+///   b"etc/passwd" -> "etc/passwd"
+///   b"caf\xe9" -> "caf\u{10FFE9}"
+pub fn path_from_bytes(bytes: &[u8]) -> Cow<'_, str> {
+    if let Ok(path) = std::str::from_utf8(bytes) {
+        if !path.chars().any(is_raw_byte) {
+            return Cow::Borrowed(path);
+        }
+    }
+    let mut path = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            // A literal U+10FFxx would read back as a raw byte, so it's kept as its bytes
+            if is_raw_byte(c) {
+                path.extend(c.to_string().bytes().map(raw_byte));
+            } else {
+                path.push(c);
+            }
+        }
+        path.extend(chunk.invalid().iter().copied().map(raw_byte));
+    }
+    Cow::Owned(path)
+}
+
+/// The bytes a path from [`path_from_bytes`] was made of
+pub fn path_bytes(path: &str) -> Cow<'_, [u8]> {
+    if !path.chars().any(is_raw_byte) {
+        return Cow::Borrowed(path.as_bytes());
+    }
+    let mut bytes = Vec::with_capacity(path.len());
+    for c in path.chars() {
+        if is_raw_byte(c) {
+            bytes.push((c as u32 - RAW_BYTE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+/// A path for showing to people, with bytes that aren't UTF-8 as `\xNN`
+/// Examples:
+///   "caf\u{10FFE9}" -> "caf\xe9"
+pub fn display_path(path: &str) -> Cow<'_, str> {
+    if !path.chars().any(is_raw_byte) {
+        return Cow::Borrowed(path);
+    }
+    let mut shown = String::with_capacity(path.len());
+    for c in path.chars() {
+        if is_raw_byte(c) {
+            shown.push_str(&format!("\\x{:02x}", c as u32 - RAW_BYTE_BASE));
+        } else {
+            shown.push(c);
+        }
+    }
+    Cow::Owned(shown)
+}
+
+/// A path of the tree on the local filesystem, with the bytes it had in the
+/// layer where the platform allows any
+pub fn os_path(path: &str) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(&path_bytes(path)))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(display_path(path).into_owned())
+    }
+}
+
+fn is_raw_byte(c: char) -> bool {
+    c as u32 >= RAW_BYTE_BASE
+}
+
+fn raw_byte(byte: u8) -> char {
+    char::from_u32(RAW_BYTE_BASE + u32::from(byte)).expect("U+10FF00..=U+10FFFF are chars")
+}
+
+/// Seconds since the epoch for an RFC 3339 timestamp, as in the `created`
+/// fields of an image config
+/// Examples:
+///   "2024-01-02T03:04:05Z" -> 1704164645
+///   "2024-01-02T05:04:05.123456789+02:00" -> 1704164645
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = s.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let bytes = s.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let days = days_from_civil(num(0..4)?, num(5..7)? as u32, num(8..10)? as u32);
+    let mut secs = days * 86400 + num(11..13)? * 3600 + num(14..16)? * 60 + num(17..19)?;
+
+    // Skip fractional seconds, then apply the offset
+    let zone = s[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    match zone.as_bytes().first()? {
+        b'Z' | b'z' => {}
+        sign @ (b'+' | b'-') => {
+            let offset = zone.get(1..3)?.parse::<i64>().ok()? * 3600 + zone.get(4..6)?.parse::<i64>().ok()? * 60;
+            secs += if *sign == b'+' { -offset } else { offset };
+        }
+        _ => return None,
+    }
+    Some(secs)
+}
+
+/// UTC calendar date (year, month, day) of seconds since the epoch
+pub fn civil_date(secs: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's civil_from_days
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Format seconds since the epoch as "2024-01-02 03:04:05 UTC"
+pub fn format_timestamp(secs: i64) -> String {
+    let (year, month, day) = civil_date(secs);
+    let time = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Format a byte count using binary units
+/// Examples:
+///   512 -> "512 B"
+///   1536 -> "1.5 KiB"
+#[cfg_attr(not(feature = "analyzers"), allow(dead_code))]
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any one
+/// Examples:
+///   ("*.jar", "log4j-core-2.14.1.jar") -> true
+///   ("lib?.so", "lib.so") -> false
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken so far
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// contree's directory in the user's cache, honoring $XDG_CACHE_HOME
+pub fn cache_dir() -> anyhow::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").context("HOME is not set")?).join(".cache"),
+    };
+    Ok(base.join("contree"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -28,4 +286,74 @@ mod tests {
         assert_eq!(split_path("/root/file"), ("/root", "file"));
         assert_eq!(split_path("/file"), ("", "file"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("./etc/passwd"), "etc/passwd");
+        assert_eq!(normalize_path("usr/lib/"), "usr/lib");
+        assert_eq!(normalize_path("./"), "");
+    }
+
+    #[test]
+    fn test_clean_path() {
+        let clean = clean_path("./usr//lib/../bin/");
+        assert_eq!(clean.path, "usr/bin");
+        assert!(clean.dot_dot && !clean.escapes && !clean.absolute);
+
+        let clean = clean_path("/etc/passwd");
+        assert_eq!(clean.path, "etc/passwd");
+        assert!(clean.absolute && !clean.dot_dot);
+
+        let clean = clean_path("usr/../../etc/shadow");
+        assert_eq!(clean.path, "etc/shadow");
+        assert!(clean.escapes);
+    }
+
+    #[test]
+    fn test_raw_byte_paths() {
+        assert!(matches!(path_from_bytes(b"etc/passwd"), Cow::Borrowed("etc/passwd")));
+        for bytes in [&b"caf\xe9/x"[..], b"\xff\xfe", "caf\u{e9}".as_bytes(), &b"\xf4\x8f\xbd\x81\xc3"[..]] {
+            let path = path_from_bytes(bytes);
+            assert_eq!(&*path_bytes(&path), bytes);
+        }
+        let path = path_from_bytes(b"caf\xe9/x");
+        assert_eq!(path, "caf\u{10ffe9}/x");
+        assert_eq!(display_path(&path), "caf\\xe9/x");
+        assert_eq!(split_path(&path), ("caf\u{10ffe9}", "x"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(os_path(&path).as_os_str().as_bytes(), b"caf\xe9/x");
+        }
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2024-01-02T03:04:05Z"), Some(1704164645));
+        assert_eq!(parse_timestamp("2024-01-02T05:04:05.123456789+02:00"), Some(1704164645));
+        assert_eq!(parse_timestamp("2024-02-29T00:00:00-01:00"), Some(1709168400));
+        assert_eq!(parse_timestamp("yesterday"), None);
+
+        assert_eq!(civil_date(1709168400), (2024, 2, 29));
+        assert_eq!(format_timestamp(1704164645), "2024-01-02 03:04:05 UTC");
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("libssl.so*", "libssl.so.3"));
+        assert!(glob_match("*.jar", "log4j-core-2.14.1.jar"));
+        assert!(glob_match("log4j-*-2.1?.?.jar", "log4j-core-2.14.1.jar"));
+        assert!(glob_match("usr/lib/*/libz.so.1", "usr/lib/x86_64-linux-gnu/libz.so.1"));
+        assert!(!glob_match("*.jar", "app.jar.bak"));
+        assert!(!glob_match("lib?.so", "lib.so"));
+    }
+}
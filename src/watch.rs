@@ -0,0 +1,80 @@
+//! Waiting for archives to change, for `--watch`
+//!
+//! Archives are polled rather than watched through the OS: `docker save -o`
+//! replaces the file, which many notification APIs report as the watched
+//! file going away. A change only counts once the file has stopped growing,
+//! so a half-written archive isn't parsed.
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often archives are looked at
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What tells one version of a file from the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// The stamp of the file at `path`, or None while it doesn't exist
+pub fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some(Stamp { len: metadata.len(), modified: metadata.modified().ok() })
+}
+
+/// Stamps of every path
+pub fn stamps(paths: &[PathBuf]) -> Vec<Option<Stamp>> {
+    paths.iter().map(|path| stamp(path)).collect()
+}
+
+/// Whether `current` is a settled change from `seen`: different, present,
+/// and the same as a poll ago (`previous`)
+pub fn settled(seen: &[Option<Stamp>], previous: &[Option<Stamp>], current: &[Option<Stamp>]) -> bool {
+    current != seen && current == previous && current.iter().all(Option::is_some)
+}
+
+/// Block until any of `paths` differs from its stamp in `seen` and has
+/// settled, then return the new stamps
+pub fn wait_for_change(paths: &[PathBuf], seen: &[Option<Stamp>], interval: Duration) -> Vec<Option<Stamp>> {
+    let mut previous = seen.to_vec();
+    loop {
+        thread::sleep(interval);
+        let current = stamps(paths);
+        if settled(seen, &previous, &current) {
+            return current;
+        }
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settled() {
+        let at = |secs, len| Some(Stamp { len, modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)) });
+        let seen = [at(1, 100)];
+        // Unchanged
+        assert!(!settled(&seen, &seen, &seen));
+        // Still being written
+        assert!(!settled(&seen, &[at(2, 50)], &[at(3, 80)]));
+        assert!(!settled(&seen, &seen, &[at(2, 50)]));
+        // Replaced: gone for a moment, then back
+        assert!(!settled(&seen, &[None], &[None]));
+        assert!(!settled(&seen, &[None], &[at(4, 120)]));
+        assert!(settled(&seen, &[at(4, 120)], &[at(4, 120)]));
+    }
+
+    #[test]
+    fn test_stamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        assert_eq!(stamp(&path), None);
+        std::fs::write(&path, b"layer").unwrap();
+        assert_eq!(stamp(&path).map(|stamp| stamp.len), Some(5));
+    }
+}
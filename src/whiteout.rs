@@ -1,8 +1,49 @@
+use std::collections::BTreeMap;
+
+use crate::tree::{Device, DeviceKind};
 use crate::utils;
 
 /// Docker whiteout handling for layer deletions
 const WHITEOUT_PREFIX: &str = ".wh.";
-const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Extended attributes overlayfs marks an opaque directory with (`user.` when
+/// mounted with `userxattr`)
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// How layers mark deleted files and opaque directories
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Either of the two, whichever each entry uses
+    #[default]
+    Auto,
+    /// `.wh.` marker files, as `docker save` and the OCI layout write them
+    Aufs,
+    /// 0:0 character devices and a `trusted.overlay.opaque` xattr, as in
+    /// layers exported straight from an overlayfs upper directory
+    Overlay,
+}
+
+impl Dialect {
+    /// `aufs` or `overlay`; anything else means auto
+    pub fn from_name(s: &str) -> Self {
+        match s {
+            "aufs" => Dialect::Aufs,
+            "overlay" => Dialect::Overlay,
+            _ => Dialect::Auto,
+        }
+    }
+
+    /// Whether `.wh.` files are markers rather than ordinary files
+    pub fn marker_files(self) -> bool {
+        self != Dialect::Overlay
+    }
+
+    /// Whether 0:0 character devices and opaque xattrs are markers
+    pub fn overlay(self) -> bool {
+        self != Dialect::Aufs
+    }
+}
 
 /// Check if a path is a Docker whiteout marker
 pub fn is_whiteout(path: &str) -> bool {
@@ -35,6 +76,24 @@ pub fn whiteout_target(path: &str) -> String {
     }
 }
 
+/// Check if a device is an overlayfs whiteout (a character device 0:0)
+pub fn is_device_whiteout(device: &Device) -> bool {
+    device.kind == DeviceKind::Char && device.major == 0 && device.minor == 0
+}
+
+/// Check if a directory's xattrs mark it opaque the overlayfs way
+pub fn has_opaque_xattr(xattrs: &BTreeMap<String, Vec<u8>>) -> bool {
+    OPAQUE_XATTRS.iter().any(|name| xattrs.get(*name).is_some_and(|value| value == b"y"))
+}
+
+/// Drop the opaque markers from a directory's xattrs, which describe the
+/// layer rather than the directory
+pub fn strip_opaque_xattrs(xattrs: &mut BTreeMap<String, Vec<u8>>) {
+    for name in OPAQUE_XATTRS {
+        xattrs.remove(name);
+    }
+}
+
 /// Get the directory path from an opaque whiteout marker
 /// Example: "foo/bar/.wh..wh..opq" -> "foo/bar"
 pub fn opaque_dir(path: &str) -> &str {
@@ -70,6 +129,24 @@ mod tests {
         assert_eq!(whiteout_target("a/b/c/.wh.test"), "a/b/c/test");
     }
 
+    #[test]
+    fn test_overlay_markers() {
+        let device = |kind, major, minor| Device { kind, major, minor };
+        assert!(is_device_whiteout(&device(DeviceKind::Char, 0, 0)));
+        assert!(!is_device_whiteout(&device(DeviceKind::Char, 1, 3)));
+        assert!(!is_device_whiteout(&device(DeviceKind::Block, 0, 0)));
+
+        let mut xattrs = BTreeMap::from([
+            ("trusted.overlay.opaque".to_string(), b"y".to_vec()),
+            ("user.mime_type".to_string(), b"text/plain".to_vec()),
+        ]);
+        assert!(has_opaque_xattr(&xattrs));
+        strip_opaque_xattrs(&mut xattrs);
+        assert!(!has_opaque_xattr(&xattrs));
+        assert_eq!(xattrs.len(), 1);
+        assert!(!has_opaque_xattr(&BTreeMap::from([("user.overlay.opaque".to_string(), b"n".to_vec())])));
+    }
+
     #[test]
     fn test_opaque_dir() {
         assert_eq!(opaque_dir("dir/.wh..wh..opq"), "dir");